@@ -0,0 +1,262 @@
+//! Admin UI descriptors.
+//!
+//! Each block module exposes `admin_descriptor()`, describing the node config
+//! keys it reads (type, default, help text) and, for blocks holding runtime
+//! state, where the admin panel can read it. `crate::admin_descriptors()`
+//! collects them for every block in `BLOCKS`. The JSON form is:
+//!
+//! ```json
+//! {
+//!   "fields": [
+//!     {"key": "max_requests", "kind": {"type": "integer"}, "default": "1000",
+//!      "help": "Requests allowed per client per window"},
+//!     {"key": "quota_period", "kind": {"type": "enum", "values": ["month", "day"]},
+//!      "default": "month", "help": "..."}
+//!   ],
+//!   "status": {"endpoints": [{"path": "/_ratelimit", "format": "json"}], "headers": []}
+//! }
+//! ```
+//!
+//! Defaults are given as the config string a block would read; a field
+//! without one is unset unless configured.
+//!
+//! A block's `BlockInfo::admin_ui` carries this JSON (see `ui`), with the
+//! fields its registration wrappers read added (see
+//! `AdminDescriptor::registered`); `admin_descriptors()` includes them too.
+//!
+//! Status endpoints expose traffic details (client keys, paths), so blocks
+//! serve them only to requests `status_allowed` admits: users holding
+//! `status_role` (default `admin`, as set by an auth block earlier in the
+//! chain), or anyone once `status_public` is set. Others get a 404.
+
+use serde::{Deserialize, Serialize};
+use wafer_run::{Context, Message};
+
+use crate::meta;
+
+/// Role that may read status endpoints when `status_role` is not set.
+pub const DEFAULT_STATUS_ROLE: &str = "admin";
+
+/// Whether the request may read the block's status endpoints: always with
+/// `status_public: true`, otherwise only for users with `status_role`.
+pub fn status_allowed(ctx: &dyn Context, msg: &Message) -> bool {
+    if ctx
+        .config_get("status_public")
+        .is_some_and(|s| s == "true" || s == "1")
+    {
+        return true;
+    }
+    let role = ctx
+        .config_get("status_role")
+        .filter(|r| !r.is_empty())
+        .unwrap_or(DEFAULT_STATUS_ROLE);
+    meta::user_roles(msg).contains(&role)
+}
+
+/// The value type of a config field, for picking an input widget.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "values", rename_all = "snake_case")]
+pub enum FieldKind {
+    String,
+    /// `true`/`false` (most blocks also accept `1`).
+    Bool,
+    Integer,
+    /// A decimal number such as a fraction.
+    Number,
+    /// A JSON document, as a string.
+    Json,
+    /// A comma-separated list.
+    List,
+    /// One of the given values.
+    Enum(Vec<String>),
+}
+
+impl FieldKind {
+    pub fn one_of(values: &[&str]) -> Self {
+        Self::Enum(values.iter().map(|v| v.to_string()).collect())
+    }
+}
+
+/// One node config key a block reads.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigField {
+    pub key: String,
+    pub kind: FieldKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    pub help: String,
+}
+
+/// An endpoint serving a block's runtime state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusEndpoint {
+    pub path: String,
+    /// `json` or `prometheus`.
+    pub format: String,
+}
+
+/// Where a stateful block reports its state: endpoints it serves and
+/// response headers it stamps.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusDescriptor {
+    #[serde(default)]
+    pub endpoints: Vec<StatusEndpoint>,
+    #[serde(default)]
+    pub headers: Vec<String>,
+}
+
+impl StatusDescriptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn endpoint(mut self, path: &str, format: &str) -> Self {
+        self.endpoints.push(StatusEndpoint {
+            path: path.to_string(),
+            format: format.to_string(),
+        });
+        self
+    }
+
+    pub fn header(mut self, name: &str) -> Self {
+        self.headers.push(name.to_string());
+        self
+    }
+}
+
+/// The admin UI payload of a block.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdminDescriptor {
+    #[serde(default)]
+    pub fields: Vec<ConfigField>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<StatusDescriptor>,
+}
+
+impl AdminDescriptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a field; an empty `default` means none.
+    pub fn field(mut self, key: &str, kind: FieldKind, default: &str, help: &str) -> Self {
+        self.fields.push(ConfigField {
+            key: key.to_string(),
+            kind,
+            default: (!default.is_empty()).then(|| default.to_string()),
+            help: help.to_string(),
+        });
+        self
+    }
+
+    /// Add the `skip_paths` field honored through `path::is_skipped`.
+    pub fn skip_paths(self) -> Self {
+        self.field(
+            "skip_paths",
+            FieldKind::List,
+            "",
+            "Path prefixes this block passes through untouched",
+        )
+    }
+
+    pub fn status(mut self, status: StatusDescriptor) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Add the `status_public` and `status_role` fields read by `status_allowed`.
+    pub fn status_access(self) -> Self {
+        self.field(
+            "status_public",
+            FieldKind::Bool,
+            "false",
+            "Serve the status endpoints to everyone, not only `status_role`",
+        )
+        .field(
+            "status_role",
+            FieldKind::String,
+            DEFAULT_STATUS_ROLE,
+            "Role allowed to read the status endpoints",
+        )
+    }
+
+    /// Add the fields every block registered through `blocks::register_as`
+    /// honors: `debug_trace` and `messages_file` (read by `TracedBlock`) and
+    /// `error_page_5xx` (read by `hooks::ErrorPages`).
+    pub fn registered(self) -> Self {
+        self.field(
+            "debug_trace",
+            FieldKind::Bool,
+            "false",
+            "Name the block in X-Wafer-Block on its error responses",
+        )
+        .field(
+            "messages_file",
+            FieldKind::String,
+            "",
+            "Message catalog for localized error messages from here on",
+        )
+        .field(
+            "error_page_5xx",
+            FieldKind::String,
+            "",
+            "HTML file sent in place of 5xx bodies to browsers",
+        )
+    }
+
+    /// Parse and validate the JSON form.
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let d: Self =
+            serde_json::from_str(json).map_err(|e| format!("invalid admin descriptor: {}", e))?;
+        d.validate()?;
+        Ok(d)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("descriptor serializes")
+    }
+
+    /// Check that keys are unique and non-empty, every field has help text,
+    /// and defaults are valid for their kind.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut seen = std::collections::HashSet::new();
+        for f in &self.fields {
+            if f.key.is_empty() {
+                return Err("field with empty key".to_string());
+            }
+            if !seen.insert(f.key.as_str()) {
+                return Err(format!("{}: duplicate field", f.key));
+            }
+            if f.help.trim().is_empty() {
+                return Err(format!("{}: missing help text", f.key));
+            }
+            let default = match &f.default {
+                Some(d) => d,
+                None => continue,
+            };
+            let valid = match &f.kind {
+                FieldKind::Bool => default == "true" || default == "false",
+                FieldKind::Integer => default.parse::<i64>().is_ok(),
+                FieldKind::Number => default.parse::<f64>().is_ok(),
+                FieldKind::Json => serde_json::from_str::<serde_json::Value>(default).is_ok(),
+                FieldKind::Enum(values) => values.contains(default),
+                FieldKind::String | FieldKind::List => true,
+            };
+            if !valid {
+                return Err(format!("{}: invalid default {:?}", f.key, default));
+            }
+        }
+        if let Some(status) = &self.status {
+            if let Some(e) = status.endpoints.iter().find(|e| !e.path.starts_with('/')) {
+                return Err(format!("status endpoint {:?} must start with '/'", e.path));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A block's `BlockInfo::admin_ui`: `descriptor` with the registration
+/// wrappers' fields, as JSON.
+pub fn ui(descriptor: AdminDescriptor) -> Option<serde_json::Value> {
+    Some(descriptor.registered().to_json())
+}
@@ -1,37 +1,356 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use wafer_run::services::database::{DatabaseService, Filter, FilterOp, ListOptions};
 use wafer_run::*;
 
+use super::hooks;
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::clock::{self, Clock};
+use crate::errors::{self, CoreError, Outcome};
+use crate::http::{self, HttpClient};
+use crate::meta;
+use crate::net;
+use crate::path;
+
 /// AuthBlock validates authentication from HTTP request metadata.
 /// Supports JWT Bearer tokens, API keys (sb_ prefix), and httpOnly cookies.
-pub struct AuthBlock;
+///
+/// With `auth_bind_tokens` set, JWTs must carry an `fp` claim matching the
+/// requesting client's fingerprint (see `TokenBinding`); OAuthBlock embeds
+/// it at issuance when its node has the same settings. The older
+/// `bind_fingerprint: true` is the same check in strict mode, over the User-Agent
+/// and device cookie unless `fingerprint_components` says otherwise. With
+/// `clear_invalid_cookie: true`, a 401 for a cookie token also clears the
+/// cookie, using the attributes described on `CookieAttributes`.
+///
+/// `degraded_mode` selects what happens when the services a token needs are
+/// unavailable (see `DegradedMode`). At most `max_roles` (default 100) roles
+/// are taken from a JWT.
+///
+/// With `replay_protection: true`, API key requests must also send
+/// `X-Timestamp` (Unix seconds, within `replay_window_secs` of now, default
+/// 300) and an `X-Nonce` not seen for that key's user within the window.
+/// Stale timestamps and reused nonces are rejected with 401. Nonces are
+/// remembered until their timestamps could no longer be accepted; when
+/// 100,000 live nonces are held, new requests are refused with 503
+/// `replay_cache_full` (and `Retry-After`) rather than forgetting a live
+/// nonce, which would let it be replayed.
+///
+/// API key `expires_at` may be RFC 3339, Unix epoch seconds or
+/// milliseconds, or `api_key_expiry_format` (a chrono format read as UTC,
+/// default `%Y-%m-%d %H:%M:%S`). Unparseable values are logged and treated
+/// as not expired, or as expired with `api_key_expiry_unparseable: "expired"`.
+///
+/// JWTs are verified by the crypto service unless `auth_issuers` is set: a
+/// JSON object mapping each trusted `iss` to its verification settings (see
+/// `IssuerConfig`), e.g.
+/// `{"https://idp.example.com": {"jwks_url": "https://idp.example.com/jwks.json", "audience": "api"}}`.
+/// The token's `iss` selects the entry and its `kid` the JWKS key; tokens from
+/// issuers not listed are rejected. A JWKS is refetched after five minutes;
+/// if that fails, the cached key keeps verifying tokens for up to an hour
+/// longer (the failure is logged and retried at most every 30 seconds).
+///
+/// Requests under `skip_paths` (see `path::is_skipped`) pass unauthenticated;
+/// every path listed there is public.
+///
+/// `login_path` (e.g. `/auth/login`) turns on password login there (see
+/// `login`), with per-account lockout by the block's `LockoutTracker`:
+/// failed logins escalate the lock and a successful one clears it. Token
+/// and API key requests never touch the counters; with `lockout_enforce:
+/// true` they are refused while their user id or email is locked.
+///
+/// Token, API key and replay timestamps are checked against the block's
+/// `Clock` (see `with_clock`). Tokens verified by the crypto service are
+/// checked by that service.
+///
+/// `auth_expired_grace_secs` (default 0) eases token refresh: an
+/// `auth_issuers` token expired less than that long ago still authenticates
+/// reads, tagged with `auth.token_expired: true` meta, while writes (per
+/// `meta::resolved_action`) get 401 `token_expired` so the client refreshes
+/// first. Later handlers can use the meta to refuse other requests. Tokens
+/// verified by the crypto service get no grace.
+///
+/// An app authenticating against its own backend (LDAP, an identity API)
+/// supplies an `IdentityResolver` and registers the block under the
+/// canonical name in place of the default instance:
+///
+/// ```ignore
+/// blocks::register_as(
+///     w,
+///     "@wafer/auth",
+///     Arc::new(AuthBlock::new_with_resolver(Arc::new(LdapResolver::new(...)))),
+/// );
+/// wafer_core::register_blocks(w, &["@wafer/iam", "@wafer/web"])?;
+/// ```
+pub struct AuthBlock {
+    lockout: Arc<LockoutTracker>,
+    nonces: NonceCache,
+    http: Option<Arc<dyn HttpClient>>,
+    /// Parsed `auth_issuers`, keyed by the raw config it was parsed from.
+    issuers: Mutex<Option<(String, Arc<HashMap<String, IssuerConfig>>)>>,
+    /// Fetched key sets by JWKS URL.
+    jwks: Mutex<HashMap<String, CachedJwks>>,
+    clock: Arc<dyn Clock>,
+    resolver: Option<Arc<dyn IdentityResolver>>,
+}
+
+/// A resolved identity: user id, email (may be empty) and roles.
+pub type Identity = (String, String, Vec<String>);
+
+/// Resolves credentials to identities for AuthBlock, replacing its
+/// database and crypto service lookups.
+///
+/// AuthBlock still extracts the token (cookie or `Bearer`), enforces
+/// `replay_protection`, `lockout_enforce` and `max_roles`, and writes the
+/// `auth.*` meta. Errors are sent as returned, so use 401 `Unauthorized`
+/// for bad credentials and 503 `Unavailable` when the backend is down.
+pub trait IdentityResolver: Send + Sync {
+    /// Resolve a bearer token (anything without the `sb_` API key prefix).
+    fn resolve_bearer(&self, ctx: &dyn Context, token: &str) -> Result<Identity, CoreError>;
+
+    /// Resolve an `sb_` API key. By default API keys are rejected.
+    fn resolve_api_key(&self, _ctx: &dyn Context, _key: &str) -> Result<Identity, CoreError> {
+        Err(CoreError::Unauthorized(
+            "API keys are not accepted".to_string(),
+        ))
+    }
+}
 
 impl AuthBlock {
     pub fn new() -> Self {
-        Self
+        Self::with_lockout(Arc::new(LockoutTracker::new()))
+    }
+
+    /// Create an AuthBlock sharing an existing lockout tracker (e.g. with a login handler).
+    pub fn with_lockout(lockout: Arc<LockoutTracker>) -> Self {
+        Self {
+            lockout,
+            nonces: NonceCache::new(),
+            http: http::default_client(),
+            issuers: Mutex::new(None),
+            jwks: Mutex::new(HashMap::new()),
+            clock: clock::system(),
+            resolver: None,
+        }
+    }
+
+    /// Create an AuthBlock resolving credentials with `resolver` instead of
+    /// the database and crypto services.
+    pub fn new_with_resolver(resolver: Arc<dyn IdentityResolver>) -> Self {
+        Self {
+            resolver: Some(resolver),
+            ..Self::new()
+        }
+    }
+
+    /// Resolve `token` with the custom resolver, applying `max_roles`.
+    fn resolve_custom(
+        ctx: &dyn Context,
+        msg: &mut Message,
+        resolver: &dyn IdentityResolver,
+        token: &str,
+        is_api_key: bool,
+    ) -> std::result::Result<Identity, Result_> {
+        let resolved = if is_api_key {
+            resolver.resolve_api_key(ctx, token)
+        } else {
+            resolver.resolve_bearer(ctx, token)
+        };
+        let (user_id, email, roles) = resolved.map_err(|e| e.respond(msg))?;
+        if user_id.is_empty() {
+            return Err(auth_error(msg, 401, "Token missing user_id"));
+        }
+        let max_roles = ctx
+            .config_get("max_roles")
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_MAX_ROLES);
+        Ok((user_id, email, cap_roles(roles.into_iter(), max_roles)))
+    }
+
+    /// Read the current time from `clock` instead of the system clock. The
+    /// lockout tracker has its own (`LockoutTracker::with_clock`).
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Use `http` to fetch issuer JWKS documents.
+    pub fn with_http_client(mut self, http: Arc<dyn HttpClient>) -> Self {
+        self.http = Some(http);
+        self
+    }
+
+    /// The lockout tracker consulted by this block.
+    pub fn lockout(&self) -> &Arc<LockoutTracker> {
+        &self.lockout
+    }
+
+    /// Password login at `login_path`: `POST {"email", "password"}` checked
+    /// against the `password_hash` of the `auth_users` row with that email.
+    ///
+    /// Every failure, unknown email or wrong password alike, is recorded
+    /// against the lowercased email in the lockout tracker and answered with
+    /// the same 401; once locked, attempts get 429 with `Retry-After` before
+    /// any password is checked. Success clears the failures and answers 200
+    /// with `{"token"}` and the auth cookie.
+    fn login(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        if meta::http_method(msg) != meta::Method::Post {
+            return errors::method_not_allowed(msg, &["POST"]);
+        }
+        let body: serde_json::Value = match serde_json::from_slice(&msg.data) {
+            Ok(v) => v,
+            Err(_) => {
+                return CoreError::BadRequest("Expected a JSON login body".to_string()).respond(msg)
+            }
+        };
+        let field = |name: &str| body.get(name).and_then(|v| v.as_str()).unwrap_or("");
+        let (email, password) = (field("email").trim().to_string(), field("password"));
+        if email.is_empty() || password.is_empty() {
+            return CoreError::BadRequest("email and password are required".to_string())
+                .respond(msg);
+        }
+        let identifier = email.to_ascii_lowercase();
+        if let Some(remaining) = self.lockout.locked_for(&identifier) {
+            return lockout_error(msg, remaining);
+        }
+
+        let services = ctx.services();
+        let (db, crypto) = match (
+            services.and_then(|s| s.database.as_ref()),
+            services.and_then(|s| s.crypto.as_ref()),
+        ) {
+            (Some(db), Some(crypto)) => (db, crypto),
+            _ => {
+                return CoreError::Unavailable("Login is temporarily unavailable".to_string())
+                    .respond(msg)
+            }
+        };
+
+        let opts = ListOptions {
+            filters: vec![Filter {
+                field: "email".to_string(),
+                operator: FilterOp::Equal,
+                value: serde_json::Value::String(email.clone()),
+            }],
+            limit: 1,
+            ..Default::default()
+        };
+        let user = match db.list("auth_users", &opts) {
+            Ok(r) => r.records.into_iter().next(),
+            Err(e) => {
+                tracing::warn!("AuthBlock: login lookup failed: {}", e);
+                return CoreError::Unavailable("Login is temporarily unavailable".to_string())
+                    .respond(msg);
+            }
+        };
+        let verified = user.as_ref().is_some_and(|u| {
+            u.data
+                .get("password_hash")
+                .and_then(|v| v.as_str())
+                .is_some_and(|hash| crypto.compare_hash(password, hash).is_ok())
+        });
+        let user = match user {
+            Some(user) if verified => user,
+            _ => {
+                if let Some(lock) = self.lockout.record_failure(&identifier) {
+                    return lockout_error(msg, lock);
+                }
+                return auth_error(msg, 401, "Invalid email or password");
+            }
+        };
+        self.lockout.record_success(&identifier);
+
+        let ttl_secs = ctx
+            .config_get("token_ttl_seconds")
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(86400);
+        let roles = user_roles(db.as_ref(), &user.id);
+        let mut claims = HashMap::new();
+        claims.insert("user_id".to_string(), serde_json::Value::String(user.id));
+        claims.insert("email".to_string(), serde_json::Value::String(email));
+        claims.insert("roles".to_string(), serde_json::Value::from(roles));
+        if let Some(binding) = TokenBinding::from_config(ctx) {
+            binding.embed(msg, &mut claims);
+        }
+        let token = match crypto.sign(claims, Duration::from_secs(ttl_secs)) {
+            Ok(t) => t,
+            Err(_) => return auth_error(msg, 500, "Failed to issue token"),
+        };
+        let mut m = msg.clone();
+        meta::set_resp_header(
+            &mut m,
+            "Set-Cookie",
+            &CookieAttributes::from_config(ctx).set(AUTH_COOKIE, &token, Some(ttl_secs)),
+        );
+        json_respond(m, 200, &serde_json::json!({ "token": token }))
     }
 
     /// Extract auth token from Cookie header or Authorization header.
     fn extract_token(msg: &Message) -> Option<String> {
         // 1. Try httpOnly cookie
-        let cookie_token = msg.cookie("auth_token");
+        let cookie_token = msg.cookie(AUTH_COOKIE);
         if !cookie_token.is_empty() {
             return Some(cookie_token.to_string());
         }
 
         // 2. Try Authorization header
-        let auth_header = msg.header("Authorization").to_string();
-        if auth_header.is_empty() {
-            return None;
-        }
+        bearer_token(msg.header("Authorization")).map(|t| t.to_string())
+    }
 
-        if let Some(token) = auth_header.strip_prefix("Bearer ") {
-            let token = token.trim();
-            if !token.is_empty() {
-                return Some(token.to_string());
+    /// Enforce `replay_protection` for an authenticated API key request.
+    fn check_replay(
+        &self,
+        ctx: &dyn Context,
+        msg: &mut Message,
+        user_id: &str,
+    ) -> std::result::Result<(), Result_> {
+        let window = ctx
+            .config_get("replay_window_secs")
+            .and_then(|s| s.parse::<i64>().ok())
+            .unwrap_or(300);
+        let timestamp = msg.header("X-Timestamp").trim().parse::<i64>().ok();
+        let nonce = msg.header("X-Nonce").trim().to_string();
+        let now = self.clock.now_utc().timestamp();
+        let fresh = timestamp.is_some_and(|ts| (now - ts).abs() <= window);
+        if !fresh {
+            return Err(auth_error(
+                msg,
+                401,
+                "Request timestamp is missing or stale",
+            ));
+        }
+        if nonce.is_empty() {
+            return Err(auth_error(msg, 401, "Request nonce is missing"));
+        }
+        // A nonce only has to stay unique while its timestamp would be accepted
+        let ttl = Duration::from_secs(window.max(0) as u64 * 2);
+        let key = format!("{}:{}", user_id, nonce);
+        match self.nonces.insert(&key, ttl, self.clock.now_instant()) {
+            NonceCheck::Fresh => Ok(()),
+            NonceCheck::Replayed => {
+                Err(auth_error(msg, 401, "Request nonce has already been used"))
+            }
+            NonceCheck::Full { retry_after } => {
+                tracing::warn!("AuthBlock: nonce cache full, refusing replay-protected request");
+                meta::set_resp_header(
+                    msg,
+                    "Retry-After",
+                    &retry_after.as_secs().max(1).to_string(),
+                );
+                Err(CoreError::custom(
+                    503,
+                    "replay_cache_full",
+                    "Too many recent requests to check for replays; retry later",
+                )
+                .respond(msg))
             }
         }
-
-        None
     }
 
     /// Check if token is an API key (sb_ prefix).
@@ -41,6 +360,7 @@ impl AuthBlock {
 
     /// Validate API key against database.
     fn validate_api_key(
+        &self,
         ctx: &dyn Context,
         msg: &mut Message,
         token: &str,
@@ -99,12 +419,28 @@ impl AuthBlock {
 
         // Check if expired
         if let Some(expires) = key_record.data.get("expires_at") {
-            if let Some(expires_str) = expires.as_str() {
-                if !expires_str.is_empty() {
-                    if let Ok(exp_time) = chrono::DateTime::parse_from_rfc3339(expires_str) {
-                        if exp_time < chrono::Utc::now() {
-                            return Err(auth_error(msg, 401, "API key has expired"));
+            let format = ctx
+                .config_get("api_key_expiry_format")
+                .unwrap_or(DEFAULT_EXPIRY_FORMAT);
+            match parse_expiry(expires, format) {
+                Ok(Some(exp_time)) if exp_time < self.clock.now_utc() => {
+                    return Err(auth_error(msg, 401, "API key has expired"));
+                }
+                Ok(_) => {}
+                Err(()) => {
+                    let treat_expired =
+                        ctx.config_get("api_key_expiry_unparseable") == Some("expired");
+                    tracing::warn!(
+                        "AuthBlock: unparseable API key expires_at {}; treating as {}",
+                        expires,
+                        if treat_expired {
+                            "expired"
+                        } else {
+                            "not expired"
                         }
+                    );
+                    if treat_expired {
+                        return Err(auth_error(msg, 401, "API key has expired"));
                     }
                 }
             }
@@ -133,58 +469,230 @@ impl AuthBlock {
             Err(_) => String::new(),
         };
 
-        // Get user roles from iam_roles table
-        let role_filters = vec![wafer_run::services::database::Filter {
-            field: "user_id".to_string(),
-            operator: wafer_run::services::database::FilterOp::Equal,
-            value: serde_json::Value::String(user_id.clone()),
-        }];
+        let roles = user_roles(db.as_ref(), &user_id);
 
-        let role_opts = wafer_run::services::database::ListOptions {
-            filters: role_filters,
-            ..Default::default()
-        };
+        Ok((user_id, email, roles))
+    }
 
-        let roles: Vec<String> = match db.list("iam_user_roles", &role_opts) {
-            Ok(r) => r
-                .records
-                .iter()
-                .filter_map(|rec| rec.data.get("role").and_then(|v| v.as_str()).map(|s| s.to_string()))
-                .collect(),
-            Err(_) => Vec::new(),
+    /// The parsed `auth_issuers` config, if set.
+    fn issuers(
+        &self,
+        ctx: &dyn Context,
+    ) -> std::result::Result<Option<Arc<HashMap<String, IssuerConfig>>>, String> {
+        let raw = ctx.config_get("auth_issuers").unwrap_or("").trim();
+        if raw.is_empty() {
+            return Ok(None);
+        }
+        let mut cached = self.issuers.lock();
+        if let Some((source, issuers)) = cached.as_ref() {
+            if source == raw {
+                return Ok(Some(issuers.clone()));
+            }
+        }
+        let issuers: HashMap<String, IssuerConfig> =
+            serde_json::from_str(raw).map_err(|e| e.to_string())?;
+        let issuers = Arc::new(issuers);
+        *cached = Some((raw.to_string(), issuers.clone()));
+        Ok(Some(issuers))
+    }
+
+    /// Verify a JWT against the issuer it names, returning its claims or the
+    /// status and message to reject it with.
+    /// A token expired less than `grace` seconds ago is accepted and
+    /// returned as expired (`true`).
+    fn verify_issuer_token(
+        &self,
+        issuers: &HashMap<String, IssuerConfig>,
+        token: &str,
+        grace: i64,
+    ) -> std::result::Result<(serde_json::Value, bool), (u16, &'static str)> {
+        let invalid = (401, "Invalid or expired token");
+        let header = jsonwebtoken::decode_header(token).map_err(|_| invalid)?;
+        let iss = unverified_issuer(token).ok_or(invalid)?;
+        let issuer = issuers
+            .get(&iss)
+            .ok_or((401, "Token issuer is not trusted"))?;
+        let algorithms = issuer.algorithms();
+        if !algorithms.contains(&header.alg) {
+            return Err((401, "Token algorithm is not allowed"));
+        }
+
+        let key = match header.alg {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 if !issuer.secret.is_empty() => {
+                DecodingKey::from_secret(issuer.secret.as_bytes())
+            }
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                return Err((500, "Issuer has no secret configured"));
+            }
+            alg if !issuer.public_key_pem.is_empty() => {
+                let pem = issuer.public_key_pem.as_bytes();
+                match alg {
+                    Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem),
+                    Algorithm::EdDSA => DecodingKey::from_ed_pem(pem),
+                    _ => DecodingKey::from_rsa_pem(pem),
+                }
+                .map_err(|_| (500, "Issuer public key is invalid"))?
+            }
+            _ if !issuer.jwks_url.is_empty() => {
+                self.jwks_key(&issuer.jwks_url, header.kid.as_deref())?
+            }
+            _ => return Err((500, "Issuer has no verification key configured")),
         };
 
-        Ok((user_id, email, roles))
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = algorithms;
+        // Expiry is checked against the block's clock below
+        validation.validate_exp = false;
+        validation.set_issuer(&[&iss]);
+        let audience = issuer.audience();
+        if audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&audience);
+        }
+        let claims = jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|_| invalid)?;
+        let exp = claims.get("exp").and_then(|v| v.as_f64()).ok_or(invalid)?;
+        let expires = exp as i64 + (validation.leeway as i64);
+        let now = self.clock.now_utc().timestamp();
+        if expires + grace < now {
+            return Err(invalid);
+        }
+        Ok((claims, expires < now))
+    }
+
+    /// The key `kid` from the JWKS at `url`, fetching the set when it is not
+    /// cached, stale, or lacks the key (at most every `JWKS_MIN_REFRESH`).
+    /// While refetches fail, a stale key is used for up to `JWKS_STALE_GRACE`.
+    fn jwks_key(
+        &self,
+        url: &str,
+        kid: Option<&str>,
+    ) -> std::result::Result<DecodingKey, (u16, &'static str)> {
+        let unknown = (401, "Token signing key is unknown");
+        let find = |set: &JwkSet| match kid {
+            Some(kid) => set.find(kid).cloned(),
+            // Without a kid, only an unambiguous set will do
+            None if set.keys.len() == 1 => set.keys.first().cloned(),
+            None => None,
+        };
+        let unavailable = (503, "Token signing keys are unavailable");
+        let now = self.clock.now_instant();
+        let cached = self.jwks.lock().get(url).map(|entry| {
+            let age = now.saturating_duration_since(entry.fetched);
+            let since_attempt = now.saturating_duration_since(entry.attempted);
+            (age, since_attempt, find(&entry.set))
+        });
+        // A key past its TTL stands in while refetches fail, within the grace
+        let stale = match &cached {
+            Some((age, _, Some(jwk))) if *age < JWKS_TTL + JWKS_STALE_GRACE => Some(jwk.clone()),
+            _ => None,
+        };
+        let jwk = match cached {
+            Some((age, _, Some(jwk))) if age < JWKS_TTL => jwk,
+            // Fetched, or tried to, moments ago: don't ask again yet
+            Some((_, since_attempt, found)) if since_attempt < JWKS_MIN_REFRESH => match stale {
+                Some(jwk) => jwk,
+                None if found.is_none() => return Err(unknown),
+                None => return Err(unavailable),
+            },
+            _ => {
+                if let Some(entry) = self.jwks.lock().get_mut(url) {
+                    entry.attempted = now;
+                }
+                let http = self
+                    .http
+                    .as_ref()
+                    .ok_or((500, "No HTTP client configured for JWKS"))?;
+                let fetched = http
+                    .get_json(url, "")
+                    .and_then(|v| serde_json::from_value::<JwkSet>(v).map_err(|e| e.to_string()));
+                match fetched {
+                    Ok(set) => {
+                        let jwk = find(&set);
+                        self.jwks.lock().insert(
+                            url.to_string(),
+                            CachedJwks {
+                                fetched: now,
+                                attempted: now,
+                                set,
+                            },
+                        );
+                        jwk.ok_or(unknown)?
+                    }
+                    Err(e) => match stale {
+                        Some(jwk) => {
+                            tracing::warn!(
+                                "AuthBlock: failed to refresh JWKS {}: {}; using the cached key",
+                                url,
+                                e
+                            );
+                            jwk
+                        }
+                        None => {
+                            tracing::warn!("AuthBlock: failed to fetch JWKS {}: {}", url, e);
+                            return Err(unavailable);
+                        }
+                    },
+                }
+            }
+        };
+        DecodingKey::from_jwk(&jwk).map_err(|_| unknown)
     }
 
     /// Validate JWT token.
     fn validate_jwt(
+        &self,
         ctx: &dyn Context,
         msg: &mut Message,
         token: &str,
     ) -> std::result::Result<(String, String, Vec<String>), Result_> {
-        let services = match ctx.services() {
-            Some(s) => s,
-            None => return Err(auth_error(msg, 500, "Auth services unavailable")),
+        let issuers = match self.issuers(ctx) {
+            Ok(issuers) => issuers,
+            Err(e) => {
+                tracing::warn!("AuthBlock: invalid auth_issuers config: {}", e);
+                return Err(auth_error(msg, 500, "Auth issuers are misconfigured"));
+            }
         };
 
-        let crypto = match &services.crypto {
-            Some(c) => c,
-            None => return Err(auth_error(msg, 500, "Crypto service unavailable")),
-        };
+        let grace = ctx
+            .config_get("auth_expired_grace_secs")
+            .and_then(|s| s.parse::<i64>().ok())
+            .unwrap_or(0)
+            .max(0);
+        let (claims, expired) = match issuers {
+            Some(issuers) => match self.verify_issuer_token(&issuers, token, grace) {
+                Ok(verified) => verified,
+                Err((status, message)) => return Err(auth_error(msg, status, message)),
+            },
+            None => {
+                let services = match ctx.services() {
+                    Some(s) => s,
+                    None => return Err(auth_error(msg, 500, "Auth services unavailable")),
+                };
 
-        // Verify JWT signature and extract claims
-        let claims_map = match crypto.verify(token) {
-            Ok(data) => data,
-            Err(_) => return Err(auth_error(msg, 401, "Invalid or expired token")),
-        };
+                let crypto = match &services.crypto {
+                    Some(c) => c,
+                    None => return Err(auth_error(msg, 500, "Crypto service unavailable")),
+                };
 
-        // Convert claims HashMap to serde_json::Value for uniform access
-        let claims = serde_json::Value::Object(
-            claims_map
-                .into_iter()
-                .collect::<serde_json::Map<String, serde_json::Value>>(),
-        );
+                // Verify JWT signature and extract claims
+                let claims_map = match crypto.verify(token) {
+                    Ok(data) => data,
+                    Err(_) => return Err(auth_error(msg, 401, "Invalid or expired token")),
+                };
+
+                // Convert claims HashMap to serde_json::Value for uniform access
+                let claims = serde_json::Value::Object(
+                    claims_map
+                        .into_iter()
+                        .collect::<serde_json::Map<String, serde_json::Value>>(),
+                );
+                // The service rejects expired tokens itself, so no grace applies
+                (claims, false)
+            }
+        };
 
         let user_id = claims
             .get("user_id")
@@ -199,28 +707,506 @@ impl AuthBlock {
             .unwrap_or("")
             .to_string();
 
-        let roles: Vec<String> = if let Some(roles_arr) = claims.get("roles") {
-            if let Some(arr) = roles_arr.as_array() {
-                arr.iter()
-                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                    .collect()
-            } else if let Some(s) = roles_arr.as_str() {
-                s.split(',').map(|r| r.trim().to_string()).collect()
-            } else {
-                Vec::new()
+        // Bound the roles a token can carry so oversized claims can't bloat meta
+        let max_roles = ctx
+            .config_get("max_roles")
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_MAX_ROLES);
+        let roles: Vec<String> = match claims.get("roles") {
+            Some(serde_json::Value::Array(arr)) => cap_roles(
+                arr.iter().filter_map(|v| v.as_str().map(|s| s.to_string())),
+                max_roles,
+            ),
+            Some(serde_json::Value::String(s)) => {
+                cap_roles(s.split(',').map(|r| r.trim().to_string()), max_roles)
             }
-        } else {
-            Vec::new()
+            _ => Vec::new(),
         };
 
         if user_id.is_empty() {
             return Err(auth_error(msg, 401, "Token missing user_id"));
         }
 
+        // Optionally require the token to be bound to this client
+        if let Some(binding) = TokenBinding::from_config(ctx) {
+            let bound = claims
+                .get(FINGERPRINT_CLAIM)
+                .and_then(|v| v.as_str())
+                .unwrap_or("");
+            let current = binding.fingerprint(msg);
+            if !bound.is_empty() && current.as_deref() == Some(bound) {
+                msg.set_meta(meta::AUTH_TOKEN_BINDING, "ok");
+            } else {
+                tracing::warn!(
+                    "AuthBlock: token binding mismatch for user {} ({})",
+                    user_id,
+                    match current {
+                        _ if bound.is_empty() => "no fp claim",
+                        None => "client address unparseable",
+                        Some(_) => "fingerprint differs",
+                    }
+                );
+                msg.set_meta(meta::AUTH_TOKEN_BINDING, "mismatch");
+                if binding.mode == BindingMode::Strict {
+                    return Err(CoreError::custom(
+                        401,
+                        "token_binding_failed",
+                        "Token is not valid for this client",
+                    )
+                    .respond(msg));
+                }
+            }
+        }
+
+        // A token in its expiry grace period may only read
+        if expired {
+            if meta::is_write_action(&meta::resolved_action(ctx, msg)) {
+                return Err(CoreError::custom(
+                    401,
+                    "token_expired",
+                    "Token has expired; refresh it before writing",
+                )
+                .respond(msg));
+            }
+            msg.set_meta(meta::AUTH_TOKEN_EXPIRED, "true");
+        }
+
         Ok((user_id, email, roles))
     }
 }
 
+/// How long a fetched JWKS is used before it is fetched again.
+const JWKS_TTL: Duration = Duration::from_secs(300);
+
+/// Minimum time between JWKS fetches, for an unknown `kid` or after a
+/// failed refresh.
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(30);
+
+/// How long past `JWKS_TTL` a cached key is still used while refetches fail.
+const JWKS_STALE_GRACE: Duration = Duration::from_secs(3600);
+
+/// A fetched JWKS, with when it was fetched and last attempted.
+struct CachedJwks {
+    fetched: Instant,
+    attempted: Instant,
+    set: JwkSet,
+}
+
+/// Verification settings for one `auth_issuers` entry.
+///
+/// HMAC tokens (`HS*`) are verified with `secret`; asymmetric ones with
+/// `public_key_pem` if set, otherwise with the key named by the token's `kid`
+/// in the JWKS at `jwks_url`. `algorithms` lists the accepted `alg` values
+/// (default `["HS256"]` with a secret, `["RS256"]` otherwise). `audience` is
+/// a string or list the `aud` claim must match; when unset, `aud` is not
+/// checked.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct IssuerConfig {
+    pub secret: String,
+    pub public_key_pem: String,
+    pub jwks_url: String,
+    pub algorithms: Vec<String>,
+    pub audience: serde_json::Value,
+}
+
+impl IssuerConfig {
+    fn algorithms(&self) -> Vec<Algorithm> {
+        if self.algorithms.is_empty() {
+            let default = if self.secret.is_empty() {
+                Algorithm::RS256
+            } else {
+                Algorithm::HS256
+            };
+            return vec![default];
+        }
+        self.algorithms
+            .iter()
+            .filter_map(|a| match a.parse::<Algorithm>() {
+                Ok(alg) => Some(alg),
+                Err(_) => {
+                    tracing::warn!("AuthBlock: unknown issuer algorithm {:?} ignored", a);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn audience(&self) -> Vec<String> {
+        match &self.audience {
+            serde_json::Value::String(s) => vec![s.clone()],
+            serde_json::Value::Array(arr) => arr
+                .iter()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// The `iss` claim of a JWT, read without verifying it, to pick the issuer
+/// whose key will verify the token.
+fn unverified_issuer(token: &str) -> Option<String> {
+    let payload = token.split('.').nth(1)?;
+    let bytes = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&bytes).ok()?;
+    claims.get("iss")?.as_str().map(|s| s.to_string())
+}
+
+/// Fallback `expires_at` format (UTC) when `api_key_expiry_format` is unset.
+pub const DEFAULT_EXPIRY_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Epoch values at or above this are milliseconds: as seconds they would
+/// fall past the year 5000, as milliseconds they are after early 1973.
+const EPOCH_MILLIS_FROM: i64 = 100_000_000_000;
+
+/// Epoch values at or above this are unparseable, being past the year 5000
+/// even as milliseconds.
+const EPOCH_MAX_MILLIS: i64 = EPOCH_MILLIS_FROM * 1000;
+
+/// Parse an API key `expires_at` value: RFC 3339, Unix epoch seconds or
+/// milliseconds (as a number or numeric string), or `format` read as UTC.
+/// `Ok(None)` means the key never expires (null or empty); `Err` means the
+/// value is unparseable.
+fn parse_expiry(
+    value: &serde_json::Value,
+    format: &str,
+) -> std::result::Result<Option<chrono::DateTime<chrono::Utc>>, ()> {
+    let from_epoch = |n: i64| match n.unsigned_abs() {
+        m if m >= EPOCH_MAX_MILLIS as u64 => Err(()),
+        m if m >= EPOCH_MILLIS_FROM as u64 => chrono::DateTime::from_timestamp_millis(n).ok_or(()),
+        _ => chrono::DateTime::from_timestamp(n, 0).ok_or(()),
+    };
+    match value {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::Number(n) => n.as_i64().ok_or(()).and_then(from_epoch).map(Some),
+        serde_json::Value::String(s) => {
+            let s = s.trim();
+            if s.is_empty() {
+                return Ok(None);
+            }
+            if let Ok(t) = chrono::DateTime::parse_from_rfc3339(s) {
+                return Ok(Some(t.with_timezone(&chrono::Utc)));
+            }
+            if let Ok(secs) = s.parse::<i64>() {
+                return from_epoch(secs).map(Some);
+            }
+            chrono::NaiveDateTime::parse_from_str(s, format)
+                .map(|t| Some(t.and_utc()))
+                .map_err(|_| ())
+        }
+        _ => Err(()),
+    }
+}
+
+/// Roles accepted from one token when `max_roles` is not configured.
+pub const DEFAULT_MAX_ROLES: usize = 100;
+
+/// Collect at most `max` roles, warning when more were present.
+fn cap_roles(roles: impl Iterator<Item = String>, max: usize) -> Vec<String> {
+    let mut out: Vec<String> = roles.take(max.saturating_add(1)).collect();
+    if out.len() > max {
+        out.truncate(max);
+        tracing::warn!(
+            "AuthBlock: token carries more than {} roles; extra roles ignored",
+            max
+        );
+    }
+    out
+}
+
+/// The token of a `Bearer` Authorization header value. The scheme matches
+/// case-insensitively (`bearer`, `BEARER`); the token is returned as sent.
+pub fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim_start().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    Some(token.trim()).filter(|t| !t.is_empty())
+}
+
+/// Compare two secrets in time independent of where they differ.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes()
+        .zip(b.bytes())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+/// Cookie carrying the auth token.
+pub const AUTH_COOKIE: &str = "auth_token";
+
+/// Attributes applied whenever the auth blocks set a cookie.
+///
+/// Read from node config: `cookie_secure` (default true), `cookie_http_only`
+/// (default true), `cookie_same_site` (default "Lax"), `cookie_domain`,
+/// `cookie_path` (default "/") and `cookie_max_age` (seconds, default unset).
+/// Set `cookie_secure: false` for local development over plain HTTP.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CookieAttributes {
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: String,
+    pub domain: String,
+    pub path: String,
+    pub max_age: Option<u64>,
+}
+
+impl Default for CookieAttributes {
+    fn default() -> Self {
+        Self {
+            secure: true,
+            http_only: true,
+            same_site: "Lax".to_string(),
+            domain: String::new(),
+            path: "/".to_string(),
+            max_age: None,
+        }
+    }
+}
+
+impl CookieAttributes {
+    pub fn from_config(ctx: &dyn Context) -> Self {
+        let defaults = Self::default();
+        let flag = |key: &str, default: bool| {
+            ctx.config_get(key)
+                .map(|s| s == "true" || s == "1")
+                .unwrap_or(default)
+        };
+        Self {
+            secure: flag("cookie_secure", defaults.secure),
+            http_only: flag("cookie_http_only", defaults.http_only),
+            same_site: ctx
+                .config_get("cookie_same_site")
+                .map(|s| s.to_string())
+                .unwrap_or(defaults.same_site),
+            domain: ctx
+                .config_get("cookie_domain")
+                .map(|s| s.to_string())
+                .unwrap_or(defaults.domain),
+            path: ctx
+                .config_get("cookie_path")
+                .map(|s| s.to_string())
+                .unwrap_or(defaults.path),
+            max_age: ctx
+                .config_get("cookie_max_age")
+                .and_then(|s| s.parse::<u64>().ok()),
+        }
+    }
+
+    /// Build a `Set-Cookie` value. `max_age` overrides the configured Max-Age.
+    pub fn set(&self, name: &str, value: &str, max_age: Option<u64>) -> String {
+        let mut cookie = format!("{}={}", name, value);
+        if !self.path.is_empty() {
+            cookie.push_str(&format!("; Path={}", self.path));
+        }
+        if !self.domain.is_empty() {
+            cookie.push_str(&format!("; Domain={}", self.domain));
+        }
+        if let Some(age) = max_age.or(self.max_age) {
+            cookie.push_str(&format!("; Max-Age={}", age));
+        }
+        if self.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        if !self.same_site.is_empty() {
+            cookie.push_str(&format!("; SameSite={}", self.same_site));
+        }
+        cookie
+    }
+
+    /// Build a `Set-Cookie` value that deletes the cookie.
+    pub fn clear(&self, name: &str) -> String {
+        self.set(name, "", Some(0))
+    }
+}
+
+/// JWT claim holding the client fingerprint a token was issued to.
+pub const FINGERPRINT_CLAIM: &str = "fp";
+
+/// Cookie holding a long-lived random device identifier, set by the login flow.
+pub const DEVICE_COOKIE: &str = "device_id";
+
+/// Coarse client fingerprint: a hash of the User-Agent and device cookie,
+/// what `bind_fingerprint` checks by default.
+///
+/// Token issuers should embed this as the `fp` claim so AuthBlock's
+/// `bind_fingerprint` mode can reject tokens replayed from another client.
+pub fn client_fingerprint(msg: &Message) -> String {
+    fingerprint(msg, FingerprintComponents::LEGACY).unwrap_or_default()
+}
+
+/// Request properties hashed into a client fingerprint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FingerprintComponents {
+    /// The client address's /24 (IPv4) or /64 (IPv6) network, per
+    /// `net::client_ip`.
+    pub ip_prefix: bool,
+    pub user_agent: bool,
+    /// The `DEVICE_COOKIE` value.
+    pub device: bool,
+}
+
+impl FingerprintComponents {
+    /// What `client_fingerprint` hashes, and the `bind_fingerprint` default.
+    pub const LEGACY: Self = Self {
+        ip_prefix: false,
+        user_agent: true,
+        device: true,
+    };
+
+    /// The `auth_bind_tokens` default.
+    pub const DEFAULT: Self = Self {
+        ip_prefix: true,
+        user_agent: true,
+        device: false,
+    };
+
+    /// Parse a `fingerprint_components` list of `ip`, `ua` and `device`;
+    /// unknown names are ignored, and a list naming none gives `default`.
+    pub fn parse(raw: &str, default: Self) -> Self {
+        let mut c = Self {
+            ip_prefix: false,
+            user_agent: false,
+            device: false,
+        };
+        for name in raw.split(',').map(|n| n.trim()).filter(|n| !n.is_empty()) {
+            match name.to_ascii_lowercase().as_str() {
+                "ip" => c.ip_prefix = true,
+                "ua" => c.user_agent = true,
+                "device" => c.device = true,
+                other => tracing::warn!("fingerprint_components: unknown component {:?}", other),
+            }
+        }
+        if c.ip_prefix || c.user_agent || c.device {
+            c
+        } else {
+            default
+        }
+    }
+}
+
+/// The network part of a client address: /24 for IPv4, /64 for IPv6, so
+/// clients moving within their provider's range keep their fingerprint.
+/// `None` if `addr` is not an IP address.
+fn ip_prefix(addr: &str) -> Option<String> {
+    match net::parse_ip(addr)? {
+        std::net::IpAddr::V4(v4) => {
+            let o = v4.octets();
+            Some(format!("{}.{}.{}.0/24", o[0], o[1], o[2]))
+        }
+        std::net::IpAddr::V6(v6) => {
+            let s = v6.segments();
+            Some(format!("{:x}:{:x}:{:x}:{:x}::/64", s[0], s[1], s[2], s[3]))
+        }
+    }
+}
+
+/// Hex fingerprint of the request's `components`, each separated by a
+/// newline in the order IP prefix, User-Agent, device cookie. `None` when
+/// the IP prefix is a component and the client address doesn't parse, so
+/// such clients can't all share one fingerprint.
+pub fn fingerprint(msg: &Message, components: FingerprintComponents) -> Option<String> {
+    use sha2::{Digest, Sha256};
+
+    let mut parts = Vec::new();
+    if components.ip_prefix {
+        parts.push(ip_prefix(&net::client_ip(msg))?);
+    }
+    if components.user_agent {
+        parts.push(msg.header("User-Agent").to_string());
+    }
+    if components.device {
+        parts.push(msg.cookie(DEVICE_COOKIE).to_string());
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(parts.join("\n").as_bytes());
+    Some(
+        hasher
+            .finalize()
+            .iter()
+            .take(16)
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    )
+}
+
+/// What a token binding mismatch does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingMode {
+    /// Reject with 401 `token_binding_failed`.
+    Strict,
+    /// Log and set `auth.token_binding: mismatch`, but accept the token;
+    /// for rolling binding out while old tokens are still around.
+    Soft,
+}
+
+/// Token binding settings, shared by AuthBlock (checking the `fp` claim)
+/// and OAuthBlock (embedding it).
+///
+/// `auth_bind_tokens` is `true`/`strict` or `soft`; `fingerprint_components`
+/// lists what the fingerprint covers (default `ip, ua`, see
+/// `FingerprintComponents`). Use `ua` alone for mobile clients whose address
+/// changes between networks. The older `bind_fingerprint: true` means
+/// strict, with `FingerprintComponents::LEGACY` as the default components.
+/// Either way `auth.token_binding` meta is set to `ok` or `mismatch`; a
+/// client whose address doesn't parse never matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenBinding {
+    pub mode: BindingMode,
+    pub components: FingerprintComponents,
+}
+
+impl TokenBinding {
+    /// The binding configured on the node, if any.
+    pub fn from_config(ctx: &dyn Context) -> Option<Self> {
+        let legacy = ctx
+            .config_get("bind_fingerprint")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+        let (mode, default) = match ctx.config_get("auth_bind_tokens").unwrap_or("") {
+            "true" | "1" | "strict" => (BindingMode::Strict, FingerprintComponents::DEFAULT),
+            "soft" => (BindingMode::Soft, FingerprintComponents::DEFAULT),
+            _ if legacy => (BindingMode::Strict, FingerprintComponents::LEGACY),
+            _ => return None,
+        };
+        Some(Self {
+            mode,
+            components: FingerprintComponents::parse(
+                ctx.config_get("fingerprint_components").unwrap_or(""),
+                default,
+            ),
+        })
+    }
+
+    /// The requesting client's fingerprint under this binding, `None` if
+    /// the client address is needed and doesn't parse.
+    pub fn fingerprint(&self, msg: &Message) -> Option<String> {
+        fingerprint(msg, self.components)
+    }
+
+    /// Put the client's fingerprint in `claims` as `fp`. A client whose
+    /// fingerprint can't be taken gets an unbound token, which fails the
+    /// check later.
+    pub fn embed(&self, msg: &Message, claims: &mut HashMap<String, serde_json::Value>) {
+        match self.fingerprint(msg) {
+            Some(fp) => {
+                claims.insert(FINGERPRINT_CLAIM.to_string(), serde_json::Value::String(fp));
+            }
+            None => tracing::warn!(
+                "token binding: client address {:?} doesn't parse; issuing an unbound token",
+                net::client_ip(msg)
+            ),
+        }
+    }
+}
+
 impl Block for AuthBlock {
     fn info(&self) -> BlockInfo {
         BlockInfo {
@@ -230,37 +1216,120 @@ impl Block for AuthBlock {
             summary: "Authentication middleware: JWT, API key, and cookie auth".to_string(),
             instance_mode: InstanceMode::Singleton,
             allowed_modes: Vec::new(),
-            admin_ui: None,
+            admin_ui: crate::admin::ui(admin_descriptor()),
         }
     }
 
     fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let login_path = ctx.config_get("login_path").unwrap_or("").trim();
+        if !login_path.is_empty() && path::request_path(msg) == path::normalize(login_path, false) {
+            return self.login(ctx, msg);
+        }
+        if path::is_skipped(ctx, msg) {
+            return msg.clone().cont();
+        }
         // Extract token
         let token = match Self::extract_token(msg) {
             Some(t) => t,
             None => return auth_error(msg, 401, "No authentication token provided"),
         };
 
-        // Validate based on token type
-        let (user_id, email, roles) = if Self::is_api_key(&token) {
-            match Self::validate_api_key(ctx, msg, &token) {
-                Ok(v) => v,
-                Err(r) => return r,
+        // API keys need the database, JWTs only the crypto service (or,
+        // with `auth_issuers` or a custom resolver, nothing at all)
+        let is_api_key = Self::is_api_key(&token);
+        let issuer_jwt = !is_api_key
+            && ctx
+                .config_get("auth_issuers")
+                .is_some_and(|s| !s.trim().is_empty());
+        let available = issuer_jwt
+            || self.resolver.is_some()
+            || ctx
+                .services()
+                .is_some_and(|s| s.crypto.is_some() && (!is_api_key || s.database.is_some()));
+        let mode = DegradedMode::from_config(ctx);
+        if !available && mode != DegradedMode::Fail {
+            msg.set_meta(DEGRADED_META, mode.as_str());
+            if mode == DegradedMode::AllowAll {
+                tracing::warn!("AuthBlock: services unavailable, admitting request (allow_all)");
+                // Identified, but with no roles: role checks still apply
+                msg.set_meta(meta::AUTH_USER_ID, "dev");
+                msg.set_meta(meta::AUTH_USER_ROLES, "");
+                return msg.clone().cont();
+            }
+            if is_api_key {
+                return CoreError::custom(
+                    503,
+                    "api_keys_unavailable",
+                    "API key authentication is unavailable without a database",
+                )
+                .respond(msg);
             }
+        }
+
+        // Validate based on token type
+        let validated = if let Some(resolver) = &self.resolver {
+            Self::resolve_custom(ctx, msg, resolver.as_ref(), &token, is_api_key)
+        } else if is_api_key {
+            self.validate_api_key(ctx, msg, &token)
         } else {
-            match Self::validate_jwt(ctx, msg, &token) {
-                Ok(v) => v,
-                Err(r) => return r,
+            self.validate_jwt(ctx, msg, &token)
+        };
+        let (user_id, email, roles) = match validated {
+            Ok(v) => v,
+            Err(mut r) => {
+                // Validators build some rejections without auth_error
+                if matches!(hooks::result_status(&r), 401 | 403) && Outcome::of(&r).is_none() {
+                    Outcome::AuthFailed.record_result(&mut r);
+                }
+                // Stop browsers from resending a cookie that will never validate
+                let clear_cookie = ctx
+                    .config_get("clear_invalid_cookie")
+                    .map(|s| s == "true" || s == "1")
+                    .unwrap_or(false);
+                if clear_cookie
+                    && msg.cookie(AUTH_COOKIE) == token
+                    && hooks::result_status(&r) == 401
+                {
+                    let attrs = CookieAttributes::from_config(ctx);
+                    hooks::result_set_header(&mut r, "Set-Cookie", &attrs.clear(AUTH_COOKIE));
+                }
+                return r;
             }
         };
 
+        let replay_protection = ctx
+            .config_get("replay_protection")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+        if replay_protection && is_api_key {
+            if let Err(r) = self.check_replay(ctx, msg, &user_id) {
+                return r;
+            }
+        }
+
+        // Reject identities that are locked out after repeated failures
+        let enforce_lockout = ctx
+            .config_get("lockout_enforce")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+        if enforce_lockout {
+            // Logins record failures under the lowercased email
+            let locked = self
+                .lockout
+                .locked_for(&user_id)
+                .or_else(|| self.lockout.locked_for(&email.trim().to_ascii_lowercase()));
+            if let Some(remaining) = locked {
+                return lockout_error(msg, remaining);
+            }
+        }
+
         // Set auth metadata on the message
-        msg.set_meta("auth.user_id", &user_id);
+        msg.set_meta(meta::AUTH_USER_ID, &user_id);
         if !email.is_empty() {
-            msg.set_meta("auth.user_email", &email);
+            msg.set_meta(meta::AUTH_USER_EMAIL, &email);
         }
         if !roles.is_empty() {
-            msg.set_meta("auth.user_roles", &roles.join(","));
+            msg.set_meta(meta::AUTH_USER_ROLES, &roles.join(","));
         }
 
         msg.clone().cont()
@@ -268,17 +1337,1345 @@ impl Block for AuthBlock {
 
     fn lifecycle(
         &self,
-        _ctx: &dyn Context,
-        _event: LifecycleEvent,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
     ) -> std::result::Result<(), WaferError> {
+        if matches!(event.event_type, LifecycleType::Start) {
+            DegradedMode::report(ctx, "@wafer/auth");
+        }
         Ok(())
     }
 }
 
-fn auth_error(msg: &mut Message, status: u16, message: &str) -> Result_ {
-    error(msg.clone(), status, "unauthorized", message)
+/// Meta key set to the degraded mode in effect when a block ran without the
+/// services it normally needs.
+pub const DEGRADED_META: &str = meta::DEGRADED_MODE;
+
+/// How AuthBlock and IAMBlock behave when `ctx.services()` (or the database
+/// or crypto service) is unavailable, from the `degraded_mode` config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DegradedMode {
+    /// Keep the normal behavior (the default): auth answers 500, IAM falls
+    /// back to `auth.user_roles` meta.
+    Fail,
+    /// Auth accepts only JWTs and rejects API keys with `api_keys_unavailable`;
+    /// IAM checks `auth.user_roles` meta without querying the database.
+    MetaOnly,
+    /// Admit everything. Insecure, for local development only: auth passes
+    /// requests as user `dev` with no roles and IAM allows every request.
+    /// Only in effect with `allow_insecure_dev_mode: true` on the same node;
+    /// without it `allow_all` is refused and the block behaves as `Fail`.
+    AllowAll,
+}
+
+impl DegradedMode {
+    pub fn from_config(ctx: &dyn Context) -> Self {
+        match ctx.config_get("degraded_mode").unwrap_or("fail") {
+            "meta_only" => Self::MetaOnly,
+            "allow_all" if Self::dev_mode(ctx) => Self::AllowAll,
+            _ => Self::Fail,
+        }
+    }
+
+    fn dev_mode(ctx: &dyn Context) -> bool {
+        ctx.config_get("allow_insecure_dev_mode")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Fail => "fail",
+            Self::MetaOnly => "meta_only",
+            Self::AllowAll => "allow_all",
+        }
+    }
+
+    /// Log the selected mode, or that `allow_all` was refused; called once
+    /// per block at lifecycle Start.
+    pub fn report(ctx: &dyn Context, block: &str) {
+        match Self::from_config(ctx) {
+            Self::AllowAll => tracing::warn!(
+                "{}: degraded_mode=allow_all is INSECURE and admits every request \
+                 when services are unavailable; never use it outside local development",
+                block
+            ),
+            Self::Fail if ctx.config_get("degraded_mode") == Some("allow_all") => tracing::warn!(
+                "{}: degraded_mode=allow_all refused without allow_insecure_dev_mode; \
+                 using fail",
+                block
+            ),
+            mode => tracing::info!("{}: degraded_mode={}", block, mode.as_str()),
+        }
+    }
+}
+
+/// Roles granted to `user_id` in `iam_user_roles` (none if the query fails).
+fn user_roles(db: &dyn DatabaseService, user_id: &str) -> Vec<String> {
+    let opts = ListOptions {
+        filters: vec![Filter {
+            field: "user_id".to_string(),
+            operator: FilterOp::Equal,
+            value: serde_json::Value::String(user_id.to_string()),
+        }],
+        ..Default::default()
+    };
+    match db.list("iam_user_roles", &opts) {
+        Ok(r) => r
+            .records
+            .iter()
+            .filter_map(|rec| {
+                rec.data
+                    .get("role")
+                    .and_then(|v| v.as_str())
+                    .map(|s| s.to_string())
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn auth_error(msg: &mut Message, status: u16, message: &str) -> Result_ {
+    if status < 500 {
+        Outcome::AuthFailed.record(msg);
+    }
+    // Non-401 auth failures have always carried the `unauthorized` code
+    let err = if status == 401 {
+        CoreError::Unauthorized(message.to_string())
+    } else {
+        CoreError::custom(status, "unauthorized", message)
+    };
+    err.respond(msg)
+}
+
+/// Respond to a locked-out identity. The message is deliberately generic so
+/// it does not confirm whether the account exists.
+pub fn lockout_error(msg: &mut Message, retry_after: Duration) -> Result_ {
+    let mut m = msg.clone();
+    // Round up so clients never retry a second too early
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    meta::set_resp_header(&mut m, "Retry-After", &secs.to_string());
+    Outcome::AuthLocked.record(&mut m);
+    CoreError::custom(
+        429,
+        "too_many_attempts",
+        "Too many failed attempts. Please try again later.",
+    )
+    .respond(&m)
+}
+
+/// Upper bound on remembered nonces.
+const MAX_NONCES: usize = 100_000;
+
+/// Outcome of recording a nonce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NonceCheck {
+    Fresh,
+    /// Seen before and not yet expired.
+    Replayed,
+    /// Every slot holds a live nonce; the first frees up after `retry_after`.
+    Full {
+        retry_after: Duration,
+    },
+}
+
+/// Recently seen request nonces, each remembered for its TTL. Expiries are
+/// kept in order, so expired nonces are dropped without a scan.
+struct NonceCache {
+    capacity: usize,
+    seen: Mutex<Nonces>,
+}
+
+#[derive(Default)]
+struct Nonces {
+    expires: HashMap<String, (Instant, u64)>,
+    by_expiry: BTreeMap<(Instant, u64), String>,
+    seq: u64,
+}
+
+impl NonceCache {
+    fn new() -> Self {
+        Self::with_capacity(MAX_NONCES)
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: Mutex::new(Nonces::default()),
+        }
+    }
+
+    /// Record `nonce` at `now`. A live nonce is never forgotten to make room.
+    fn insert(&self, nonce: &str, ttl: Duration, now: Instant) -> NonceCheck {
+        let mut seen = self.seen.lock();
+        while let Some((&(expiry, seq), _)) = seen.by_expiry.first_key_value() {
+            if expiry > now {
+                break;
+            }
+            if let Some(key) = seen.by_expiry.remove(&(expiry, seq)) {
+                seen.expires.remove(&key);
+            }
+        }
+        if seen.expires.contains_key(nonce) {
+            return NonceCheck::Replayed;
+        }
+        if seen.expires.len() >= self.capacity {
+            let retry_after = seen
+                .by_expiry
+                .first_key_value()
+                .map_or(Duration::ZERO, |((expiry, _), _)| {
+                    expiry.saturating_duration_since(now)
+                });
+            return NonceCheck::Full { retry_after };
+        }
+        seen.seq += 1;
+        let entry = (now + ttl, seen.seq);
+        seen.by_expiry.insert(entry, nonce.to_string());
+        seen.expires.insert(nonce.to_string(), entry);
+        NonceCheck::Fresh
+    }
+}
+
+/// Persistence backend for lockout state, so locks survive restarts.
+pub trait LockoutStore: Send + Sync {
+    /// Load all persisted entries.
+    fn load(&self) -> Vec<(String, LockoutState)>;
+    /// Persist the state of one identifier (`None` means cleared).
+    fn save(&self, identifier: &str, state: Option<&LockoutState>);
+}
+
+/// Persisted lockout state for one identifier.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LockoutState {
+    pub failures: u32,
+    /// Unix timestamp (seconds) until which the identifier is locked, 0 if not locked.
+    pub locked_until: i64,
+}
+
+/// LockoutTracker counts failed login attempts per account identifier and
+/// locks the identifier with exponential backoff once a threshold is reached.
+///
+/// With the defaults, 5 failures lock for 1 minute, and every further failure
+/// doubles the lock up to a 1 hour cap. A successful login clears the counter.
+/// Locks are timed by the tracker's `Clock` (see `with_clock`).
+pub struct LockoutTracker {
+    threshold: u32,
+    base_lock: Duration,
+    max_lock: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, LockoutEntry>>,
+    store: Option<Arc<dyn LockoutStore>>,
+    clock: Arc<dyn Clock>,
+}
+
+struct LockoutEntry {
+    failures: u32,
+    locked_until: Option<Instant>,
+    last_failure: Instant,
+}
+
+impl LockoutTracker {
+    pub fn new() -> Self {
+        Self {
+            threshold: 5,
+            base_lock: Duration::from_secs(60),
+            max_lock: Duration::from_secs(3600),
+            max_entries: 100_000,
+            entries: Mutex::new(HashMap::new()),
+            store: None,
+            clock: clock::system(),
+        }
+    }
+
+    /// Time locks with `clock` instead of the system clock. Call before
+    /// `with_store`, which restores locks relative to the current time.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Override the failure threshold and lock durations.
+    pub fn with_policy(mut self, threshold: u32, base_lock: Duration, max_lock: Duration) -> Self {
+        self.threshold = threshold.max(1);
+        self.base_lock = base_lock;
+        self.max_lock = max_lock.max(base_lock);
+        self
+    }
+
+    /// Attach a persistence backend and restore its entries.
+    pub fn with_store(mut self, store: Arc<dyn LockoutStore>) -> Self {
+        {
+            let now = self.clock.now_instant();
+            let now_unix = self.clock.now_utc().timestamp();
+            let mut entries = self.entries.lock();
+            for (id, state) in store.load() {
+                let locked_until = (state.locked_until > now_unix)
+                    .then(|| now + Duration::from_secs((state.locked_until - now_unix) as u64));
+                entries.insert(
+                    id,
+                    LockoutEntry {
+                        failures: state.failures,
+                        locked_until,
+                        last_failure: now,
+                    },
+                );
+            }
+        }
+        self.store = Some(store);
+        self
+    }
+
+    /// Lock duration for the given number of consecutive failures.
+    pub fn lock_duration(&self, failures: u32) -> Option<Duration> {
+        if failures < self.threshold {
+            return None;
+        }
+        let doublings = (failures - self.threshold).min(31);
+        let lock = self
+            .base_lock
+            .checked_mul(1u32 << doublings)
+            .unwrap_or(self.max_lock);
+        Some(lock.min(self.max_lock))
+    }
+
+    /// Record a failed attempt. Returns the lock duration if the identifier is now locked.
+    pub fn record_failure(&self, identifier: &str) -> Option<Duration> {
+        if identifier.is_empty() {
+            return None;
+        }
+        let now = self.clock.now_instant();
+        let mut entries = self.entries.lock();
+        if entries.len() >= self.max_entries && !entries.contains_key(identifier) {
+            self.evict(&mut entries, now);
+        }
+
+        let entry = entries
+            .entry(identifier.to_string())
+            .or_insert(LockoutEntry {
+                failures: 0,
+                locked_until: None,
+                last_failure: now,
+            });
+        // Forget old failures once the maximum lock period has passed quietly
+        if !entry.locked_until.is_some_and(|t| t > now)
+            && now.duration_since(entry.last_failure) > self.max_lock
+        {
+            entry.failures = 0;
+        }
+        entry.failures = entry.failures.saturating_add(1);
+        entry.last_failure = now;
+
+        let lock = self.lock_duration(entry.failures);
+        if let Some(d) = lock {
+            entry.locked_until = Some(now + d);
+        }
+        let failures = entry.failures;
+        drop(entries);
+
+        self.persist(identifier, failures, lock);
+        lock
+    }
+
+    /// Record a successful attempt, clearing any failures and lock.
+    pub fn record_success(&self, identifier: &str) {
+        let removed = self.entries.lock().remove(identifier).is_some();
+        if removed {
+            if let Some(store) = &self.store {
+                store.save(identifier, None);
+            }
+        }
+    }
+
+    /// Administratively unlock an identifier.
+    pub fn unlock(&self, identifier: &str) {
+        self.record_success(identifier);
+    }
+
+    /// Whether the identifier is currently locked.
+    pub fn is_locked(&self, identifier: &str) -> bool {
+        self.locked_for(identifier).is_some()
+    }
+
+    /// Remaining lock time for the identifier, if locked.
+    pub fn locked_for(&self, identifier: &str) -> Option<Duration> {
+        if identifier.is_empty() {
+            return None;
+        }
+        let now = self.clock.now_instant();
+        let entries = self.entries.lock();
+        entries
+            .get(identifier)
+            .and_then(|e| e.locked_until)
+            .and_then(|t| t.checked_duration_since(now))
+            .filter(|d| !d.is_zero())
+    }
+
+    fn persist(&self, identifier: &str, failures: u32, lock: Option<Duration>) {
+        if let Some(store) = &self.store {
+            let locked_until = lock
+                .map(|d| self.clock.now_utc().timestamp() + d.as_secs() as i64)
+                .unwrap_or(0);
+            store.save(
+                identifier,
+                Some(&LockoutState {
+                    failures,
+                    locked_until,
+                }),
+            );
+        }
+    }
+
+    fn evict(&self, entries: &mut HashMap<String, LockoutEntry>, now: Instant) {
+        let max_lock = self.max_lock;
+        entries.retain(|_, e| {
+            e.locked_until.is_some_and(|t| t > now)
+                || now.duration_since(e.last_failure) <= max_lock
+        });
+        // Still full: drop the stalest entry
+        if entries.len() >= self.max_entries {
+            if let Some(oldest) = entries
+                .iter()
+                .filter(|(_, e)| !e.locked_until.is_some_and(|t| t > now))
+                .min_by_key(|(_, e)| e.last_failure)
+                .map(|(k, _)| k.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "auth_issuers",
+            FieldKind::Json,
+            "",
+            "Trusted JWT issuers and their verification settings",
+        )
+        .field(
+            "max_roles",
+            FieldKind::Integer,
+            "100",
+            "Most roles accepted from a token",
+        )
+        .field(
+            "api_key_expiry_format",
+            FieldKind::String,
+            DEFAULT_EXPIRY_FORMAT,
+            "chrono format of API key `expires_at` values",
+        )
+        .field(
+            "api_key_expiry_unparseable",
+            FieldKind::one_of(&["valid", "expired"]),
+            "valid",
+            "How API keys with an unreadable expiry are treated",
+        )
+        .field(
+            "replay_protection",
+            FieldKind::Bool,
+            "false",
+            "Require X-Timestamp and X-Nonce on API key requests",
+        )
+        .field(
+            "replay_window_secs",
+            FieldKind::Integer,
+            "300",
+            "Accepted clock skew of X-Timestamp",
+        )
+        .field(
+            "auth_expired_grace_secs",
+            FieldKind::Integer,
+            "0",
+            "Seconds an expired issuer token may still authenticate reads",
+        )
+        .field(
+            "method_actions",
+            FieldKind::List,
+            "",
+            "`METHOD=action` overrides of the method to action mapping",
+        )
+        .field(
+            "auth_bind_tokens",
+            FieldKind::one_of(&["false", "true", "strict", "soft"]),
+            "false",
+            "Check tokens' fp claim against the client fingerprint; soft only logs",
+        )
+        .field(
+            "fingerprint_components",
+            FieldKind::List,
+            "ip, ua",
+            "What the fingerprint covers: ip (/24 or /64), ua, device",
+        )
+        .field(
+            "bind_fingerprint",
+            FieldKind::Bool,
+            "false",
+            "Strict binding, by default to the User-Agent and device cookie",
+        )
+        .field(
+            "login_path",
+            FieldKind::String,
+            "",
+            "Path answering password logins, with lockout; off when empty",
+        )
+        .field(
+            "token_ttl_seconds",
+            FieldKind::Integer,
+            "86400",
+            "Lifetime of tokens issued by password login",
+        )
+        .field(
+            "lockout_enforce",
+            FieldKind::Bool,
+            "false",
+            "Refuse clients locked out after repeated failures",
+        )
+        .field(
+            "clear_invalid_cookie",
+            FieldKind::Bool,
+            "false",
+            "Expire the auth cookie when its token is rejected",
+        )
+        .field(
+            "cookie_secure",
+            FieldKind::Bool,
+            "true",
+            "Secure attribute of the auth cookie",
+        )
+        .field(
+            "cookie_http_only",
+            FieldKind::Bool,
+            "true",
+            "HttpOnly attribute of the auth cookie",
+        )
+        .field(
+            "cookie_same_site",
+            FieldKind::one_of(&["Strict", "Lax", "None"]),
+            "Lax",
+            "SameSite attribute of the auth cookie",
+        )
+        .field(
+            "cookie_domain",
+            FieldKind::String,
+            "",
+            "Domain attribute of the auth cookie",
+        )
+        .field(
+            "cookie_path",
+            FieldKind::String,
+            "/",
+            "Path attribute of the auth cookie",
+        )
+        .field(
+            "cookie_max_age",
+            FieldKind::Integer,
+            "",
+            "Max-Age of the auth cookie in seconds",
+        )
+        .field(
+            "degraded_mode",
+            FieldKind::one_of(&["fail", "meta_only", "allow_all"]),
+            "fail",
+            "Behavior when the database or crypto service is missing",
+        )
+        .field(
+            "allow_insecure_dev_mode",
+            FieldKind::Bool,
+            "false",
+            "Permit `degraded_mode: allow_all` (local development only)",
+        )
+        .skip_paths()
 }
 
 pub fn register(w: &mut Wafer) {
-    w.register_block("@wafer/auth", Arc::new(AuthBlock::new()));
+    register_as(w, "@wafer/auth");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(AuthBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use serde_json::json;
+
+    const KEY: &str = "sb_live_123";
+
+    fn key_db(key: serde_json::Value) -> MockDatabase {
+        MockDatabase::new()
+            .with_row("api_keys", key)
+            .with_row("auth_users", json!({"id": "u1", "email": "u1@example.com"}))
+            .with_row("iam_user_roles", json!({"user_id": "u1", "role": "editor"}))
+    }
+
+    fn key_row(extra: serde_json::Value) -> serde_json::Value {
+        let mut row = json!({"key_hash": MockCrypto::digest(KEY), "user_id": "u1"});
+        for (k, v) in extra.as_object().unwrap() {
+            row[k] = v.clone();
+        }
+        row
+    }
+
+    fn run(ctx: &MockContext, token: &str) -> (SimulatedResponse, Message) {
+        let mut msg = MockRequest::get("/api/items")
+            .header("Authorization", &format!("Bearer {}", token))
+            .build();
+        let result = AuthBlock::new().handle(ctx, &mut msg);
+        let out = result.message.clone().unwrap_or(msg);
+        (SimulatedResponse::from_result(&result), out)
+    }
+
+    fn services(db: MockDatabase) -> MockContext {
+        MockContext::new().with_services(MockServices::new().with_database(db).with_crypto())
+    }
+
+    #[test]
+    fn api_key_resolves_user_and_roles() {
+        let ctx = services(key_db(key_row(json!({}))));
+        let (resp, msg) = run(&ctx, KEY);
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::AUTH_USER_ID), "u1");
+        assert_eq!(msg.get_meta(meta::AUTH_USER_EMAIL), "u1@example.com");
+        assert_eq!(msg.get_meta(meta::AUTH_USER_ROLES), "editor");
+    }
+
+    #[test]
+    fn unknown_api_key_is_rejected() {
+        let ctx = services(key_db(key_row(json!({}))));
+        let (resp, _) = run(&ctx, "sb_other");
+        assert_status(&resp, 401, Some("unauthorized"));
+    }
+
+    #[test]
+    fn revoked_api_key_is_rejected() {
+        let ctx = services(key_db(key_row(
+            json!({"revoked_at": "2024-01-01T00:00:00Z"}),
+        )));
+        let (resp, _) = run(&ctx, KEY);
+        assert_status(&resp, 401, Some("unauthorized"));
+    }
+
+    #[test]
+    fn expired_api_key_is_rejected() {
+        let ctx = services(key_db(key_row(
+            json!({"expires_at": "2000-01-01T00:00:00Z"}),
+        )));
+        let (resp, _) = run(&ctx, KEY);
+        assert_status(&resp, 401, Some("unauthorized"));
+
+        let ctx = services(key_db(key_row(
+            json!({"expires_at": "2999-01-01T00:00:00Z"}),
+        )));
+        assert_status(&run(&ctx, KEY).0, 200, None);
+    }
+
+    #[test]
+    fn api_key_expiry_formats() {
+        let at = |v: serde_json::Value| parse_expiry(&v, DEFAULT_EXPIRY_FORMAT);
+        let expected = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        assert_eq!(at(json!("2023-11-14T22:13:20Z")), Ok(Some(expected)));
+        assert_eq!(at(json!(1_700_000_000)), Ok(Some(expected)));
+        assert_eq!(at(json!("1700000000")), Ok(Some(expected)));
+        assert_eq!(at(json!("2023-11-14 22:13:20")), Ok(Some(expected)));
+        assert_eq!(at(json!(null)), Ok(None));
+        assert_eq!(at(json!("")), Ok(None));
+        assert_eq!(at(json!("next tuesday")), Err(()));
+        assert_eq!(at(json!(true)), Err(()));
+        assert_eq!(
+            parse_expiry(&json!("14/11/2023 22:13:20"), "%d/%m/%Y %H:%M:%S"),
+            Ok(Some(expected))
+        );
+    }
+
+    #[test]
+    fn epoch_millisecond_expiry_is_normalized() {
+        let expected = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let at = |v: serde_json::Value| parse_expiry(&v, DEFAULT_EXPIRY_FORMAT);
+        assert_eq!(at(json!(1_700_000_000_000i64)), Ok(Some(expected)));
+        assert_eq!(at(json!("1700000000000")), Ok(Some(expected)));
+        // Past the year 5000 even as milliseconds
+        assert_eq!(at(json!(1_700_000_000_000_000i64)), Err(()));
+
+        // Unparseable keys count as live by default, so this 401 means the
+        // millisecond value was read as a past time
+        let expired = (chrono::Utc::now().timestamp() - 60) * 1000;
+        let ctx = services(key_db(key_row(json!({ "expires_at": expired }))));
+        assert_status(&run(&ctx, KEY).0, 401, Some("unauthorized"));
+    }
+
+    #[test]
+    fn api_key_without_database_is_unavailable() {
+        let ctx = MockContext::new()
+            .with_crypto()
+            .with_config("degraded_mode", "meta_only");
+        let (resp, _) = run(&ctx, KEY);
+        assert_status(&resp, 503, Some("api_keys_unavailable"));
+    }
+
+    #[test]
+    fn allow_all_needs_the_dev_flag_and_grants_no_roles() {
+        let refused = MockContext::new().with_config("degraded_mode", "allow_all");
+        assert_eq!(DegradedMode::from_config(&refused), DegradedMode::Fail);
+        let (resp, msg) = run(&refused, "any.token.here");
+        assert_ne!(resp.status, 200);
+        assert_eq!(msg.get_meta(meta::AUTH_USER_ID), "");
+
+        let dev = MockContext::new()
+            .with_config("degraded_mode", "allow_all")
+            .with_config("allow_insecure_dev_mode", "true");
+        let (resp, msg) = run(&dev, "any.token.here");
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::AUTH_USER_ID), "dev");
+        assert_eq!(msg.get_meta(meta::AUTH_USER_ROLES), "");
+    }
+
+    #[test]
+    fn jwt_claim_variations() {
+        let ctx = MockContext::new().with_crypto();
+        let exp = chrono::Utc::now().timestamp() + 600;
+
+        let token = MockCrypto::token(json!({"user_id": "u1", "roles": ["a", "b"], "exp": exp}));
+        let (resp, msg) = run(&ctx, &token);
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::AUTH_USER_ROLES), "a,b");
+
+        // `sub` stands in for `user_id`; roles may be a comma-separated string
+        let token =
+            MockCrypto::token(json!({"sub": "u2", "roles": "a, b", "email": "e@x", "exp": exp}));
+        let (resp, msg) = run(&ctx, &token);
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::AUTH_USER_ID), "u2");
+        assert_eq!(msg.get_meta(meta::AUTH_USER_EMAIL), "e@x");
+        assert_eq!(msg.get_meta(meta::AUTH_USER_ROLES), "a,b");
+
+        let token = MockCrypto::token(json!({"roles": ["admin"], "exp": exp}));
+        assert_status(&run(&ctx, &token).0, 401, Some("unauthorized"));
+
+        let token = MockCrypto::token(json!({"user_id": "u1", "exp": exp - 3600}));
+        assert_status(&run(&ctx, &token).0, 401, Some("unauthorized"));
+
+        assert_status(&run(&ctx, "not.a.jwt").0, 401, Some("unauthorized"));
+    }
+
+    #[test]
+    fn max_roles_caps_token_roles() {
+        let ctx = MockContext::new()
+            .with_crypto()
+            .with_config("max_roles", "2");
+        let exp = chrono::Utc::now().timestamp() + 600;
+        let token =
+            MockCrypto::token(json!({"user_id": "u1", "roles": ["a", "b", "c"], "exp": exp}));
+        let (_, msg) = run(&ctx, &token);
+        assert_eq!(msg.get_meta(meta::AUTH_USER_ROLES), "a,b");
+    }
+
+    #[test]
+    fn missing_token_is_rejected() {
+        let ctx = MockContext::new().with_crypto();
+        let mut msg = MockRequest::get("/api/items").build();
+        let resp = SimulatedResponse::from_result(&AuthBlock::new().handle(&ctx, &mut msg));
+        assert_status(&resp, 401, Some("unauthorized"));
+    }
+
+    fn signed(block: &AuthBlock, ctx: &MockContext, nonce: &str) -> SimulatedResponse {
+        let mut msg = MockRequest::get("/api/items")
+            .header("Authorization", &format!("Bearer {}", KEY))
+            .header("X-Timestamp", &chrono::Utc::now().timestamp().to_string())
+            .header("X-Nonce", nonce)
+            .build();
+        SimulatedResponse::from_result(&block.handle(ctx, &mut msg))
+    }
+
+    #[test]
+    fn replayed_nonce_is_rejected() {
+        let ctx = services(key_db(key_row(json!({})))).with_config("replay_protection", "true");
+        let block = AuthBlock::new();
+        assert_status(&signed(&block, &ctx, "n1"), 200, None);
+        assert_status(&signed(&block, &ctx, "n1"), 401, Some("unauthorized"));
+        assert_status(&signed(&block, &ctx, "n2"), 200, None);
+    }
+
+    #[test]
+    fn full_nonce_cache_refuses_instead_of_forgetting() {
+        let ctx = services(key_db(key_row(json!({})))).with_config("replay_protection", "true");
+        let mut block = AuthBlock::new();
+        block.nonces = NonceCache::with_capacity(2);
+        assert_status(&signed(&block, &ctx, "n1"), 200, None);
+        assert_status(&signed(&block, &ctx, "n2"), 200, None);
+
+        let resp = signed(&block, &ctx, "n3");
+        assert_status(&resp, 503, Some("replay_cache_full"));
+        assert!(resp.header("Retry-After").is_some());
+        // The earlier nonces are still remembered
+        assert_status(&signed(&block, &ctx, "n1"), 401, Some("unauthorized"));
+        assert_status(&signed(&block, &ctx, "n2"), 401, Some("unauthorized"));
+    }
+
+    #[test]
+    fn expired_nonces_free_their_slots() {
+        let cache = NonceCache::with_capacity(1);
+        let start = Instant::now();
+        let ttl = Duration::from_secs(10);
+        assert_eq!(cache.insert("a", ttl, start), NonceCheck::Fresh);
+        assert_eq!(
+            cache.insert("b", ttl, start + Duration::from_secs(4)),
+            NonceCheck::Full {
+                retry_after: Duration::from_secs(6)
+            }
+        );
+        assert_eq!(cache.insert("b", ttl, start + ttl), NonceCheck::Fresh);
+        assert_eq!(
+            cache.insert("a", ttl, start + ttl),
+            NonceCheck::Full { retry_after: ttl }
+        );
+    }
+
+    /// A JWKS endpoint that can be taken down, counting fetches.
+    #[derive(Default)]
+    struct FlakyJwks {
+        down: std::sync::atomic::AtomicBool,
+        fetches: std::sync::atomic::AtomicUsize,
+    }
+
+    impl HttpClient for FlakyJwks {
+        fn post_form(
+            &self,
+            _url: &str,
+            _form: &[(&str, &str)],
+        ) -> std::result::Result<serde_json::Value, String> {
+            Err("unexpected".to_string())
+        }
+
+        fn post_json(
+            &self,
+            _url: &str,
+            _body: &serde_json::Value,
+            _timeout: Duration,
+        ) -> std::result::Result<serde_json::Value, String> {
+            Err("unexpected".to_string())
+        }
+
+        fn get_json(
+            &self,
+            _url: &str,
+            _bearer: &str,
+        ) -> std::result::Result<serde_json::Value, String> {
+            use std::sync::atomic::Ordering;
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            if self.down.load(Ordering::SeqCst) {
+                return Err("connection refused".to_string());
+            }
+            Ok(json!({"keys": [{"kty": "oct", "kid": "k1", "k": "c2VjcmV0"}]}))
+        }
+    }
+
+    #[test]
+    fn stale_jwks_key_is_used_while_refetches_fail() {
+        use std::sync::atomic::Ordering;
+        const URL: &str = "https://idp.test/jwks.json";
+        let http = Arc::new(FlakyJwks::default());
+        let clock = Arc::new(ManualClock::new());
+        let block = AuthBlock::new()
+            .with_http_client(http.clone())
+            .with_clock(clock.clone());
+        let fetches = || http.fetches.load(Ordering::SeqCst);
+
+        assert!(block.jwks_key(URL, Some("k1")).is_ok());
+        assert_eq!(fetches(), 1);
+
+        http.down.store(true, Ordering::SeqCst);
+        clock.advance(JWKS_TTL);
+        assert!(block.jwks_key(URL, Some("k1")).is_ok());
+        assert_eq!(fetches(), 2);
+        // The failed refresh isn't retried on every request
+        assert!(block.jwks_key(URL, Some("k1")).is_ok());
+        assert_eq!(fetches(), 2);
+        assert_eq!(
+            block.jwks_key(URL, Some("k2")).err(),
+            Some((401, "Token signing key is unknown"))
+        );
+
+        clock.advance(JWKS_STALE_GRACE);
+        assert_eq!(
+            block.jwks_key(URL, Some("k1")).err(),
+            Some((503, "Token signing keys are unavailable"))
+        );
+
+        http.down.store(false, Ordering::SeqCst);
+        clock.advance(JWKS_MIN_REFRESH);
+        assert!(block.jwks_key(URL, Some("k1")).is_ok());
+    }
+
+    fn bound_token(fp: Option<String>) -> String {
+        let mut claims = json!({"user_id": "u1", "exp": chrono::Utc::now().timestamp() + 600});
+        if let Some(fp) = fp {
+            claims[FINGERPRINT_CLAIM] = json!(fp);
+        }
+        MockCrypto::token(claims)
+    }
+
+    fn client(ua: &str, ip: &str) -> Message {
+        MockRequest::get("/api/items")
+            .header("User-Agent", ua)
+            .remote_addr(ip)
+            .build()
+    }
+
+    /// Send `token` from the client `ua` at `ip`.
+    fn bound(ctx: &MockContext, token: &str, ua: &str, ip: &str) -> (SimulatedResponse, Message) {
+        let mut msg = MockRequest::get("/api/items")
+            .header("Authorization", &format!("Bearer {}", token))
+            .header("User-Agent", ua)
+            .remote_addr(ip)
+            .build();
+        let result = AuthBlock::new().handle(ctx, &mut msg);
+        let out = result.message.clone().unwrap_or(msg);
+        (SimulatedResponse::from_result(&result), out)
+    }
+
+    #[test]
+    fn strict_binding_rejects_other_clients() {
+        let ctx = MockContext::new()
+            .with_crypto()
+            .with_config("auth_bind_tokens", "strict");
+        let fp = fingerprint(
+            &client("Firefox", "203.0.113.5"),
+            FingerprintComponents::DEFAULT,
+        );
+        let token = bound_token(fp);
+
+        let (resp, msg) = bound(&ctx, &token, "Firefox", "203.0.113.5");
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::AUTH_TOKEN_BINDING), "ok");
+        // Same /24, so a client moving within its network keeps the binding
+        assert_status(&bound(&ctx, &token, "Firefox", "203.0.113.77").0, 200, None);
+
+        for (ua, ip) in [("Firefox", "198.51.100.5"), ("curl", "203.0.113.5")] {
+            let (resp, _) = bound(&ctx, &token, ua, ip);
+            assert_status(&resp, 401, Some("token_binding_failed"));
+        }
+        let unbound = bound_token(None);
+        let (resp, _) = bound(&ctx, &unbound, "Firefox", "203.0.113.5");
+        assert_status(&resp, 401, Some("token_binding_failed"));
+    }
+
+    #[test]
+    fn soft_binding_only_marks_mismatches() {
+        let ctx = MockContext::new()
+            .with_crypto()
+            .with_config("auth_bind_tokens", "soft");
+        let token = bound_token(fingerprint(
+            &client("Firefox", "203.0.113.5"),
+            FingerprintComponents::DEFAULT,
+        ));
+        let (resp, msg) = bound(&ctx, &token, "curl", "198.51.100.5");
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::AUTH_TOKEN_BINDING), "mismatch");
+        assert_eq!(msg.get_meta(meta::AUTH_USER_ID), "u1");
+    }
+
+    #[test]
+    fn ua_only_binding_survives_address_changes() {
+        let ctx = MockContext::new()
+            .with_crypto()
+            .with_config("auth_bind_tokens", "true")
+            .with_config("fingerprint_components", "ua");
+        let components = FingerprintComponents::parse("ua", FingerprintComponents::DEFAULT);
+        let token = bound_token(fingerprint(&client("App/2.0", "203.0.113.5"), components));
+        assert_status(&bound(&ctx, &token, "App/2.0", "2001:db8::1").0, 200, None);
+        let (resp, _) = bound(&ctx, &token, "App/3.0", "203.0.113.5");
+        assert_status(&resp, 401, Some("token_binding_failed"));
+    }
+
+    #[test]
+    fn unparseable_client_address_never_binds() {
+        let ip_bound = FingerprintComponents::DEFAULT;
+        assert_eq!(
+            fingerprint(&client("Firefox", "unix:/run/app.sock"), ip_bound),
+            None
+        );
+        assert_eq!(fingerprint(&client("Firefox", ""), ip_bound), None);
+
+        let ctx = MockContext::new()
+            .with_crypto()
+            .with_config("auth_bind_tokens", "strict");
+        for claim in ["", "00000000000000000000000000000000"] {
+            let token = bound_token(Some(claim.to_string()));
+            let (resp, _) = bound(&ctx, &token, "Firefox", "unix:/run/app.sock");
+            assert_status(&resp, 401, Some("token_binding_failed"));
+        }
+    }
+
+    #[test]
+    fn bind_fingerprint_is_strict_binding_to_the_client_fingerprint() {
+        let ctx = MockContext::new()
+            .with_crypto()
+            .with_config("bind_fingerprint", "true");
+        let binding = TokenBinding::from_config(&ctx).unwrap();
+        assert_eq!(binding.mode, BindingMode::Strict);
+        assert_eq!(binding.components, FingerprintComponents::LEGACY);
+
+        let token = bound_token(Some(client_fingerprint(&client("Firefox", "203.0.113.5"))));
+        // The legacy fingerprint doesn't cover the address
+        assert_status(&bound(&ctx, &token, "Firefox", "198.51.100.5").0, 200, None);
+        let (resp, _) = bound(&ctx, &token, "curl", "203.0.113.5");
+        assert_status(&resp, 401, Some("token_binding_failed"));
+    }
+
+    fn login_block(clock: Arc<ManualClock>) -> AuthBlock {
+        let tracker = LockoutTracker::new().with_clock(clock).with_policy(
+            3,
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+        );
+        AuthBlock::with_lockout(Arc::new(tracker))
+    }
+
+    fn login_ctx() -> MockContext {
+        let db = MockDatabase::new()
+            .with_row(
+                "auth_users",
+                json!({"id": "u1", "email": "a@example.com", "password_hash": MockCrypto::digest("pw")}),
+            )
+            .with_row("iam_user_roles", json!({"user_id": "u1", "role": "editor"}));
+        services(db).with_config("login_path", "/auth/login")
+    }
+
+    fn login(
+        block: &AuthBlock,
+        ctx: &MockContext,
+        email: &str,
+        password: &str,
+    ) -> SimulatedResponse {
+        let body = json!({"email": email, "password": password}).to_string();
+        let mut msg = MockRequest::post("/auth/login")
+            .body(body.as_bytes())
+            .build();
+        SimulatedResponse::from_result(&block.handle(ctx, &mut msg))
+    }
+
+    #[test]
+    fn login_issues_a_token_for_the_right_password() {
+        let block = login_block(Arc::new(ManualClock::new()));
+        let resp = login(&block, &login_ctx(), "a@example.com", "pw");
+        assert_status(&resp, 200, None);
+        let token = resp.json().unwrap()["token"].as_str().unwrap().to_string();
+        let claims = MockCrypto.verify(&token).unwrap();
+        assert_eq!(claims["user_id"], "u1");
+        assert_eq!(claims["roles"], json!(["editor"]));
+        assert!(resp
+            .header("Set-Cookie")
+            .unwrap()
+            .starts_with("auth_token="));
+    }
+
+    #[test]
+    fn repeated_failures_lock_with_retry_after() {
+        let clock = Arc::new(ManualClock::new());
+        let block = login_block(clock.clone());
+        let ctx = login_ctx();
+        for _ in 0..2 {
+            let resp = login(&block, &ctx, "a@example.com", "wrong");
+            assert_status(&resp, 401, Some("unauthorized"));
+        }
+        let resp = login(&block, &ctx, "a@example.com", "wrong");
+        assert_status(&resp, 429, Some("too_many_attempts"));
+        assert_header(&resp, "Retry-After", "60");
+
+        // Locked: the right password is not even checked
+        clock.advance(Duration::from_secs(20));
+        let resp = login(&block, &ctx, "A@example.com", "pw");
+        assert_status(&resp, 429, Some("too_many_attempts"));
+        assert_header(&resp, "Retry-After", "40");
+
+        // The next failure after the lock doubles it
+        clock.advance(Duration::from_secs(41));
+        let resp = login(&block, &ctx, "a@example.com", "wrong");
+        assert_status(&resp, 429, Some("too_many_attempts"));
+        assert_header(&resp, "Retry-After", "120");
+    }
+
+    #[test]
+    fn successful_login_resets_failures() {
+        let clock = Arc::new(ManualClock::new());
+        let block = login_block(clock.clone());
+        let ctx = login_ctx();
+        for _ in 0..2 {
+            login(&block, &ctx, "a@example.com", "wrong");
+        }
+        assert_status(&login(&block, &ctx, "a@example.com", "pw"), 200, None);
+        for _ in 0..2 {
+            assert_status(&login(&block, &ctx, "a@example.com", "wrong"), 401, None);
+        }
+        assert!(!block.lockout().is_locked("a@example.com"));
+    }
+
+    #[test]
+    fn unknown_accounts_fail_like_wrong_passwords() {
+        let block = login_block(Arc::new(ManualClock::new()));
+        let ctx = login_ctx();
+        let unknown = login(&block, &ctx, "nobody@example.com", "pw");
+        let wrong = login(&block, &ctx, "a@example.com", "wrong");
+        assert_status(&unknown, 401, Some("unauthorized"));
+        assert_eq!(unknown.body, wrong.body);
+        for _ in 0..2 {
+            login(&block, &ctx, "nobody@example.com", "pw");
+        }
+        assert!(block.lockout().is_locked("nobody@example.com"));
+    }
+
+    #[test]
+    fn token_auth_does_not_clear_login_failures() {
+        let block = login_block(Arc::new(ManualClock::new()));
+        let ctx = login_ctx().with_config("lockout_enforce", "true");
+        for _ in 0..2 {
+            login(&block, &ctx, "a@example.com", "wrong");
+        }
+        let exp = chrono::Utc::now().timestamp() + 600;
+        let token =
+            MockCrypto::token(json!({"user_id": "u1", "email": "a@example.com", "exp": exp}));
+        let mut msg = MockRequest::get("/api")
+            .header("Authorization", &format!("Bearer {}", token))
+            .build();
+        assert_status(
+            &SimulatedResponse::from_result(&block.handle(&ctx, &mut msg)),
+            200,
+            None,
+        );
+        // Still the third failure, so the account locks
+        let resp = login(&block, &ctx, "a@example.com", "wrong");
+        assert_status(&resp, 429, Some("too_many_attempts"));
+
+        // And the lock now refuses that user's tokens
+        let mut msg = MockRequest::get("/api")
+            .header("Authorization", &format!("Bearer {}", token))
+            .build();
+        let resp = SimulatedResponse::from_result(&block.handle(&ctx, &mut msg));
+        assert_status(&resp, 429, Some("too_many_attempts"));
+        assert!(resp.header("Retry-After").is_some());
+    }
+
+    #[test]
+    fn token_emails_are_matched_to_locks_in_any_case() {
+        let block = login_block(Arc::new(ManualClock::new()));
+        let ctx = login_ctx().with_config("lockout_enforce", "true");
+        for _ in 0..3 {
+            login(&block, &ctx, "a@example.com", "wrong");
+        }
+        let exp = chrono::Utc::now().timestamp() + 600;
+        let token =
+            MockCrypto::token(json!({"user_id": "u2", "email": "A@Example.COM", "exp": exp}));
+        let mut msg = MockRequest::get("/api")
+            .header("Authorization", &format!("Bearer {}", token))
+            .build();
+        let resp = SimulatedResponse::from_result(&block.handle(&ctx, &mut msg));
+        assert_status(&resp, 429, Some("too_many_attempts"));
+    }
+
+    #[test]
+    fn concurrent_failures_are_all_counted() {
+        let tracker = Arc::new(LockoutTracker::new().with_policy(
+            80,
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+        ));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let tracker = tracker.clone();
+                std::thread::spawn(move || {
+                    (0..10)
+                        .filter(|_| tracker.record_failure("a@example.com").is_some())
+                        .count()
+                })
+            })
+            .collect();
+        let locks: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        // Only the 80th failure reaches the threshold, so no update was lost
+        assert_eq!(locks, 1);
+        assert!(tracker.is_locked("a@example.com"));
+        assert_eq!(
+            tracker.record_failure("a@example.com"),
+            Some(Duration::from_secs(120))
+        );
+    }
+
+    #[derive(Default)]
+    struct MemoryStore {
+        saved: Mutex<HashMap<String, LockoutState>>,
+    }
+
+    impl LockoutStore for MemoryStore {
+        fn load(&self) -> Vec<(String, LockoutState)> {
+            self.saved
+                .lock()
+                .iter()
+                .map(|(id, state)| (id.clone(), state.clone()))
+                .collect()
+        }
+
+        fn save(&self, identifier: &str, state: Option<&LockoutState>) {
+            let mut saved = self.saved.lock();
+            match state {
+                Some(state) => saved.insert(identifier.to_string(), state.clone()),
+                None => saved.remove(identifier),
+            };
+        }
+    }
+
+    #[test]
+    fn stored_locks_survive_a_restart() {
+        let clock = Arc::new(ManualClock::new());
+        let store = Arc::new(MemoryStore::default());
+        let tracker = |store: &Arc<MemoryStore>| {
+            LockoutTracker::new()
+                .with_clock(clock.clone())
+                .with_policy(2, Duration::from_secs(60), Duration::from_secs(3600))
+                .with_store(store.clone())
+        };
+
+        let first = tracker(&store);
+        first.record_failure("a@example.com");
+        assert_eq!(
+            store.saved.lock()["a@example.com"],
+            LockoutState {
+                failures: 1,
+                locked_until: 0
+            }
+        );
+        assert_eq!(
+            first.record_failure("a@example.com"),
+            Some(Duration::from_secs(60))
+        );
+        let until = clock.now_utc().timestamp() + 60;
+        assert_eq!(store.saved.lock()["a@example.com"].locked_until, until);
+
+        clock.advance(Duration::from_secs(15));
+        let restarted = tracker(&store);
+        assert_eq!(
+            restarted.locked_for("a@example.com"),
+            Some(Duration::from_secs(45))
+        );
+        // The restored count keeps escalating
+        assert_eq!(
+            restarted.record_failure("a@example.com"),
+            Some(Duration::from_secs(120))
+        );
+
+        restarted.unlock("a@example.com");
+        assert!(store.saved.lock().is_empty());
+        assert!(!tracker(&store).is_locked("a@example.com"));
+    }
+
+    #[test]
+    fn login_requires_post_and_a_json_body() {
+        let block = login_block(Arc::new(ManualClock::new()));
+        let ctx = login_ctx();
+        let mut msg = MockRequest::get("/auth/login").build();
+        let resp = SimulatedResponse::from_result(&block.handle(&ctx, &mut msg));
+        assert_status(&resp, 405, Some("method_not_allowed"));
+        let mut msg = MockRequest::post("/auth/login").body(b"email=a").build();
+        let resp = SimulatedResponse::from_result(&block.handle(&ctx, &mut msg));
+        assert_status(&resp, 400, Some("bad_request"));
+    }
+
+    #[test]
+    fn lock_escalation_schedule() {
+        let tracker = LockoutTracker::new();
+        assert_eq!(tracker.lock_duration(4), None);
+        assert_eq!(tracker.lock_duration(5), Some(Duration::from_secs(60)));
+        assert_eq!(tracker.lock_duration(6), Some(Duration::from_secs(120)));
+        assert_eq!(tracker.lock_duration(10), Some(Duration::from_secs(1920)));
+        assert_eq!(tracker.lock_duration(11), Some(Duration::from_secs(3600)));
+        assert_eq!(
+            tracker.lock_duration(u32::MAX),
+            Some(Duration::from_secs(3600))
+        );
+    }
+
+    #[test]
+    fn bearer_scheme_is_case_insensitive_and_the_token_untouched() {
+        for scheme in ["Bearer", "bearer", "BEARER"] {
+            let header = format!("{} AbC.dEf-Gh", scheme);
+            let msg = MockRequest::get("/")
+                .header("Authorization", &header)
+                .build();
+            assert_eq!(
+                AuthBlock::extract_token(&msg).as_deref(),
+                Some("AbC.dEf-Gh"),
+                "{}",
+                scheme
+            );
+
+            let ctx = services(key_db(key_row(json!({}))));
+            let mut msg = MockRequest::get("/api/items")
+                .header("Authorization", &format!("{} {}", scheme, KEY))
+                .build();
+            let resp = SimulatedResponse::from_result(&AuthBlock::new().handle(&ctx, &mut msg));
+            assert_status(&resp, 200, None);
+        }
+        let basic = MockRequest::get("/")
+            .header("Authorization", "Basic AbC")
+            .build();
+        assert_eq!(AuthBlock::extract_token(&basic), None);
+    }
+
+    #[test]
+    fn cookies_can_drop_secure_for_local_http() {
+        let defaults = CookieAttributes::from_config(&MockContext::new());
+        assert_eq!(defaults, CookieAttributes::default());
+        assert_eq!(
+            defaults.set(AUTH_COOKIE, "t", None),
+            "auth_token=t; Path=/; HttpOnly; Secure; SameSite=Lax"
+        );
+
+        let dev = MockContext::new()
+            .with_config("cookie_secure", "false")
+            .with_config("cookie_max_age", "600");
+        let attrs = CookieAttributes::from_config(&dev);
+        assert!(!attrs.secure);
+        assert_eq!(
+            attrs.set(AUTH_COOKIE, "t", None),
+            "auth_token=t; Path=/; Max-Age=600; HttpOnly; SameSite=Lax"
+        );
+        assert_eq!(
+            attrs.clear(AUTH_COOKIE),
+            "auth_token=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax"
+        );
+    }
+
+    #[test]
+    fn invalid_cookie_tokens_are_cleared_when_configured() {
+        let ctx = |clear: bool| {
+            let ctx = MockContext::new().with_crypto();
+            if clear {
+                ctx.with_config("clear_invalid_cookie", "true")
+            } else {
+                ctx
+            }
+        };
+        let send = |ctx: &MockContext, req: MockRequest| {
+            let mut msg = req.build();
+            SimulatedResponse::from_result(&AuthBlock::new().handle(ctx, &mut msg))
+        };
+        let stale = || MockRequest::get("/api/items").cookie(AUTH_COOKIE, "not.a.jwt");
+
+        let resp = send(&ctx(true), stale());
+        assert_status(&resp, 401, Some("unauthorized"));
+        assert_header(
+            &resp,
+            "Set-Cookie",
+            "auth_token=; Path=/; Max-Age=0; HttpOnly; Secure; SameSite=Lax",
+        );
+
+        // Off by default, and never for a header token
+        assert_no_header(&send(&ctx(false), stale()), "Set-Cookie");
+        let header = MockRequest::get("/api/items").header("Authorization", "Bearer not.a.jwt");
+        let resp = send(&ctx(true), header);
+        assert_status(&resp, 401, Some("unauthorized"));
+        assert_no_header(&resp, "Set-Cookie");
+    }
 }
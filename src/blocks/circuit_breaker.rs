@@ -0,0 +1,564 @@
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::{Duration, Instant};
+use wafer_run::*;
+
+use super::hooks::{self, ResponseHook};
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::clock::{self, Clock};
+use crate::errors::CoreError;
+use crate::meta;
+
+/// CircuitBreakerBlock stops sending traffic to a failing downstream.
+///
+/// The breaker counts downstream failures (results with status 500 and above)
+/// and opens once `failure_threshold` (default 5) of them fall within
+/// `window` seconds (default 30). While open, requests are answered with 503
+/// `circuit_open` and `Retry-After` for `cooldown` seconds (default 30). It
+/// then half-opens and admits `half_open_probes` requests (default 1): if
+/// they all succeed it closes, and any failure opens it again. A probe that
+/// hasn't reported back within `probe_timeout` seconds (default 30) counts
+/// as failed, so a lost result can't leave the breaker half-open for good.
+///
+/// Each distinct node config gets a breaker of its own, so nodes with
+/// different thresholds never share state.
+///
+/// Outcomes reach the breaker through a response hook. Either wrap the
+/// downstream handler so the block observes it directly:
+///
+/// ```ignore
+/// circuit_breaker::register_guarding(w, "@app/api", api_handler);
+/// ```
+///
+/// or register the block as middleware and attach the outcome hook to the
+/// handler: `hooks::with_hooks(handler, vec![circuit_breaker::outcome_hook()])`.
+/// The middleware names the breaker that admitted a request in
+/// `circuit_breaker.id` meta, and the hook reports to that breaker.
+pub struct CircuitBreakerBlock {
+    breakers: Mutex<HashMap<Settings, Arc<CircuitBreaker>>>,
+    inner: Option<Arc<dyn Block>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl CircuitBreakerBlock {
+    /// Middleware; pair it with `outcome_hook` on the guarded handler.
+    pub fn new() -> Self {
+        Self {
+            breakers: Mutex::new(HashMap::new()),
+            inner: None,
+            clock: clock::system(),
+        }
+    }
+
+    /// Guard `inner`, observing its results directly.
+    pub fn wrapping(inner: Arc<dyn Block>) -> Self {
+        Self {
+            inner: Some(inner),
+            ..Self::new()
+        }
+    }
+
+    /// Use `clock` for windows, cooldowns and probe timeouts.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The breaker for a node with this config.
+    pub fn breaker(&self, ctx: &dyn Context) -> Arc<CircuitBreaker> {
+        let settings = Settings::from_config(ctx);
+        self.breakers
+            .lock()
+            .entry(settings)
+            .or_insert_with(|| Arc::new(CircuitBreaker::with(settings, self.clock.clone())))
+            .clone()
+    }
+}
+
+/// Breaker thresholds, from node config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Settings {
+    failure_threshold: usize,
+    window: Duration,
+    cooldown: Duration,
+    half_open_probes: u32,
+    probe_timeout: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            window: Duration::from_secs(30),
+            cooldown: Duration::from_secs(30),
+            half_open_probes: 1,
+            probe_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Settings {
+    fn from_config(ctx: &dyn Context) -> Self {
+        let defaults = Self::default();
+        let num = |key: &str, default: u64| {
+            ctx.config_get(key)
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            failure_threshold: num("failure_threshold", defaults.failure_threshold as u64).max(1)
+                as usize,
+            window: Duration::from_secs(num("window", defaults.window.as_secs())),
+            cooldown: Duration::from_secs(num("cooldown", defaults.cooldown.as_secs())),
+            half_open_probes: num("half_open_probes", defaults.half_open_probes as u64).max(1)
+                as u32,
+            probe_timeout: Duration::from_secs(
+                num("probe_timeout", defaults.probe_timeout.as_secs()).max(1),
+            ),
+        }
+    }
+}
+
+/// Breaker state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::HalfOpen => "half_open",
+        }
+    }
+}
+
+struct Inner {
+    state: BreakerState,
+    /// Recent failure times while closed, at most `failure_threshold`.
+    failures: VecDeque<Instant>,
+    opened_at: Instant,
+    probes_admitted: u32,
+    probes_succeeded: u32,
+    /// When the latest half-open probe was admitted.
+    probed_at: Instant,
+}
+
+/// Shared, thread-safe breaker state. As a `ResponseHook` it records the
+/// outcome of every result it observes.
+pub struct CircuitBreaker {
+    id: u64,
+    settings: Settings,
+    clock: Arc<dyn Clock>,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    /// A breaker with the default thresholds.
+    pub fn new() -> Arc<Self> {
+        Self::with(Settings::default(), clock::system())
+    }
+
+    fn with(settings: Settings, clock: Arc<dyn Clock>) -> Arc<Self> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        let now = clock.now_instant();
+        let breaker = Arc::new(Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            settings,
+            clock,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                failures: VecDeque::new(),
+                opened_at: now,
+                probes_admitted: 0,
+                probes_succeeded: 0,
+                probed_at: now,
+            }),
+        });
+        let mut live = live_breakers().write();
+        live.retain(|_, b| b.strong_count() > 0);
+        live.insert(breaker.id, Arc::downgrade(&breaker));
+        breaker
+    }
+
+    /// The breaker that admitted the request, if it is still alive.
+    pub fn of(msg: &Message) -> Option<Arc<Self>> {
+        let id = msg.get_meta(meta::CIRCUIT_BREAKER).parse::<u64>().ok()?;
+        live_breakers().read().get(&id)?.upgrade()
+    }
+
+    /// Current state.
+    pub fn state(&self) -> BreakerState {
+        self.inner.lock().state
+    }
+
+    /// Admit a request, or return how long until it is worth retrying.
+    fn admit(&self) -> Result<(), Duration> {
+        let settings = self.settings;
+        let mut inner = self.inner.lock();
+        let now = self.clock.now_instant();
+        if inner.state == BreakerState::HalfOpen
+            && inner.probes_admitted >= settings.half_open_probes
+        {
+            let waited = now.duration_since(inner.probed_at);
+            if waited < settings.probe_timeout {
+                return Err(settings.probe_timeout - waited);
+            }
+            tracing::warn!(
+                "circuit-breaker: probe unanswered after {:?}, reopening",
+                settings.probe_timeout
+            );
+            inner.state = BreakerState::Open;
+            inner.opened_at = now;
+        }
+        if inner.state == BreakerState::Open {
+            let elapsed = now.duration_since(inner.opened_at);
+            if elapsed < settings.cooldown {
+                return Err(settings.cooldown - elapsed);
+            }
+            tracing::info!("circuit-breaker: half-open, probing downstream");
+            inner.state = BreakerState::HalfOpen;
+            inner.probes_admitted = 0;
+            inner.probes_succeeded = 0;
+        }
+        if inner.state == BreakerState::HalfOpen {
+            inner.probes_admitted += 1;
+            inner.probed_at = now;
+        }
+        Ok(())
+    }
+
+    /// Record one downstream outcome.
+    pub fn record(&self, failed: bool) {
+        let settings = self.settings;
+        let mut inner = self.inner.lock();
+        let now = self.clock.now_instant();
+        match inner.state {
+            BreakerState::Closed if failed => {
+                inner
+                    .failures
+                    .retain(|t| now.duration_since(*t) < settings.window);
+                inner.failures.push_back(now);
+                while inner.failures.len() > settings.failure_threshold {
+                    inner.failures.pop_front();
+                }
+                if inner.failures.len() >= settings.failure_threshold {
+                    tracing::warn!(
+                        "circuit-breaker: {} failures within {:?}, opening",
+                        inner.failures.len(),
+                        settings.window
+                    );
+                    inner.state = BreakerState::Open;
+                    inner.opened_at = now;
+                }
+            }
+            BreakerState::Closed => {}
+            BreakerState::HalfOpen if failed => {
+                tracing::warn!("circuit-breaker: probe failed, reopening");
+                inner.state = BreakerState::Open;
+                inner.opened_at = now;
+            }
+            BreakerState::HalfOpen => {
+                inner.probes_succeeded += 1;
+                if inner.probes_succeeded >= settings.half_open_probes {
+                    tracing::info!("circuit-breaker: probes succeeded, closing");
+                    inner.state = BreakerState::Closed;
+                    inner.failures.clear();
+                }
+            }
+            // Stragglers admitted before the breaker opened
+            BreakerState::Open => {}
+        }
+    }
+}
+
+impl ResponseHook for CircuitBreaker {
+    fn on_result(&self, _ctx: &dyn Context, _req: &Message, result: &mut Result_) {
+        self.record(hooks::result_status(result) >= 500);
+    }
+}
+
+/// Reports results to the breaker named in the request's meta.
+struct OutcomeHook;
+
+impl ResponseHook for OutcomeHook {
+    fn on_result(&self, _ctx: &dyn Context, req: &Message, result: &mut Result_) {
+        if let Some(breaker) = CircuitBreaker::of(req) {
+            breaker.record(hooks::result_status(result) >= 500);
+        }
+    }
+}
+
+/// The hook to attach to a handler guarded by CircuitBreakerBlock middleware.
+pub fn outcome_hook() -> Arc<dyn ResponseHook> {
+    Arc::new(OutcomeHook)
+}
+
+/// Live breakers by id. Only weak references, so a dropped block's breakers
+/// go with it.
+fn live_breakers() -> &'static RwLock<HashMap<u64, Weak<CircuitBreaker>>> {
+    static LIVE: OnceLock<RwLock<HashMap<u64, Weak<CircuitBreaker>>>> = OnceLock::new();
+    LIVE.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// Answer a request the open breaker rejects.
+fn circuit_open(msg: &Message, retry_after: Duration) -> Result_ {
+    let mut m = msg.clone();
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    meta::set_resp_header(&mut m, "Retry-After", &secs.max(1).to_string());
+    CoreError::custom(
+        503,
+        "circuit_open",
+        "Service temporarily unavailable. Please try again later.",
+    )
+    .respond(&m)
+}
+
+impl Block for CircuitBreakerBlock {
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "@wafer/circuit-breaker".to_string(),
+            version: "0.1.0".to_string(),
+            interface: "middleware@v1".to_string(),
+            summary: "Fast-fails requests to a failing downstream".to_string(),
+            instance_mode: InstanceMode::Singleton,
+            allowed_modes: Vec::new(),
+            admin_ui: crate::admin::ui(admin_descriptor()),
+        }
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let breaker = self.breaker(ctx);
+        if let Err(retry_after) = breaker.admit() {
+            return circuit_open(msg, retry_after);
+        }
+        match &self.inner {
+            Some(inner) => {
+                let result = inner.handle(ctx, msg);
+                breaker.record(hooks::result_status(&result) >= 500);
+                result
+            }
+            None => {
+                msg.set_meta(meta::CIRCUIT_BREAKER, &breaker.id.to_string());
+                msg.clone().cont()
+            }
+        }
+    }
+
+    fn lifecycle(
+        &self,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        match &self.inner {
+            Some(inner) => inner.lifecycle(ctx, event),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "failure_threshold",
+            FieldKind::Integer,
+            "5",
+            "Failures within the window that open the circuit",
+        )
+        .field(
+            "window",
+            FieldKind::Integer,
+            "30",
+            "Seconds over which failures are counted",
+        )
+        .field(
+            "cooldown",
+            FieldKind::Integer,
+            "30",
+            "Seconds the circuit stays open before probing",
+        )
+        .field(
+            "half_open_probes",
+            FieldKind::Integer,
+            "1",
+            "Successful probes needed to close the circuit",
+        )
+        .field(
+            "probe_timeout",
+            FieldKind::Integer,
+            "30",
+            "Seconds to wait for a probe's result before reopening",
+        )
+}
+
+pub fn register(w: &mut Wafer) {
+    register_as(w, "@wafer/circuit-breaker");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(CircuitBreakerBlock::new()));
+}
+
+/// Register `inner` under `name`, guarded by a breaker of its own.
+pub fn register_guarding(w: &mut Wafer, name: &str, inner: Arc<dyn Block>) {
+    super::register_as(w, name, Arc::new(CircuitBreakerBlock::wrapping(inner)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// Answers with the status in its `status` config.
+    struct Status;
+
+    impl Block for Status {
+        fn info(&self) -> BlockInfo {
+            BlockInfo {
+                name: "@test/status".to_string(),
+                version: "0.1.0".to_string(),
+                interface: "handler@v1".to_string(),
+                summary: "Answers with a configured status".to_string(),
+                instance_mode: InstanceMode::Singleton,
+                allowed_modes: Vec::new(),
+                admin_ui: None,
+            }
+        }
+
+        fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+            let status = ctx
+                .config_get("status")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(200);
+            respond(msg.clone(), status, Vec::new(), "text/plain")
+        }
+
+        fn lifecycle(
+            &self,
+            _ctx: &dyn Context,
+            _event: LifecycleEvent,
+        ) -> std::result::Result<(), WaferError> {
+            Ok(())
+        }
+    }
+
+    fn guarded(clock: Arc<ManualClock>) -> CircuitBreakerBlock {
+        CircuitBreakerBlock::wrapping(Arc::new(Status)).with_clock(clock)
+    }
+
+    fn ctx(status: u16) -> MockContext {
+        MockContext::new()
+            .with_config("status", &status.to_string())
+            .with_config("failure_threshold", "2")
+            .with_config("cooldown", "10")
+            .with_config("probe_timeout", "5")
+    }
+
+    fn call(block: &CircuitBreakerBlock, ctx: &MockContext) -> SimulatedResponse {
+        let mut msg = MockRequest::get("/api/items").build();
+        SimulatedResponse::from_result(&block.handle(ctx, &mut msg))
+    }
+
+    #[test]
+    fn opens_after_threshold_and_closes_on_probe_success() {
+        let clock = Arc::new(ManualClock::new());
+        let block = guarded(clock.clone());
+        assert_status(&call(&block, &ctx(500)), 500, None);
+        assert_eq!(block.breaker(&ctx(500)).state(), BreakerState::Closed);
+        assert_status(&call(&block, &ctx(500)), 500, None);
+        assert_eq!(block.breaker(&ctx(500)).state(), BreakerState::Open);
+
+        let resp = call(&block, &ctx(500));
+        assert_status(&resp, 503, Some("circuit_open"));
+        assert_header(&resp, "Retry-After", "10");
+
+        clock.advance(Duration::from_secs(10));
+        assert_status(&call(&block, &ctx(200)), 200, None);
+        assert_eq!(block.breaker(&ctx(200)).state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn failures_outside_the_window_do_not_open() {
+        let clock = Arc::new(ManualClock::new());
+        let breaker = CircuitBreaker::with(
+            Settings {
+                failure_threshold: 2,
+                ..Settings::default()
+            },
+            clock.clone(),
+        );
+        breaker.record(true);
+        clock.advance(Duration::from_secs(31));
+        breaker.record(true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record(true);
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let clock = Arc::new(ManualClock::new());
+        let block = guarded(clock.clone());
+        call(&block, &ctx(500));
+        call(&block, &ctx(500));
+        clock.advance(Duration::from_secs(10));
+        assert_status(&call(&block, &ctx(500)), 500, None);
+        assert_eq!(block.breaker(&ctx(500)).state(), BreakerState::Open);
+        assert_status(&call(&block, &ctx(500)), 503, Some("circuit_open"));
+    }
+
+    #[test]
+    fn unanswered_probe_times_out_and_reopens() {
+        let clock = Arc::new(ManualClock::new());
+        let block = guarded(clock.clone());
+        let breaker = block.breaker(&ctx(500));
+        breaker.record(true);
+        breaker.record(true);
+        clock.advance(Duration::from_secs(10));
+        // Admitted as the probe, but its result never arrives
+        assert!(breaker.admit().is_ok());
+        assert_eq!(breaker.admit(), Err(Duration::from_secs(5)));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(breaker.admit(), Err(Duration::from_secs(10)));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        clock.advance(Duration::from_secs(10));
+        assert!(breaker.admit().is_ok());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+    }
+
+    #[test]
+    fn nodes_with_different_config_do_not_share_a_breaker() {
+        let clock = Arc::new(ManualClock::new());
+        let block = guarded(clock);
+        let strict = ctx(500).with_config("failure_threshold", "1");
+        call(&block, &strict);
+        assert_status(&call(&block, &strict), 503, Some("circuit_open"));
+        assert_status(&call(&block, &ctx(500)), 500, None);
+        assert_eq!(block.breaker(&ctx(500)).state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn middleware_breaker_hears_from_the_outcome_hook() {
+        let middleware = CircuitBreakerBlock::new();
+        let handler = hooks::with_hooks(Arc::new(Status), vec![outcome_hook()]);
+        let ctx = ctx(502);
+        for _ in 0..2 {
+            let mut msg = MockRequest::get("/api/items").build();
+            let result = middleware.handle(&ctx, &mut msg);
+            let mut msg = result.message.unwrap_or(msg);
+            handler.handle(&ctx, &mut msg);
+        }
+        assert_eq!(middleware.breaker(&ctx).state(), BreakerState::Open);
+        let mut msg = MockRequest::get("/api/items").build();
+        let resp = SimulatedResponse::from_result(&middleware.handle(&ctx, &mut msg));
+        assert_status(&resp, 503, Some("circuit_open"));
+    }
+}
@@ -0,0 +1,242 @@
+use std::sync::Arc;
+use wafer_run::*;
+
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::meta;
+
+/// Hints requested when `hints` is not configured.
+const DEFAULT_HINTS: &str = "Sec-CH-UA-Mobile, Sec-CH-UA-Platform, Downlink, Save-Data";
+
+/// ClientHintsBlock derives device-class metadata from client hints.
+///
+/// It advertises the configured `hints` via `Accept-CH`, then parses the hint
+/// headers (falling back to a small User-Agent heuristic) into meta:
+/// `client.mobile` ("true"/"false"), `client.platform` (e.g. "Android", or
+/// empty if unknown), `client.save_data` ("true"/"false") and
+/// `client.downlink` (Mbps, when sent). The hints consumed are added to
+/// `Vary`, and so is `User-Agent` whenever the heuristic decided a value.
+pub struct ClientHintsBlock;
+
+impl ClientHintsBlock {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+/// Parse a structured-header boolean (`?1` / `?0`).
+fn parse_sf_bool(v: &str) -> Option<bool> {
+    match v.trim() {
+        "?1" => Some(true),
+        "?0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Guess mobile from the User-Agent string.
+fn ua_is_mobile(ua: &str) -> bool {
+    ["Mobi", "Android", "iPhone", "iPod"]
+        .iter()
+        .any(|m| ua.contains(m))
+}
+
+/// Guess the platform from the User-Agent string, using client-hint names.
+fn ua_platform(ua: &str) -> &'static str {
+    if ua.contains("Android") {
+        "Android"
+    } else if ua.contains("iPhone") || ua.contains("iPad") || ua.contains("iPod") {
+        "iOS"
+    } else if ua.contains("Windows") {
+        "Windows"
+    } else if ua.contains("CrOS") {
+        "Chrome OS"
+    } else if ua.contains("Mac OS X") || ua.contains("Macintosh") {
+        "macOS"
+    } else if ua.contains("Linux") {
+        "Linux"
+    } else {
+        ""
+    }
+}
+
+impl Block for ClientHintsBlock {
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "@wafer/client-hints".to_string(),
+            version: "0.1.0".to_string(),
+            interface: "middleware@v1".to_string(),
+            summary: "Client hints and device-class metadata".to_string(),
+            instance_mode: InstanceMode::Singleton,
+            allowed_modes: Vec::new(),
+            admin_ui: crate::admin::ui(admin_descriptor()),
+        }
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let hints: Vec<String> = ctx
+            .config_get("hints")
+            .unwrap_or(DEFAULT_HINTS)
+            .split(',')
+            .map(|h| h.trim().to_string())
+            .filter(|h| !h.is_empty())
+            .collect();
+        let wants = |name: &str| hints.iter().any(|h| h.eq_ignore_ascii_case(name));
+
+        if !hints.is_empty() {
+            meta::set_resp_header(msg, "Accept-CH", &hints.join(", "));
+        }
+
+        let ua = msg.header("User-Agent").to_string();
+        let mut consumed: Vec<&str> = Vec::new();
+        // Set when a value comes from the User-Agent, which caches must then key on
+        let mut from_ua = false;
+
+        let mobile = match parse_sf_bool(msg.header("Sec-CH-UA-Mobile")) {
+            Some(m) if wants("Sec-CH-UA-Mobile") => {
+                consumed.push("Sec-CH-UA-Mobile");
+                m
+            }
+            _ => {
+                from_ua = true;
+                ua_is_mobile(&ua)
+            }
+        };
+
+        let platform_hint = msg
+            .header("Sec-CH-UA-Platform")
+            .trim()
+            .trim_matches('"')
+            .to_string();
+        let platform = if !platform_hint.is_empty() && wants("Sec-CH-UA-Platform") {
+            consumed.push("Sec-CH-UA-Platform");
+            platform_hint
+        } else {
+            from_ua = true;
+            ua_platform(&ua).to_string()
+        };
+
+        let save_data = msg.header("Save-Data").trim().eq_ignore_ascii_case("on");
+        if wants("Save-Data") {
+            consumed.push("Save-Data");
+        }
+
+        let downlink = msg.header("Downlink").trim().to_string();
+        if wants("Downlink") && downlink.parse::<f64>().is_ok() {
+            consumed.push("Downlink");
+            msg.set_meta(meta::CLIENT_DOWNLINK, &downlink);
+        }
+        if from_ua {
+            consumed.push("User-Agent");
+        }
+
+        meta::set_flag(msg, meta::CLIENT_MOBILE, mobile);
+        msg.set_meta(meta::CLIENT_PLATFORM, &platform);
+        meta::set_flag(msg, meta::CLIENT_SAVE_DATA, save_data);
+
+        if !consumed.is_empty() {
+            meta::set_resp_header(msg, "Vary", &consumed.join(", "));
+        }
+
+        msg.clone().cont()
+    }
+
+    fn lifecycle(
+        &self,
+        _ctx: &dyn Context,
+        _event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        Ok(())
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new().field(
+        "hints",
+        FieldKind::List,
+        DEFAULT_HINTS,
+        "Client hints requested through Accept-CH",
+    )
+}
+
+pub fn register(w: &mut Wafer) {
+    register_as(w, "@wafer/client-hints");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(ClientHintsBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn run(ctx: &MockContext, req: MockRequest) -> (SimulatedResponse, Message) {
+        let mut msg = req.build();
+        let result = ClientHintsBlock::new().handle(ctx, &mut msg);
+        let out = result.message.clone().unwrap_or(msg);
+        (SimulatedResponse::from_result(&result), out)
+    }
+
+    const IPHONE: &str = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) Mobile/15E148";
+
+    #[test]
+    fn hints_decide_when_sent() {
+        let req = MockRequest::get("/")
+            .header("User-Agent", IPHONE)
+            .header("Sec-CH-UA-Mobile", "?0")
+            .header("Sec-CH-UA-Platform", "\"Windows\"")
+            .header("Downlink", "2.5");
+        let (resp, msg) = run(&MockContext::new(), req);
+        assert_eq!(msg.get_meta(meta::CLIENT_MOBILE), "false");
+        assert_eq!(msg.get_meta(meta::CLIENT_PLATFORM), "Windows");
+        assert_eq!(msg.get_meta(meta::CLIENT_DOWNLINK), "2.5");
+        assert_header(&resp, "Accept-CH", DEFAULT_HINTS);
+        assert_header(
+            &resp,
+            "Vary",
+            "Sec-CH-UA-Mobile, Sec-CH-UA-Platform, Save-Data, Downlink",
+        );
+    }
+
+    #[test]
+    fn user_agent_fallback_varies_on_user_agent() {
+        let req = MockRequest::get("/").header("User-Agent", IPHONE);
+        let (resp, msg) = run(&MockContext::new(), req);
+        assert_eq!(msg.get_meta(meta::CLIENT_MOBILE), "true");
+        assert_eq!(msg.get_meta(meta::CLIENT_PLATFORM), "iOS");
+        assert_eq!(msg.get_meta(meta::CLIENT_DOWNLINK), "");
+        assert_header(&resp, "Vary", "Save-Data, User-Agent");
+
+        // Hints that weren't asked for are ignored in favor of the heuristic
+        let ctx = MockContext::new().with_config("hints", "Save-Data");
+        let req = MockRequest::get("/")
+            .header("User-Agent", "Mozilla/5.0 (X11; Linux x86_64)")
+            .header("Sec-CH-UA-Mobile", "?1");
+        let (resp, msg) = run(&ctx, req);
+        assert_eq!(msg.get_meta(meta::CLIENT_MOBILE), "false");
+        assert_eq!(msg.get_meta(meta::CLIENT_PLATFORM), "Linux");
+        assert_header(&resp, "Vary", "Save-Data, User-Agent");
+    }
+
+    #[test]
+    fn save_data_is_read_from_its_header() {
+        for (value, expected) in [
+            (Some("on"), "true"),
+            (Some("off"), "false"),
+            (None, "false"),
+        ] {
+            let mut req = MockRequest::get("/");
+            if let Some(v) = value {
+                req = req.header("Save-Data", v);
+            }
+            let (_, msg) = run(&MockContext::new(), req);
+            assert_eq!(
+                msg.get_meta(meta::CLIENT_SAVE_DATA),
+                expected,
+                "{:?}",
+                value
+            );
+        }
+    }
+}
@@ -1,12 +1,120 @@
+use parking_lot::Mutex;
 use std::sync::Arc;
 use wafer_run::*;
 
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::errors::CoreError;
+use crate::meta;
+use crate::path;
+
 /// CorsBlock handles CORS preflight and sets CORS headers.
+///
+/// Headers are written as `resp.header.*` request meta. Handlers that build
+/// error results from a fresh message would drop them; registration wraps
+/// every block with `hooks::PreserveHeaders::cors`, which puts them back,
+/// so cross-origin clients can still read error responses.
+///
+/// `allowed_origins` entries may use a subdomain wildcard,
+/// `https://*.example.com`, which matches any subdomain (not the apex) with
+/// the same scheme and port. Matches reflect the concrete origin and add
+/// `Vary: Origin`.
+///
+/// Credentials are only ever allowed for an origin listed exactly in
+/// `allowed_origins`; wildcard entries and `*` reflection never send
+/// `Access-Control-Allow-Credentials`, whatever an origin policy says.
+///
+/// With `allowed_origins: *`, the request origin is reflected (also with
+/// `Vary: Origin`) unless `reflect_wildcard` is off, which sends a literal `*`.
+///
+/// `expose_headers` lists response headers scripts may read; they are merged
+/// into any `Access-Control-Expose-Headers` another block already set.
+///
+/// `origin_policies` grants matched origins their own policy, as a JSON
+/// object from origin (wildcards allowed) to `{"methods", "headers",
+/// "credentials"}`, e.g.
+/// `{"https://partner.example": {"methods": "GET, POST, DELETE", "credentials": true}}`.
+/// An exact entry beats a wildcard one; unset fields fall back to the global
+/// config. The policy is chosen only after the origin passed
+/// `allowed_origins`, and its `credentials` only applies to exact matches of
+/// both the policy and the allowlist entry.
+///
+/// `allowed_methods: auto` advertises per-path methods from
+/// `cors_methods_map`, e.g. `/api/posts=GET,POST;/api/posts/*=GET,PUT,DELETE`.
+/// Entries are path prefixes (see `path::has_prefix`); a trailing `/*`
+/// matches only paths beneath the prefix, and the longest match applies.
+/// Paths without an entry get the default method list. In this mode a
+/// preflight asking for a method the path does not allow is answered with
+/// 403 `cors_method_not_allowed`. An origin policy's `methods` still
+/// overrides the derived list.
 pub struct CorsBlock {
     allowed_origins: String,
     allowed_methods: String,
     allowed_headers: String,
     max_age: String,
+    policies: Mutex<Option<(String, Arc<Vec<(String, OriginPolicy)>>)>>,
+    methods_map: Mutex<Option<(String, Result<Arc<MethodsMap>, String>)>>,
+}
+
+/// Per-origin overrides from `origin_policies`.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct OriginPolicy {
+    pub methods: Option<String>,
+    pub headers: Option<String>,
+    pub credentials: Option<bool>,
+}
+
+/// Methods by path prefix, parsed from `cors_methods_map`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MethodsMap {
+    /// (normalized prefix, beneath the prefix only, method list)
+    entries: Vec<(String, bool, String)>,
+}
+
+impl MethodsMap {
+    /// Parse `prefix=METHOD,METHOD;prefix/*=METHOD` entries; errors name the
+    /// entry at fault.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let mut entries = Vec::new();
+        for entry in raw.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let (pattern, methods) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("cors_methods_map: {:?} has no '='", entry))?;
+            let pattern = pattern.trim();
+            if !pattern.starts_with('/') {
+                return Err(format!(
+                    "cors_methods_map: {:?} must start with '/'",
+                    pattern
+                ));
+            }
+            let methods: Vec<String> = methods
+                .split(',')
+                .map(|m| m.trim().to_ascii_uppercase())
+                .filter(|m| !m.is_empty())
+                .collect();
+            if methods.is_empty() {
+                return Err(format!("cors_methods_map: {:?} lists no methods", pattern));
+            }
+            let (prefix, beneath) = match pattern.strip_suffix("/*") {
+                Some(p) => (p, true),
+                None => (pattern, false),
+            };
+            entries.push((path::normalize(prefix, false), beneath, methods.join(", ")));
+        }
+        Ok(Self { entries })
+    }
+
+    /// The method list for a (normalized) path, by longest prefix; a `/*`
+    /// entry beats the bare prefix for paths beneath it.
+    pub fn methods_for(&self, path: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .filter(|(p, beneath, _)| {
+                path::has_prefix(path, p) && !(*beneath && path.trim_end_matches('/') == p)
+            })
+            .max_by_key(|(p, beneath, _)| (p.len(), *beneath))
+            .map(|(_, _, m)| m.as_str())
+    }
 }
 
 impl CorsBlock {
@@ -16,7 +124,121 @@ impl CorsBlock {
             allowed_methods: "GET, POST, PUT, PATCH, DELETE, OPTIONS".to_string(),
             allowed_headers: "Content-Type, Authorization, X-Requested-With".to_string(),
             max_age: "86400".to_string(),
+            policies: Mutex::new(None),
+            methods_map: Mutex::new(None),
+        }
+    }
+
+    /// The parsed `cors_methods_map`, re-parsing only when the config changes.
+    fn methods_map(&self, raw: &str) -> Result<Arc<MethodsMap>, String> {
+        let mut cached = self.methods_map.lock();
+        if cached.as_ref().map(|(k, _)| k.as_str()) != Some(raw) {
+            let parsed = MethodsMap::parse(raw).map(Arc::new);
+            if let Err(e) = &parsed {
+                tracing::warn!("cors: ignoring {}", e);
+            }
+            *cached = Some((raw.to_string(), parsed));
+        }
+        cached.as_ref().expect("methods map just set").1.clone()
+    }
+
+    /// The parsed `origin_policies`, re-parsing only when the config changes.
+    fn policies(&self, raw: &str) -> Arc<Vec<(String, OriginPolicy)>> {
+        let mut cached = self.policies.lock();
+        if let Some((source, policies)) = cached.as_ref() {
+            if source == raw {
+                return policies.clone();
+            }
+        }
+        let parsed: Vec<(String, OriginPolicy)> =
+            match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(raw) {
+                Ok(map) => map
+                    .into_iter()
+                    .filter_map(|(origin, v)| match serde_json::from_value(v) {
+                        Ok(policy) => Some((origin, policy)),
+                        Err(e) => {
+                            tracing::warn!("cors: ignoring origin policy for {}: {}", origin, e);
+                            None
+                        }
+                    })
+                    .collect(),
+                Err(e) => {
+                    tracing::warn!("cors: ignoring invalid origin_policies: {}", e);
+                    Vec::new()
+                }
+            };
+        let parsed = Arc::new(parsed);
+        *cached = Some((raw.to_string(), parsed.clone()));
+        parsed
+    }
+}
+
+/// The policy for `origin`: an exact entry, else the first wildcard match.
+/// The flag tells whether the entry was exact.
+fn select_policy<'a>(
+    policies: &'a [(String, OriginPolicy)],
+    origin: &str,
+) -> Option<(&'a OriginPolicy, bool)> {
+    policies
+        .iter()
+        .find(|(p, _)| p.trim().eq_ignore_ascii_case(origin))
+        .map(|(_, policy)| (policy, true))
+        .or_else(|| {
+            policies
+                .iter()
+                .find(|(p, _)| origin_matches(p, origin))
+                .map(|(_, policy)| (policy, false))
+        })
+}
+
+/// Split an origin into (scheme, host, port); `None` unless it is exactly
+/// `scheme://host[:port]`.
+fn split_origin(origin: &str) -> Option<(&str, &str, &str)> {
+    let (scheme, rest) = origin.split_once("://")?;
+    if scheme.is_empty() || rest.is_empty() || rest.contains(['/', '?', '#', '@']) {
+        return None;
+    }
+    let (host, port) = match rest.rsplit_once(':') {
+        Some((h, p)) if !rest.starts_with('[') || h.ends_with(']') => (h, p),
+        _ => (rest, ""),
+    };
+    Some((scheme, host, port))
+}
+
+/// Whether `origin` matches an allowlist entry, exactly or by subdomain wildcard.
+pub fn origin_matches(pattern: &str, origin: &str) -> bool {
+    let pattern = pattern.trim();
+    if pattern.eq_ignore_ascii_case(origin) {
+        return true;
+    }
+    let (p_scheme, p_host, p_port) = match split_origin(pattern) {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let base = match p_host.strip_prefix("*.") {
+        Some(b) if !b.is_empty() && !b.contains('*') => b,
+        _ => return false,
+    };
+    let (scheme, host, port) = match split_origin(origin) {
+        Some(parts) => parts,
+        None => return false,
+    };
+    if !scheme.eq_ignore_ascii_case(p_scheme) || port != p_port {
+        return false;
+    }
+    // The host must end in ".base" on a label boundary, with a non-empty
+    // subdomain of valid label characters in front of it
+    let host = host.to_ascii_lowercase();
+    let suffix = format!(".{}", base.to_ascii_lowercase());
+    match host.strip_suffix(&suffix) {
+        Some(sub) => {
+            !sub.is_empty()
+                && sub.split('.').all(|label| {
+                    !label.is_empty()
+                        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                })
         }
+        None => false,
     }
 }
 
@@ -29,7 +251,7 @@ impl Block for CorsBlock {
             summary: "CORS preflight handler and header injection".to_string(),
             instance_mode: InstanceMode::Singleton,
             allowed_modes: Vec::new(),
-            admin_ui: None,
+            admin_ui: crate::admin::ui(admin_descriptor()),
         }
     }
 
@@ -38,40 +260,105 @@ impl Block for CorsBlock {
             .config_get("allowed_origins")
             .map(|s| s.to_string())
             .unwrap_or_else(|| self.allowed_origins.clone());
-        let methods = ctx
+        let mut methods = ctx
             .config_get("allowed_methods")
             .map(|s| s.to_string())
             .unwrap_or_else(|| self.allowed_methods.clone());
-        let headers = ctx
+        let auto_methods = methods.trim().eq_ignore_ascii_case("auto");
+        if auto_methods {
+            let map = self.methods_map(ctx.config_get("cors_methods_map").unwrap_or(""));
+            let request_path = path::request_path(msg);
+            methods = map
+                .ok()
+                .and_then(|m| m.methods_for(&request_path).map(|s| s.to_string()))
+                .unwrap_or_else(|| self.allowed_methods.clone());
+        }
+        let mut headers = ctx
             .config_get("allowed_headers")
             .map(|s| s.to_string())
             .unwrap_or_else(|| self.allowed_headers.clone());
 
+        let reflect_wildcard = ctx
+            .config_get("reflect_wildcard")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(true);
+
         // Set CORS headers on the message meta (bridge will apply them)
         let origin = msg.header("Origin").to_string();
+        let policies = self.policies(ctx.config_get("origin_policies").unwrap_or("{}"));
         let mut credentials = false;
+        let mut matched = false;
+        // Whether the origin is listed verbatim, the only case credentials are safe
+        let mut exact = false;
         if !origin.is_empty() {
             if origins == "*" {
-                // Wildcard: reflect origin but credentials MUST stay false per spec
-                msg.set_meta("resp.header.Access-Control-Allow-Origin", &origin);
-            } else if origins.split(',').any(|o| o.trim() == origin) {
-                // Origin explicitly in allowlist: safe to enable credentials
-                msg.set_meta("resp.header.Access-Control-Allow-Origin", &origin);
-                credentials = true;
+                // Wildcard: reflect origin (or send a literal "*" when reflection
+                // is disabled); credentials MUST stay false
+                matched = true;
+                if reflect_wildcard {
+                    meta::set_resp_header(msg, "Access-Control-Allow-Origin", &origin);
+                    // The header differs per origin, so caches must key on it
+                    meta::set_resp_header(msg, "Vary", "Origin");
+                } else {
+                    meta::set_resp_header(msg, "Access-Control-Allow-Origin", "*");
+                }
+            } else if let Some(entry) = origins.split(',').find(|o| origin_matches(o, &origin)) {
+                // In the allowlist, explicitly or by subdomain; only an explicit
+                // entry vouches for this very origin, so only it gets credentials
+                meta::set_resp_header(msg, "Access-Control-Allow-Origin", &origin);
+                meta::set_resp_header(msg, "Vary", "Origin");
+                exact = entry.trim().eq_ignore_ascii_case(&origin);
+                credentials = exact;
+                matched = true;
             }
         } else {
-            msg.set_meta("resp.header.Access-Control-Allow-Origin", &origins);
+            meta::set_resp_header(msg, "Access-Control-Allow-Origin", &origins);
+        }
+
+        if let Some((policy, exact_policy)) = select_policy(&policies, &origin).filter(|_| matched)
+        {
+            // The answer now depends on the origin even under a wildcard
+            meta::set_resp_header(msg, "Vary", "Origin");
+            if let Some(m) = &policy.methods {
+                methods = m.clone();
+            }
+            if let Some(h) = &policy.headers {
+                headers = h.clone();
+            }
+            if let Some(c) = policy.credentials {
+                credentials = c && exact && exact_policy;
+            }
         }
 
-        msg.set_meta("resp.header.Access-Control-Allow-Methods", &methods);
-        msg.set_meta("resp.header.Access-Control-Allow-Headers", &headers);
+        meta::set_resp_header(msg, "Access-Control-Allow-Methods", &methods);
+        meta::set_resp_header(msg, "Access-Control-Allow-Headers", &headers);
         if credentials {
-            msg.set_meta("resp.header.Access-Control-Allow-Credentials", "true");
+            meta::set_resp_header(msg, "Access-Control-Allow-Credentials", "true");
+        }
+        meta::set_resp_header(msg, "Access-Control-Max-Age", &self.max_age);
+        if let Some(expose) = ctx.config_get("expose_headers").filter(|s| !s.is_empty()) {
+            meta::set_resp_header(msg, "Access-Control-Expose-Headers", expose);
         }
-        msg.set_meta("resp.header.Access-Control-Max-Age", &self.max_age);
 
         // Handle OPTIONS preflight
-        if msg.get_meta("http.method") == "OPTIONS" {
+        if meta::http_method(msg) == meta::Method::Options {
+            let requested = msg
+                .header("Access-Control-Request-Method")
+                .trim()
+                .to_string();
+            if auto_methods
+                && !requested.is_empty()
+                && !methods
+                    .split(',')
+                    .any(|m| m.trim().eq_ignore_ascii_case(&requested))
+            {
+                return CoreError::custom(
+                    403,
+                    "cors_method_not_allowed",
+                    &format!("{} is not allowed on this path", requested),
+                )
+                .respond(msg);
+            }
             return respond(msg.clone(), 204, Vec::new(), "");
         }
 
@@ -87,6 +374,260 @@ impl Block for CorsBlock {
     }
 }
 
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "allowed_origins",
+            FieldKind::List,
+            "*",
+            "Origins allowed to make cross-origin requests",
+        )
+        .field(
+            "allowed_methods",
+            FieldKind::List,
+            "GET, POST, PUT, PATCH, DELETE, OPTIONS",
+            "Methods allowed in preflight responses, or `auto` to use cors_methods_map",
+        )
+        .field(
+            "cors_methods_map",
+            FieldKind::String,
+            "",
+            "Per-path methods for `auto`, e.g. /api/posts=GET,POST;/api/posts/*=GET,DELETE",
+        )
+        .field(
+            "allowed_headers",
+            FieldKind::List,
+            "Content-Type, Authorization, X-Requested-With",
+            "Request headers allowed in preflight responses",
+        )
+        .field(
+            "expose_headers",
+            FieldKind::List,
+            "",
+            "Response headers scripts may read",
+        )
+        .field(
+            "origin_policies",
+            FieldKind::Json,
+            "{}",
+            "Per-origin overrides of methods, headers and credentials",
+        )
+        .field(
+            "reflect_wildcard",
+            FieldKind::Bool,
+            "true",
+            "Echo the request origin when origins are `*`",
+        )
+}
+
 pub fn register(w: &mut Wafer) {
-    w.register_block("@wafer/cors", Arc::new(CorsBlock::new()));
+    register_as(w, "@wafer/cors");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(CorsBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn run(ctx: &MockContext, req: MockRequest) -> SimulatedResponse {
+        let mut msg = req.build();
+        SimulatedResponse::from_result(&CorsBlock::new().handle(ctx, &mut msg))
+    }
+
+    fn from(origin: &str) -> MockRequest {
+        MockRequest::get("/api").header("Origin", origin)
+    }
+
+    #[test]
+    fn wildcard_reflection_varies_on_origin() {
+        let resp = run(&MockContext::new(), from("https://a.example"));
+        assert_header(&resp, "Access-Control-Allow-Origin", "https://a.example");
+        assert_header(&resp, "Vary", "Origin");
+        assert_no_header(&resp, "Access-Control-Allow-Credentials");
+    }
+
+    #[test]
+    fn literal_wildcard_does_not_vary() {
+        let ctx = MockContext::new().with_config("reflect_wildcard", "false");
+        let resp = run(&ctx, from("https://a.example"));
+        assert_header(&resp, "Access-Control-Allow-Origin", "*");
+        assert_no_header(&resp, "Vary");
+    }
+
+    #[test]
+    fn allowlisted_origins_vary_on_origin() {
+        let ctx = MockContext::new().with_config("allowed_origins", "https://a.example");
+        let resp = run(&ctx, from("https://a.example"));
+        assert_header(&resp, "Access-Control-Allow-Origin", "https://a.example");
+        assert_header(&resp, "Vary", "Origin");
+
+        let resp = run(&ctx, from("https://evil.example"));
+        assert_no_header(&resp, "Access-Control-Allow-Origin");
+    }
+
+    fn partner_ctx() -> MockContext {
+        MockContext::new()
+            .with_config(
+                "allowed_origins",
+                "https://partner.example, https://*.apps.example",
+            )
+            .with_config(
+                "origin_policies",
+                r#"{"https://partner.example": {"methods": "GET, POST, DELETE", "credentials": true},
+                    "https://*.apps.example": {"headers": "X-App", "credentials": true}}"#,
+            )
+    }
+
+    #[test]
+    fn partner_origin_gets_its_policy() {
+        let resp = run(&partner_ctx(), from("https://partner.example"));
+        assert_header(&resp, "Access-Control-Allow-Methods", "GET, POST, DELETE");
+        assert_header(&resp, "Access-Control-Allow-Credentials", "true");
+        assert_header(
+            &resp,
+            "Access-Control-Allow-Headers",
+            "Content-Type, Authorization, X-Requested-With",
+        );
+    }
+
+    #[test]
+    fn other_origins_get_the_defaults() {
+        let ctx = partner_ctx().with_config("allowed_origins", "*");
+        let resp = run(&ctx, from("https://other.example"));
+        assert_header(
+            &resp,
+            "Access-Control-Allow-Methods",
+            "GET, POST, PUT, PATCH, DELETE, OPTIONS",
+        );
+        assert_no_header(&resp, "Access-Control-Allow-Credentials");
+    }
+
+    #[test]
+    fn wildcard_matches_never_allow_credentials() {
+        // A subdomain wildcard in both the allowlist and the policy
+        let resp = run(&partner_ctx(), from("https://x.apps.example"));
+        assert_header(
+            &resp,
+            "Access-Control-Allow-Origin",
+            "https://x.apps.example",
+        );
+        assert_header(&resp, "Access-Control-Allow-Headers", "X-App");
+        assert_no_header(&resp, "Access-Control-Allow-Credentials");
+
+        // `*` reflection, even with an exact policy granting credentials
+        let ctx = partner_ctx().with_config("allowed_origins", "*");
+        let resp = run(&ctx, from("https://partner.example"));
+        assert_header(&resp, "Access-Control-Allow-Methods", "GET, POST, DELETE");
+        assert_no_header(&resp, "Access-Control-Allow-Credentials");
+
+        // An exact allowlist entry with only a wildcard policy
+        let ctx = partner_ctx().with_config(
+            "origin_policies",
+            r#"{"https://*.example": {"credentials": true}}"#,
+        );
+        let resp = run(&ctx, from("https://partner.example"));
+        assert_no_header(&resp, "Access-Control-Allow-Credentials");
+
+        // ...and a policy can still turn credentials off for an exact entry
+        let ctx = partner_ctx().with_config(
+            "origin_policies",
+            r#"{"https://partner.example": {"credentials": false}}"#,
+        );
+        let resp = run(&ctx, from("https://partner.example"));
+        assert_no_header(&resp, "Access-Control-Allow-Credentials");
+    }
+
+    #[test]
+    fn subdomain_wildcards_resist_lookalike_origins() {
+        let pattern = "https://*.example.com";
+        assert!(origin_matches(pattern, "https://app.example.com"));
+        assert!(origin_matches(pattern, "https://a.b.EXAMPLE.com"));
+        for origin in [
+            "https://evil-example.com",
+            "https://example.com.evil.com",
+            "https://example.com",
+            "https://.example.com",
+            "https://a..example.com",
+            "https://a_b.example.com",
+            "http://app.example.com",
+            "https://app.example.com:8443",
+            "https://app.example.com.",
+            "null",
+        ] {
+            assert!(!origin_matches(pattern, origin), "{} matched", origin);
+        }
+        assert!(!origin_matches("https://*", "https://example.com"));
+        assert!(!origin_matches("https://*.*.com", "https://a.b.com"));
+    }
+
+    #[test]
+    fn subdomain_wildcards_reflect_only_matching_origins() {
+        let ctx = MockContext::new().with_config("allowed_origins", "https://*.example.com");
+        let resp = run(&ctx, from("https://app.example.com"));
+        assert_header(
+            &resp,
+            "Access-Control-Allow-Origin",
+            "https://app.example.com",
+        );
+        assert_header(&resp, "Vary", "Origin");
+
+        for origin in ["https://evil-example.com", "https://example.com.evil.com"] {
+            let resp = run(&ctx, from(origin));
+            assert_no_header(&resp, "Access-Control-Allow-Origin");
+            assert_no_header(&resp, "Access-Control-Allow-Credentials");
+        }
+    }
+
+    fn auto_ctx() -> MockContext {
+        MockContext::new()
+            .with_config("allowed_methods", "auto")
+            .with_config(
+                "cors_methods_map",
+                "/api/posts=GET,POST;/api/posts/*=GET,PUT,DELETE",
+            )
+    }
+
+    fn preflight(path: &str, method: &str) -> MockRequest {
+        MockRequest::new("OPTIONS", path)
+            .header("Origin", "https://a.example")
+            .header("Access-Control-Request-Method", method)
+    }
+
+    #[test]
+    fn auto_methods_validate_preflights_per_path() {
+        let ctx = auto_ctx();
+        let resp = run(&ctx, preflight("/api/posts", "DELETE"));
+        assert_status(&resp, 403, Some("cors_method_not_allowed"));
+
+        let resp = run(&ctx, preflight("/api/posts/7", "DELETE"));
+        assert_status(&resp, 204, None);
+        assert_header(&resp, "Access-Control-Allow-Methods", "GET, PUT, DELETE");
+
+        let resp = run(&ctx, preflight("/api/posts", "post"));
+        assert_status(&resp, 204, None);
+        assert_header(&resp, "Access-Control-Allow-Methods", "GET, POST");
+    }
+
+    #[test]
+    fn auto_methods_fall_back_to_the_default_list() {
+        let resp = run(&auto_ctx(), preflight("/other", "PATCH"));
+        assert_status(&resp, 204, None);
+        assert_header(
+            &resp,
+            "Access-Control-Allow-Methods",
+            "GET, POST, PUT, PATCH, DELETE, OPTIONS",
+        );
+
+        let parsed = MethodsMap::parse("/a=GET;/a/*=PUT").unwrap();
+        assert_eq!(parsed.methods_for("/a"), Some("GET"));
+        assert_eq!(parsed.methods_for("/a/b"), Some("PUT"));
+        assert_eq!(parsed.methods_for("/ab"), None);
+        assert!(MethodsMap::parse("api=GET").is_err());
+        assert!(MethodsMap::parse("/api=").is_err());
+    }
 }
@@ -0,0 +1,317 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use wafer_run::*;
+
+use super::report_intake::{self, ReportIntake};
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::errors::{self, CoreError};
+use crate::meta;
+use crate::path;
+
+/// Path reports are received on when `report_path` is not set.
+pub const DEFAULT_REPORT_PATH: &str = "/_csp-report";
+
+/// CspReportBlock receives Content-Security-Policy violation reports.
+/// Configure via node config: {"report_path": "/_csp-report"}
+///
+/// `POST <report_path>` takes either form browsers send: a legacy
+/// `application/csp-report` body (`{"csp-report": {...}}`, from a
+/// `report-uri` directive) or an `application/reports+json` batch whose
+/// `csp-violation` entries are kept. Each violation is logged at warn level
+/// and passed to the block's `CspReportSink`, if it has one, and the request
+/// is answered with 204. Bodies over `max_body_bytes` (default 65536) get
+/// 413 and clients are limited to `report_rate_limit` posts per minute
+/// (default 60), as for ReportingBlock (see [`ReportIntake`]). Other paths
+/// pass through, so place the block before auth.
+///
+/// SecurityHeadersBlock's `csp_report_uri` adds the matching `report-uri`
+/// to the policy it sends.
+pub struct CspReportBlock {
+    sink: Option<Arc<dyn CspReportSink>>,
+    received: AtomicU64,
+    intake: ReportIntake,
+}
+
+/// A CSP violation, from either report format.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct CspViolation {
+    pub document_uri: String,
+    pub blocked_uri: String,
+    /// The directive that was violated (`effective-directive` when sent).
+    pub directive: String,
+    /// `enforce` or `report`.
+    pub disposition: String,
+    pub source_file: String,
+    pub line_number: u64,
+    pub sample: String,
+}
+
+/// Receives the violations CspReportBlock accepts, e.g. to store or forward
+/// them.
+pub trait CspReportSink: Send + Sync {
+    fn record(&self, ctx: &dyn Context, violation: &CspViolation);
+}
+
+impl CspViolation {
+    /// Read one violation from a legacy `csp-report` object (hyphenated
+    /// keys) or a Reporting API `body` (camelCase keys).
+    pub fn from_json(v: &serde_json::Value) -> Self {
+        let field = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|k| v.get(*k).and_then(|f| f.as_str()))
+                .unwrap_or("")
+                .to_string()
+        };
+        let directive = field(&["effective-directive", "effectiveDirective"]);
+        Self {
+            document_uri: field(&["document-uri", "documentURL"]),
+            blocked_uri: field(&["blocked-uri", "blockedURL"]),
+            directive: if directive.is_empty() {
+                field(&["violated-directive"])
+            } else {
+                directive
+            },
+            disposition: field(&["disposition"]),
+            source_file: field(&["source-file", "sourceFile"]),
+            line_number: ["line-number", "lineNumber"]
+                .iter()
+                .find_map(|k| v.get(*k).and_then(|n| n.as_u64()))
+                .unwrap_or(0),
+            sample: field(&["script-sample", "sample"]),
+        }
+    }
+
+    /// Parse a report body by its content type. `None` when the body is not
+    /// a report of that type.
+    pub fn parse_body(content_type: &str, body: &[u8]) -> Option<Vec<Self>> {
+        if content_type.starts_with("application/reports+json") {
+            let reports = report_intake::parse_batch(body)?;
+            return Some(
+                reports
+                    .iter()
+                    .filter(|r| r.get("type").and_then(|t| t.as_str()) == Some("csp-violation"))
+                    .filter_map(|r| r.get("body"))
+                    .map(Self::from_json)
+                    .collect(),
+            );
+        }
+        let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+        value.get("csp-report").map(|r| vec![Self::from_json(r)])
+    }
+}
+
+impl CspReportBlock {
+    pub fn new() -> Self {
+        Self {
+            sink: None,
+            received: AtomicU64::new(0),
+            intake: ReportIntake::new("@wafer/csp-report"),
+        }
+    }
+
+    /// Pass accepted violations to `sink` as well as the log.
+    pub fn with_sink(mut self, sink: Arc<dyn CspReportSink>) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    /// Violations accepted since start.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    fn collect(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let accepted = [
+            "application/csp-report",
+            "application/reports+json",
+            "application/json",
+        ];
+        if let Some(rejected) = self.intake.admit(ctx, msg, "report_rate_limit", &accepted) {
+            return rejected;
+        }
+
+        let content_type = msg.header("Content-Type").to_ascii_lowercase();
+        let violations = match CspViolation::parse_body(&content_type, &msg.data) {
+            Some(v) => v,
+            None => {
+                return CoreError::BadRequest("Expected a CSP violation report".to_string())
+                    .respond(msg)
+            }
+        };
+
+        for v in &violations {
+            tracing::warn!(
+                "csp-report: {} blocked {} on {} ({}, {}:{})",
+                v.directive,
+                v.blocked_uri,
+                v.document_uri,
+                v.disposition,
+                v.source_file,
+                v.line_number
+            );
+            if let Some(sink) = &self.sink {
+                sink.record(ctx, v);
+            }
+        }
+        self.received
+            .fetch_add(violations.len() as u64, Ordering::Relaxed);
+        respond(msg.clone(), 204, Vec::new(), "")
+    }
+}
+
+impl Block for CspReportBlock {
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "@wafer/csp-report".to_string(),
+            version: "0.1.0".to_string(),
+            interface: "middleware@v1".to_string(),
+            summary: "Receives Content-Security-Policy violation reports".to_string(),
+            instance_mode: InstanceMode::Singleton,
+            allowed_modes: Vec::new(),
+            admin_ui: crate::admin::ui(admin_descriptor()),
+        }
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let report_path = path::normalize(
+            ctx.config_get("report_path").unwrap_or(DEFAULT_REPORT_PATH),
+            false,
+        );
+        if path::request_path(msg) != report_path {
+            return msg.clone().cont();
+        }
+        if meta::http_method(msg) != meta::Method::Post {
+            return errors::method_not_allowed(msg, &["POST"]);
+        }
+        self.collect(ctx, msg)
+    }
+
+    fn lifecycle(
+        &self,
+        _ctx: &dyn Context,
+        event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        match event.event_type {
+            LifecycleType::Start => self.intake.start(),
+            LifecycleType::Stop => self.intake.stop(),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "report_path",
+            FieldKind::String,
+            DEFAULT_REPORT_PATH,
+            "Path the block receives reports on",
+        )
+        .field(
+            "max_body_bytes",
+            FieldKind::Integer,
+            "65536",
+            "Largest report accepted",
+        )
+        .field(
+            "report_rate_limit",
+            FieldKind::Integer,
+            "60",
+            "Reports accepted per client per minute",
+        )
+}
+
+pub fn register(w: &mut Wafer) {
+    register_as(w, "@wafer/csp-report");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(CspReportBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const LEGACY: &str = r#"{"csp-report": {
+        "document-uri": "https://app.example/page",
+        "blocked-uri": "https://evil.example/x.js",
+        "violated-directive": "script-src 'self'",
+        "disposition": "enforce",
+        "source-file": "https://app.example/app.js",
+        "line-number": 12,
+        "script-sample": "alert(1)"
+    }}"#;
+
+    const BATCH: &str = r#"[
+        {"type": "csp-violation", "body": {
+            "documentURL": "https://app.example/page",
+            "blockedURL": "inline",
+            "effectiveDirective": "script-src-elem",
+            "disposition": "report",
+            "sourceFile": "https://app.example/page",
+            "lineNumber": 3,
+            "sample": "eval()"
+        }},
+        {"type": "deprecation", "body": {"id": "x"}}
+    ]"#;
+
+    #[test]
+    fn parses_legacy_reports() {
+        let violations = CspViolation::parse_body("application/csp-report", LEGACY.as_bytes());
+        assert_eq!(
+            violations.unwrap(),
+            vec![CspViolation {
+                document_uri: "https://app.example/page".to_string(),
+                blocked_uri: "https://evil.example/x.js".to_string(),
+                directive: "script-src 'self'".to_string(),
+                disposition: "enforce".to_string(),
+                source_file: "https://app.example/app.js".to_string(),
+                line_number: 12,
+                sample: "alert(1)".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn parses_csp_violations_from_report_batches() {
+        let violations =
+            CspViolation::parse_body("application/reports+json", BATCH.as_bytes()).unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].blocked_uri, "inline");
+        assert_eq!(violations[0].directive, "script-src-elem");
+        assert_eq!(violations[0].disposition, "report");
+        assert_eq!(violations[0].line_number, 3);
+        assert_eq!(violations[0].sample, "eval()");
+    }
+
+    #[test]
+    fn rejects_bodies_that_are_not_reports() {
+        assert!(CspViolation::parse_body("application/csp-report", b"{}").is_none());
+        assert!(CspViolation::parse_body("application/csp-report", b"nope").is_none());
+        assert!(CspViolation::parse_body("application/reports+json", LEGACY.as_bytes()).is_none());
+    }
+
+    #[test]
+    fn accepts_reports_on_the_report_path() {
+        let block = CspReportBlock::new();
+        let ctx = MockContext::new();
+        let mut msg = MockRequest::post(DEFAULT_REPORT_PATH)
+            .header("Content-Type", "application/csp-report")
+            .body(LEGACY.as_bytes())
+            .build();
+        let resp = SimulatedResponse::from_result(&block.handle(&ctx, &mut msg));
+        assert_status(&resp, 204, None);
+        assert_eq!(block.received(), 1);
+
+        let mut msg = MockRequest::post(DEFAULT_REPORT_PATH)
+            .header("Content-Type", "application/csp-report")
+            .body(b"{}")
+            .build();
+        let resp = SimulatedResponse::from_result(&block.handle(&ctx, &mut msg));
+        assert_status(&resp, 400, Some("bad_request"));
+    }
+}
@@ -0,0 +1,198 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use wafer_run::*;
+
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::meta;
+use crate::path;
+
+/// DeprecationBlock announces the retirement of API paths with the
+/// `Deprecation` (RFC 9745) and `Sunset` (RFC 8594) response headers.
+/// Configure via node config, `deprecation_rules` as a JSON list:
+/// [{"path_pattern": "/api/v1", "deprecation_date": "2026-01-01",
+///   "sunset_date": "2026-07-01T00:00:00Z", "link": "https://example.com/migrate-v2"}]
+///
+/// `path_pattern` is a path prefix (see `path::has_prefix`); the longest
+/// matching rule applies. Dates are RFC 3339 or `YYYY-MM-DD` (midnight UTC),
+/// and each rule needs at least one. `Deprecation` is sent as `@<unix
+/// seconds>` and `Sunset` as an HTTP-date, whether the dates are past or
+/// future. `link` adds a `Link` entry with `rel="deprecation"`
+/// (`rel="sunset"` when the rule has only a sunset date), merged with any
+/// set earlier in the chain.
+///
+/// Rules are parsed at lifecycle Start. An invalid rule list is logged and
+/// no headers are added; requests are never rejected.
+pub struct DeprecationBlock {
+    rules: Mutex<Option<(String, Result<Arc<Vec<DeprecationRule>>, String>)>>,
+}
+
+/// One parsed entry of `deprecation_rules`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeprecationRule {
+    pub path_pattern: String,
+    pub deprecation_date: Option<DateTime<Utc>>,
+    pub sunset_date: Option<DateTime<Utc>>,
+    pub link: String,
+}
+
+#[derive(serde::Deserialize)]
+struct RawRule {
+    path_pattern: String,
+    #[serde(default)]
+    deprecation_date: String,
+    #[serde(default)]
+    sunset_date: String,
+    #[serde(default)]
+    link: String,
+}
+
+/// Parse an RFC 3339 timestamp or a `YYYY-MM-DD` date (midnight UTC).
+fn parse_date(raw: &str) -> Result<Option<DateTime<Utc>>, String> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Ok(None);
+    }
+    if let Ok(t) = DateTime::parse_from_rfc3339(raw) {
+        return Ok(Some(t.with_timezone(&Utc)));
+    }
+    NaiveDate::parse_from_str(raw, "%Y-%m-%d")
+        .map(|d| Some(d.and_time(NaiveTime::MIN).and_utc()))
+        .map_err(|_| format!("invalid date {:?}", raw))
+}
+
+/// Format a timestamp as an HTTP-date (IMF-fixdate).
+pub fn http_date(t: &DateTime<Utc>) -> String {
+    t.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+impl DeprecationRule {
+    /// Parse a `deprecation_rules` JSON list; errors name the rule at fault.
+    pub fn parse_list(json: &str) -> Result<Vec<Self>, String> {
+        let raw: Vec<RawRule> = serde_json::from_str(json)
+            .map_err(|e| format!("deprecation_rules: invalid JSON: {}", e))?;
+        raw.into_iter()
+            .enumerate()
+            .map(|(i, r)| {
+                if !r.path_pattern.starts_with('/') {
+                    return Err(format!("rule {}: path_pattern must start with '/'", i));
+                }
+                let deprecation_date = parse_date(&r.deprecation_date)
+                    .map_err(|e| format!("rule {}: deprecation_date: {}", i, e))?;
+                let sunset_date = parse_date(&r.sunset_date)
+                    .map_err(|e| format!("rule {}: sunset_date: {}", i, e))?;
+                if deprecation_date.is_none() && sunset_date.is_none() {
+                    return Err(format!("rule {}: needs deprecation_date or sunset_date", i));
+                }
+                Ok(Self {
+                    path_pattern: path::normalize(&r.path_pattern, false),
+                    deprecation_date,
+                    sunset_date,
+                    link: r.link.trim().to_string(),
+                })
+            })
+            .collect()
+    }
+
+    /// Stamp this rule's headers on the response.
+    fn apply(&self, msg: &mut Message) {
+        if let Some(t) = &self.deprecation_date {
+            meta::set_resp_header(msg, "Deprecation", &format!("@{}", t.timestamp()));
+        }
+        if let Some(t) = &self.sunset_date {
+            meta::set_resp_header(msg, "Sunset", &http_date(t));
+        }
+        if !self.link.is_empty() {
+            let rel = if self.deprecation_date.is_some() {
+                "deprecation"
+            } else {
+                "sunset"
+            };
+            meta::set_resp_header(msg, "Link", &format!("<{}>; rel=\"{}\"", self.link, rel));
+        }
+    }
+}
+
+impl DeprecationBlock {
+    pub fn new() -> Self {
+        Self {
+            rules: Mutex::new(None),
+        }
+    }
+
+    /// The parsed rules for the current config, reparsing when it changed.
+    fn rules(&self, ctx: &dyn Context) -> Result<Arc<Vec<DeprecationRule>>, String> {
+        let raw = ctx.config_get("deprecation_rules").unwrap_or("[]");
+        let mut cached = self.rules.lock();
+        if cached.as_ref().map(|(k, _)| k.as_str()) != Some(raw) {
+            let parsed = DeprecationRule::parse_list(raw).map(Arc::new);
+            if let Err(e) = &parsed {
+                tracing::error!("deprecation: {}", e);
+            }
+            *cached = Some((raw.to_string(), parsed));
+        }
+        cached.as_ref().expect("rules just set").1.clone()
+    }
+}
+
+impl Block for DeprecationBlock {
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "@wafer/deprecation".to_string(),
+            version: "0.1.0".to_string(),
+            interface: "middleware@v1".to_string(),
+            summary: "Adds Deprecation and Sunset headers to retiring API paths".to_string(),
+            instance_mode: InstanceMode::Singleton,
+            allowed_modes: Vec::new(),
+            admin_ui: crate::admin::ui(admin_descriptor()),
+        }
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let rules = match self.rules(ctx) {
+            Ok(rules) if !rules.is_empty() => rules,
+            _ => return msg.clone().cont(),
+        };
+        let request_path = path::request_path(msg);
+        if let Some(rule) = rules
+            .iter()
+            .filter(|r| path::has_prefix(&request_path, &r.path_pattern))
+            .max_by_key(|r| r.path_pattern.len())
+        {
+            rule.apply(msg);
+        }
+        msg.clone().cont()
+    }
+
+    fn lifecycle(
+        &self,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        if matches!(event.event_type, LifecycleType::Start) {
+            // Parse eagerly so a bad rule is reported at startup
+            if let Ok(rules) = self.rules(ctx) {
+                tracing::info!("deprecation: {} rules loaded", rules.len());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new().field(
+        "deprecation_rules",
+        FieldKind::Json,
+        "[]",
+        "List of {path_pattern, deprecation_date, sunset_date, link} rules",
+    )
+}
+
+pub fn register(w: &mut Wafer) {
+    register_as(w, "@wafer/deprecation");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(DeprecationBlock::new()));
+}
@@ -0,0 +1,524 @@
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use wafer_run::*;
+
+use super::auth::CookieAttributes;
+use crate::admin::{self, AdminDescriptor, FieldKind, StatusDescriptor};
+use crate::errors::CoreError;
+use crate::meta;
+use crate::path;
+
+/// Cookie persisting the anonymous id and anonymous-scope assignments.
+pub const EXPERIMENT_COOKIE: &str = "wafer_exp";
+
+/// Buckets per experiment; weights are percentages, so one percent is 100 buckets.
+const BUCKETS: u64 = 10_000;
+
+/// Default lifetime of the experiment cookie (one year).
+const COOKIE_MAX_AGE: u64 = 365 * 24 * 3600;
+
+/// ExperimentBlock assigns A/B experiment variants at the edge.
+/// Configure via node config:
+/// {"experiments": "[{\"name\": \"checkout\", \"scope\": \"user\",
+///   \"variants\": [{\"name\": \"control\", \"weight\": 50}, {\"name\": \"b\", \"weight\": 50}]}]",
+///  "debug": "true"}
+///
+/// Each request gets `experiment.<name>=<variant>` meta, plus an
+/// `X-Experiment-<name>` response header with `debug: true`. Variants come from
+/// a stable hash of the experiment name and the subject (`user` scope: the
+/// user id, falling back to the anonymous id; `anonymous` scope: an id kept in
+/// the `wafer_exp` cookie). The hash picks a bucket out of 10000 and variants
+/// own consecutive bucket ranges sized by weight, so changing weights only
+/// moves users near the shifted boundaries. Assignments are also stored in
+/// the cookie; anonymous ones stick until the variant is removed.
+///
+/// Experiment names are letters, digits, `-`, `_` and `.` (they name a
+/// header); cookie entries are percent-encoded. Weights must sum to 100; an
+/// invalid config is logged and no experiments are assigned.
+///
+/// `GET /_experiments` returns assignment counts per variant: an assignment
+/// is counted when a browser first gets (or changes) its variant, not on
+/// every request. Like other status endpoints it needs `status_public` or a
+/// user with `status_role` (see `admin::status_allowed`).
+pub struct ExperimentBlock {
+    compiled: Mutex<Option<(String, Arc<Vec<Experiment>>)>>,
+    counts: Mutex<BTreeMap<(String, String), u64>>,
+    seq: AtomicU64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    User,
+    Anonymous,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Variant {
+    pub name: String,
+    pub weight: u32,
+}
+
+/// One experiment definition.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Experiment {
+    pub name: String,
+    pub variants: Vec<Variant>,
+    #[serde(default = "default_scope")]
+    pub scope: Scope,
+}
+
+fn default_scope() -> Scope {
+    Scope::Anonymous
+}
+
+impl Experiment {
+    /// Parse and validate a JSON array of experiments.
+    pub fn parse_list(json: &str) -> Result<Vec<Self>, String> {
+        let list: Vec<Self> =
+            serde_json::from_str(json).map_err(|e| format!("experiments: invalid JSON: {}", e))?;
+        for (i, exp) in list.iter().enumerate() {
+            if exp.name.is_empty() {
+                return Err(format!("experiment {}: missing name", i));
+            }
+            let token = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+            if !exp.name.chars().all(token) || exp.name == ANON_ID_KEY {
+                return Err(format!("experiment {}: invalid name '{}'", i, exp.name));
+            }
+            if exp.variants.is_empty() {
+                return Err(format!("experiment '{}': no variants", exp.name));
+            }
+            let total: u64 = exp.variants.iter().map(|v| u64::from(v.weight)).sum();
+            if total != 100 {
+                return Err(format!(
+                    "experiment '{}': weights sum to {}, expected 100",
+                    exp.name, total
+                ));
+            }
+        }
+        Ok(list)
+    }
+
+    /// Bucket of a subject in `0..10000`, stable for a given experiment name.
+    pub fn bucket(&self, subject: &str) -> u64 {
+        let digest = Sha256::digest(format!("{}:{}", self.name, subject).as_bytes());
+        let mut first = [0u8; 8];
+        first.copy_from_slice(&digest[..8]);
+        u64::from_be_bytes(first) % BUCKETS
+    }
+
+    /// The variant owning a subject's bucket.
+    pub fn assign(&self, subject: &str) -> &str {
+        let bucket = self.bucket(subject);
+        let mut upper = 0;
+        for v in &self.variants {
+            upper += u64::from(v.weight) * (BUCKETS / 100);
+            if bucket < upper {
+                return &v.name;
+            }
+        }
+        // Unreachable while weights sum to 100
+        &self.variants[self.variants.len() - 1].name
+    }
+
+    fn has_variant(&self, name: &str) -> bool {
+        self.variants.iter().any(|v| v.name == name)
+    }
+}
+
+/// Cookie entry holding the anonymous id.
+const ANON_ID_KEY: &str = "aid";
+
+/// Decode the experiment cookie (`aid=...&name=variant...`, each key and
+/// value percent-encoded). Cookies written before entries were encoded
+/// separately had the whole string encoded instead, and decode the same.
+fn decode_cookie(raw: &str) -> HashMap<String, String> {
+    let decode = |s: &str| urlencoding::decode(s).map(|s| s.into_owned()).ok();
+    let raw = if raw.contains('=') {
+        raw.to_string()
+    } else {
+        decode(raw).unwrap_or_default()
+    };
+    raw.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter_map(|(k, v)| Some((decode(k)?, decode(v)?)))
+        .collect()
+}
+
+fn encode_cookie(values: &BTreeMap<String, String>) -> String {
+    values
+        .iter()
+        .map(|(k, v)| format!("{}={}", urlencoding::encode(k), urlencoding::encode(v)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+impl ExperimentBlock {
+    pub fn new() -> Self {
+        Self {
+            compiled: Mutex::new(None),
+            counts: Mutex::new(BTreeMap::new()),
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    /// Assignment counts per (experiment, variant).
+    pub fn stats(&self) -> Vec<(String, String, u64)> {
+        self.counts
+            .lock()
+            .iter()
+            .map(|((e, v), n)| (e.clone(), v.clone(), *n))
+            .collect()
+    }
+
+    fn experiments(&self, raw: &str) -> Arc<Vec<Experiment>> {
+        let mut cached = self.compiled.lock();
+        if cached.as_ref().map(|(k, _)| k.as_str()) != Some(raw) {
+            let list = Experiment::parse_list(raw).unwrap_or_else(|e| {
+                tracing::error!("experiment: {}; no experiments will be assigned", e);
+                Vec::new()
+            });
+            *cached = Some((raw.to_string(), Arc::new(list)));
+        }
+        cached.as_ref().expect("experiments just set").1.clone()
+    }
+
+    /// A fresh anonymous id. Only needs to be unique, not unguessable.
+    fn new_anonymous_id(&self, msg: &Message) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(msg.remote_addr().as_bytes());
+        hasher.update(msg.header("User-Agent").as_bytes());
+        hasher.update(
+            chrono::Utc::now()
+                .timestamp_nanos_opt()
+                .unwrap_or_default()
+                .to_be_bytes(),
+        );
+        hasher.update(self.seq.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .take(12)
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+impl Block for ExperimentBlock {
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "@wafer/experiment".to_string(),
+            version: "0.1.0".to_string(),
+            interface: "middleware@v1".to_string(),
+            summary: "A/B experiment variant assignment".to_string(),
+            instance_mode: InstanceMode::Singleton,
+            allowed_modes: Vec::new(),
+            admin_ui: crate::admin::ui(admin_descriptor()),
+        }
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        if path::request_path(msg) == "/_experiments" {
+            if !admin::status_allowed(ctx, msg) {
+                return CoreError::NotFound("Not found".to_string()).respond(msg);
+            }
+            let counts: Vec<serde_json::Value> = self
+                .stats()
+                .into_iter()
+                .map(|(e, v, n)| serde_json::json!({ "experiment": e, "variant": v, "count": n }))
+                .collect();
+            return json_respond(
+                msg.clone(),
+                200,
+                &serde_json::json!({ "assignments": counts }),
+            );
+        }
+
+        let experiments = self.experiments(ctx.config_get("experiments").unwrap_or("[]"));
+        if experiments.is_empty() {
+            return msg.clone().cont();
+        }
+        let debug = ctx
+            .config_get("debug")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+
+        let stored = decode_cookie(msg.cookie(EXPERIMENT_COOKIE));
+        let mut cookie: BTreeMap<String, String> = stored.clone().into_iter().collect();
+        let anon_id = match stored.get(ANON_ID_KEY).filter(|s| !s.is_empty()) {
+            Some(id) => id.clone(),
+            None => {
+                let id = self.new_anonymous_id(msg);
+                cookie.insert(ANON_ID_KEY.to_string(), id.clone());
+                id
+            }
+        };
+        let user_id = meta::user_id(msg).unwrap_or("").to_string();
+
+        for exp in experiments.iter() {
+            let variant = match exp.scope {
+                Scope::User if !user_id.is_empty() => exp.assign(&user_id).to_string(),
+                _ => match stored.get(&exp.name).filter(|v| exp.has_variant(v)) {
+                    Some(v) => v.clone(),
+                    None => exp.assign(&anon_id).to_string(),
+                },
+            };
+            // Count each browser's assignment once, and again only if it changes
+            if stored.get(&exp.name) != Some(&variant) {
+                *self
+                    .counts
+                    .lock()
+                    .entry((exp.name.clone(), variant.clone()))
+                    .or_insert(0) += 1;
+                cookie.insert(exp.name.clone(), variant.clone());
+            }
+
+            msg.set_meta(
+                &format!("{}{}", meta::EXPERIMENT_PREFIX, exp.name),
+                &variant,
+            );
+            if debug {
+                meta::set_resp_header(msg, &format!("X-Experiment-{}", exp.name), &variant);
+            }
+        }
+
+        let unchanged =
+            cookie.len() == stored.len() && cookie.iter().all(|(k, v)| stored.get(k) == Some(v));
+        if !unchanged {
+            let attrs = CookieAttributes::from_config(ctx);
+            let max_age = attrs.max_age.unwrap_or(COOKIE_MAX_AGE);
+            meta::set_resp_header(
+                msg,
+                "Set-Cookie",
+                &attrs.set(EXPERIMENT_COOKIE, &encode_cookie(&cookie), Some(max_age)),
+            );
+        }
+
+        msg.clone().cont()
+    }
+
+    fn lifecycle(
+        &self,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        if matches!(event.event_type, LifecycleType::Start) {
+            // Validate eagerly so a bad config is reported at startup
+            let list = self.experiments(ctx.config_get("experiments").unwrap_or("[]"));
+            tracing::info!("experiment: {} experiments configured", list.len());
+        }
+        Ok(())
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "experiments",
+            FieldKind::Json,
+            "[]",
+            "List of experiments and their weighted variants",
+        )
+        .field(
+            "debug",
+            FieldKind::Bool,
+            "false",
+            "Echo assignments in response headers",
+        )
+        .status_access()
+        .status(StatusDescriptor::new().endpoint("/_experiments", "json"))
+}
+
+pub fn register(w: &mut Wafer) {
+    register_as(w, "@wafer/experiment");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(ExperimentBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use serde_json::json;
+
+    fn ctx() -> MockContext {
+        let experiments = json!([{
+            "name": "checkout",
+            "variants": [{"name": "a&b=c", "weight": 50}, {"name": "d", "weight": 50}],
+        }]);
+        MockContext::new()
+            .with_config("experiments", &experiments.to_string())
+            .with_config("status_public", "true")
+    }
+
+    /// Run a request, returning the variant and the cookie it set, if any.
+    fn assign(
+        block: &ExperimentBlock,
+        ctx: &MockContext,
+        cookie: Option<&str>,
+    ) -> (String, Option<String>) {
+        let mut req = MockRequest::get("/");
+        if let Some(c) = cookie {
+            req = req.cookie(EXPERIMENT_COOKIE, c);
+        }
+        let mut msg = req.build();
+        block.handle(ctx, &mut msg);
+        let variant = msg.get_meta("experiment.checkout").to_string();
+        let set = meta::resp_header(&msg, "Set-Cookie");
+        let value = set
+            .split(';')
+            .next()
+            .and_then(|kv| kv.split_once('='))
+            .map(|(_, v)| v.to_string());
+        (variant, value)
+    }
+
+    fn count(block: &ExperimentBlock) -> u64 {
+        block.stats().iter().map(|(_, _, n)| n).sum()
+    }
+
+    #[test]
+    fn repeat_visits_are_not_recounted() {
+        let (block, ctx) = (ExperimentBlock::new(), ctx());
+        let (variant, cookie) = assign(&block, &ctx, None);
+        let cookie = cookie.expect("first visit sets the cookie");
+        assert_eq!(count(&block), 1);
+
+        for _ in 0..3 {
+            let (again, set) = assign(&block, &ctx, Some(&cookie));
+            assert_eq!(again, variant);
+            assert_eq!(set, None);
+        }
+        assert_eq!(count(&block), 1);
+    }
+
+    #[test]
+    fn cookie_entries_survive_reserved_characters() {
+        let mut values = BTreeMap::new();
+        values.insert("aid".to_string(), "x=1".to_string());
+        values.insert("checkout".to_string(), "a&b=c".to_string());
+        let decoded = decode_cookie(&encode_cookie(&values));
+        assert_eq!(decoded.get("aid").map(String::as_str), Some("x=1"));
+        assert_eq!(decoded.get("checkout").map(String::as_str), Some("a&b=c"));
+
+        // Cookies from before per-entry encoding still decode
+        let legacy = urlencoding::encode("aid=1&checkout=d").into_owned();
+        assert_eq!(
+            decode_cookie(&legacy).get("checkout").map(String::as_str),
+            Some("d")
+        );
+    }
+
+    #[test]
+    fn invalid_names_and_overflowing_weights_are_rejected() {
+        let list = |exps: serde_json::Value| Experiment::parse_list(&exps.to_string());
+        assert!(
+            list(json!([{"name": "a&b", "variants": [{"name": "x", "weight": 100}]}])).is_err()
+        );
+        assert!(
+            list(json!([{"name": "aid", "variants": [{"name": "x", "weight": 100}]}])).is_err()
+        );
+        let huge = json!([{"name": "w", "variants": [
+            {"name": "x", "weight": u32::MAX},
+            {"name": "y", "weight": 101},
+        ]}]);
+        assert!(list(huge).unwrap_err().contains("weights sum to"));
+    }
+
+    #[test]
+    fn stats_endpoint_needs_status_access() {
+        let block = ExperimentBlock::new();
+        let hidden = MockContext::new();
+        let resp = |ctx: &MockContext, req: MockRequest| {
+            SimulatedResponse::from_result(&block.handle(ctx, &mut req.build()))
+        };
+        assert_status(
+            &resp(&hidden, MockRequest::get("/_experiments")),
+            404,
+            Some("not_found"),
+        );
+        let admin = MockRequest::get("/_experiments").meta(meta::AUTH_USER_ROLES, "admin");
+        assert_status(&resp(&hidden, admin), 200, None);
+        assert_status(&resp(&ctx(), MockRequest::get("/_experiments")), 200, None);
+    }
+
+    fn experiment(weights: &[(&str, u32)]) -> Experiment {
+        let variants: Vec<_> = weights
+            .iter()
+            .map(|(name, weight)| json!({"name": name, "weight": weight}))
+            .collect();
+        let list = json!([{"name": "pricing", "scope": "user", "variants": variants}]);
+        Experiment::parse_list(&list.to_string()).unwrap().remove(0)
+    }
+
+    const USERS: usize = 10_000;
+
+    fn users() -> impl Iterator<Item = String> {
+        (0..USERS).map(|i| format!("user-{}", i))
+    }
+
+    #[test]
+    fn users_keep_their_variant_without_a_cookie() {
+        let exps = json!([{
+            "name": "pricing",
+            "scope": "user",
+            "variants": [{"name": "a", "weight": 50}, {"name": "b", "weight": 50}],
+        }]);
+        let ctx = MockContext::new().with_config("experiments", &exps.to_string());
+        let variant = |block: &ExperimentBlock, user: &str| {
+            let mut msg = MockRequest::get("/").meta(meta::AUTH_USER_ID, user).build();
+            block.handle(&ctx, &mut msg);
+            msg.get_meta("experiment.pricing").to_string()
+        };
+        for user in ["u1", "u2", "u3"] {
+            let first = variant(&ExperimentBlock::new(), user);
+            // Any instance, any request
+            for _ in 0..3 {
+                assert_eq!(variant(&ExperimentBlock::new(), user), first);
+            }
+            let exp = experiment(&[("a", 50), ("b", 50)]);
+            assert_eq!(exp.assign(user), first);
+        }
+    }
+
+    #[test]
+    fn assignments_follow_the_weights() {
+        let exp = experiment(&[("a", 20), ("b", 30), ("c", 50)]);
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        for user in users() {
+            *seen.entry(exp.assign(&user).to_string()).or_insert(0) += 1;
+        }
+        for (name, weight) in [("a", 20), ("b", 30), ("c", 50)] {
+            let expected = USERS * weight / 100;
+            let got = seen.get(name).copied().unwrap_or(0);
+            // Within two percentage points
+            assert!(got.abs_diff(expected) <= USERS / 50, "{}: {}", name, got);
+        }
+    }
+
+    #[test]
+    fn weight_changes_move_only_the_shifted_share() {
+        let before = experiment(&[("control", 50), ("b", 50)]);
+        let after = experiment(&[("control", 60), ("b", 40)]);
+        let mut moved = 0;
+        for user in users() {
+            let (was, now) = (before.assign(&user), after.assign(&user));
+            if was != now {
+                // Only users at the boundary move, and only towards the grown variant
+                assert_eq!((was, now), ("b", "control"), "{}", user);
+                moved += 1;
+            }
+        }
+        assert!(
+            (USERS * 8 / 100..=USERS * 12 / 100).contains(&moved),
+            "{} moved",
+            moved
+        );
+    }
+}
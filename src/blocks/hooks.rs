@@ -0,0 +1,376 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+use wafer_run::*;
+
+use crate::errors::{self, CoreError};
+use crate::meta;
+
+/// ResponseHook observes, and may rewrite, the result a wrapped block produced.
+///
+/// Middleware in a chain runs before the handler and never sees its result.
+/// Wrapping the handler with `with_hooks` gives middleware-style code a
+/// response path: every hook runs, in order, after the inner block returns.
+pub trait ResponseHook: Send + Sync {
+    /// Called with the request as the wrapped block received it and its result.
+    fn on_result(&self, ctx: &dyn Context, req: &Message, result: &mut Result_);
+}
+
+/// HookedBlock runs an inner block and then its response hooks.
+pub struct HookedBlock {
+    inner: Arc<dyn Block>,
+    hooks: Vec<Arc<dyn ResponseHook>>,
+}
+
+impl HookedBlock {
+    pub fn new(inner: Arc<dyn Block>, hooks: Vec<Arc<dyn ResponseHook>>) -> Self {
+        Self { inner, hooks }
+    }
+}
+
+/// The hooks `blocks::register_as` wraps every block with: `ErrorPages`,
+/// then `PreserveHeaders::cors`, so CORS headers also reach error pages.
+pub fn default_hooks() -> Vec<Arc<dyn ResponseHook>> {
+    vec![
+        Arc::new(ErrorPages::new()),
+        Arc::new(PreserveHeaders::cors()),
+    ]
+}
+
+/// Wrap a block so `hooks` observe its results.
+pub fn with_hooks(inner: Arc<dyn Block>, hooks: Vec<Arc<dyn ResponseHook>>) -> Arc<dyn Block> {
+    Arc::new(HookedBlock::new(inner, hooks))
+}
+
+impl Block for HookedBlock {
+    fn info(&self) -> BlockInfo {
+        self.inner.info()
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let req = msg.clone();
+        let mut result = self.inner.handle(ctx, msg);
+        for hook in &self.hooks {
+            hook.on_result(ctx, &req, &mut result);
+        }
+        result
+    }
+
+    fn lifecycle(
+        &self,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        self.inner.lifecycle(ctx, event)
+    }
+}
+
+/// HTTP status carried by a result (200 when a block continued without one).
+pub fn result_status(result: &Result_) -> u16 {
+    let status = result
+        .message
+        .as_ref()
+        .and_then(|m| m.get_meta(meta::RESP_STATUS).parse::<u16>().ok());
+    match (status, &result.action) {
+        (Some(s), _) => s,
+        (None, Action::Error) => 500,
+        (None, _) => 200,
+    }
+}
+
+/// Whether a result is an error: an error action or a status of 400 and above.
+pub fn is_error_result(result: &Result_) -> bool {
+    matches!(result.action, Action::Error) || result_status(result) >= 400
+}
+
+/// Read a response header from a result, or "" if unset.
+pub fn result_header<'a>(result: &'a Result_, name: &str) -> &'a str {
+    let key = meta::resp_header_key(name);
+    if let Some(v) = result.response.as_ref().and_then(|r| r.meta.get(&key)) {
+        return v;
+    }
+    result
+        .message
+        .as_ref()
+        .map(|m| meta::resp_header(m, name))
+        .unwrap_or("")
+}
+
+/// Set a response header on a result, whichever form (response or error) it takes.
+pub fn result_set_header(result: &mut Result_, name: &str, value: &str) {
+    let key = meta::resp_header_key(name);
+    if let Some(resp) = result.response.as_mut() {
+        resp.meta.insert(key, value.to_string());
+    }
+    if let Some(m) = result.message.as_mut() {
+        meta::set_resp_header(m, name, value);
+    }
+}
+
+/// PreserveHeaders re-applies `resp.header.*` meta set on the request path
+/// (e.g. by CorsBlock) to results that dropped them, such as error results
+/// built from a fresh message. Headers already present on the result win.
+///
+/// Only blocks registered through `blocks::register_as` are wrapped. App
+/// handlers registered directly with `Wafer::register_block` keep those
+/// headers only when they build their responses from the request message.
+pub struct PreserveHeaders {
+    prefixes: Vec<String>,
+}
+
+impl PreserveHeaders {
+    /// Preserve the given header names, matched by case-insensitive prefix.
+    pub fn new(prefixes: &[&str]) -> Self {
+        Self {
+            prefixes: prefixes.iter().map(|p| p.to_ascii_lowercase()).collect(),
+        }
+    }
+
+    /// Preserve the headers CorsBlock sets.
+    pub fn cors() -> Self {
+        Self::new(&["Access-Control-", "Vary"])
+    }
+}
+
+impl ResponseHook for PreserveHeaders {
+    fn on_result(&self, _ctx: &dyn Context, req: &Message, result: &mut Result_) {
+        if matches!(result.action, Action::Continue) {
+            return;
+        }
+        for (key, value) in req.meta.iter() {
+            let name = match key.strip_prefix(meta::RESP_HEADER_PREFIX) {
+                Some(n) => n,
+                None => continue,
+            };
+            let lower = name.to_ascii_lowercase();
+            if !self.prefixes.iter().any(|p| lower.starts_with(p.as_str())) {
+                continue;
+            }
+            if result_header(result, name).is_empty() {
+                result_set_header(result, name, value);
+            }
+        }
+    }
+}
+
+/// Request header read as the request id in `ErrorPages` logs.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// ErrorPages replaces the body of 5xx results with a friendly page, keeping
+/// their status and headers. It is one of the `default_hooks`, so every
+/// registered block has it; results pass through untouched unless the
+/// block's node config sets `error_page_5xx`.
+///
+/// `error_page_5xx` names an HTML file sent to clients whose `Accept`
+/// prefers HTML (see `errors::prefers_html`). Other clients, and all
+/// clients when the page is unreadable, get the JSON envelope with the
+/// result's `error.code` (`internal_error` if none) and a generic message. The original status, code and body are
+/// logged with the request's `X-Request-Id`, so the detail the client no
+/// longer sees is not lost.
+pub struct ErrorPages {
+    /// The page last read, keyed by its path.
+    page: Mutex<Option<(String, Arc<Vec<u8>>)>>,
+}
+
+/// Most bytes of an original error body written to the log.
+const LOGGED_BODY_BYTES: usize = 512;
+
+impl ErrorPages {
+    pub fn new() -> Self {
+        Self {
+            page: Mutex::new(None),
+        }
+    }
+
+    /// The configured page, read once per path. A page that cannot be read
+    /// is retried on the next error, so it can be fixed without a restart.
+    fn page(&self, path: &str) -> Option<Arc<Vec<u8>>> {
+        let mut cached = self.page.lock();
+        if let Some((p, page)) = cached.as_ref() {
+            if p == path {
+                return Some(page.clone());
+            }
+        }
+        match std::fs::read(path) {
+            Ok(bytes) => {
+                let page = Arc::new(bytes);
+                *cached = Some((path.to_string(), page.clone()));
+                Some(page)
+            }
+            Err(e) => {
+                tracing::warn!("error-pages: cannot read {}: {}", path, e);
+                None
+            }
+        }
+    }
+}
+
+impl ResponseHook for ErrorPages {
+    fn on_result(&self, ctx: &dyn Context, req: &Message, result: &mut Result_) {
+        let status = result_status(result);
+        if status < 500 {
+            return;
+        }
+        let page_path = match ctx.config_get("error_page_5xx").filter(|p| !p.is_empty()) {
+            Some(p) => p,
+            None => return,
+        };
+        let code = result
+            .message
+            .as_ref()
+            .map(|m| m.get_meta(meta::ERROR_CODE))
+            .filter(|c| !c.is_empty())
+            .unwrap_or("internal_error")
+            .to_string();
+        let body = result
+            .response
+            .as_ref()
+            .map(|r| r.data.as_slice())
+            .unwrap_or(&[]);
+        let request_id = Some(req.header(REQUEST_ID_HEADER)).filter(|s| !s.is_empty());
+        tracing::error!(
+            "{} {} failed with {} {} (request {}): {}",
+            meta::http_method(req).as_str(),
+            req.path(),
+            status,
+            code,
+            request_id.unwrap_or("-"),
+            String::from_utf8_lossy(&body[..body.len().min(LOGGED_BODY_BYTES)])
+        );
+
+        // Keep the headers the failing block set, on either form of the result
+        let mut m = result.message.clone().unwrap_or_else(|| req.clone());
+        if let Some(resp) = &result.response {
+            for (key, value) in &resp.meta {
+                if key.starts_with(meta::RESP_HEADER_PREFIX) {
+                    m.set_meta(key, value);
+                }
+            }
+        }
+        let page = self.page(page_path);
+        meta::set_resp_header(&mut m, "Vary", "Accept");
+        *result = match page {
+            Some(page) if errors::prefers_html(req.header("Accept")) => {
+                m.set_meta(meta::RESP_STATUS, &status.to_string());
+                m.set_meta(meta::ERROR_CODE, &code);
+                respond(m, status, page.to_vec(), "text/html; charset=utf-8")
+            }
+            _ => CoreError::custom(
+                status,
+                &code,
+                "Something went wrong on our side. Please try again later.",
+            )
+            .respond(&m),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// Fails with a 500 built from a fresh message, as a careless handler would.
+    struct Boom;
+
+    impl Block for Boom {
+        fn info(&self) -> BlockInfo {
+            BlockInfo {
+                name: "@app/boom".to_string(),
+                version: "0.1.0".to_string(),
+                interface: "handler@v1".to_string(),
+                summary: "Test handler".to_string(),
+                instance_mode: InstanceMode::Singleton,
+                allowed_modes: Vec::new(),
+                admin_ui: None,
+            }
+        }
+
+        fn handle(&self, _ctx: &dyn Context, _msg: &mut Message) -> Result_ {
+            let fresh = Message::new("http.request", Vec::new());
+            CoreError::custom(500, "db_down", "connection refused to 10.0.0.5").respond(&fresh)
+        }
+
+        fn lifecycle(
+            &self,
+            _ctx: &dyn Context,
+            _event: LifecycleEvent,
+        ) -> std::result::Result<(), WaferError> {
+            Ok(())
+        }
+    }
+
+    fn cors_request(accept: &str) -> Message {
+        MockRequest::get("/api")
+            .header("Origin", "https://app.example")
+            .header("Accept", accept)
+            .meta(
+                &meta::resp_header_key("Access-Control-Allow-Origin"),
+                "https://app.example",
+            )
+            .meta(&meta::resp_header_key("Vary"), "Origin")
+            .build()
+    }
+
+    fn run(block: Arc<dyn Block>, ctx: &MockContext, mut msg: Message) -> SimulatedResponse {
+        SimulatedResponse::from_result(&block.handle(ctx, &mut msg))
+    }
+
+    #[test]
+    fn preserve_headers_restores_cors_on_errors() {
+        let block = with_hooks(Arc::new(Boom), vec![Arc::new(PreserveHeaders::cors())]);
+        let resp = run(block, &MockContext::new(), cors_request("*/*"));
+        assert_status(&resp, 500, Some("db_down"));
+        assert_header(&resp, "Access-Control-Allow-Origin", "https://app.example");
+    }
+
+    #[test]
+    fn error_pages_leave_results_alone_when_unconfigured() {
+        let block = with_hooks(Arc::new(Boom), vec![Arc::new(ErrorPages::new())]);
+        let resp = run(block, &MockContext::new(), cors_request("text/html"));
+        assert_status(&resp, 500, Some("db_down"));
+        assert!(resp.text().contains("10.0.0.5"));
+    }
+
+    #[test]
+    fn error_pages_replace_the_body_and_keep_the_status() {
+        let dir = TempDir::new().with_file("500.html", b"<h1>Sorry</h1>");
+        let ctx = MockContext::new()
+            .with_config("error_page_5xx", &format!("{}/500.html", dir.path_str()));
+        let block = with_hooks(Arc::new(Boom), vec![Arc::new(ErrorPages::new())]);
+
+        let resp = run(block.clone(), &ctx, cors_request("text/html,*/*;q=0.8"));
+        assert_eq!(resp.status, 500);
+        assert_eq!(resp.text(), "<h1>Sorry</h1>");
+
+        let resp = run(block, &ctx, cors_request("application/json"));
+        assert_status(&resp, 500, Some("db_down"));
+        assert!(!resp.text().contains("10.0.0.5"));
+    }
+
+    #[test]
+    fn unreadable_error_pages_are_retried() {
+        let dir = TempDir::new();
+        let ctx = MockContext::new()
+            .with_config("error_page_5xx", &format!("{}/500.html", dir.path_str()));
+        let block = with_hooks(Arc::new(Boom), vec![Arc::new(ErrorPages::new())]);
+
+        let resp = run(block.clone(), &ctx, cors_request("text/html"));
+        assert_status(&resp, 500, Some("db_down"));
+
+        dir.write("500.html", b"<h1>Sorry</h1>");
+        let resp = run(block, &ctx, cors_request("text/html"));
+        assert_eq!(resp.text(), "<h1>Sorry</h1>");
+    }
+
+    #[test]
+    fn registered_blocks_get_the_default_hooks() {
+        let dir = TempDir::new().with_file("500.html", b"<h1>Sorry</h1>");
+        let ctx = MockContext::new()
+            .with_config("error_page_5xx", &format!("{}/500.html", dir.path_str()));
+        let block = crate::blocks::traced("@app/boom", Arc::new(Boom));
+        let resp = run(block, &ctx, cors_request("text/html"));
+        assert_eq!(resp.status, 500);
+        assert_eq!(resp.text(), "<h1>Sorry</h1>");
+        assert_header(&resp, "Access-Control-Allow-Origin", "https://app.example");
+    }
+}
@@ -1,21 +1,236 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use wafer_run::*;
 
+use super::auth::{DegradedMode, DEGRADED_META};
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::errors::{CoreError, Outcome};
+use crate::http::{self, HttpClient};
+use crate::meta;
+use crate::path::{self, PrefixList};
+
+/// Meta keys written by AuthBlock; used to detect misordered chains.
+const AUTH_META_KEYS: &[&str] = &[
+    meta::AUTH_USER_ID,
+    meta::AUTH_USER_EMAIL,
+    meta::AUTH_USER_ROLES,
+];
+
 /// IAMBlock checks if the authenticated user has a required role.
-/// Configure the required role via node config: {"role": "admin"}.
-pub struct IAMBlock;
+/// Configure the required role via node config: {"role": "admin"}. With
+/// `role_from_route: true` and no `role`, the role RouterBlock tagged the
+/// request with (`route.role`) is required instead; requests no route tagged
+/// still need `admin`.
+///
+/// `public_paths` (a comma-separated `PrefixList`, e.g. `"/admin/status"`)
+/// lets requests under those prefixes through without authentication or a
+/// role check, so a public sub-path of an admin route needs no separate
+/// chain. It behaves like `skip_paths` but is scoped to IAM, and marks the
+/// request with `iam.source` `public`.
+///
+/// Set `iam_require_auth_block` to fail with 500 when no auth block ran
+/// before IAM, and `iam_hide_as_404` to answer role denials with 404. Hidden
+/// denials still count as the `forbidden_role` outcome, so monitoring's
+/// `outcomes` separate them from real 404s.
+///
+/// With `authz_url` set, the decision is delegated to an external policy
+/// service instead: IAM POSTs `{"user_id", "roles", "action", "path"}` and
+/// allows the request when the response is `{"allow": true}`. Decisions are
+/// cached per `authz_url` for `authz_cache_seconds` (default 5). Errors deny
+/// the request unless `authz_fail_open: true`.
+///
+/// `iam_source` (see `IamSource`) selects where roles come from. Under the
+/// default `db_then_meta`, `degraded_mode` (see `DegradedMode`) controls the
+/// role check when the database service is unavailable, and
+/// `db_error_policy` controls it when the role query fails:
+/// `fallback_meta` (the default) checks `auth.user_roles` meta, `deny`
+/// fails closed and `allow` fails open. What decided is written to
+/// `iam.source` meta.
+///
+/// Roles can be scoped to a tenant or resource named in the path: with
+/// `scope_path` a path template such as `/tenants/:tenant_id` and
+/// `scope_field` a column of `iam_user_roles` (e.g. `tenant_id`), a request
+/// to `/tenants/acme/...` needs a role row whose `scope_field` is `acme`,
+/// and `iam.scope` meta is set to it. Requests outside the template get 403
+/// rather than the global check, and `scope_path` without `scope_field` is a
+/// 500 `iam_misconfigured`; nodes without `scope_path` get the global
+/// `(user_id, role)` check. Roles in `auth.user_roles` meta carry no scope,
+/// so a scoped request is never decided by them: under `iam_source: meta`,
+/// and when the database is unavailable or its lookup fails (whatever
+/// `degraded_mode` and `db_error_policy` say), it gets 503
+/// `authorization_unavailable`.
+pub struct IAMBlock {
+    http: Option<Arc<dyn HttpClient>>,
+    authz_cache: Mutex<HashMap<String, (bool, Instant)>>,
+    /// Parsed `public_paths`, keyed by the raw config it was parsed from.
+    public_paths: Mutex<Option<(String, Arc<PrefixList>)>>,
+}
+
+/// Upper bound on cached external authorization decisions.
+const MAX_AUTHZ_CACHE: usize = 10_000;
+
+/// Where IAMBlock looks up a user's roles (`iam_source` config).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IamSource {
+    /// The `iam_user_roles` table only. A missing or failing database
+    /// answers 503 `authorization_unavailable`; it never falls back.
+    Db,
+    /// `auth.user_roles` meta (the token's claims) only; no database call.
+    Meta,
+    /// The database, falling back per `degraded_mode` and `db_error_policy`
+    /// (the default).
+    DbThenMeta,
+}
+
+/// The value of the one `:name` segment of `template` in a (normalized)
+/// path, if the path starts with the template's segments. Other segments
+/// must match literally.
+pub fn scope_from_path(template: &str, path: &str) -> Option<String> {
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    let mut scope = None;
+    for part in template.split('/').filter(|s| !s.is_empty()) {
+        let segment = segments.next()?;
+        if part.starts_with(':') {
+            scope = Some(segment.to_string());
+        } else if part != segment {
+            return None;
+        }
+    }
+    scope
+}
+
+impl IamSource {
+    pub fn from_config(ctx: &dyn Context) -> Self {
+        match ctx.config_get("iam_source").unwrap_or("db_then_meta") {
+            "db" => Self::Db,
+            "meta" => Self::Meta,
+            "db_then_meta" => Self::DbThenMeta,
+            other => {
+                tracing::warn!("IAM: unknown iam_source {:?}; using db_then_meta", other);
+                Self::DbThenMeta
+            }
+        }
+    }
+}
+
+fn authorization_unavailable(msg: &mut Message) -> Result_ {
+    CoreError::custom(
+        503,
+        "authorization_unavailable",
+        "Authorization is temporarily unavailable",
+    )
+    .respond(msg)
+}
 
 impl IAMBlock {
     pub fn new() -> Self {
-        Self
+        Self {
+            http: http::default_client(),
+            authz_cache: Mutex::new(HashMap::new()),
+            public_paths: Mutex::new(None),
+        }
+    }
+
+    /// The parsed `public_paths`, re-parsing only when the config changes.
+    fn public_paths(&self, raw: &str) -> Arc<PrefixList> {
+        let mut cached = self.public_paths.lock();
+        if cached.as_ref().map(|(k, _)| k.as_str()) != Some(raw) {
+            *cached = Some((raw.to_string(), Arc::new(PrefixList::parse(raw))));
+        }
+        cached.as_ref().expect("public paths just set").1.clone()
     }
 
-    /// Check if user has the required role by querying iam_user_roles table.
-    fn has_role_db(ctx: &dyn Context, user_id: &str, role: &str) -> Option<bool> {
+    /// Use a custom HTTP client for the external authorization service.
+    pub fn with_http_client(http: Arc<dyn HttpClient>) -> Self {
+        Self {
+            http: Some(http),
+            ..Self::new()
+        }
+    }
+
+    /// Ask the external authorization service whether to allow the request.
+    fn authorize_external(
+        &self,
+        ctx: &dyn Context,
+        msg: &Message,
+        url: &str,
+        user_id: &str,
+    ) -> bool {
+        let fail_open = ctx
+            .config_get("authz_fail_open")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+        let ttl = Duration::from_secs(
+            ctx.config_get("authz_cache_seconds")
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(5),
+        );
+
+        let roles = meta::user_roles(msg);
+        let body = serde_json::json!({
+            "user_id": user_id,
+            "roles": roles,
+            "action": meta::action(msg),
+            "path": path::request_path_of(msg),
+        });
+        // Nodes can share this block with different policy services
+        let cache_key = format!("{} {}", url, body);
+
+        let now = Instant::now();
+        if let Some((allow, at)) = self.authz_cache.lock().get(&cache_key) {
+            if now.duration_since(*at) < ttl {
+                return *allow;
+            }
+        }
+
+        let http = match &self.http {
+            Some(h) => h,
+            None => {
+                tracing::warn!("IAM: authz_url is set but no HTTP client is available");
+                return fail_open;
+            }
+        };
+        let timeout = Duration::from_millis(
+            ctx.config_get("authz_timeout_ms")
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(2000),
+        );
+        let allow = match http.post_json(url, &body, timeout) {
+            Ok(resp) => resp.get("allow").and_then(|v| v.as_bool()) == Some(true),
+            Err(e) => {
+                tracing::warn!("IAM: external authorization failed: {}", e);
+                // Failures are not cached so recovery is picked up immediately
+                return fail_open;
+            }
+        };
+
+        if !ttl.is_zero() {
+            let mut cache = self.authz_cache.lock();
+            if cache.len() >= MAX_AUTHZ_CACHE {
+                cache.retain(|_, (_, at)| now.duration_since(*at) < ttl);
+                if cache.len() >= MAX_AUTHZ_CACHE {
+                    cache.clear();
+                }
+            }
+            cache.insert(cache_key, (allow, now));
+        }
+        allow
+    }
+
+    /// Check if user has the required role by querying iam_user_roles table,
+    /// within `scope` (field, value) when given.
+    fn has_role_db(
+        ctx: &dyn Context,
+        user_id: &str,
+        role: &str,
+        scope: Option<(&str, &str)>,
+    ) -> Option<bool> {
         let services = ctx.services()?;
         let db = services.database.as_ref()?;
 
-        let filters = vec![
+        let mut filters = vec![
             wafer_run::services::database::Filter {
                 field: "user_id".to_string(),
                 operator: wafer_run::services::database::FilterOp::Equal,
@@ -27,6 +242,13 @@ impl IAMBlock {
                 value: serde_json::Value::String(role.to_string()),
             },
         ];
+        if let Some((field, value)) = scope {
+            filters.push(wafer_run::services::database::Filter {
+                field: field.to_string(),
+                operator: wafer_run::services::database::FilterOp::Equal,
+                value: serde_json::Value::String(value.to_string()),
+            });
+        }
 
         let opts = wafer_run::services::database::ListOptions {
             filters,
@@ -40,13 +262,47 @@ impl IAMBlock {
         }
     }
 
+    /// Whether any auth.* meta is present, i.e. an auth block ran earlier in the chain.
+    fn auth_ran(msg: &Message) -> bool {
+        AUTH_META_KEYS.iter().any(|k| !msg.get_meta(k).is_empty())
+    }
+
     /// Check if user has the required role from message meta (fallback).
     fn has_role_meta(msg: &Message, role: &str) -> bool {
-        let roles_str = msg.get_meta("auth.user_roles");
-        if roles_str.is_empty() {
-            return false;
+        meta::user_roles(msg).contains(&role)
+    }
+}
+
+/// Why a request reached IAM without a user, and so how it is refused.
+#[derive(Debug, PartialEq, Eq)]
+enum MissingUser {
+    /// Authentication ran and found no valid credentials: 401.
+    AuthFailed,
+    /// No `auth.*` meta at all, so no authentication block ran first: 401.
+    NoAuth,
+    /// As `NoAuth`, with `iam_require_auth_block` set: 500.
+    Misconfigured,
+}
+
+impl MissingUser {
+    fn of(ctx: &dyn Context, msg: &Message) -> Self {
+        if IAMBlock::auth_ran(msg) {
+            return MissingUser::AuthFailed;
+        }
+        let require_auth_block = ctx
+            .config_get("iam_require_auth_block")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+        if require_auth_block {
+            MissingUser::Misconfigured
+        } else {
+            MissingUser::NoAuth
         }
-        roles_str.split(',').any(|r| r.trim() == role)
+    }
+
+    /// Whether the chain is likely misordered, which is logged as a warning.
+    fn misordered(&self) -> bool {
+        !matches!(self, MissingUser::AuthFailed)
     }
 }
 
@@ -59,55 +315,664 @@ impl Block for IAMBlock {
             summary: "Role-based access control middleware".to_string(),
             instance_mode: InstanceMode::Singleton,
             allowed_modes: Vec::new(),
-            admin_ui: None,
+            admin_ui: crate::admin::ui(admin_descriptor()),
         }
     }
 
     fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        if path::is_skipped(ctx, msg) {
+            return msg.clone().cont();
+        }
+        let public_paths = ctx.config_get("public_paths").unwrap_or("");
+        if !public_paths.trim().is_empty()
+            && self
+                .public_paths(public_paths)
+                .matches(&path::request_path(msg))
+        {
+            msg.set_meta(meta::IAM_SOURCE, "public");
+            return msg.clone().cont();
+        }
         // Check that user is authenticated
-        let user_id = msg.user_id().to_string();
+        let user_id = meta::user_id(msg).unwrap_or("").to_string();
         if user_id.is_empty() {
-            return error(
-                msg.clone(),
-                401,
-                "unauthorized",
-                "Authentication required",
-            );
-        }
-
-        // Get required role from config (default: "admin")
-        let required_role = ctx
-            .config_get("role")
-            .unwrap_or("admin")
-            .to_string();
-
-        // Try database lookup first, fall back to meta roles
-        let has_role = match Self::has_role_db(ctx, &user_id, &required_role) {
-            Some(result) => result,
-            None => Self::has_role_meta(msg, &required_role),
+            let missing = MissingUser::of(ctx, msg);
+            if missing.misordered() {
+                tracing::warn!(
+                    path = %msg.path(),
+                    "IAM: no auth.* meta on request; @wafer/auth likely did not run before @wafer/iam"
+                );
+            }
+            return match missing {
+                MissingUser::AuthFailed => {
+                    Outcome::AuthFailed.record(msg);
+                    CoreError::Unauthorized("Authentication required".to_string()).respond(msg)
+                }
+                MissingUser::NoAuth => {
+                    Outcome::AuthFailed.record(msg);
+                    CoreError::Unauthorized(
+                        "Authentication required (no authentication was performed)".to_string(),
+                    )
+                    .respond(msg)
+                }
+                MissingUser::Misconfigured => CoreError::custom(
+                    500,
+                    "iam_misconfigured",
+                    "Authorization check ran without a preceding authentication block",
+                )
+                .respond(msg),
+            };
+        }
+
+        // Required role from config, or with `role_from_route` the router's
+        // route tag (default: "admin")
+        let from_route = ctx
+            .config_get("role_from_route")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+        let route_role = msg.get_meta(meta::ROUTE_ROLE);
+        let required_role = match ctx.config_get("role") {
+            Some(role) => role,
+            None if from_route && !route_role.is_empty() => route_role,
+            None => "admin",
+        }
+        .to_string();
+
+        let scope_field = ctx.config_get("scope_field").unwrap_or("").trim();
+        let scope = match ctx
+            .config_get("scope_path")
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+        {
+            Some(_) if scope_field.is_empty() => {
+                return CoreError::custom(
+                    500,
+                    "iam_misconfigured",
+                    "scope_path is set without scope_field",
+                )
+                .respond(msg);
+            }
+            Some(template) => match scope_from_path(template, &path::request_path(msg)) {
+                Some(value) => Some(value),
+                None => {
+                    Outcome::ForbiddenRole.record(msg);
+                    return CoreError::Forbidden("Path is outside the role scope".to_string())
+                        .respond_with_details(msg, serde_json::json!({ "scope_path": template }));
+                }
+            },
+            None => None,
         };
+        if let Some(value) = &scope {
+            msg.set_meta(meta::IAM_SCOPE, value);
+        }
 
-        if has_role {
-            msg.clone().cont()
-        } else {
-            error(
-                msg.clone(),
-                403,
-                "forbidden",
-                &format!("Requires '{}' role", required_role),
+        let authz_url = ctx.config_get("authz_url").unwrap_or("");
+        let source = IamSource::from_config(ctx);
+        // Meta roles carry no scope, so no fallback to them can decide a
+        // scoped request
+        let scope_unchecked = |msg: &mut Message, why: &str| {
+            tracing::warn!("IAM: {}; cannot check roles within scope, refusing", why);
+            msg.set_meta(meta::IAM_SOURCE, "none");
+            authorization_unavailable(msg)
+        };
+        let (has_role, decided_by) = if !authz_url.is_empty() {
+            // Centralized policy decides instead of the role check
+            (
+                self.authorize_external(ctx, msg, authz_url, &user_id),
+                "authz",
             )
+        } else if source == IamSource::Meta {
+            if scope.is_some() {
+                return scope_unchecked(msg, "iam_source is meta");
+            }
+            (Self::has_role_meta(msg, &required_role), "meta")
+        } else {
+            let db_available = ctx.services().is_some_and(|s| s.database.is_some());
+            if db_available {
+                // Try database lookup first; db_error_policy decides on failure
+                let db_scope = scope.as_deref().map(|v| (scope_field, v));
+                match Self::has_role_db(ctx, &user_id, &required_role, db_scope) {
+                    Some(result) => (result, "db"),
+                    None if scope.is_some() => {
+                        return scope_unchecked(msg, "role lookup failed");
+                    }
+                    None if source == IamSource::Db => {
+                        tracing::warn!("IAM: role lookup failed (iam_source=db)");
+                        msg.set_meta(meta::IAM_SOURCE, "db");
+                        return authorization_unavailable(msg);
+                    }
+                    None => match ctx.config_get("db_error_policy").unwrap_or("fallback_meta") {
+                        "deny" => {
+                            tracing::warn!(
+                                "IAM: role lookup failed, denying (db_error_policy=deny)"
+                            );
+                            (false, "none")
+                        }
+                        "allow" => {
+                            tracing::warn!(
+                                "IAM: role lookup failed, allowing (db_error_policy=allow)"
+                            );
+                            (true, "none")
+                        }
+                        _ => (Self::has_role_meta(msg, &required_role), "meta"),
+                    },
+                }
+            } else if source == IamSource::Db {
+                tracing::warn!("IAM: database unavailable (iam_source=db)");
+                msg.set_meta(meta::IAM_SOURCE, "db");
+                return authorization_unavailable(msg);
+            } else if scope.is_some() {
+                return scope_unchecked(msg, "database unavailable");
+            } else {
+                let mode = DegradedMode::from_config(ctx);
+                if mode != DegradedMode::Fail {
+                    msg.set_meta(DEGRADED_META, mode.as_str());
+                }
+                match mode {
+                    DegradedMode::AllowAll => {
+                        tracing::warn!("IAM: database unavailable, allowing request (allow_all)");
+                        (true, "none")
+                    }
+                    DegradedMode::Fail | DegradedMode::MetaOnly => {
+                        (Self::has_role_meta(msg, &required_role), "meta")
+                    }
+                }
+            }
+        };
+        msg.set_meta(meta::IAM_SOURCE, decided_by);
+
+        if has_role {
+            return msg.clone().cont();
+        }
+
+        // Recorded before hiding, so a hidden denial still counts as one
+        Outcome::ForbiddenRole.record(msg);
+        // Hide protected resources from unauthorized users (anti-enumeration)
+        let hide_as_404 = ctx
+            .config_get("iam_hide_as_404")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+        if hide_as_404 {
+            return CoreError::NotFound("Not found".to_string()).respond(msg);
+        }
+
+        if !authz_url.is_empty() {
+            return CoreError::Forbidden("Access denied by policy".to_string()).respond(msg);
+        }
+        let mut details = serde_json::json!({ "role": required_role });
+        if let Some(value) = &scope {
+            details["scope"] = serde_json::Value::String(value.clone());
         }
+        CoreError::Forbidden(format!("Requires '{}' role", required_role))
+            .respond_with_details(msg, details)
     }
 
     fn lifecycle(
         &self,
-        _ctx: &dyn Context,
-        _event: LifecycleEvent,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
     ) -> std::result::Result<(), WaferError> {
+        if matches!(event.event_type, LifecycleType::Start) {
+            DegradedMode::report(ctx, "@wafer/iam");
+        }
         Ok(())
     }
 }
 
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field("role", FieldKind::String, "admin", "Role required to pass")
+        .field(
+            "role_from_route",
+            FieldKind::Bool,
+            "false",
+            "Without `role`, require the role the router tagged the route with",
+        )
+        .field(
+            "scope_path",
+            FieldKind::String,
+            "",
+            "Path template naming the role scope, e.g. /tenants/:tenant_id",
+        )
+        .field(
+            "scope_field",
+            FieldKind::String,
+            "",
+            "iam_user_roles column the path's scope is matched against",
+        )
+        .field(
+            "iam_source",
+            FieldKind::one_of(&["db", "meta", "db_then_meta"]),
+            "db_then_meta",
+            "Where roles are read from",
+        )
+        .field(
+            "db_error_policy",
+            FieldKind::one_of(&["fallback_meta", "deny", "allow"]),
+            "fallback_meta",
+            "Decision when the role lookup fails",
+        )
+        .field(
+            "public_paths",
+            FieldKind::List,
+            "",
+            "Path prefixes that need no role",
+        )
+        .field(
+            "authz_url",
+            FieldKind::String,
+            "",
+            "External authorization service deciding instead of roles",
+        )
+        .field(
+            "authz_cache_seconds",
+            FieldKind::Integer,
+            "5",
+            "How long authorization decisions are cached",
+        )
+        .field(
+            "authz_timeout_ms",
+            FieldKind::Integer,
+            "2000",
+            "Timeout of authorization service calls",
+        )
+        .field(
+            "authz_fail_open",
+            FieldKind::Bool,
+            "false",
+            "Allow requests when the authorization service fails",
+        )
+        .field(
+            "iam_require_auth_block",
+            FieldKind::Bool,
+            "false",
+            "Reject requests auth has not run on",
+        )
+        .field(
+            "iam_hide_as_404",
+            FieldKind::Bool,
+            "false",
+            "Answer role denials with 404",
+        )
+        .field(
+            "degraded_mode",
+            FieldKind::one_of(&["fail", "meta_only", "allow_all"]),
+            "fail",
+            "Behavior when the database is missing",
+        )
+        .field(
+            "allow_insecure_dev_mode",
+            FieldKind::Bool,
+            "false",
+            "Permit `degraded_mode: allow_all` (local development only)",
+        )
+        .skip_paths()
+}
+
 pub fn register(w: &mut Wafer) {
-    w.register_block("@wafer/iam", Arc::new(IAMBlock::new()));
+    register_as(w, "@wafer/iam");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(IAMBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use serde_json::json;
+
+    fn run(ctx: &MockContext, msg: MockRequest) -> (SimulatedResponse, Message) {
+        run_with(&IAMBlock::new(), ctx, msg)
+    }
+
+    fn run_with(
+        block: &IAMBlock,
+        ctx: &MockContext,
+        msg: MockRequest,
+    ) -> (SimulatedResponse, Message) {
+        let mut msg = msg.build();
+        let result = block.handle(ctx, &mut msg);
+        let out = result.message.clone().unwrap_or(msg);
+        (SimulatedResponse::from_result(&result), out)
+    }
+
+    fn user(id: &str, roles: &str) -> MockRequest {
+        MockRequest::get("/admin/users")
+            .meta(meta::AUTH_USER_ID, id)
+            .meta(meta::AUTH_USER_ROLES, roles)
+    }
+
+    fn with_roles(rows: &[(&str, &str)]) -> MockContext {
+        let mut db = MockDatabase::new();
+        for (user_id, role) in rows {
+            db = db.with_row("iam_user_roles", json!({"user_id": user_id, "role": role}));
+        }
+        MockContext::new().with_database(db)
+    }
+
+    #[test]
+    fn database_role_grant_allows() {
+        let ctx = with_roles(&[("u1", "admin")]);
+        let (resp, msg) = run(&ctx, user("u1", ""));
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::IAM_SOURCE), "db");
+    }
+
+    #[test]
+    fn missing_grant_is_forbidden_even_with_meta_role() {
+        let ctx = with_roles(&[("u1", "editor")]);
+        let (resp, _) = run(&ctx, user("u1", "admin"));
+        assert_status(&resp, 403, Some("forbidden"));
+        assert_eq!(resp.json().unwrap()["error"]["details"]["role"], "admin");
+    }
+
+    #[test]
+    fn configured_role_is_required() {
+        let ctx = with_roles(&[("u1", "admin")]).with_config("role", "billing");
+        assert_status(&run(&ctx, user("u1", "")).0, 403, Some("forbidden"));
+    }
+
+    #[test]
+    fn meta_source_reads_token_roles() {
+        let ctx = MockContext::new().with_config("iam_source", "meta");
+        let (resp, msg) = run(&ctx, user("u1", "viewer,admin"));
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::IAM_SOURCE), "meta");
+        assert_status(&run(&ctx, user("u1", "viewer")).0, 403, Some("forbidden"));
+    }
+
+    #[test]
+    fn db_source_without_database_is_unavailable() {
+        let ctx = MockContext::new().with_config("iam_source", "db");
+        assert_status(
+            &run(&ctx, user("u1", "admin")).0,
+            503,
+            Some("authorization_unavailable"),
+        );
+    }
+
+    fn failing_roles() -> MockContext {
+        MockContext::new().with_database(MockDatabase::new().with_failing("iam_user_roles"))
+    }
+
+    #[test]
+    fn failing_role_lookups_per_source() {
+        // db never falls back
+        let ctx = failing_roles().with_config("iam_source", "db");
+        let (resp, msg) = run(&ctx, user("u1", "admin"));
+        assert_status(&resp, 503, Some("authorization_unavailable"));
+        assert_eq!(msg.get_meta(meta::IAM_SOURCE), "db");
+
+        // meta never asks the database, failing or granting
+        let ctx = failing_roles().with_config("iam_source", "meta");
+        let (resp, msg) = run(&ctx, user("u1", "admin"));
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::IAM_SOURCE), "meta");
+        let ctx = with_roles(&[("u1", "admin")]).with_config("iam_source", "meta");
+        assert_status(&run(&ctx, user("u1", "viewer")).0, 403, Some("forbidden"));
+
+        // db_then_meta falls back to the token's roles
+        let ctx = failing_roles().with_config("iam_source", "db_then_meta");
+        let (resp, msg) = run(&ctx, user("u1", "admin"));
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::IAM_SOURCE), "meta");
+        assert_status(&run(&ctx, user("u1", "viewer")).0, 403, Some("forbidden"));
+    }
+
+    #[test]
+    fn db_error_policy_deny_ignores_meta_roles() {
+        let ctx = failing_roles().with_config("db_error_policy", "deny");
+        let (resp, msg) = run(&ctx, user("u1", "admin"));
+        assert_status(&resp, 403, Some("forbidden"));
+        assert_eq!(msg.get_meta(meta::IAM_SOURCE), "none");
+
+        let ctx = failing_roles().with_config("db_error_policy", "allow");
+        let (resp, msg) = run(&ctx, user("u1", "viewer"));
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::IAM_SOURCE), "none");
+    }
+
+    #[test]
+    fn requests_without_auth_meta_are_flagged_as_misordered() {
+        let ctx = MockContext::new();
+        let failed = MockRequest::get("/admin")
+            .meta(meta::AUTH_USER_EMAIL, "a@example.com")
+            .build();
+        assert_eq!(MissingUser::of(&ctx, &failed), MissingUser::AuthFailed);
+        assert!(!MissingUser::AuthFailed.misordered());
+
+        let bare = MockRequest::get("/admin").build();
+        assert_eq!(MissingUser::of(&ctx, &bare), MissingUser::NoAuth);
+        assert!(MissingUser::NoAuth.misordered());
+
+        let strict = MockContext::new().with_config("iam_require_auth_block", "true");
+        assert_eq!(MissingUser::of(&strict, &bare), MissingUser::Misconfigured);
+        assert!(MissingUser::Misconfigured.misordered());
+    }
+
+    #[test]
+    fn required_auth_block_turns_a_misordered_chain_into_a_500() {
+        let ctx = MockContext::new().with_config("iam_require_auth_block", "true");
+        let (resp, _) = run(&ctx, MockRequest::get("/admin"));
+        assert_status(&resp, 500, Some("iam_misconfigured"));
+
+        let (resp, _) = run(&MockContext::new(), MockRequest::get("/admin"));
+        assert_status(&resp, 401, Some("unauthorized"));
+    }
+
+    #[test]
+    fn unauthenticated_request_is_rejected() {
+        let ctx = with_roles(&[("u1", "admin")]);
+        let (resp, _) = run(&ctx, MockRequest::get("/admin"));
+        assert_status(&resp, 401, Some("unauthorized"));
+    }
+
+    #[test]
+    fn hide_as_404_masks_denials() {
+        let ctx = with_roles(&[]).with_config("iam_hide_as_404", "true");
+        let (resp, msg) = run(&ctx, user("u1", ""));
+        assert_status(&resp, 404, Some("not_found"));
+        assert_eq!(msg.get_meta(meta::OUTCOME_CODE), "forbidden_role");
+    }
+
+    #[test]
+    fn public_paths_skip_the_check() {
+        let ctx = with_roles(&[]).with_config("public_paths", "/admin/status");
+        let (resp, msg) = run(&ctx, MockRequest::get("/admin/status"));
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::IAM_SOURCE), "public");
+    }
+
+    /// Policy service answering `{"allow": ...}`, or failing when `allow` is
+    /// `None`, recording the URLs it was asked.
+    struct Policy {
+        allow: Mutex<Option<bool>>,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Policy {
+        fn new(allow: Option<bool>) -> Arc<Self> {
+            Arc::new(Self {
+                allow: Mutex::new(allow),
+                calls: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.lock().len()
+        }
+    }
+
+    impl HttpClient for Policy {
+        fn post_form(
+            &self,
+            _url: &str,
+            _form: &[(&str, &str)],
+        ) -> std::result::Result<serde_json::Value, String> {
+            Err("unexpected".to_string())
+        }
+
+        fn post_json(
+            &self,
+            url: &str,
+            body: &serde_json::Value,
+            _timeout: Duration,
+        ) -> std::result::Result<serde_json::Value, String> {
+            assert_eq!(body["user_id"], "u1");
+            assert_eq!(body["path"], "/admin/users");
+            self.calls.lock().push(url.to_string());
+            match *self.allow.lock() {
+                Some(allow) => Ok(json!({ "allow": allow })),
+                None => Err("connection refused".to_string()),
+            }
+        }
+
+        fn get_json(
+            &self,
+            _url: &str,
+            _bearer: &str,
+        ) -> std::result::Result<serde_json::Value, String> {
+            Err("unexpected".to_string())
+        }
+    }
+
+    fn authz(url: &str) -> MockContext {
+        MockContext::new().with_config("authz_url", url)
+    }
+
+    #[test]
+    fn external_policy_allows_and_denies() {
+        let policy = Policy::new(Some(true));
+        let block = IAMBlock::with_http_client(policy.clone());
+        let ctx = authz("https://authz.example/check").with_config("authz_cache_seconds", "0");
+        let (resp, msg) = run_with(&block, &ctx, user("u1", ""));
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::IAM_SOURCE), "authz");
+
+        *policy.allow.lock() = Some(false);
+        let (resp, msg) = run_with(&block, &ctx, user("u1", "admin"));
+        assert_status(&resp, 403, Some("forbidden"));
+        assert_eq!(msg.get_meta(meta::OUTCOME_CODE), "forbidden_role");
+        assert_eq!(policy.calls(), 2);
+    }
+
+    #[test]
+    fn unreachable_policies_fail_closed_unless_configured_open() {
+        let policy = Policy::new(None);
+        let block = IAMBlock::with_http_client(policy.clone());
+        let ctx = authz("https://authz.example/check");
+        assert_status(&run_with(&block, &ctx, user("u1", "admin")).0, 403, None);
+        // Failures are not cached
+        assert_status(&run_with(&block, &ctx, user("u1", "admin")).0, 403, None);
+        assert_eq!(policy.calls(), 2);
+
+        let ctx = authz("https://authz.example/check").with_config("authz_fail_open", "true");
+        assert_status(&run_with(&block, &ctx, user("u1", "")).0, 200, None);
+    }
+
+    #[test]
+    fn policy_decisions_are_cached_per_policy_url() {
+        let policy = Policy::new(Some(true));
+        let block = IAMBlock::with_http_client(policy.clone());
+        let ctx = authz("https://authz.example/check").with_config("authz_cache_seconds", "60");
+        for _ in 0..3 {
+            assert_status(&run_with(&block, &ctx, user("u1", "")).0, 200, None);
+        }
+        assert_eq!(policy.calls(), 1);
+
+        // Another node's policy service is asked for its own decision
+        *policy.allow.lock() = Some(false);
+        let other = authz("https://strict.example/check").with_config("authz_cache_seconds", "60");
+        assert_status(&run_with(&block, &other, user("u1", "")).0, 403, None);
+        assert_status(&run_with(&block, &ctx, user("u1", "")).0, 200, None);
+        assert_eq!(
+            *policy.calls.lock(),
+            [
+                "https://authz.example/check",
+                "https://strict.example/check"
+            ]
+        );
+    }
+
+    fn scoped(db: MockDatabase) -> MockContext {
+        MockContext::new()
+            .with_database(db)
+            .with_config("scope_path", "/tenants/:tenant_id")
+            .with_config("scope_field", "tenant_id")
+    }
+
+    fn tenant_admin(tenant: &str) -> MockDatabase {
+        MockDatabase::new().with_row(
+            "iam_user_roles",
+            json!({"user_id": "u1", "role": "admin", "tenant_id": tenant}),
+        )
+    }
+
+    fn to_tenant(tenant: &str) -> MockRequest {
+        MockRequest::get(&format!("/tenants/{}/users", tenant))
+            .meta(meta::AUTH_USER_ID, "u1")
+            .meta(meta::AUTH_USER_ROLES, "admin")
+    }
+
+    #[test]
+    fn scoped_roles_allow_only_their_scope() {
+        let ctx = scoped(tenant_admin("a"));
+        let (resp, msg) = run(&ctx, to_tenant("a"));
+        assert_status(&resp, 200, None);
+        assert_eq!(msg.get_meta(meta::IAM_SCOPE), "a");
+
+        let (resp, _) = run(&ctx, to_tenant("b"));
+        assert_status(&resp, 403, Some("forbidden"));
+        assert_eq!(resp.json().unwrap()["error"]["details"]["scope"], "b");
+        // The decoded path is the one scoped
+        let req = MockRequest::get("/%74enants/b/users").meta(meta::AUTH_USER_ID, "u1");
+        assert_status(&run(&ctx, req).0, 403, Some("forbidden"));
+    }
+
+    #[test]
+    fn paths_outside_the_scope_template_are_denied() {
+        let ctx = scoped(tenant_admin("a"));
+        let req = MockRequest::get("/admin/users").meta(meta::AUTH_USER_ID, "u1");
+        let (resp, _) = run(&ctx, req);
+        assert_status(&resp, 403, Some("forbidden"));
+        assert_eq!(
+            resp.json().unwrap()["error"]["details"]["scope_path"],
+            "/tenants/:tenant_id"
+        );
+
+        let ctx = MockContext::new().with_config("scope_path", "/tenants/:tenant_id");
+        let (resp, _) = run(&ctx, to_tenant("a"));
+        assert_status(&resp, 500, Some("iam_misconfigured"));
+    }
+
+    #[test]
+    fn scoped_requests_never_fall_back_to_meta_roles() {
+        for policy in ["fallback_meta", "allow"] {
+            let ctx = scoped(tenant_admin("a").with_failing("iam_user_roles"))
+                .with_config("db_error_policy", policy);
+            let (resp, msg) = run(&ctx, to_tenant("b"));
+            assert_status(&resp, 503, Some("authorization_unavailable"));
+            assert_eq!(msg.get_meta(meta::IAM_SOURCE), "none");
+        }
+
+        let no_db = || {
+            MockContext::new()
+                .with_config("scope_path", "/tenants/:tenant_id")
+                .with_config("scope_field", "tenant_id")
+        };
+        let ctx = no_db().with_config("degraded_mode", "meta_only");
+        let resp = run(&ctx, to_tenant("b")).0;
+        assert_status(&resp, 503, Some("authorization_unavailable"));
+        let ctx = no_db()
+            .with_config("degraded_mode", "allow_all")
+            .with_config("allow_insecure_dev_mode", "true");
+        let resp = run(&ctx, to_tenant("b")).0;
+        assert_status(&resp, 503, Some("authorization_unavailable"));
+
+        let ctx = no_db().with_config("iam_source", "meta");
+        let resp = run(&ctx, to_tenant("a")).0;
+        assert_status(&resp, 503, Some("authorization_unavailable"));
+    }
 }
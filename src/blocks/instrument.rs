@@ -0,0 +1,308 @@
+use parking_lot::RwLock;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use wafer_run::*;
+
+/// Upper bounds (seconds) of the duration histogram buckets.
+pub const DURATION_BUCKETS: [f64; 10] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable instrumentation of blocks registered from now on.
+/// Off by default; set it before calling `register_all`. The flag is only
+/// read at registration; hosts running blocks without registering them wrap
+/// them with `InstrumentedBlock::with_registry` instead.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether newly registered blocks are instrumented.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Register a block, wrapping it in `InstrumentedBlock` when instrumentation is enabled.
+pub fn register_block(w: &mut Wafer, name: &str, block: Arc<dyn Block>) {
+    if is_enabled() {
+        w.register_block(name, Arc::new(InstrumentedBlock::new(name, block)));
+    } else {
+        w.register_block(name, block);
+    }
+}
+
+/// Per-block invocation counters and duration histogram.
+pub struct BlockSeries {
+    invocations: AtomicU64,
+    errors: AtomicU64,
+    duration_micros: AtomicU64,
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+}
+
+impl BlockSeries {
+    fn new() -> Self {
+        Self {
+            invocations: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            duration_micros: AtomicU64::new(0),
+            buckets: Default::default(),
+        }
+    }
+
+    fn observe(&self, secs: f64, is_error: bool) {
+        self.invocations.fetch_add(1, Ordering::Relaxed);
+        if is_error {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.duration_micros
+            .fetch_add((secs * 1_000_000.0) as u64, Ordering::Relaxed);
+        // Non-cumulative per bucket; summed when rendered
+        if let Some(idx) = DURATION_BUCKETS.iter().position(|b| secs <= *b) {
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn invocations(&self) -> u64 {
+        self.invocations.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+}
+
+/// MetricsRegistry holds one series per instrumented block name.
+#[derive(Default)]
+pub struct MetricsRegistry {
+    series: RwLock<BTreeMap<String, Arc<BlockSeries>>>,
+}
+
+impl MetricsRegistry {
+    /// Get or create the series for a block.
+    pub fn series(&self, block: &str) -> Arc<BlockSeries> {
+        if let Some(s) = self.series.read().get(block) {
+            return s.clone();
+        }
+        self.series
+            .write()
+            .entry(block.to_string())
+            .or_insert_with(|| Arc::new(BlockSeries::new()))
+            .clone()
+    }
+
+    /// Names of all blocks with a series.
+    pub fn blocks(&self) -> Vec<String> {
+        self.series.read().keys().cloned().collect()
+    }
+
+    /// Render all series in the Prometheus text exposition format.
+    pub fn render_prometheus(&self, out: &mut String) {
+        let mut buf = Vec::new();
+        let _ = self.write_prometheus(&mut buf);
+        out.push_str(&String::from_utf8_lossy(&buf));
+    }
+
+    /// Write all series in the Prometheus text exposition format to `out`
+    /// as they are read, one metric family (`# HELP`, `# TYPE`, series) at a
+    /// time.
+    pub fn write_prometheus<W: Write + ?Sized>(&self, out: &mut W) -> io::Result<()> {
+        let series = self.series.read();
+        if series.is_empty() {
+            return Ok(());
+        }
+
+        out.write_all(b"# HELP wafer_block_invocations_total Block handle() invocations.\n")?;
+        out.write_all(b"# TYPE wafer_block_invocations_total counter\n")?;
+        for (name, s) in series.iter() {
+            writeln!(
+                out,
+                "wafer_block_invocations_total{{block=\"{}\"}} {}",
+                escape_label(name),
+                s.invocations()
+            )?;
+        }
+
+        out.write_all(
+            b"# HELP wafer_block_errors_total Block invocations that returned an error.\n",
+        )?;
+        out.write_all(b"# TYPE wafer_block_errors_total counter\n")?;
+        for (name, s) in series.iter() {
+            writeln!(
+                out,
+                "wafer_block_errors_total{{block=\"{}\"}} {}",
+                escape_label(name),
+                s.errors()
+            )?;
+        }
+
+        out.write_all(b"# HELP wafer_block_duration_seconds Block handle() duration.\n")?;
+        out.write_all(b"# TYPE wafer_block_duration_seconds histogram\n")?;
+        for (name, s) in series.iter() {
+            let label = escape_label(name);
+            let mut cumulative = 0u64;
+            for (i, bound) in DURATION_BUCKETS.iter().enumerate() {
+                cumulative += s.buckets[i].load(Ordering::Relaxed);
+                writeln!(
+                    out,
+                    "wafer_block_duration_seconds_bucket{{block=\"{}\",le=\"{}\"}} {}",
+                    label, bound, cumulative
+                )?;
+            }
+            writeln!(
+                out,
+                "wafer_block_duration_seconds_bucket{{block=\"{}\",le=\"+Inf\"}} {}",
+                label,
+                s.invocations()
+            )?;
+            writeln!(
+                out,
+                "wafer_block_duration_seconds_sum{{block=\"{}\"}} {}",
+                label,
+                s.duration_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+            )?;
+            writeln!(
+                out,
+                "wafer_block_duration_seconds_count{{block=\"{}\"}} {}",
+                label,
+                s.invocations()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The process-wide metrics registry.
+pub fn registry() -> &'static MetricsRegistry {
+    static REGISTRY: OnceLock<MetricsRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(MetricsRegistry::default)
+}
+
+/// Escape a Prometheus label value.
+pub fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// InstrumentedBlock records invocation counts, errors, and durations of an inner block.
+pub struct InstrumentedBlock<B: Block + ?Sized> {
+    inner: Arc<B>,
+    series: Arc<BlockSeries>,
+}
+
+impl<B: Block + ?Sized> InstrumentedBlock<B> {
+    /// Record into the process-wide `registry()`, as `register_block` does.
+    pub fn new(name: &str, inner: Arc<B>) -> Self {
+        Self::with_registry(name, inner, registry())
+    }
+
+    /// Record into `registry` under `name`.
+    pub fn with_registry(name: &str, inner: Arc<B>, registry: &MetricsRegistry) -> Self {
+        Self {
+            inner,
+            series: registry.series(name),
+        }
+    }
+}
+
+impl<B: Block + ?Sized> Block for InstrumentedBlock<B> {
+    fn info(&self) -> BlockInfo {
+        self.inner.info()
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let start = Instant::now();
+        let result = self.inner.handle(ctx, msg);
+        self.series.observe(
+            start.elapsed().as_secs_f64(),
+            super::hooks::is_error_result(&result),
+        );
+        result
+    }
+
+    fn lifecycle(
+        &self,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        self.inner.lifecycle(ctx, event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use serde_json::json;
+
+    const CHAIN: [&str; 3] = ["@wafer/security-headers", "@wafer/cors", "@wafer/iam"];
+
+    // A private registry, so the counts don't depend on other tests or on
+    // the process-wide `set_enabled` flag
+    fn instrumented(metrics: &MetricsRegistry) -> ChainHarness {
+        let def: ChainDef = serde_json::from_value(json!({
+            "id": "instrumented",
+            "config": { "on_error": "stop" },
+            "root": {
+                "block": CHAIN[0],
+                "next": [{ "block": CHAIN[1], "next": [{ "block": CHAIN[2] }] }],
+            },
+        }))
+        .unwrap();
+        CHAIN
+            .iter()
+            .fold(ChainHarness::new().with_chain(&def), |harness, name| {
+                let block = crate::new_block(name).unwrap();
+                let block = InstrumentedBlock::with_registry(name, block, metrics);
+                harness.with_block(name, Arc::new(block))
+            })
+    }
+
+    #[test]
+    fn each_block_in_a_chain_gets_a_labeled_series() {
+        let metrics = MetricsRegistry::default();
+        let harness = instrumented(&metrics);
+        for _ in 0..3 {
+            let resp = harness.run("instrumented", MockRequest::get("/admin").build());
+            assert_status(&resp, 401, Some("unauthorized"));
+        }
+
+        let mut names = CHAIN.map(String::from).to_vec();
+        names.sort();
+        assert_eq!(metrics.blocks(), names);
+        for name in CHAIN {
+            assert_eq!(metrics.series(name).invocations(), 3, "{}", name);
+        }
+        assert_eq!(metrics.series("@wafer/security-headers").errors(), 0);
+        assert_eq!(metrics.series("@wafer/cors").errors(), 0);
+        assert_eq!(metrics.series("@wafer/iam").errors(), 3);
+
+        let mut out = String::new();
+        metrics.render_prometheus(&mut out);
+        assert!(out.contains("wafer_block_invocations_total{block=\"@wafer/cors\"} 3"));
+        assert!(out.contains("wafer_block_errors_total{block=\"@wafer/iam\"} 3"));
+        assert!(out.contains("wafer_block_errors_total{block=\"@wafer/security-headers\"} 0"));
+        assert!(
+            out.contains("wafer_block_duration_seconds_bucket{block=\"@wafer/iam\",le=\"+Inf\"} 3")
+        );
+        assert!(
+            out.contains("wafer_block_duration_seconds_count{block=\"@wafer/security-headers\"} 3")
+        );
+    }
+
+    #[test]
+    fn empty_registries_render_nothing() {
+        let mut out = String::new();
+        MetricsRegistry::default().render_prometheus(&mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
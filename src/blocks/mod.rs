@@ -1,8 +1,100 @@
 pub mod auth;
+pub mod circuit_breaker;
+pub mod client_hints;
 pub mod cors;
+pub mod csp_report;
+pub mod deprecation;
+pub mod experiment;
+pub mod hooks;
 pub mod iam;
+pub mod instrument;
 pub mod monitoring;
+pub mod mount;
+pub mod oauth;
+pub mod quota;
 pub mod rate_limit;
 pub mod readonly_guard;
+pub mod report_intake;
+pub mod reporting;
+pub mod router;
 pub mod security_headers;
+pub mod tasks;
+pub mod tls_guard;
+pub mod trace;
+pub mod trust_boundary;
+pub mod ua_filter;
+pub mod validate_json;
 pub mod web;
+
+use std::sync::Arc;
+use wafer_run::{Block, Context, Wafer};
+
+/// Register a block instance under `name`.
+///
+/// Each module's `register` uses the block's canonical `@wafer/...` name;
+/// `register_as` (here or per module) adds further instances under aliases,
+/// so e.g. two WebBlocks with different defaults can serve `/docs` and
+/// `/assets` from one runtime:
+///
+/// ```ignore
+/// blocks::register_as(w, "@app/docs", Arc::new(WebBlock::new().with_root("./docs")));
+/// ```
+///
+/// Registered blocks are wrapped in `trace::TracedBlock` and given the
+/// `hooks::default_hooks`.
+pub fn register_as(w: &mut Wafer, name: &str, block: Arc<dyn Block>) {
+    instrument::register_block(w, name, traced(name, block));
+}
+
+/// `block` as `register_as` registers it under `name`, for hosts (and the
+/// test harness) that run blocks without registering them.
+pub fn traced(name: &str, block: Arc<dyn Block>) -> Arc<dyn Block> {
+    let hooked = hooks::with_hooks(block, hooks::default_hooks());
+    Arc::new(trace::TracedBlock::new(name, hooked))
+}
+
+/// Where a block's JSON table (routes, schemas) comes from: the file named by
+/// one config key, else the inline JSON of another.
+pub(crate) enum TableSource<'a> {
+    File { key: &'a str, path: &'a str },
+    Inline(&'a str),
+}
+
+impl<'a> TableSource<'a> {
+    /// The file under `file_key` if set, else `inline_key` (or `default`).
+    pub(crate) fn from_config(
+        ctx: &'a dyn Context,
+        file_key: &'a str,
+        inline_key: &str,
+        default: &'a str,
+    ) -> Self {
+        match ctx.config_get(file_key).filter(|s| !s.is_empty()) {
+            Some(path) => Self::File {
+                key: file_key,
+                path,
+            },
+            None => Self::Inline(ctx.config_get(inline_key).unwrap_or(default)),
+        }
+    }
+
+    /// Changes with the config and with the file's modification time, so a
+    /// compiled table cached under it is rebuilt only when it must be.
+    pub(crate) fn cache_key(&self) -> String {
+        match self {
+            Self::File { path, .. } => {
+                let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+                format!("file:{}@{:?}", path, modified)
+            }
+            Self::Inline(json) => format!("inline:{}", json),
+        }
+    }
+
+    pub(crate) fn read(&self) -> Result<String, String> {
+        match self {
+            Self::File { key, path } => {
+                std::fs::read_to_string(path).map_err(|e| format!("{} {}: {}", key, path, e))
+            }
+            Self::Inline(json) => Ok(json.to_string()),
+        }
+    }
+}
@@ -1,15 +1,68 @@
 use parking_lot::Mutex;
 use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use wafer_run::*;
 
+use super::auth::{bearer_token, constant_time_eq};
+use super::instrument;
+use super::mount::Mount;
+use super::tasks::{self, TaskSet};
+use super::trace;
+use super::web;
+use crate::admin::{AdminDescriptor, FieldKind, StatusDescriptor};
+use crate::errors::CoreError;
+use crate::meta;
+use crate::net::{self, CidrList};
+use crate::path;
+
 /// MonitoringBlock tracks request metrics and provides a stats endpoint.
+/// `/_stats` returns JSON; `/_metrics` returns the Prometheus text format,
+/// including per-block series when `instrument::set_enabled(true)` is on.
+/// Both report error results by the block that produced them (see
+/// `trace::TracedBlock`) and WebBlock's ETag revalidation hits, misses and
+/// bytes saved (see `web::cache_stats`), and rejections by outcome code
+/// (`auth_failed`, `rate_limited`, ... see `errors::Outcome`) as `outcomes`
+/// and `wafer_outcomes_total{code="..."}`, so 401s and 429s can be charted
+/// apart from other errors. `status_counts` and `error_count` count the
+/// responses blocks produced, by status. Error results, outcomes and
+/// statuses are counted as the responding block returns, in this
+/// instance's `trace::TraceCounters`, for the requests this instance saw
+/// first; put monitoring at the head of the chain (as `http-infra-v2` does)
+/// to count rejections by blocks before it.
+/// `/_stats?fields=total_requests,error_count` keeps only the named top-level
+/// fields and `?pretty=true` indents the JSON.
+///
+/// `/_metrics` is open unless `metrics_token` or `metrics_allow_ips` is set,
+/// so a scraper can read it without passing the auth chain. Then a request
+/// must either send `Authorization: Bearer <metrics_token>` or come from a
+/// `metrics_allow_ips` CIDR (the client address per `net::client_ip`); any
+/// other request gets 404 so the endpoint is not revealed.
+///
+/// With `monitoring_persist_path` set, counters are snapshotted to that file
+/// in the background every `monitoring_persist_interval_secs` (default 60)
+/// and at Stop, and reloaded at Start, so totals survive restarts. Unknown
+/// fields in a snapshot are ignored; an unreadable one is replaced.
 pub struct MonitoringBlock {
     start_time: Instant,
-    stats: Mutex<MonitoringStats>,
+    stats: Arc<Mutex<MonitoringStats>>,
+    /// Filled in by the `TracedBlock`s of requests tagged with `counters_id`.
+    counters: Arc<trace::TraceCounters>,
+    counters_id: u64,
+    tasks: TaskSet,
+    metrics_allow: Mutex<Option<(String, CidrList)>>,
+    /// Size of the last `/_metrics` body, to presize the next.
+    metrics_size: AtomicUsize,
 }
 
+/// Counters as reported and persisted. In the block's `stats`,
+/// `error_count` and `status_counts` hold only what a snapshot restored;
+/// responses since are in its `TraceCounters` (see `merged`).
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 struct MonitoringStats {
     total_requests: u64,
     error_count: u64,
@@ -17,18 +70,235 @@ struct MonitoringStats {
     path_counts: HashMap<String, u64>,
 }
 
+/// `stats` with the responses counted in `counters` added in. Error
+/// responses are those with a status of 400 or above.
+fn merged(stats: &Mutex<MonitoringStats>, counters: &trace::TraceCounters) -> MonitoringStats {
+    let stats = stats.lock();
+    let mut status_counts = stats.status_counts.clone();
+    let mut error_count = stats.error_count;
+    for (status, n) in counters.statuses().snapshot() {
+        if status.parse::<u16>().is_ok_and(|s| s >= 400) {
+            error_count += n;
+        }
+        *status_counts.entry(status).or_insert(0) += n;
+    }
+    MonitoringStats {
+        total_requests: stats.total_requests,
+        error_count,
+        status_counts,
+        path_counts: stats.path_counts.clone(),
+    }
+}
+
 impl MonitoringBlock {
     pub fn new() -> Self {
+        let (counters, counters_id) = trace::TraceCounters::register();
         Self {
             start_time: Instant::now(),
-            stats: Mutex::new(MonitoringStats {
+            counters,
+            counters_id,
+            stats: Arc::new(Mutex::new(MonitoringStats {
                 total_requests: 0,
                 error_count: 0,
                 status_counts: HashMap::new(),
                 path_counts: HashMap::new(),
-            }),
+            })),
+            tasks: TaskSet::new("@wafer/monitoring"),
+            metrics_allow: Mutex::new(None),
+            metrics_size: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether the request may read `/_metrics`.
+    fn metrics_allowed(&self, ctx: &dyn Context, msg: &Message) -> bool {
+        let token = ctx.config_get("metrics_token").unwrap_or("");
+        let allow_ips = ctx.config_get("metrics_allow_ips").unwrap_or("");
+        if token.is_empty() && allow_ips.is_empty() {
+            return true;
+        }
+        let bearer = bearer_token(msg.header("Authorization")).unwrap_or("");
+        if !token.is_empty() && constant_time_eq(bearer, token) {
+            return true;
+        }
+        !allow_ips.is_empty() && self.ip_allowed(allow_ips, &net::client_ip(msg))
+    }
+
+    /// Whether `addr` is in `metrics_allow_ips`, re-parsing only when the config changes.
+    fn ip_allowed(&self, raw: &str, addr: &str) -> bool {
+        let mut cached = self.metrics_allow.lock();
+        if cached.as_ref().map(|(k, _)| k.as_str()) != Some(raw) {
+            *cached = Some((raw.to_string(), CidrList::parse(raw)));
+        }
+        cached
+            .as_ref()
+            .is_some_and(|(_, list)| list.contains_addr(addr))
+    }
+}
+
+/// Load a stats snapshot; `None` (with a warning) if it is missing or corrupt.
+fn load_snapshot(path: &Path) -> Option<MonitoringStats> {
+    let raw = match std::fs::read(path) {
+        Ok(r) => r,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            tracing::warn!("monitoring: cannot read {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    match serde_json::from_slice(&raw) {
+        Ok(stats) => Some(stats),
+        Err(e) => {
+            tracing::warn!(
+                "monitoring: ignoring corrupt snapshot {}: {}; starting fresh",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Write a snapshot via a temporary file so readers never see a partial one.
+fn save_snapshot(path: &Path, stats: &Mutex<MonitoringStats>, counters: &trace::TraceCounters) {
+    let json = match serde_json::to_vec(&merged(stats, counters)) {
+        Ok(j) => j,
+        Err(e) => {
+            tracing::warn!("monitoring: cannot serialize snapshot: {}", e);
+            return;
+        }
+    };
+    let tmp = path.with_extension("tmp");
+    let written = std::fs::write(&tmp, json).and_then(|_| std::fs::rename(&tmp, path));
+    if let Err(e) = written {
+        tracing::warn!("monitoring: cannot write {}: {}", path.display(), e);
+    }
+}
+
+impl MonitoringBlock {
+    /// Write the Prometheus text export to `out` metric family by metric
+    /// family, without building the whole body first; each family's
+    /// `# HELP` and `# TYPE` lines precede its series. Transports that can
+    /// stream a response body can serve `/_metrics` through this directly.
+    pub fn write_prometheus<W: Write + ?Sized>(&self, out: &mut W) -> io::Result<()> {
+        let (total_requests, error_count) = {
+            let stats = merged(&self.stats, &self.counters);
+            (stats.total_requests, stats.error_count)
+        };
+        writeln!(
+            out,
+            "# HELP wafer_uptime_seconds Seconds since the monitoring block started."
+        )?;
+        writeln!(out, "# TYPE wafer_uptime_seconds gauge")?;
+        writeln!(
+            out,
+            "wafer_uptime_seconds {}",
+            self.start_time.elapsed().as_secs()
+        )?;
+        writeln!(
+            out,
+            "# HELP wafer_requests_total Requests seen by the monitoring block."
+        )?;
+        writeln!(out, "# TYPE wafer_requests_total counter")?;
+        writeln!(out, "wafer_requests_total {}", total_requests)?;
+        writeln!(
+            out,
+            "# HELP wafer_errors_total Requests that resulted in an error."
+        )?;
+        writeln!(out, "# TYPE wafer_errors_total counter")?;
+        writeln!(out, "wafer_errors_total {}", error_count)?;
+
+        let by_block = self.counters.errors().snapshot();
+        if !by_block.is_empty() {
+            writeln!(
+                out,
+                "# HELP wafer_block_error_results_total Error results by the block that produced them."
+            )?;
+            writeln!(out, "# TYPE wafer_block_error_results_total counter")?;
+            for (block, count) in &by_block {
+                writeln!(
+                    out,
+                    "wafer_block_error_results_total{{block=\"{}\"}} {}",
+                    instrument::escape_label(block),
+                    count
+                )?;
+            }
+        }
+        writeln!(
+            out,
+            "# HELP wafer_outcomes_total Requests rejected by gatekeeping blocks, by outcome code."
+        )?;
+        writeln!(out, "# TYPE wafer_outcomes_total counter")?;
+        for (code, count) in self.counters.outcomes().snapshot() {
+            writeln!(out, "wafer_outcomes_total{{code=\"{}\"}} {}", code, count)?;
         }
+        let cache = web::cache_stats().snapshot();
+        for (name, help, value) in [
+            (
+                "wafer_web_cache_hits_total",
+                "Conditional requests answered with 304 Not Modified.",
+                cache.hits,
+            ),
+            (
+                "wafer_web_cache_misses_total",
+                "Conditional-capable responses that sent the body.",
+                cache.misses,
+            ),
+            (
+                "wafer_web_cache_bytes_saved_total",
+                "Body bytes not sent thanks to 304 responses.",
+                cache.bytes_saved,
+            ),
+        ] {
+            writeln!(out, "# HELP {} {}", name, help)?;
+            writeln!(out, "# TYPE {} counter", name)?;
+            writeln!(out, "{} {}", name, value)?;
+        }
+        instrument::registry().write_prometheus(out)
     }
+
+    /// The Prometheus export as a response body. Bodies are complete byte
+    /// buffers, so the export is written into one sized from the previous
+    /// scrape rather than grown piecemeal.
+    fn render_prometheus(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(self.metrics_size.load(Ordering::Relaxed));
+        let _ = self.write_prometheus(&mut body);
+        self.metrics_size.store(body.len(), Ordering::Relaxed);
+        body
+    }
+}
+
+/// WebBlock's revalidation counters for `/_stats`.
+fn web_cache_json() -> serde_json::Value {
+    let cache = web::cache_stats().snapshot();
+    serde_json::json!({
+        "hits": cache.hits,
+        "misses": cache.misses,
+        "bytes_saved": cache.bytes_saved,
+        "hit_rate": cache.hit_rate(),
+    })
+}
+
+/// Shape the stats body from `?fields=a,b` (projection) and `?pretty=true`.
+fn stats_respond(msg: &Message, mut body: serde_json::Value) -> Result_ {
+    let fields = msg.query("fields");
+    if !fields.is_empty() {
+        let wanted: Vec<&str> = fields
+            .split(',')
+            .map(|f| f.trim())
+            .filter(|f| !f.is_empty())
+            .collect();
+        if let Some(obj) = body.as_object_mut() {
+            obj.retain(|k, _| wanted.contains(&k.as_str()));
+        }
+    }
+
+    let pretty = matches!(msg.query("pretty"), "true" | "1");
+    if !pretty {
+        return json_respond(msg.clone(), 200, &body);
+    }
+    let mut text = serde_json::to_string_pretty(&body).unwrap_or_default();
+    text.push('\n');
+    respond(msg.clone(), 200, text.into_bytes(), "application/json")
 }
 
 impl Block for MonitoringBlock {
@@ -40,31 +310,51 @@ impl Block for MonitoringBlock {
             summary: "Request metrics and monitoring".to_string(),
             instance_mode: InstanceMode::Singleton,
             allowed_modes: Vec::new(),
-            admin_ui: None,
+            admin_ui: crate::admin::ui(admin_descriptor()),
         }
     }
 
-    fn handle(&self, _ctx: &dyn Context, msg: &mut Message) -> Result_ {
-        let path = msg.path().to_string();
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let path = path::request_path(msg);
+        let endpoint = |p: &str| Mount::new(p, false, true).match_path(&path).is_some();
 
         // If this is a stats request, return the stats
-        if path == "/_stats" || path == "/_monitoring" {
-            let stats = self.stats.lock();
-            let uptime = self.start_time.elapsed().as_secs();
-            return json_respond(
-                msg.clone(),
-                200,
-                &serde_json::json!({
-                    "uptime_seconds": uptime,
+        if endpoint("/_stats") || endpoint("/_monitoring") {
+            let body = {
+                let stats = merged(&self.stats, &self.counters);
+                serde_json::json!({
+                    "uptime_seconds": self.start_time.elapsed().as_secs(),
                     "total_requests": stats.total_requests,
                     "error_count": stats.error_count,
                     "status_counts": stats.status_counts,
                     "top_paths": stats.path_counts,
-                }),
+                    "errors_by_block": self.counters.errors().snapshot(),
+                    "outcomes": self.counters.outcomes().snapshot(),
+                    "web_cache": web_cache_json(),
+                })
+            };
+            return stats_respond(msg, body);
+        }
+
+        // Prometheus text export, including per-block series when instrumented
+        if endpoint("/_metrics") {
+            if !self.metrics_allowed(ctx, msg) {
+                return CoreError::NotFound("Not found".to_string()).respond(msg);
+            }
+            return respond(
+                msg.clone(),
+                200,
+                self.render_prometheus(),
+                "text/plain; version=0.0.4; charset=utf-8",
             );
         }
 
-        // Track the request
+        if path::is_skipped(ctx, msg) {
+            return msg.clone().cont();
+        }
+
+        // Track the request, and have downstream results counted here
+        msg.set_meta(meta::TRACE_COUNTED_BY, &self.counters_id.to_string());
         {
             let mut stats = self.stats.lock();
             stats.total_requests += 1;
@@ -76,13 +366,152 @@ impl Block for MonitoringBlock {
 
     fn lifecycle(
         &self,
-        _ctx: &dyn Context,
-        _event: LifecycleEvent,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
     ) -> std::result::Result<(), WaferError> {
+        let persist_path = match ctx
+            .config_get("monitoring_persist_path")
+            .filter(|s| !s.is_empty())
+        {
+            Some(p) => Path::new(p).to_path_buf(),
+            None => return Ok(()),
+        };
+        match event.event_type {
+            LifecycleType::Start => {
+                if let Some(restored) = load_snapshot(&persist_path) {
+                    *self.stats.lock() = restored;
+                }
+                let every = Duration::from_secs(
+                    ctx.config_get("monitoring_persist_interval_secs")
+                        .and_then(|s| s.parse::<u64>().ok())
+                        .unwrap_or(60)
+                        .max(1),
+                );
+                let stats = self.stats.clone();
+                let counters = self.counters.clone();
+                self.tasks.spawn_interval("persist", every, move || {
+                    save_snapshot(&persist_path, &stats, &counters);
+                });
+            }
+            LifecycleType::Stop => {
+                self.tasks.stop(tasks::DEFAULT_DRAIN);
+                save_snapshot(&persist_path, &self.stats, &self.counters);
+            }
+            _ => {}
+        }
         Ok(())
     }
 }
 
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "metrics_token",
+            FieldKind::String,
+            "",
+            "Bearer token required to read /_metrics",
+        )
+        .field(
+            "metrics_allow_ips",
+            FieldKind::List,
+            "",
+            "Client CIDRs allowed to read /_metrics",
+        )
+        .field(
+            "monitoring_persist_path",
+            FieldKind::String,
+            "",
+            "File the counters are saved to and restored from",
+        )
+        .field(
+            "monitoring_persist_interval_secs",
+            FieldKind::Integer,
+            "60",
+            "How often the counters are saved",
+        )
+        .skip_paths()
+        .status(
+            StatusDescriptor::new()
+                .endpoint("/_stats", "json")
+                .endpoint("/_metrics", "prometheus"),
+        )
+}
+
 pub fn register(w: &mut Wafer) {
-    w.register_block("@wafer/monitoring", Arc::new(MonitoringBlock::new()));
+    register_as(w, "@wafer/monitoring");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(MonitoringBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const TOKEN: &str = "scrape-secret";
+    const SCRAPER_IP: &str = "10.0.0.7";
+
+    fn scrape(ctx: &MockContext, bearer: Option<&str>, ip: &str) -> u16 {
+        let mut req = MockRequest::get("/_metrics").remote_addr(ip);
+        if let Some(token) = bearer {
+            req = req.header("Authorization", &format!("Bearer {}", token));
+        }
+        let mut msg = req.build();
+        SimulatedResponse::from_result(&MonitoringBlock::new().handle(ctx, &mut msg)).status
+    }
+
+    #[test]
+    fn metrics_are_open_without_protection() {
+        assert_eq!(scrape(&MockContext::new(), None, "203.0.113.9"), 200);
+    }
+
+    #[test]
+    fn metrics_token_only() {
+        let ctx = MockContext::new().with_config("metrics_token", TOKEN);
+        assert_eq!(scrape(&ctx, Some(TOKEN), "203.0.113.9"), 200);
+        assert_eq!(scrape(&ctx, Some("wrong"), "203.0.113.9"), 404);
+        assert_eq!(scrape(&ctx, None, SCRAPER_IP), 404);
+    }
+
+    #[test]
+    fn metrics_allow_ips_only() {
+        let ctx = MockContext::new().with_config("metrics_allow_ips", "10.0.0.0/24");
+        assert_eq!(scrape(&ctx, None, SCRAPER_IP), 200);
+        assert_eq!(scrape(&ctx, None, "203.0.113.9"), 404);
+        // A bearer token means nothing when none is configured
+        assert_eq!(scrape(&ctx, Some(""), "203.0.113.9"), 404);
+    }
+
+    #[test]
+    fn metrics_token_or_ip_either_passes() {
+        let ctx = MockContext::new()
+            .with_config("metrics_token", TOKEN)
+            .with_config("metrics_allow_ips", "10.0.0.0/24");
+        assert_eq!(scrape(&ctx, Some(TOKEN), SCRAPER_IP), 200);
+        assert_eq!(scrape(&ctx, Some(TOKEN), "203.0.113.9"), 200);
+        assert_eq!(scrape(&ctx, Some("wrong"), SCRAPER_IP), 200);
+        assert_eq!(scrape(&ctx, None, SCRAPER_IP), 200);
+        assert_eq!(scrape(&ctx, Some("wrong"), "203.0.113.9"), 404);
+        assert_eq!(scrape(&ctx, None, "203.0.113.9"), 404);
+    }
+
+    #[test]
+    fn forwarded_for_counts_only_behind_a_trusted_proxy() {
+        let ctx = MockContext::new().with_config("metrics_allow_ips", "10.0.0.0/24");
+        let forwarded = |trusted: bool| {
+            let mut req = MockRequest::get("/_metrics")
+                .remote_addr("192.0.2.1")
+                .header("X-Forwarded-For", SCRAPER_IP);
+            if trusted {
+                req = req.meta(meta::TRUST_PROXY, "true");
+            }
+            let mut msg = req.build();
+            SimulatedResponse::from_result(&MonitoringBlock::new().handle(&ctx, &mut msg)).status
+        };
+        assert_eq!(forwarded(false), 404);
+        assert_eq!(forwarded(true), 200);
+    }
 }
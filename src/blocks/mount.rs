@@ -0,0 +1,266 @@
+use std::sync::Arc;
+use wafer_run::*;
+
+use crate::meta;
+use crate::path;
+
+/// Mount claims a path subtree for a handler block.
+///
+/// Node config: `prefix` (e.g. "/docs"), `strip` (default true: the sub-path
+/// is reported relative to the prefix) and `exact` (default false: only the
+/// prefix itself matches, not paths beneath it). Matching is segment-aware on
+/// the normalized path, so `/docs` claims `/docs/` and `/docs/a` but not
+/// `/docsx`. An empty prefix claims everything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    prefix: String,
+    strip: bool,
+    exact: bool,
+}
+
+impl Mount {
+    pub fn new(prefix: &str, strip: bool, exact: bool) -> Self {
+        let prefix = path::normalize(prefix, false);
+        Self {
+            prefix: if prefix == "/" { String::new() } else { prefix },
+            strip,
+            exact,
+        }
+    }
+
+    /// Read `prefix`, `strip` and `exact` from node config.
+    pub fn from_config(ctx: &dyn Context) -> Self {
+        let flag = |key: &str, default: bool| {
+            ctx.config_get(key)
+                .map(|s| s == "true" || s == "1")
+                .unwrap_or(default)
+        };
+        Self::new(
+            ctx.config_get("prefix").unwrap_or(""),
+            flag("strip", true),
+            flag("exact", false),
+        )
+    }
+
+    /// The normalized prefix ("" for the root).
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The sub-path for `msg` when the mount claims it, from the decoded
+    /// request path (see `path::request_path`).
+    pub fn matches(&self, msg: &Message) -> Option<String> {
+        self.match_path(&path::request_path_of(msg))
+    }
+
+    /// Like `matches`, for an already decoded path.
+    pub fn match_path(&self, raw: &str) -> Option<String> {
+        let p = path::normalize(raw, false);
+        let claimed = if self.exact {
+            p == self.prefix || (self.prefix.is_empty() && p == "/")
+        } else {
+            path::has_prefix(&p, &self.prefix)
+        };
+        if !claimed {
+            return None;
+        }
+        if !self.strip {
+            return Some(p);
+        }
+        let rest = &p[self.prefix.len()..];
+        Some(if rest.is_empty() {
+            "/".to_string()
+        } else {
+            rest.to_string()
+        })
+    }
+}
+
+/// MountedBlock runs the inner block only for requests its mount claims and
+/// continues the chain for everything else, so several handler blocks can
+/// share one chain, each serving its own subtree. The sub-path and prefix are
+/// passed on in `mount.path` and `mount.prefix` meta.
+///
+/// With `MountedBlock::from_config`, the mount is read from node config on
+/// every request instead of fixed at construction.
+pub struct MountedBlock<B: Block + ?Sized> {
+    inner: Arc<B>,
+    mount: Option<Mount>,
+}
+
+impl<B: Block + ?Sized> MountedBlock<B> {
+    pub fn new(inner: Arc<B>, mount: Mount) -> Self {
+        Self {
+            inner,
+            mount: Some(mount),
+        }
+    }
+
+    pub fn from_config(inner: Arc<B>) -> Self {
+        Self { inner, mount: None }
+    }
+}
+
+impl<B: Block + ?Sized> Block for MountedBlock<B> {
+    fn info(&self) -> BlockInfo {
+        self.inner.info()
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let mount = match &self.mount {
+            Some(m) => m.clone(),
+            None => Mount::from_config(ctx),
+        };
+        let sub_path = match mount.matches(msg) {
+            Some(p) => p,
+            None => return msg.clone().cont(),
+        };
+        msg.set_meta(meta::MOUNT_PATH, &sub_path);
+        msg.set_meta(meta::MOUNT_PREFIX, mount.prefix());
+        self.inner.handle(ctx, msg)
+    }
+
+    fn lifecycle(
+        &self,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        self.inner.lifecycle(ctx, event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use serde_json::json;
+
+    /// Answers 200 with the mount meta it was given.
+    struct Claimed;
+
+    impl Block for Claimed {
+        fn info(&self) -> BlockInfo {
+            BlockInfo {
+                name: "@test/claimed".to_string(),
+                version: "0.1.0".to_string(),
+                interface: "handler@v1".to_string(),
+                summary: "Test handler".to_string(),
+                instance_mode: InstanceMode::Singleton,
+                allowed_modes: Vec::new(),
+                admin_ui: None,
+            }
+        }
+
+        fn handle(&self, _ctx: &dyn Context, msg: &mut Message) -> Result_ {
+            let body = json!({
+                "path": msg.get_meta(meta::MOUNT_PATH),
+                "prefix": msg.get_meta(meta::MOUNT_PREFIX),
+            });
+            json_respond(msg.clone(), 200, &body)
+        }
+
+        fn lifecycle(
+            &self,
+            _ctx: &dyn Context,
+            _event: LifecycleEvent,
+        ) -> std::result::Result<(), WaferError> {
+            Ok(())
+        }
+    }
+
+    fn matched(mount: &Mount, paths: &[(&str, Option<&str>)]) {
+        for (path, expected) in paths {
+            assert_eq!(
+                mount.match_path(path).as_deref(),
+                *expected,
+                "{} under {:?}",
+                path,
+                mount
+            );
+        }
+    }
+
+    #[test]
+    fn prefixes_claim_whole_segments_and_strip_them() {
+        let mount = Mount::new("/docs/", true, false);
+        assert_eq!(mount.prefix(), "/docs");
+        matched(
+            &mount,
+            &[
+                ("/docs", Some("/")),
+                ("/docs/", Some("/")),
+                ("/docs//guide/", Some("/guide")),
+                ("/docs/api/v1", Some("/api/v1")),
+                ("/docsx", None),
+                ("/", None),
+            ],
+        );
+        let nested = Mount::new("/docs/api", true, false);
+        matched(
+            &nested,
+            &[("/docs/api/v1", Some("/v1")), ("/docs/guide", None)],
+        );
+        let unstripped = Mount::new("/docs", false, false);
+        matched(&unstripped, &[("/docs/a/", Some("/docs/a"))]);
+    }
+
+    #[test]
+    fn exact_mounts_claim_only_the_prefix() {
+        let mount = Mount::new("/health", true, true);
+        matched(
+            &mount,
+            &[
+                ("/health", Some("/")),
+                ("/health/", Some("/")),
+                ("/health/db", None),
+            ],
+        );
+    }
+
+    #[test]
+    fn root_mounts_claim_everything() {
+        for prefix in ["", "/"] {
+            let mount = Mount::new(prefix, true, false);
+            assert_eq!(mount.prefix(), "");
+            matched(&mount, &[("/", Some("/")), ("/a/b", Some("/a/b"))]);
+        }
+        let exact = Mount::new("/", true, true);
+        matched(&exact, &[("/", Some("/")), ("/a", None)]);
+    }
+
+    #[test]
+    fn mounted_blocks_continue_for_other_paths() {
+        let block = MountedBlock::new(Arc::new(Claimed), Mount::new("/docs", true, false));
+        let ctx = MockContext::new();
+
+        let mut msg = MockRequest::get("/docs/guide").build();
+        let resp = SimulatedResponse::from_result(&block.handle(&ctx, &mut msg));
+        assert_status(&resp, 200, None);
+        assert_eq!(
+            resp.json().unwrap(),
+            json!({"path": "/guide", "prefix": "/docs"})
+        );
+
+        let mut msg = MockRequest::get("/docsx").build();
+        let result = block.handle(&ctx, &mut msg);
+        assert!(matches!(result.action, Action::Continue));
+        assert_eq!(msg.get_meta(meta::MOUNT_PATH), "");
+    }
+
+    #[test]
+    fn config_mounts_are_read_per_request() {
+        let block = MountedBlock::from_config(Arc::new(Claimed));
+        let ctx = MockContext::new()
+            .with_config("prefix", "/api")
+            .with_config("strip", "false");
+        let mut msg = MockRequest::get("/api/items").build();
+        let resp = SimulatedResponse::from_result(&block.handle(&ctx, &mut msg));
+        assert_eq!(resp.json().unwrap()["path"], "/api/items");
+
+        let mut msg = MockRequest::get("/other").build();
+        assert!(matches!(
+            block.handle(&ctx, &mut msg).action,
+            Action::Continue
+        ));
+    }
+}
@@ -0,0 +1,870 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use wafer_run::*;
+
+use super::auth::{constant_time_eq, CookieAttributes, TokenBinding, AUTH_COOKIE};
+use super::hooks;
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::errors::{self, CoreError};
+use crate::http::{self, HttpClient};
+use crate::meta;
+use crate::path;
+
+/// Cookie binding a started login's state to the browser.
+const STATE_COOKIE: &str = "oauth_state";
+/// How long a started login may take before its state expires.
+const STATE_TTL: Duration = Duration::from_secs(600);
+/// How long used authorization codes are remembered to detect reuse.
+const USED_CODE_TTL: Duration = Duration::from_secs(600);
+/// Upper bound on in-flight logins and remembered codes.
+const MAX_PENDING: usize = 10_000;
+
+/// OAuthBlock implements "Sign in with ..." via the OAuth2 authorization code
+/// flow with PKCE.
+///
+/// Routes (relative to `oauth_prefix`, default `/auth/oauth`):
+/// - `GET /:provider/start` redirects to the provider's authorization URL
+/// - `GET /:provider/callback` validates state, exchanges the code, upserts the
+///   user in `auth_users`, issues a JWT, sets the auth cookie and redirects
+///
+/// Configure via node config:
+/// {"providers": "{\"google\": {\"client_id\": \"...\", \"client_secret\": \"...\"}}",
+///  "redirect_base": "https://app.example.com", "success_redirect": "/"}
+///
+/// Providers named `google` or `github` start from built-in presets, so only
+/// the client credentials are required. The token exchange needs an HTTP
+/// client: enable the `http-client` feature or supply one via `with_http_client`.
+///
+/// Sign-in requires an email the provider vouches for: a missing or false
+/// `verified_field` claim (or an unverified primary email on providers with
+/// `emails_url`) is refused, so an account is only ever linked by a verified
+/// email. Custom providers without `emails_url` must set `verified_field`.
+///
+/// With `auth_bind_tokens` (and `fingerprint_components`) set as on the auth
+/// node, issued JWTs carry the signing-in client's `fp` claim (see
+/// `auth::TokenBinding`).
+pub struct OAuthBlock {
+    http: Option<Arc<dyn HttpClient>>,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+    used_codes: Mutex<HashMap<String, Instant>>,
+}
+
+struct PendingLogin {
+    provider: String,
+    verifier: String,
+    created: Instant,
+}
+
+/// Provider endpoints, credentials, and claim mapping.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct ProviderConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub auth_url: String,
+    pub token_url: String,
+    pub userinfo_url: String,
+    /// Optional endpoint listing the user's emails (GitHub).
+    pub emails_url: String,
+    pub scopes: Vec<String>,
+    /// Claim holding the provider's stable user id.
+    pub id_field: String,
+    /// Claim holding whether the email is verified; required unless
+    /// `emails_url` is set.
+    pub verified_field: String,
+}
+
+impl ProviderConfig {
+    /// Built-in preset for a well-known provider.
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "google" => Some(Self {
+                auth_url: "https://accounts.google.com/o/oauth2/v2/auth".to_string(),
+                token_url: "https://oauth2.googleapis.com/token".to_string(),
+                userinfo_url: "https://openidconnect.googleapis.com/v1/userinfo".to_string(),
+                scopes: vec!["openid".to_string(), "email".to_string(), "profile".to_string()],
+                id_field: "sub".to_string(),
+                verified_field: "email_verified".to_string(),
+                ..Default::default()
+            }),
+            "github" => Some(Self {
+                auth_url: "https://github.com/login/oauth/authorize".to_string(),
+                token_url: "https://github.com/login/oauth/access_token".to_string(),
+                userinfo_url: "https://api.github.com/user".to_string(),
+                emails_url: "https://api.github.com/user/emails".to_string(),
+                scopes: vec!["read:user".to_string(), "user:email".to_string()],
+                id_field: "id".to_string(),
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Fill unset fields from `base`.
+    fn merged_over(self, base: Self) -> Self {
+        fn pick(v: String, base: String) -> String {
+            if v.is_empty() {
+                base
+            } else {
+                v
+            }
+        }
+        Self {
+            client_id: pick(self.client_id, base.client_id),
+            client_secret: pick(self.client_secret, base.client_secret),
+            auth_url: pick(self.auth_url, base.auth_url),
+            token_url: pick(self.token_url, base.token_url),
+            userinfo_url: pick(self.userinfo_url, base.userinfo_url),
+            emails_url: pick(self.emails_url, base.emails_url),
+            scopes: if self.scopes.is_empty() {
+                base.scopes
+            } else {
+                self.scopes
+            },
+            id_field: pick(self.id_field, base.id_field),
+            verified_field: pick(self.verified_field, base.verified_field),
+        }
+    }
+
+    /// Why this configuration can't be used for sign-in, if it can't.
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.emails_url.is_empty() && self.verified_field.is_empty() {
+            return Err("verified_field is required without emails_url");
+        }
+        Ok(())
+    }
+}
+
+/// An email address the provider reports as verified.
+struct VerifiedEmail(String);
+
+impl OAuthBlock {
+    pub fn new() -> Self {
+        Self {
+            http: http::default_client(),
+            pending: Mutex::new(HashMap::new()),
+            used_codes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Use a custom HTTP client (e.g. pointing at a stub provider).
+    pub fn with_http_client(http: Arc<dyn HttpClient>) -> Self {
+        Self {
+            http: Some(http),
+            ..Self::new()
+        }
+    }
+
+    /// Resolve a provider's configuration, answering 404 for unknown
+    /// providers and 500 for unusable ones.
+    fn resolve(ctx: &dyn Context, name: &str) -> Result<ProviderConfig, CoreError> {
+        let cfg = Self::provider(ctx, name)
+            .ok_or_else(|| CoreError::NotFound("Unknown OAuth provider".to_string()))?;
+        if let Err(problem) = cfg.validate() {
+            tracing::warn!("oauth: provider '{}' misconfigured: {}", name, problem);
+            return Err(CoreError::custom(
+                500,
+                "oauth_misconfigured",
+                "OAuth provider is misconfigured",
+            ));
+        }
+        Ok(cfg)
+    }
+
+    /// Resolve a provider's configuration from node config and presets.
+    fn provider(ctx: &dyn Context, name: &str) -> Option<ProviderConfig> {
+        let configured: HashMap<String, ProviderConfig> = ctx
+            .config_get("providers")
+            .and_then(|s| match serde_json::from_str(s) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    tracing::warn!("oauth: invalid providers config: {}", e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        let cfg = configured.get(name).cloned()?;
+        let cfg = match ProviderConfig::preset(name) {
+            Some(preset) => cfg.merged_over(preset),
+            None => cfg,
+        };
+        if cfg.client_id.is_empty() || cfg.auth_url.is_empty() || cfg.token_url.is_empty() {
+            return None;
+        }
+        Some(cfg)
+    }
+
+    fn callback_url(ctx: &dyn Context, prefix: &str, provider: &str) -> String {
+        let base = ctx.config_get("redirect_base").unwrap_or("");
+        format!(
+            "{}{}/{}/callback",
+            base.trim_end_matches('/'),
+            prefix,
+            provider
+        )
+    }
+
+    fn start(&self, ctx: &dyn Context, msg: &mut Message, prefix: &str, provider: &str) -> Result_ {
+        let cfg = match Self::resolve(ctx, provider) {
+            Ok(c) => c,
+            Err(e) => return e.respond(msg),
+        };
+
+        let (state, verifier) = match (random_token(ctx), random_token(ctx)) {
+            (Some(s), Some(v)) => (s, v),
+            _ => return oauth_error(msg, 500, "oauth_unavailable", "Crypto service unavailable"),
+        };
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+        {
+            let mut pending = self.pending.lock();
+            let now = Instant::now();
+            pending.retain(|_, p| now.duration_since(p.created) < STATE_TTL);
+            if pending.len() >= MAX_PENDING {
+                return oauth_error(msg, 503, "oauth_busy", "Too many pending logins");
+            }
+            pending.insert(
+                state.clone(),
+                PendingLogin {
+                    provider: provider.to_string(),
+                    verifier,
+                    created: now,
+                },
+            );
+        }
+
+        let redirect_uri = Self::callback_url(ctx, prefix, provider);
+        let scope = cfg.scopes.join(" ");
+        let params = [
+            ("response_type", "code"),
+            ("client_id", cfg.client_id.as_str()),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("scope", scope.as_str()),
+            ("state", state.as_str()),
+            ("code_challenge", challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ];
+        let separator = if cfg.auth_url.contains('?') { '&' } else { '?' };
+        let location = format!("{}{}{}", cfg.auth_url, separator, encode_query(&params));
+
+        let mut m = msg.clone();
+        meta::set_resp_header(&mut m, "Location", &location);
+        // Bind the state to this browser so a login can't be completed from another one
+        meta::set_resp_header(
+            &mut m,
+            "Set-Cookie",
+            &Self::state_cookie(ctx, prefix).set(STATE_COOKIE, &state, Some(STATE_TTL.as_secs())),
+        );
+        respond(m, 302, Vec::new(), "")
+    }
+
+    /// Attributes of the state cookie, scoped to the OAuth routes.
+    fn state_cookie(ctx: &dyn Context, prefix: &str) -> CookieAttributes {
+        CookieAttributes {
+            path: prefix.to_string(),
+            http_only: true,
+            // The provider redirects back cross-site, so Strict would drop the cookie
+            same_site: "Lax".to_string(),
+            ..CookieAttributes::from_config(ctx)
+        }
+    }
+
+    /// Handle the provider's redirect back. The state is single-use, so its
+    /// cookie is cleared, except on success: a response carries one
+    /// `Set-Cookie`, which then sets the auth cookie, and the spent state
+    /// cookie is left to expire.
+    fn callback(&self, ctx: &dyn Context, msg: &mut Message, prefix: &str, provider: &str) -> Result_ {
+        let mut result = self.complete_login(ctx, msg, prefix, provider);
+        if hooks::result_header(&result, "Set-Cookie").is_empty() {
+            let clear = Self::state_cookie(ctx, prefix).clear(STATE_COOKIE);
+            hooks::result_set_header(&mut result, "Set-Cookie", &clear);
+        }
+        result
+    }
+
+    fn complete_login(&self, ctx: &dyn Context, msg: &mut Message, prefix: &str, provider: &str) -> Result_ {
+        let cfg = match Self::resolve(ctx, provider) {
+            Ok(c) => c,
+            Err(e) => return e.respond(msg),
+        };
+
+        if !msg.query("error").is_empty() {
+            return oauth_error(msg, 400, "oauth_denied", "Sign-in was cancelled or denied");
+        }
+
+        // Validate state: present, bound to this browser, unexpired, for this provider
+        let state = msg.query("state").to_string();
+        let cookie_state = msg.cookie(STATE_COOKIE).to_string();
+        if state.is_empty() || !constant_time_eq(&state, &cookie_state) {
+            return oauth_error(msg, 400, "invalid_state", "Invalid sign-in state");
+        }
+        let pending = match self.pending.lock().remove(&state) {
+            Some(p) if p.provider == provider && p.created.elapsed() < STATE_TTL => p,
+            _ => return oauth_error(msg, 400, "invalid_state", "Invalid sign-in state"),
+        };
+
+        let code = msg.query("code").to_string();
+        if code.is_empty() {
+            return oauth_error(msg, 400, "invalid_request", "Missing authorization code");
+        }
+        if !self.mark_code_used(&code) {
+            return oauth_error(msg, 400, "code_reused", "Authorization code already used");
+        }
+
+        let http = match &self.http {
+            Some(h) => h,
+            None => {
+                return oauth_error(msg, 500, "oauth_unavailable", "OAuth HTTP client not enabled")
+            }
+        };
+
+        // Exchange the code for an access token
+        let redirect_uri = Self::callback_url(ctx, prefix, provider);
+        let token = http.post_form(
+            &cfg.token_url,
+            &[
+                ("grant_type", "authorization_code"),
+                ("code", &code),
+                ("redirect_uri", &redirect_uri),
+                ("client_id", &cfg.client_id),
+                ("client_secret", &cfg.client_secret),
+                ("code_verifier", &pending.verifier),
+            ],
+        );
+        let access_token = match token
+            .as_ref()
+            .ok()
+            .and_then(|t| t.get("access_token"))
+            .and_then(|v| v.as_str())
+        {
+            Some(t) => t.to_string(),
+            None => {
+                tracing::warn!("oauth: token exchange with '{}' failed: {:?}", provider, token.err());
+                return oauth_error(msg, 502, "oauth_exchange_failed", "Sign-in failed");
+            }
+        };
+
+        // Fetch the user's identity
+        let profile = match http.get_json(&cfg.userinfo_url, &access_token) {
+            Ok(p) => p,
+            Err(e) => {
+                tracing::warn!("oauth: userinfo from '{}' failed: {}", provider, e);
+                return oauth_error(msg, 502, "oauth_exchange_failed", "Sign-in failed");
+            }
+        };
+        let email = match Self::verified_email(http.as_ref(), &cfg, &profile, &access_token) {
+            Some((email, true)) => VerifiedEmail(email),
+            Some((_, false)) => {
+                return oauth_error(msg, 403, "email_unverified", "Email address is not verified")
+            }
+            None => return oauth_error(msg, 403, "email_missing", "No email address available"),
+        };
+        let provider_id = match profile.get(&cfg.id_field) {
+            Some(serde_json::Value::String(s)) => s.clone(),
+            Some(v) if !v.is_null() => v.to_string(),
+            _ => String::new(),
+        };
+
+        // Upsert the user and issue our own token
+        let services = match ctx.services() {
+            Some(s) => s,
+            None => return oauth_error(msg, 500, "oauth_unavailable", "Auth services unavailable"),
+        };
+        let (db, crypto) = match (&services.database, &services.crypto) {
+            (Some(db), Some(c)) => (db, c),
+            _ => return oauth_error(msg, 500, "oauth_unavailable", "Auth services unavailable"),
+        };
+
+        let user_id = match upsert_user(db.as_ref(), &email, provider, &provider_id) {
+            Some(id) => id,
+            None => return oauth_error(msg, 500, "oauth_unavailable", "Failed to store user"),
+        };
+
+        let ttl_secs = ctx
+            .config_get("token_ttl_seconds")
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(86400);
+        let mut claims = HashMap::new();
+        claims.insert("user_id".to_string(), serde_json::Value::String(user_id));
+        claims.insert("email".to_string(), serde_json::Value::String(email.0));
+        claims.insert(
+            "auth_provider".to_string(),
+            serde_json::Value::String(provider.to_string()),
+        );
+        // Bind the token to this browser when auth checks bindings
+        if let Some(binding) = TokenBinding::from_config(ctx) {
+            binding.embed(msg, &mut claims);
+        }
+        let jwt = match crypto.sign(claims, Duration::from_secs(ttl_secs)) {
+            Ok(t) => t,
+            Err(_) => return oauth_error(msg, 500, "oauth_unavailable", "Failed to issue token"),
+        };
+
+        let success = ctx.config_get("success_redirect").unwrap_or("/");
+        let mut m = msg.clone();
+        meta::set_resp_header(&mut m, "Location", success);
+        meta::set_resp_header(
+            &mut m,
+            "Set-Cookie",
+            &CookieAttributes::from_config(ctx).set(AUTH_COOKIE, &jwt, Some(ttl_secs)),
+        );
+        respond(m, 302, Vec::new(), "")
+    }
+
+    /// Extract the email and its verification status from the profile, or the
+    /// provider's email list when it has one.
+    fn verified_email(
+        http: &dyn HttpClient,
+        cfg: &ProviderConfig,
+        profile: &serde_json::Value,
+        access_token: &str,
+    ) -> Option<(String, bool)> {
+        if !cfg.emails_url.is_empty() {
+            let emails = http.get_json(&cfg.emails_url, access_token).ok()?;
+            let primary = emails
+                .as_array()?
+                .iter()
+                .find(|e| e.get("primary").and_then(|v| v.as_bool()) == Some(true))?;
+            let email = primary.get("email")?.as_str()?.to_string();
+            let verified = primary.get("verified").and_then(|v| v.as_bool()) == Some(true);
+            return Some((email, verified));
+        }
+
+        let email = profile.get("email")?.as_str()?.to_string();
+        if email.is_empty() {
+            return None;
+        }
+        // A missing claim counts as unverified
+        let verified = match profile.get(&cfg.verified_field) {
+            Some(serde_json::Value::Bool(b)) => *b,
+            Some(serde_json::Value::String(s)) => s == "true",
+            _ => false,
+        };
+        Some((email, verified))
+    }
+
+    /// Remember the code; returns false if it was already used.
+    fn mark_code_used(&self, code: &str) -> bool {
+        let mut used = self.used_codes.lock();
+        let now = Instant::now();
+        if used.len() >= MAX_PENDING {
+            used.retain(|_, t| now.duration_since(*t) < USED_CODE_TTL);
+        }
+        match used.get(code) {
+            Some(t) if now.duration_since(*t) < USED_CODE_TTL => false,
+            _ => {
+                used.insert(code.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+/// Find the user by (verified) email or create them. Returns the user id, or
+/// `None` if the lookup fails, so an outage never creates a duplicate account.
+fn upsert_user(
+    db: &dyn wafer_run::services::database::DatabaseService,
+    email: &VerifiedEmail,
+    provider: &str,
+    provider_id: &str,
+) -> Option<String> {
+    let email = email.0.as_str();
+    let opts = wafer_run::services::database::ListOptions {
+        filters: vec![wafer_run::services::database::Filter {
+            field: "email".to_string(),
+            operator: wafer_run::services::database::FilterOp::Equal,
+            value: serde_json::Value::String(email.to_string()),
+        }],
+        limit: 1,
+        ..Default::default()
+    };
+
+    let result = db.list("auth_users", &opts).ok()?;
+    if let Some(existing) = result.records.first() {
+        return Some(existing.id.clone());
+    }
+
+    let mut data = HashMap::new();
+    data.insert("email".to_string(), serde_json::Value::String(email.to_string()));
+    data.insert(
+        "auth_provider".to_string(),
+        serde_json::Value::String(provider.to_string()),
+    );
+    data.insert(
+        "auth_provider_id".to_string(),
+        serde_json::Value::String(provider_id.to_string()),
+    );
+    data.insert(
+        "created_at".to_string(),
+        serde_json::Value::String(chrono::Utc::now().to_rfc3339()),
+    );
+    db.create("auth_users", data).ok().map(|r| r.id)
+}
+
+/// 32 random bytes from the crypto service, base64url-encoded.
+fn random_token(ctx: &dyn Context) -> Option<String> {
+    let services = ctx.services()?;
+    let crypto = services.crypto.as_ref()?;
+    let bytes = crypto.random_bytes(32).ok()?;
+    Some(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+fn encode_query(params: &[(&str, &str)]) -> String {
+    params
+        .iter()
+        .map(|(k, v)| format!("{}={}", urlencoding::encode(k), urlencoding::encode(v)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn oauth_error(msg: &mut Message, status: u16, code: &str, message: &str) -> Result_ {
+    CoreError::custom(status, code, message).respond(msg)
+}
+
+impl Block for OAuthBlock {
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "@wafer/oauth".to_string(),
+            version: "0.1.0".to_string(),
+            interface: "handler@v1".to_string(),
+            summary: "OAuth2 social login (authorization code + PKCE)".to_string(),
+            instance_mode: InstanceMode::Singleton,
+            allowed_modes: vec![InstanceMode::PerNode],
+            admin_ui: crate::admin::ui(admin_descriptor()),
+        }
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let prefix = path::normalize(
+            ctx.config_get("oauth_prefix").unwrap_or("/auth/oauth"),
+            false,
+        );
+        let prefix = prefix.trim_end_matches('/').to_string();
+
+        let req_path = path::request_path(msg);
+        if !path::has_prefix(&req_path, &prefix) {
+            return CoreError::NotFound("Not found".to_string()).respond(msg);
+        }
+        let rest = req_path[prefix.len()..].trim_start_matches('/').to_string();
+
+        let mut parts = rest.splitn(2, '/');
+        let provider = parts.next().unwrap_or("");
+        let step = parts.next().unwrap_or("");
+        if provider.is_empty() {
+            return CoreError::NotFound("Not found".to_string()).respond(msg);
+        }
+
+        // The provider redirects back with a GET; refuse anything else
+        let is_get = meta::http_method(msg) == meta::Method::Get;
+        match step {
+            "start" | "callback" if !is_get => errors::method_not_allowed(msg, &["GET"]),
+            "start" => self.start(ctx, msg, &prefix, provider),
+            "callback" => self.callback(ctx, msg, &prefix, provider),
+            _ => CoreError::NotFound("Not found".to_string()).respond(msg),
+        }
+    }
+
+    fn lifecycle(
+        &self,
+        _ctx: &dyn Context,
+        _event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        Ok(())
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "providers",
+            FieldKind::Json,
+            "",
+            "OAuth providers and their client settings",
+        )
+        .field(
+            "redirect_base",
+            FieldKind::String,
+            "",
+            "Public base URL callbacks are built on",
+        )
+        .field(
+            "success_redirect",
+            FieldKind::String,
+            "/",
+            "Where users land after signing in",
+        )
+        .field(
+            "token_ttl_seconds",
+            FieldKind::Integer,
+            "86400",
+            "Lifetime of issued tokens",
+        )
+        .field(
+            "oauth_prefix",
+            FieldKind::String,
+            "/auth/oauth",
+            "Path prefix of the login and callback routes",
+        )
+        .field(
+            "auth_bind_tokens",
+            FieldKind::one_of(&["false", "true", "strict", "soft"]),
+            "false",
+            "Embed the client fingerprint as the fp claim; match auth's setting",
+        )
+        .field(
+            "fingerprint_components",
+            FieldKind::List,
+            "ip, ua",
+            "What the fingerprint covers; match auth's setting",
+        )
+}
+
+pub fn register(w: &mut Wafer) {
+    register_as(w, "@wafer/oauth");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(OAuthBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use serde_json::json;
+
+    /// A provider that answers every exchange with a fixed profile.
+    struct StubProvider {
+        profile: serde_json::Value,
+    }
+
+    impl HttpClient for StubProvider {
+        fn post_form(
+            &self,
+            url: &str,
+            _form: &[(&str, &str)],
+        ) -> Result<serde_json::Value, String> {
+            assert_eq!(url, "https://idp.test/token");
+            Ok(json!({"access_token": "at-1"}))
+        }
+
+        fn post_json(
+            &self,
+            _url: &str,
+            _body: &serde_json::Value,
+            _timeout: Duration,
+        ) -> Result<serde_json::Value, String> {
+            Err("unexpected".to_string())
+        }
+
+        fn get_json(&self, url: &str, bearer: &str) -> Result<serde_json::Value, String> {
+            assert_eq!((url, bearer), ("https://idp.test/userinfo", "at-1"));
+            Ok(self.profile.clone())
+        }
+    }
+
+    fn ctx(verified_field: &str) -> MockContext {
+        let providers = json!({"stub": {
+            "client_id": "cid",
+            "auth_url": "https://idp.test/auth",
+            "token_url": "https://idp.test/token",
+            "userinfo_url": "https://idp.test/userinfo",
+            "id_field": "sub",
+            "verified_field": verified_field,
+        }});
+        MockContext::new()
+            .with_config("providers", &providers.to_string())
+            .with_database(MockDatabase::new())
+            .with_crypto()
+    }
+
+    fn stub(profile: serde_json::Value) -> OAuthBlock {
+        OAuthBlock::with_http_client(Arc::new(StubProvider { profile }))
+    }
+
+    fn verified() -> serde_json::Value {
+        json!({"sub": "u1", "email": "a@example.com", "email_verified": true})
+    }
+
+    /// Run the start step, returning the state its cookie carries.
+    fn start(block: &OAuthBlock, ctx: &MockContext) -> String {
+        let mut start = MockRequest::get("/auth/oauth/stub/start").build();
+        let started = SimulatedResponse::from_result(&block.handle(ctx, &mut start));
+        assert_eq!(started.status, 302);
+        let cookie = started.header("Set-Cookie").expect("state cookie");
+        let state = cookie["oauth_state=".len()..].split(';').next().unwrap();
+        state.to_string()
+    }
+
+    /// Come back through the callback with `state` in the query and cookie.
+    fn come_back(
+        block: &OAuthBlock,
+        ctx: &MockContext,
+        state: &str,
+        cookie: &str,
+        code: &str,
+    ) -> SimulatedResponse {
+        let mut callback = MockRequest::get("/auth/oauth/stub/callback")
+            .query("state", state)
+            .query("code", code)
+            .cookie("oauth_state", cookie)
+            .build();
+        SimulatedResponse::from_result(&block.handle(ctx, &mut callback))
+    }
+
+    /// Run the start step and come back through the callback.
+    fn sign_in(ctx: &MockContext, profile: serde_json::Value) -> SimulatedResponse {
+        let block = stub(profile);
+        let state = start(&block, ctx);
+        come_back(&block, ctx, &state, &state, "code-1")
+    }
+
+    const CLEARED_STATE: &str =
+        "oauth_state=; Path=/auth/oauth; Max-Age=0; HttpOnly; Secure; SameSite=Lax";
+
+    #[test]
+    fn verified_email_signs_in() {
+        let ctx = ctx("email_verified");
+        let resp = sign_in(
+            &ctx,
+            json!({"sub": "u1", "email": "a@example.com", "email_verified": true}),
+        );
+        assert_eq!(resp.status, 302);
+        assert_eq!(resp.header("Location"), Some("/"));
+        assert!(resp.header("Set-Cookie").unwrap().starts_with(AUTH_COOKIE));
+        assert_eq!(ctx.database().unwrap().rows("auth_users").len(), 1);
+    }
+
+    #[test]
+    fn verified_email_links_existing_account() {
+        let ctx = ctx("email_verified");
+        ctx.database()
+            .unwrap()
+            .insert("auth_users", json!({"email": "a@example.com"}));
+        let resp = sign_in(
+            &ctx,
+            json!({"sub": "u1", "email": "a@example.com", "email_verified": "true"}),
+        );
+        assert_eq!(resp.status, 302);
+        assert_eq!(ctx.database().unwrap().rows("auth_users").len(), 1);
+    }
+
+    #[test]
+    fn unverified_email_is_refused() {
+        let ctx = ctx("email_verified");
+        ctx.database()
+            .unwrap()
+            .insert("auth_users", json!({"email": "a@example.com"}));
+        let resp = sign_in(
+            &ctx,
+            json!({"sub": "u1", "email": "a@example.com", "email_verified": false}),
+        );
+        assert_status(&resp, 403, Some("email_unverified"));
+        assert!(resp.header("Set-Cookie").is_none());
+    }
+
+    #[test]
+    fn missing_verified_claim_is_refused() {
+        let ctx = ctx("email_verified");
+        let resp = sign_in(&ctx, json!({"sub": "u1", "email": "a@example.com"}));
+        assert_status(&resp, 403, Some("email_unverified"));
+        assert!(ctx.database().unwrap().rows("auth_users").is_empty());
+    }
+
+    #[test]
+    fn missing_email_is_refused() {
+        let ctx = ctx("email_verified");
+        let resp = sign_in(&ctx, json!({"sub": "u1", "email_verified": true}));
+        assert_status(&resp, 403, Some("email_missing"));
+    }
+
+    #[test]
+    fn empty_verified_field_is_a_config_error() {
+        let ctx = ctx("");
+        let block = OAuthBlock::new();
+        let mut msg = MockRequest::get("/auth/oauth/stub/start").build();
+        let resp = SimulatedResponse::from_result(&block.handle(&ctx, &mut msg));
+        assert_status(&resp, 500, Some("oauth_misconfigured"));
+    }
+
+    #[test]
+    fn callback_accepts_only_get() {
+        let ctx = ctx("email_verified");
+        let block = OAuthBlock::new();
+        for path in ["/auth/oauth/stub/callback", "/auth/oauth/stub/start"] {
+            let mut msg = MockRequest::post(path).build();
+            let resp = SimulatedResponse::from_result(&block.handle(&ctx, &mut msg));
+            assert_eq!(resp.status, 405);
+            assert_eq!(resp.header("Allow"), Some("GET"));
+        }
+    }
+
+    #[test]
+    fn state_must_match_the_cookie() {
+        let ctx = ctx("email_verified");
+        let block = stub(verified());
+        let state = start(&block, &ctx);
+        for cookie in ["forged", ""] {
+            let resp = come_back(&block, &ctx, &state, cookie, "code-1");
+            assert_status(&resp, 400, Some("invalid_state"));
+            assert_header(&resp, "Set-Cookie", CLEARED_STATE);
+        }
+        // A mismatch leaves the login pending for its own browser
+        let resp = come_back(&block, &ctx, &state, &state, "code-1");
+        assert_eq!(resp.status, 302);
+        assert!(resp.header("Set-Cookie").unwrap().starts_with(AUTH_COOKIE));
+    }
+
+    #[test]
+    fn expired_and_foreign_states_are_refused() {
+        let ctx = ctx("email_verified");
+        let block = stub(verified());
+        let login = |provider: &str, created: Instant| PendingLogin {
+            provider: provider.to_string(),
+            verifier: "v".to_string(),
+            created,
+        };
+        block
+            .pending
+            .lock()
+            .insert("other".to_string(), login("github", Instant::now()));
+        let resp = come_back(&block, &ctx, "other", "other", "code-1");
+        assert_status(&resp, 400, Some("invalid_state"));
+        assert_header(&resp, "Set-Cookie", CLEARED_STATE);
+
+        // Only expressible once the monotonic clock has run past the TTL
+        if let Some(created) = Instant::now().checked_sub(STATE_TTL + Duration::from_secs(1)) {
+            block
+                .pending
+                .lock()
+                .insert("old".to_string(), login("stub", created));
+            let resp = come_back(&block, &ctx, "old", "old", "code-2");
+            assert_status(&resp, 400, Some("invalid_state"));
+        }
+    }
+
+    #[test]
+    fn states_and_codes_are_single_use() {
+        let ctx = ctx("email_verified");
+        let block = stub(verified());
+        let state = start(&block, &ctx);
+        let resp = come_back(&block, &ctx, &state, &state, "code-1");
+        assert_eq!(resp.status, 302);
+
+        let replay = come_back(&block, &ctx, &state, &state, "code-1");
+        assert_status(&replay, 400, Some("invalid_state"));
+        assert_header(&replay, "Set-Cookie", CLEARED_STATE);
+
+        let fresh = start(&block, &ctx);
+        let reused = come_back(&block, &ctx, &fresh, &fresh, "code-1");
+        assert_status(&reused, 400, Some("code_reused"));
+        assert_header(&reused, "Set-Cookie", CLEARED_STATE);
+    }
+}
@@ -0,0 +1,556 @@
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, Utc};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use wafer_run::services::database::{DatabaseService, Filter, FilterOp, ListOptions};
+use wafer_run::*;
+
+use super::tasks::{self, TaskSet};
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::clock::{self, Clock};
+use crate::errors::CoreError;
+use crate::meta;
+use crate::path;
+
+/// Table holding one usage row per key and period.
+const USAGE_TABLE: &str = "quota_usage";
+
+/// Upper bound on usage entries kept in memory.
+const MAX_KEYS: usize = 100_000;
+
+/// QuotaBlock enforces cumulative request quotas per authenticated user and
+/// calendar period, for plans sold as "N requests per month".
+///
+/// Config: `quota_period` (`month`, the default, or `day`; periods roll over
+/// at UTC midnight), `soft_limit` (requests past it are allowed but carry
+/// `X-Quota-Warning` and `quota.warning` meta) and `hard_limit` (requests at
+/// it are rejected with 429 `quota_exceeded`, naming the reset time). A limit
+/// of 0 disables it. Requests without `auth.user_id` are not counted.
+///
+/// Usage is counted in memory and written behind to the `quota_usage` table
+/// by a background task every `quota_flush_interval_secs` (default 10), and
+/// at Stop, so no request waits on the write. A key's
+/// count is restored from the database the first time it is seen, so totals
+/// survive restarts. Each flush adds this instance's increments to the stored
+/// count and adopts the result, so instances sharing the database converge
+/// on an approximate shared total. Without a database, counting is per
+/// instance and lost on restart.
+///
+/// Periods are read from the block's `Clock` (see `with_clock`).
+pub struct QuotaBlock {
+    ledger: Arc<Ledger>,
+    tasks: TaskSet,
+    clock: Arc<dyn Clock>,
+}
+
+/// In-memory usage by key, shared with the flush task.
+#[derive(Default)]
+struct Ledger {
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+/// Usage of one key in one period.
+struct Usage {
+    period: String,
+    /// Best known total, including `pending`.
+    count: u64,
+    /// Increments not yet written to the database.
+    pending: u64,
+}
+
+impl QuotaBlock {
+    pub fn new() -> Self {
+        Self {
+            ledger: Arc::new(Ledger::default()),
+            tasks: TaskSet::new("@wafer/quota"),
+            clock: clock::system(),
+        }
+    }
+
+    /// Read periods from `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Count one request for `key`, returning the total including it, or
+    /// `Err(total)` without counting when the hard limit is reached.
+    fn hit(&self, ctx: &dyn Context, key: &str, period: &str, hard_limit: u64) -> Result<u64, u64> {
+        let known = self
+            .ledger
+            .usage
+            .lock()
+            .get(key)
+            .is_some_and(|u| u.period == period);
+        // Restore from the database outside the lock
+        let restored = if known {
+            None
+        } else {
+            Some(database(ctx).map_or(0, |db| load_count(db, key, period).map_or(0, |(_, c)| c)))
+        };
+
+        let mut usage = self.ledger.usage.lock();
+        if usage.len() >= MAX_KEYS && !usage.contains_key(key) {
+            usage.retain(|_, u| u.pending > 0 && u.period == period);
+        }
+        let entry = usage.entry(key.to_string()).or_insert_with(|| Usage {
+            period: period.to_string(),
+            count: 0,
+            pending: 0,
+        });
+        if let Some(restored) = restored {
+            if entry.period != period {
+                // Rolled over; unflushed increments of the old period are dropped
+                entry.period = period.to_string();
+                entry.count = 0;
+                entry.pending = 0;
+            }
+            entry.count = entry.count.max(restored);
+        }
+        if hard_limit > 0 && entry.count >= hard_limit {
+            return Err(entry.count);
+        }
+        entry.count += 1;
+        entry.pending += 1;
+        Ok(entry.count)
+    }
+}
+
+impl Ledger {
+    /// Add pending increments to the stored counts and adopt the totals.
+    fn flush(&self, db: &dyn DatabaseService) {
+        let dirty: Vec<(String, String, u64)> = {
+            let mut usage = self.usage.lock();
+            usage
+                .iter_mut()
+                .filter(|(_, u)| u.pending > 0)
+                .map(|(k, u)| {
+                    let pending = std::mem::take(&mut u.pending);
+                    (k.clone(), u.period.clone(), pending)
+                })
+                .collect()
+        };
+        for (key, period, pending) in dirty {
+            let stored = load_count(db, &key, &period).and_then(|(id, count)| {
+                let total = count + pending;
+                store_count(db, id.as_deref(), &key, &period, total).map(|_| total)
+            });
+            let mut usage = self.usage.lock();
+            let entry = match usage.get_mut(&key) {
+                Some(u) if u.period == period => u,
+                _ => continue,
+            };
+            match stored {
+                // Adopt the shared total plus whatever arrived during the flush
+                Ok(total) => entry.count = entry.count.max(total + entry.pending),
+                Err(e) => {
+                    tracing::warn!("quota: failed to persist usage for {}: {}", key, e);
+                    entry.pending += pending;
+                }
+            }
+        }
+    }
+}
+
+fn database(ctx: &dyn Context) -> Option<&dyn DatabaseService> {
+    ctx.services()
+        .and_then(|s| s.database.as_ref())
+        .map(|db| db.as_ref())
+}
+
+/// Stored usage row for `key` in `period`: its id (if any) and count.
+fn load_count(
+    db: &dyn DatabaseService,
+    key: &str,
+    period: &str,
+) -> Result<(Option<String>, u64), String> {
+    let opts = ListOptions {
+        filters: vec![
+            Filter {
+                field: "key".to_string(),
+                operator: FilterOp::Equal,
+                value: serde_json::Value::String(key.to_string()),
+            },
+            Filter {
+                field: "period".to_string(),
+                operator: FilterOp::Equal,
+                value: serde_json::Value::String(period.to_string()),
+            },
+        ],
+        limit: 1,
+        ..Default::default()
+    };
+    let result = db.list(USAGE_TABLE, &opts).map_err(|e| e.to_string())?;
+    Ok(match result.records.first() {
+        Some(rec) => (
+            Some(rec.id.clone()),
+            rec.data.get("count").and_then(|v| v.as_u64()).unwrap_or(0),
+        ),
+        None => (None, 0),
+    })
+}
+
+fn store_count(
+    db: &dyn DatabaseService,
+    id: Option<&str>,
+    key: &str,
+    period: &str,
+    count: u64,
+) -> Result<(), String> {
+    let mut data = HashMap::new();
+    data.insert(
+        "key".to_string(),
+        serde_json::Value::String(key.to_string()),
+    );
+    data.insert(
+        "period".to_string(),
+        serde_json::Value::String(period.to_string()),
+    );
+    data.insert("count".to_string(), serde_json::Value::from(count));
+    data.insert(
+        "updated_at".to_string(),
+        serde_json::Value::String(Utc::now().to_rfc3339()),
+    );
+    match id {
+        Some(id) => db.update(USAGE_TABLE, id, data).map(|_| ()),
+        None => db.create(USAGE_TABLE, data).map(|_| ()),
+    }
+    .map_err(|e| e.to_string())
+}
+
+/// Calendar period a quota is counted over, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    Day,
+    Month,
+}
+
+impl QuotaPeriod {
+    pub fn from_config(ctx: &dyn Context) -> Self {
+        match ctx.config_get("quota_period").unwrap_or("month") {
+            "day" => Self::Day,
+            _ => Self::Month,
+        }
+    }
+
+    /// The period containing `now` (e.g. "2026-10" or "2026-10-14") and the
+    /// instant the next one starts.
+    pub fn bounds(&self, now: DateTime<Utc>) -> (String, DateTime<Utc>) {
+        let today = now.date_naive();
+        let (id, next) = match self {
+            Self::Day => (
+                today.format("%Y-%m-%d").to_string(),
+                today.succ_opt().unwrap_or(today),
+            ),
+            Self::Month => {
+                let (y, m) = if today.month() == 12 {
+                    (today.year() + 1, 1)
+                } else {
+                    (today.year(), today.month() + 1)
+                };
+                (
+                    today.format("%Y-%m").to_string(),
+                    NaiveDate::from_ymd_opt(y, m, 1).unwrap_or(today),
+                )
+            }
+        };
+        (id, next.and_time(NaiveTime::MIN).and_utc())
+    }
+}
+
+impl Block for QuotaBlock {
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "@wafer/quota".to_string(),
+            version: "0.1.0".to_string(),
+            interface: "middleware@v1".to_string(),
+            summary: "Per-user request quotas per calendar period".to_string(),
+            instance_mode: InstanceMode::Singleton,
+            allowed_modes: Vec::new(),
+            admin_ui: crate::admin::ui(admin_descriptor()),
+        }
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        if path::is_skipped(ctx, msg) {
+            return msg.clone().cont();
+        }
+        let user_id = match meta::user_id(msg) {
+            Some(u) => u.to_string(),
+            None => return msg.clone().cont(),
+        };
+        let limit = |key: &str| {
+            ctx.config_get(key)
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(0)
+        };
+        let soft_limit = limit("soft_limit");
+        let hard_limit = limit("hard_limit");
+        if soft_limit == 0 && hard_limit == 0 {
+            return msg.clone().cont();
+        }
+
+        let now = self.clock.now_utc();
+        let (period, resets_at) = QuotaPeriod::from_config(ctx).bounds(now);
+        let used = match self.hit(ctx, &user_id, &period, hard_limit) {
+            Ok(used) => used,
+            Err(_) => {
+                let mut m = msg.clone();
+                let retry = (resets_at - now).num_seconds().max(1);
+                meta::set_resp_header(&mut m, "Retry-After", &retry.to_string());
+                return CoreError::custom(
+                    429,
+                    "quota_exceeded",
+                    &format!(
+                        "Request quota exceeded; it resets at {}",
+                        resets_at.to_rfc3339()
+                    ),
+                )
+                .respond_with_details(
+                    &m,
+                    serde_json::json!({
+                        "limit": hard_limit,
+                        "period": period,
+                        "resets_at": resets_at.to_rfc3339(),
+                    }),
+                );
+            }
+        };
+
+        msg.set_meta(meta::QUOTA_USED, &used.to_string());
+        if soft_limit > 0 && used > soft_limit {
+            meta::set_flag(msg, meta::QUOTA_WARNING, true);
+            meta::set_resp_header(
+                msg,
+                "X-Quota-Warning",
+                &format!(
+                    "{} of {} requests used; resets at {}",
+                    used,
+                    soft_limit,
+                    resets_at.to_rfc3339()
+                ),
+            );
+        }
+
+        msg.clone().cont()
+    }
+
+    fn lifecycle(
+        &self,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        match event.event_type {
+            LifecycleType::Start => {
+                let db = match ctx.services().and_then(|s| s.database.clone()) {
+                    Some(db) => db,
+                    None => {
+                        tracing::warn!(
+                            "quota: no database service; usage is per instance and not persisted"
+                        );
+                        return Ok(());
+                    }
+                };
+                let interval = Duration::from_secs(
+                    ctx.config_get("quota_flush_interval_secs")
+                        .and_then(|s| s.parse::<u64>().ok())
+                        .unwrap_or(10),
+                );
+                let ledger = self.ledger.clone();
+                self.tasks.spawn_interval("flush", interval, move || {
+                    ledger.flush(db.as_ref());
+                });
+            }
+            LifecycleType::Stop => {
+                self.tasks.stop(tasks::DEFAULT_DRAIN);
+                if let Some(db) = database(ctx) {
+                    self.ledger.flush(db);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "quota_period",
+            FieldKind::one_of(&["month", "day"]),
+            "month",
+            "Period usage is counted over",
+        )
+        .field(
+            "soft_limit",
+            FieldKind::Integer,
+            "",
+            "Requests per period after which responses carry a warning",
+        )
+        .field(
+            "hard_limit",
+            FieldKind::Integer,
+            "",
+            "Requests per period after which requests are rejected",
+        )
+        .field(
+            "quota_flush_interval_secs",
+            FieldKind::Integer,
+            "10",
+            "How often usage is written to the database",
+        )
+        .skip_paths()
+}
+
+pub fn register(w: &mut Wafer) {
+    register_as(w, "@wafer/quota");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(QuotaBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn lifecycle(event_type: LifecycleType) -> LifecycleEvent {
+        LifecycleEvent {
+            event_type,
+            data: Vec::new(),
+        }
+    }
+
+    fn hit(block: &QuotaBlock, ctx: &MockContext) -> SimulatedResponse {
+        let mut msg = MockRequest::get("/api/items")
+            .meta(meta::AUTH_USER_ID, "u1")
+            .build();
+        SimulatedResponse::from_result(&block.handle(ctx, &mut msg))
+    }
+
+    fn stored(ctx: &MockContext) -> Option<u64> {
+        let rows = ctx.database().unwrap().rows(USAGE_TABLE);
+        rows.first()
+            .and_then(|r| r.data.get("count").and_then(|v| v.as_u64()))
+    }
+
+    #[test]
+    fn requests_never_wait_on_the_flush() {
+        let ctx = MockContext::new()
+            .with_database(MockDatabase::new())
+            .with_config("hard_limit", "10")
+            .with_config("quota_flush_interval_secs", "0");
+        let block = QuotaBlock::new();
+        for _ in 0..3 {
+            assert_status(&hit(&block, &ctx), 200, None);
+        }
+        assert_eq!(stored(&ctx), None);
+
+        block
+            .lifecycle(&ctx, lifecycle(LifecycleType::Stop))
+            .unwrap();
+        assert_eq!(stored(&ctx), Some(3));
+    }
+
+    #[test]
+    fn flush_task_writes_usage_behind() {
+        let ctx = MockContext::new()
+            .with_database(MockDatabase::new())
+            .with_config("hard_limit", "10")
+            .with_config("quota_flush_interval_secs", "0");
+        let block = QuotaBlock::new();
+        block
+            .lifecycle(&ctx, lifecycle(LifecycleType::Start))
+            .unwrap();
+        hit(&block, &ctx);
+        hit(&block, &ctx);
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while stored(&ctx) != Some(2) && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(stored(&ctx), Some(2));
+        block
+            .lifecycle(&ctx, lifecycle(LifecycleType::Stop))
+            .unwrap();
+        assert_eq!(block.tasks.running(), 0);
+    }
+
+    fn at(utc: &str) -> Arc<ManualClock> {
+        Arc::new(ManualClock::starting_at(utc.parse().unwrap()))
+    }
+
+    #[test]
+    fn usage_rolls_over_with_the_period() {
+        let clock = at("2026-01-31T23:59:00Z");
+        let block = QuotaBlock::new().with_clock(clock.clone());
+        let ctx = MockContext::new().with_config("hard_limit", "2");
+        assert_status(&hit(&block, &ctx), 200, None);
+        assert_status(&hit(&block, &ctx), 200, None);
+        assert_status(&hit(&block, &ctx), 429, Some("quota_exceeded"));
+
+        clock.advance(Duration::from_secs(120));
+        assert_status(&hit(&block, &ctx), 200, None);
+
+        let daily = MockContext::new()
+            .with_config("quota_period", "day")
+            .with_config("hard_limit", "1");
+        assert_status(&hit(&block, &daily), 200, None);
+        assert_status(&hit(&block, &daily), 429, Some("quota_exceeded"));
+        clock.advance(Duration::from_secs(86_400));
+        assert_status(&hit(&block, &daily), 200, None);
+    }
+
+    #[test]
+    fn soft_limits_warn_until_the_hard_limit_rejects() {
+        let block = QuotaBlock::new().with_clock(at("2026-10-14T12:00:00Z"));
+        let ctx = MockContext::new()
+            .with_config("soft_limit", "1")
+            .with_config("hard_limit", "3");
+        let resets = "2026-11-01T00:00:00+00:00";
+
+        assert_no_header(&hit(&block, &ctx), "X-Quota-Warning");
+        for used in 2..=3 {
+            let resp = hit(&block, &ctx);
+            assert_status(&resp, 200, None);
+            assert_header(
+                &resp,
+                "X-Quota-Warning",
+                &format!("{} of 1 requests used; resets at {}", used, resets),
+            );
+        }
+
+        let resp = hit(&block, &ctx);
+        assert_status(&resp, 429, Some("quota_exceeded"));
+        let details = &resp.json().unwrap()["error"]["details"];
+        assert_eq!(details["limit"], 3);
+        assert_eq!(details["period"], "2026-10");
+        assert_eq!(details["resets_at"], resets);
+        // Seconds until the first of the month
+        let retry = 18 * 86_400 - 12 * 3_600;
+        assert_header(&resp, "Retry-After", &retry.to_string());
+    }
+
+    #[test]
+    fn usage_survives_a_restart() {
+        let clock = at("2026-10-14T12:00:00Z");
+        let ctx = MockContext::new()
+            .with_database(MockDatabase::new())
+            .with_config("hard_limit", "3");
+
+        let first = QuotaBlock::new().with_clock(clock.clone());
+        hit(&first, &ctx);
+        hit(&first, &ctx);
+        first
+            .lifecycle(&ctx, lifecycle(LifecycleType::Stop))
+            .unwrap();
+
+        // A new instance restores the count the first one stored
+        let second = QuotaBlock::new().with_clock(clock);
+        assert_status(&hit(&second, &ctx), 200, None);
+        assert_status(&hit(&second, &ctx), 429, Some("quota_exceeded"));
+    }
+}
@@ -1,19 +1,227 @@
 use parking_lot::Mutex;
+use regex::Regex;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 use wafer_run::*;
 
+use super::readonly_guard;
+use super::tasks::{self, TaskSet};
+use crate::admin::{self, AdminDescriptor, FieldKind, StatusDescriptor};
+use crate::clock::{self, Clock};
+use crate::errors::{CoreError, Outcome};
+use crate::meta;
+use crate::net::{self, CidrList};
+use crate::path;
+use crate::window::{Lane, WindowKind, WindowedCounter};
+
+/// Upper bound on tracked client keys.
+const MAX_KEYS: usize = 100_000;
+
+/// Number of keys tracked in the per-key rejection counts.
+const MAX_REJECTED_KEYS: usize = 1000;
+
 /// RateLimitBlock provides per-IP rate limiting.
+///
+/// `GET /_ratelimit` returns the limiter's own counters (requests checked,
+/// requests rejected, and the most rejected keys), so a burst of 429s can be
+/// told apart from application errors. Like other status endpoints it needs
+/// `status_public` or a user with `status_role` (see `admin::status_allowed`).
+///
+/// Clients in `exempt_cidrs` (comma-separated CIDRs, e.g. for uptime checks)
+/// skip counting entirely.
+///
+/// Setting `write_max_requests` (and optionally `write_window_seconds`,
+/// defaulting to `window_seconds`) gives writes their own budget: requests
+/// whose action is create/update/delete (per `meta::resolved_action`, so by
+/// default POST, PUT, PATCH and DELETE) draw from it and carry
+/// `X-RateLimit-Write-Limit` and `X-RateLimit-Write-Remaining`, while all
+/// other requests draw from the `max_requests` budget. Without it, every
+/// request shares `max_requests`.
+///
+/// `tenant_limits` gives tenants their own budgets: a JSON object mapping a
+/// tenant id to `{"max": 100, "window": 60}` (`window` defaulting to
+/// `window_seconds`), e.g. `{"acme.example.com": {"max": 5000}}`. The tenant
+/// is the request's host (see `net::request_host`), or the value of
+/// `tenant_header` when set. Clients of a listed tenant are counted per
+/// tenant, so their requests to it never draw on another tenant's budget.
+/// Requests to tenants not listed share the client's `max_requests` and
+/// `window_seconds` bucket, whatever host they name. Writes keep
+/// `write_max_requests`.
+///
+/// `route_limits` gives endpoints their own budgets: a JSON list of rules
+/// with a path `prefix` or a regex `pattern`, a `max` and an optional
+/// `window`, e.g. `[{"prefix": "/api/search", "max": 30},
+/// {"pattern": "^/users/[^/]+/posts$", "max": 10, "window": 10}]`. A pattern
+/// must match the whole normalized path. A matching pattern beats any
+/// prefix, the first matching pattern wins among patterns and the longest
+/// prefix among prefixes. Each rule counts its clients separately and
+/// replaces the `max_requests` and tenant budgets of the requests it
+/// matches; writes keep `write_max_requests`. Rules match the decoded path
+/// (see `path::request_path`) and are compiled at lifecycle Start; an
+/// invalid list is logged and no rule applies.
+///
+/// With `count_readonly_rejections_extra: N`, every write ReadonlyGuardBlock
+/// rejected in read-only mode costs the client N more units, charged to the
+/// write budget (or the shared one) on its next request here, so clients
+/// hammering writes during an incident are limited early. The guard named by
+/// `readonly_guard` (default `@wafer/readonly-guard`) keeps rejections for up
+/// to `readonly_guard::REJECTION_MEMORY`; the limiter never sees the rejected
+/// request itself when the guard runs first, and counts it before the guard
+/// decides when it runs after, so the charge is always applied a request
+/// later.
+///
+/// Expired client windows are swept in the background every window
+/// (between lifecycle Start and Stop), so idle clients don't hold memory.
+///
+/// Windows are timed by the block's `Clock` (see `with_clock`).
 pub struct RateLimitBlock {
     max_requests: u32,
     window: Duration,
-    buckets: Mutex<HashMap<String, RateBucket>>,
+    counter: Arc<WindowedCounter>,
+    checked: AtomicU64,
+    rejected: AtomicU64,
+    rejected_by_key: Mutex<HashMap<String, u64>>,
+    exempt: Mutex<Option<(String, CidrList)>>,
+    /// Parsed `tenant_limits`, keyed by the raw config it was parsed from.
+    tenant_limits: Mutex<Option<(String, Arc<HashMap<String, TenantLimit>>)>>,
+    /// Compiled `route_limits`, keyed by the raw config it was compiled from.
+    route_limits: Mutex<Option<(String, Arc<RouteLimits>)>>,
+    tasks: TaskSet,
+    clock: Arc<dyn Clock>,
+}
+
+/// One tenant's entry in `tenant_limits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+pub struct TenantLimit {
+    pub max: u32,
+    /// Window in seconds; `window_seconds` when unset.
+    #[serde(default)]
+    pub window: Option<u64>,
+}
+
+impl TenantLimit {
+    /// Parse a `tenant_limits` JSON object; tenant ids are matched lowercase.
+    pub fn parse_map(json: &str) -> Result<HashMap<String, Self>, String> {
+        let raw: HashMap<String, Self> = serde_json::from_str(json)
+            .map_err(|e| format!("tenant_limits: invalid JSON: {}", e))?;
+        Ok(raw
+            .into_iter()
+            .map(|(tenant, limit)| (tenant.trim().to_ascii_lowercase(), limit))
+            .collect())
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct RouteLimitSpec {
+    prefix: Option<String>,
+    pattern: Option<String>,
+    max: u32,
+    #[serde(default)]
+    window: Option<u64>,
+}
+
+enum RouteMatcher {
+    Prefix(String),
+    /// The configured pattern and its whole-path compilation.
+    Pattern(String, Regex),
+}
+
+/// One compiled entry of `route_limits`.
+pub struct RouteLimit {
+    matcher: RouteMatcher,
+    pub max: u32,
+    /// Window in seconds; `window_seconds` when unset.
+    pub window: Option<u64>,
+}
+
+impl RouteLimit {
+    /// The prefix or pattern, which also scopes the rule's counts.
+    pub fn source(&self) -> &str {
+        match &self.matcher {
+            RouteMatcher::Prefix(p) => p,
+            RouteMatcher::Pattern(source, _) => source,
+        }
+    }
+}
+
+/// Compiled `route_limits` rules.
+#[derive(Default)]
+pub struct RouteLimits {
+    rules: Vec<RouteLimit>,
+}
+
+impl RouteLimits {
+    /// Compile a `route_limits` JSON list; errors name the offending rule index.
+    pub fn compile(json: &str) -> Result<Self, String> {
+        let specs: Vec<serde_json::Value> =
+            serde_json::from_str(json).map_err(|e| format!("route_limits: invalid JSON: {}", e))?;
+        let mut rules = Vec::with_capacity(specs.len());
+        for (i, raw) in specs.into_iter().enumerate() {
+            let spec: RouteLimitSpec =
+                serde_json::from_value(raw).map_err(|e| format!("route limit {}: {}", i, e))?;
+            let matcher = match (&spec.prefix, &spec.pattern) {
+                (Some(p), None) => {
+                    if !p.starts_with('/') {
+                        return Err(format!("route limit {}: prefix must start with '/'", i));
+                    }
+                    RouteMatcher::Prefix(path::normalize(p, false))
+                }
+                (None, Some(p)) => RouteMatcher::Pattern(
+                    p.clone(),
+                    path::whole_path_regex(p)
+                        .map_err(|e| format!("route limit {}: invalid pattern: {}", i, e))?,
+                ),
+                _ => {
+                    return Err(format!(
+                        "route limit {}: needs exactly one of prefix or pattern",
+                        i
+                    ))
+                }
+            };
+            rules.push(RouteLimit {
+                matcher,
+                max: spec.max,
+                window: spec.window,
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The rule for a (normalized) path: the first whole-path pattern match,
+    /// else the longest matching prefix.
+    pub fn select(&self, path: &str) -> Option<&RouteLimit> {
+        let pattern = self.rules.iter().find(|r| match &r.matcher {
+            RouteMatcher::Pattern(_, re) => re.is_match(path),
+            RouteMatcher::Prefix(_) => false,
+        });
+        pattern.or_else(|| {
+            self.rules
+                .iter()
+                .filter_map(|r| match &r.matcher {
+                    RouteMatcher::Prefix(p) if path::has_prefix(path, p) => Some((p.len(), r)),
+                    _ => None,
+                })
+                // Ties go to the earlier rule
+                .rev()
+                .max_by_key(|(len, _)| *len)
+                .map(|(_, r)| r)
+        })
+    }
 }
 
-struct RateBucket {
-    count: u32,
-    window_start: Instant,
+/// Snapshot of the limiter's counters.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct RateLimitStats {
+    pub total_checked: u64,
+    pub total_rejected: u64,
+    /// Most rejected keys, highest count first.
+    pub top_rejected: Vec<(String, u64)>,
 }
 
 impl RateLimitBlock {
@@ -21,9 +229,113 @@ impl RateLimitBlock {
         Self {
             max_requests: 1000,
             window: Duration::from_secs(60),
-            buckets: Mutex::new(HashMap::new()),
+            counter: Arc::new(WindowedCounter::new(WindowKind::Fixed, MAX_KEYS)),
+            checked: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            rejected_by_key: Mutex::new(HashMap::new()),
+            exempt: Mutex::new(None),
+            tenant_limits: Mutex::new(None),
+            route_limits: Mutex::new(None),
+            tasks: TaskSet::new("@wafer/rate-limit"),
+            clock: clock::system(),
         }
     }
+
+    /// Time windows with `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Whether `addr` is in the exempt ranges, re-parsing only when the config changes.
+    fn is_exempt(&self, raw: &str, addr: &str) -> bool {
+        let mut cached = self.exempt.lock();
+        if cached.as_ref().map(|(k, _)| k.as_str()) != Some(raw) {
+            *cached = Some((raw.to_string(), CidrList::parse(raw)));
+        }
+        cached
+            .as_ref()
+            .is_some_and(|(_, list)| list.contains_addr(addr))
+    }
+
+    /// The parsed `tenant_limits`, re-parsing only when the config changes.
+    /// An invalid map is logged and treated as empty.
+    fn tenant_limits(&self, raw: &str) -> Arc<HashMap<String, TenantLimit>> {
+        let mut cached = self.tenant_limits.lock();
+        if cached.as_ref().map(|(k, _)| k.as_str()) != Some(raw) {
+            let limits = TenantLimit::parse_map(raw).unwrap_or_else(|e| {
+                tracing::error!("rate-limit: {}", e);
+                HashMap::new()
+            });
+            *cached = Some((raw.to_string(), Arc::new(limits)));
+        }
+        cached.as_ref().expect("limits just set").1.clone()
+    }
+
+    /// The compiled `route_limits`, recompiling only when the config changes.
+    /// An invalid list is logged and treated as empty.
+    fn route_limits(&self, raw: &str) -> Arc<RouteLimits> {
+        let mut cached = self.route_limits.lock();
+        if cached.as_ref().map(|(k, _)| k.as_str()) != Some(raw) {
+            let rules = RouteLimits::compile(raw).unwrap_or_else(|e| {
+                tracing::error!("rate-limit: {}", e);
+                RouteLimits::default()
+            });
+            *cached = Some((raw.to_string(), Arc::new(rules)));
+        }
+        cached.as_ref().expect("rules just set").1.clone()
+    }
+
+    /// Current counters, with the `top_n` most rejected keys.
+    pub fn stats(&self, top_n: usize) -> RateLimitStats {
+        let mut top: Vec<(String, u64)> = self
+            .rejected_by_key
+            .lock()
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(top_n);
+        RateLimitStats {
+            total_checked: self.checked.load(Ordering::Relaxed),
+            total_rejected: self.rejected.load(Ordering::Relaxed),
+            top_rejected: top,
+        }
+    }
+
+    fn record_rejection(&self, key: &str) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        let mut by_key = self.rejected_by_key.lock();
+        if let Some(count) = by_key.get_mut(key) {
+            *count += 1;
+            return;
+        }
+        // Bounded: when full, replace the least rejected key
+        if by_key.len() >= MAX_REJECTED_KEYS {
+            if let Some(min_key) = by_key
+                .iter()
+                .min_by_key(|(_, v)| **v)
+                .map(|(k, _)| k.clone())
+            {
+                by_key.remove(&min_key);
+            }
+        }
+        by_key.insert(key.to_string(), 1);
+    }
+}
+
+/// Counter lane of the read budget (and of every request without a write budget).
+const READ_LANE: Lane = Lane::Primary;
+
+/// Counter lane of the `write_max_requests` budget.
+const WRITE_LANE: Lane = Lane::Secondary;
+
+fn is_write(ctx: &dyn Context, msg: &Message) -> bool {
+    meta::is_write_action(&meta::resolved_action(ctx, msg))
+}
+
+fn config_secs(ctx: &dyn Context, key: &str) -> Option<u64> {
+    ctx.config_get(key).and_then(|s| s.parse::<u64>().ok())
 }
 
 impl Block for RateLimitBlock {
@@ -35,87 +347,468 @@ impl Block for RateLimitBlock {
             summary: "Per-IP rate limiting".to_string(),
             instance_mode: InstanceMode::Singleton,
             allowed_modes: Vec::new(),
-            admin_ui: None,
+            admin_ui: crate::admin::ui(admin_descriptor()),
         }
     }
 
     fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
-        let max = ctx
+        if path::request_path(msg) == "/_ratelimit" {
+            if !admin::status_allowed(ctx, msg) {
+                return CoreError::NotFound("Not found".to_string()).respond(msg);
+            }
+            let top_n = ctx
+                .config_get("stats_top_n")
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(10);
+            let stats = self.stats(top_n);
+            return json_respond(
+                msg.clone(),
+                200,
+                &serde_json::json!({
+                    "total_checked": stats.total_checked,
+                    "total_rejected": stats.total_rejected,
+                    "top_rejected": stats
+                        .top_rejected
+                        .iter()
+                        .map(|(k, v)| serde_json::json!({ "key": k, "rejected": v }))
+                        .collect::<Vec<_>>(),
+                }),
+            );
+        }
+
+        if path::is_skipped(ctx, msg) {
+            return msg.clone().cont();
+        }
+
+        let mut max = ctx
             .config_get("max_requests")
             .and_then(|s| s.parse::<u32>().ok())
             .unwrap_or(self.max_requests);
 
-        let window_secs = ctx
-            .config_get("window_seconds")
-            .and_then(|s| s.parse::<u64>().ok())
-            .unwrap_or(self.window.as_secs());
-        let window = Duration::from_secs(window_secs);
+        let window_secs = config_secs(ctx, "window_seconds").unwrap_or(self.window.as_secs());
+        let mut window = Duration::from_secs(window_secs);
 
         let client_ip = msg.remote_addr().to_string();
         if client_ip.is_empty() {
-            return error(
-                msg.clone(),
-                400,
-                "bad_request",
-                "Client IP could not be determined",
-            );
+            return CoreError::BadRequest("Client IP could not be determined".to_string())
+                .respond(msg);
+        }
+
+        let exempt_cidrs = ctx.config_get("exempt_cidrs").unwrap_or("");
+        if !exempt_cidrs.is_empty() && self.is_exempt(exempt_cidrs, &client_ip) {
+            return msg.clone().cont();
         }
 
-        let mut buckets = self.buckets.lock();
-        let now = Instant::now();
+        self.checked.fetch_add(1, Ordering::Relaxed);
 
-        let bucket = buckets.entry(client_ip).or_insert(RateBucket {
-            count: 0,
-            window_start: now,
-        });
+        // Listed tenants count their clients separately. Unlisted ones share
+        // the client's default bucket, so rotating Host values buys nothing.
+        let mut key = client_ip.clone();
+        if let Some(raw) = ctx
+            .config_get("tenant_limits")
+            .filter(|s| !s.trim().is_empty())
+        {
+            let tenant = match ctx.config_get("tenant_header").filter(|h| !h.is_empty()) {
+                Some(header) => msg.header(header).trim().to_ascii_lowercase(),
+                None => net::request_host(msg),
+            };
+            if let Some(limit) = self.tenant_limits(raw).get(&tenant) {
+                max = limit.max;
+                window = Duration::from_secs(limit.window.unwrap_or(window_secs));
+                key = format!("{}|{}", tenant, client_ip);
+            }
+        }
 
-        // Reset window if expired
-        if now.duration_since(bucket.window_start) > window {
-            bucket.count = 0;
-            bucket.window_start = now;
+        if let Some(raw) = ctx
+            .config_get("route_limits")
+            .filter(|s| !s.trim().is_empty())
+        {
+            let rules = self.route_limits(raw);
+            if let Some(rule) = rules.select(&path::request_path(msg)) {
+                max = rule.max;
+                window = Duration::from_secs(rule.window.unwrap_or(window_secs));
+                key = format!("{}|{}", rule.source(), key);
+            }
         }
 
-        bucket.count += 1;
+        let write_max = ctx
+            .config_get("write_max_requests")
+            .and_then(|s| s.parse::<u32>().ok());
+        let write_window =
+            Duration::from_secs(config_secs(ctx, "write_window_seconds").unwrap_or(window_secs));
+        let (lane, headers) = match write_max {
+            Some(write_max) if is_write(ctx, msg) => {
+                max = write_max;
+                window = write_window;
+                (
+                    WRITE_LANE,
+                    ("X-RateLimit-Write-Limit", "X-RateLimit-Write-Remaining"),
+                )
+            }
+            _ => (READ_LANE, ("X-RateLimit-Limit", "X-RateLimit-Remaining")),
+        };
 
-        if bucket.count > max {
-            let remaining = window
-                .checked_sub(now.duration_since(bucket.window_start))
-                .unwrap_or(Duration::ZERO);
-            let retry_after = remaining.as_secs().to_string();
+        let now = self.clock.now_instant();
+        let extra = config_secs(ctx, "count_readonly_rejections_extra").unwrap_or(0);
+        if extra > 0 {
+            let guard = ctx
+                .config_get("readonly_guard")
+                .unwrap_or(readonly_guard::DEFAULT_NAME);
+            let rejected = readonly_guard::rejections(guard)
+                .map_or(0, |ledger| ledger.take_at(&client_ip, now));
+            if rejected > 0 {
+                let (penalty_lane, penalty_window) = match write_max {
+                    Some(_) => (WRITE_LANE, write_window),
+                    None => (READ_LANE, window),
+                };
+                self.counter.add_lane_at(
+                    &key,
+                    penalty_lane,
+                    penalty_window,
+                    rejected.saturating_mul(extra),
+                    now,
+                );
+            }
+        }
+
+        let hit = self.counter.hit_lane_at(&key, lane, window, now);
+        let count = u32::try_from(hit.count).unwrap_or(u32::MAX);
+
+        if count > max {
+            self.record_rejection(&key);
 
             let mut m = msg.clone();
-            m.set_meta("resp.header.Retry-After", &retry_after);
-            m.set_meta(
-                "resp.header.X-RateLimit-Limit",
-                &max.to_string(),
-            );
-            m.set_meta("resp.header.X-RateLimit-Remaining", "0");
+            meta::set_resp_header(&mut m, headers.0, &max.to_string());
+            meta::set_resp_header(&mut m, headers.1, "0");
+            Outcome::RateLimited.record(&mut m);
 
-            return error(m, 429, "rate_limited", "Too many requests");
+            return CoreError::RateLimited {
+                message: "Too many requests".to_string(),
+                retry_after: hit.reset_in.as_secs(),
+            }
+            .respond(&m);
         }
 
-        let remaining = max - bucket.count;
-        msg.set_meta(
-            "resp.header.X-RateLimit-Limit",
-            &max.to_string(),
-        );
-        msg.set_meta(
-            "resp.header.X-RateLimit-Remaining",
-            &remaining.to_string(),
-        );
+        let remaining = max - count;
+        meta::set_resp_header(msg, headers.0, &max.to_string());
+        meta::set_resp_header(msg, headers.1, &remaining.to_string());
 
         msg.clone().cont()
     }
 
     fn lifecycle(
         &self,
-        _ctx: &dyn Context,
-        _event: LifecycleEvent,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
     ) -> std::result::Result<(), WaferError> {
+        match event.event_type {
+            LifecycleType::Start => {
+                let window_secs =
+                    config_secs(ctx, "window_seconds").unwrap_or(self.window.as_secs());
+                let window = Duration::from_secs(window_secs.max(1));
+                // Keys expire once the longest of the budgets' windows has passed
+                let tenant_window = ctx
+                    .config_get("tenant_limits")
+                    .filter(|s| !s.trim().is_empty())
+                    .and_then(|raw| {
+                        self.tenant_limits(raw)
+                            .values()
+                            .filter_map(|l| l.window)
+                            .max()
+                    })
+                    .unwrap_or(0);
+                // Compiling the rules here logs an invalid list at startup
+                let route_window = ctx
+                    .config_get("route_limits")
+                    .filter(|s| !s.trim().is_empty())
+                    .and_then(|raw| {
+                        let rules = self.route_limits(raw);
+                        rules.rules.iter().filter_map(|r| r.window).max()
+                    })
+                    .unwrap_or(0);
+                let longest = Duration::from_secs(
+                    config_secs(ctx, "write_window_seconds")
+                        .filter(|_| ctx.config_get("write_max_requests").is_some())
+                        .unwrap_or(window_secs)
+                        .max(window_secs)
+                        .max(tenant_window)
+                        .max(route_window)
+                        .max(1),
+                );
+                let counter = self.counter.clone();
+                let clock = self.clock.clone();
+                self.tasks.spawn_interval("sweep", window, move || {
+                    counter.purge_expired_at(longest, clock.now_instant());
+                });
+            }
+            LifecycleType::Stop => self.tasks.stop(tasks::DEFAULT_DRAIN),
+            _ => {}
+        }
         Ok(())
     }
 }
 
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "max_requests",
+            FieldKind::Integer,
+            "1000",
+            "Requests allowed per client per window",
+        )
+        .field(
+            "window_seconds",
+            FieldKind::Integer,
+            "60",
+            "Length of the rate limit window",
+        )
+        .field(
+            "write_max_requests",
+            FieldKind::Integer,
+            "",
+            "Separate limit for writes; unset counts writes with reads",
+        )
+        .field(
+            "write_window_seconds",
+            FieldKind::Integer,
+            "",
+            "Window for the write limit; defaults to `window_seconds`",
+        )
+        .field(
+            "tenant_limits",
+            FieldKind::Json,
+            "",
+            "Per-tenant {max, window} budgets keyed by tenant id",
+        )
+        .field(
+            "tenant_header",
+            FieldKind::String,
+            "",
+            "Header naming the tenant; the Host when unset",
+        )
+        .field(
+            "route_limits",
+            FieldKind::Json,
+            "",
+            "Per-endpoint {prefix or pattern, max, window} budgets",
+        )
+        .field(
+            "count_readonly_rejections_extra",
+            FieldKind::Integer,
+            "0",
+            "Extra units charged per write rejected in read-only mode",
+        )
+        .field(
+            "readonly_guard",
+            FieldKind::String,
+            "@wafer/readonly-guard",
+            "Registered name of the guard whose rejections are charged",
+        )
+        .field(
+            "exempt_cidrs",
+            FieldKind::List,
+            "",
+            "Client CIDRs that are never limited",
+        )
+        .field(
+            "stats_top_n",
+            FieldKind::Integer,
+            "10",
+            "Busiest clients listed by the stats endpoint",
+        )
+        .field(
+            "method_actions",
+            FieldKind::List,
+            "",
+            "`METHOD=action` overrides of the method to action mapping",
+        )
+        .skip_paths()
+        .status_access()
+        .status(
+            StatusDescriptor::new()
+                .endpoint("/_ratelimit", "json")
+                .header("X-RateLimit-Remaining"),
+        )
+}
+
 pub fn register(w: &mut Wafer) {
-    w.register_block("@wafer/rate-limit", Arc::new(RateLimitBlock::new()));
+    register_as(w, "@wafer/rate-limit");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(RateLimitBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn stats(ctx: &MockContext, req: MockRequest) -> SimulatedResponse {
+        let mut msg = req.build();
+        SimulatedResponse::from_result(&RateLimitBlock::new().handle(ctx, &mut msg))
+    }
+
+    #[test]
+    fn stats_endpoint_is_hidden_by_default() {
+        let resp = stats(&MockContext::new(), MockRequest::get("/_ratelimit"));
+        assert_status(&resp, 404, Some("not_found"));
+
+        let req = MockRequest::get("/_ratelimit").meta(meta::AUTH_USER_ROLES, "editor");
+        assert_status(&stats(&MockContext::new(), req), 404, Some("not_found"));
+    }
+
+    #[test]
+    fn stats_endpoint_serves_admins() {
+        let req = MockRequest::get("/_ratelimit").meta(meta::AUTH_USER_ROLES, "editor,admin");
+        let resp = stats(&MockContext::new(), req);
+        assert_status(&resp, 200, None);
+        assert_eq!(resp.json().unwrap()["total_checked"], 0);
+
+        let ctx = MockContext::new().with_config("status_role", "ops");
+        let req = MockRequest::get("/_ratelimit").meta(meta::AUTH_USER_ROLES, "ops");
+        assert_status(&stats(&ctx, req), 200, None);
+    }
+
+    #[test]
+    fn stats_endpoint_can_be_made_public() {
+        let ctx = MockContext::new().with_config("status_public", "true");
+        assert_status(&stats(&ctx, MockRequest::get("/_ratelimit")), 200, None);
+    }
+
+    fn tenant_ctx() -> MockContext {
+        MockContext::new()
+            .with_config("max_requests", "2")
+            .with_config("tenant_header", "X-Tenant")
+            .with_config("tenant_limits", r#"{"Acme": {"max": 3}}"#)
+    }
+
+    fn hit(block: &RateLimitBlock, ctx: &MockContext, tenant: &str) -> SimulatedResponse {
+        let mut msg = MockRequest::get("/").header("X-Tenant", tenant).build();
+        SimulatedResponse::from_result(&block.handle(ctx, &mut msg))
+    }
+
+    #[test]
+    fn listed_tenants_get_their_own_budget() {
+        let block = RateLimitBlock::new();
+        let ctx = tenant_ctx();
+        for _ in 0..2 {
+            assert_status(&hit(&block, &ctx, "other"), 200, None);
+        }
+        assert_status(&hit(&block, &ctx, "other"), 429, Some("rate_limited"));
+
+        // The default bucket being spent leaves acme's untouched
+        for _ in 0..3 {
+            let resp = hit(&block, &ctx, "acme");
+            assert_status(&resp, 200, None);
+            assert_header(&resp, "X-RateLimit-Limit", "3");
+        }
+        assert_status(&hit(&block, &ctx, "acme"), 429, Some("rate_limited"));
+    }
+
+    #[test]
+    fn rotating_unlisted_tenants_share_the_default_bucket() {
+        let block = RateLimitBlock::new();
+        let ctx = tenant_ctx();
+        assert_status(&hit(&block, &ctx, "a.example"), 200, None);
+        assert_status(&hit(&block, &ctx, "b.example"), 200, None);
+        let resp = hit(&block, &ctx, "c.example");
+        assert_status(&resp, 429, Some("rate_limited"));
+        assert_eq!(
+            block.stats(10).top_rejected,
+            vec![("192.0.2.1".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn pattern_rules_limit_parameterized_paths() {
+        let block = RateLimitBlock::new();
+        let ctx = MockContext::new().with_config(
+            "route_limits",
+            r#"[{"pattern": "/users/[^/]+/posts", "max": 2}]"#,
+        );
+        let get = |path: &str| {
+            let mut msg = MockRequest::get(path).build();
+            SimulatedResponse::from_result(&block.handle(&ctx, &mut msg))
+        };
+        assert_header(&get("/users/alice/posts"), "X-RateLimit-Limit", "2");
+        // The decoded path is what counts, whatever the client's encoding
+        assert_header(&get("/users/%61lice/posts"), "X-RateLimit-Limit", "2");
+        assert_status(&get("/users/bob/posts"), 429, Some("rate_limited"));
+        // Other paths keep the default budget
+        assert_header(&get("/users/alice"), "X-RateLimit-Limit", "1000");
+    }
+
+    #[test]
+    fn patterns_match_the_whole_path_with_any_alternative() {
+        let rules = RouteLimits::compile(
+            r#"[{"pattern": "/a|/a/b", "max": 1}, {"prefix": "/a", "max": 2}]"#,
+        )
+        .unwrap();
+        assert_eq!(rules.select("/a/b").map(|r| r.max), Some(1));
+        assert_eq!(rules.select("/a").map(|r| r.max), Some(1));
+        assert_eq!(rules.select("/a/bc").map(|r| r.max), Some(2));
+        assert_eq!(rules.select("/a/b").unwrap().source(), "/a|/a/b");
+
+        let err = RouteLimits::compile(r#"[{"pattern": "(", "max": 1}]"#).err();
+        assert!(err.unwrap().starts_with("route limit 0: invalid pattern"));
+    }
+
+    #[test]
+    fn exempt_clients_are_never_counted() {
+        let block = RateLimitBlock::new();
+        let ctx = MockContext::new()
+            .with_config("max_requests", "1")
+            .with_config("exempt_cidrs", "10.0.0.0/8, 192.0.2.5/32");
+        let from = |addr: &str| {
+            let mut msg = MockRequest::get("/").remote_addr(addr).build();
+            SimulatedResponse::from_result(&block.handle(&ctx, &mut msg))
+        };
+        for addr in ["10.1.2.3", "10.1.2.3", "192.0.2.5", "192.0.2.5"] {
+            let resp = from(addr);
+            assert_status(&resp, 200, None);
+            assert_no_header(&resp, "X-RateLimit-Remaining");
+        }
+        assert_eq!(block.stats(10).total_checked, 0);
+
+        assert_header(&from("192.0.2.6"), "X-RateLimit-Remaining", "0");
+        assert_status(&from("192.0.2.6"), 429, Some("rate_limited"));
+        assert_eq!(block.stats(10).total_checked, 2);
+    }
+
+    #[test]
+    fn spent_write_budgets_leave_reads_flowing() {
+        let clock = Arc::new(ManualClock::new());
+        let block = RateLimitBlock::new().with_clock(clock.clone());
+        let ctx = MockContext::new()
+            .with_config("max_requests", "10")
+            .with_config("write_max_requests", "2")
+            .with_config("write_window_seconds", "30");
+        let send = |method: &str| {
+            let mut msg = MockRequest::new(method, "/api/items").build();
+            SimulatedResponse::from_result(&block.handle(&ctx, &mut msg))
+        };
+
+        for remaining in ["1", "0"] {
+            let resp = send("POST");
+            assert_status(&resp, 200, None);
+            assert_header(&resp, "X-RateLimit-Write-Limit", "2");
+            assert_header(&resp, "X-RateLimit-Write-Remaining", remaining);
+            assert_no_header(&resp, "X-RateLimit-Remaining");
+        }
+        let resp = send("DELETE");
+        assert_status(&resp, 429, Some("rate_limited"));
+        assert_header(&resp, "X-RateLimit-Write-Remaining", "0");
+
+        // Reads draw on their own budget
+        let resp = send("GET");
+        assert_status(&resp, 200, None);
+        assert_header(&resp, "X-RateLimit-Limit", "10");
+        assert_header(&resp, "X-RateLimit-Remaining", "9");
+        assert_no_header(&resp, "X-RateLimit-Write-Remaining");
+
+        clock.advance(Duration::from_secs(31));
+        assert_header(&send("PUT"), "X-RateLimit-Write-Remaining", "1");
+    }
 }
@@ -1,17 +1,247 @@
-use std::sync::Arc;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::{Duration, Instant};
 use wafer_run::*;
 
+use crate::admin::{AdminDescriptor, FieldKind, StatusDescriptor};
+use crate::errors::{CoreError, Outcome};
+use crate::meta;
+use crate::path;
+
+/// Meta key carrying the effective read-only state ("true"/"false").
+pub const READONLY_META: &str = meta::READONLY_ACTIVE;
+
+/// Whether ReadonlyGuardBlock marked this request as served in read-only mode.
+pub fn is_readonly(msg: &Message) -> bool {
+    meta::flag(msg, READONLY_META)
+}
+
 /// ReadonlyGuardBlock blocks write operations when in read-only mode.
+///
+/// Every request it sees, including `skip_paths` ones, is tagged with
+/// `readonly.active` meta. Checked requests also get an `X-Readonly-Mode:
+/// true` response header while read-only mode is on, rejected writes included.
+///
+/// Writes are detected from the action (create/update/delete), which non-HTTP
+/// transports supply through `meta::TransportMapping`, or else from the HTTP
+/// method (see `meta::resolved_action` and its `method_actions` config).
+/// APIs that are not CRUD-shaped can configure `readonly_write_patterns` and
+/// `readonly_read_patterns`: comma-separated `METHOD /prefix` entries (method
+/// `*` matches any) such as `"POST /graphql, * /rpc/mutate"`. The most
+/// specific matching pattern decides; on a tie the request counts as a write.
+///
+/// With `require_body_on_write: true`, create and update requests with an
+/// empty body (or `Content-Length: 0`) are rejected with 400, whether or not
+/// read-only mode is on. Off by default.
+///
+/// Rejected writes are tagged with `readonly.rejected` meta and remembered
+/// per client address for `REJECTION_MEMORY` in the guard's own
+/// `RejectionLedger`, so RateLimitBlock (`count_readonly_rejections_extra`)
+/// can charge them on the client's following requests whichever block runs
+/// first. The ledger is found by the name the guard is registered under
+/// (see `rejections`).
 pub struct ReadonlyGuardBlock {
     enabled: bool,
+    rejections: Arc<RejectionLedger>,
 }
 
 impl ReadonlyGuardBlock {
+    /// A guard registered as `@wafer/readonly-guard`.
     pub fn new() -> Self {
-        Self { enabled: false }
+        Self::named(DEFAULT_NAME)
+    }
+
+    /// A guard whose rejections are published under `name`, the name it is
+    /// registered as.
+    pub fn named(name: &str) -> Self {
+        let rejections = Arc::new(RejectionLedger::default());
+        let mut ledgers = ledgers().write();
+        ledgers.retain(|_, l| l.strong_count() > 0);
+        ledgers.insert(name.to_string(), Arc::downgrade(&rejections));
+        Self {
+            enabled: false,
+            rejections,
+        }
+    }
+}
+
+/// Name the guard registers under by default.
+pub const DEFAULT_NAME: &str = "@wafer/readonly-guard";
+
+/// How long a rejected write stays in a ledger before it is forgotten.
+pub const REJECTION_MEMORY: Duration = Duration::from_secs(60);
+
+/// Upper bound on clients tracked per ledger.
+const MAX_REJECTION_KEYS: usize = 10_000;
+
+/// Writes rejected in read-only mode per client address, kept until a
+/// consumer takes them or `REJECTION_MEMORY` passes.
+#[derive(Debug, Default)]
+pub struct RejectionLedger {
+    entries: Mutex<Entries>,
+}
+
+/// Count and last rejection per client, indexed by the time of that last
+/// rejection so expired and oldest clients are found without a scan.
+#[derive(Debug, Default)]
+struct Entries {
+    by_key: HashMap<String, (u64, Instant, u64)>,
+    by_time: BTreeMap<(Instant, u64), String>,
+    seq: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) -> Option<(u64, Instant)> {
+        let (count, at, seq) = self.by_key.remove(key)?;
+        self.by_time.remove(&(at, seq));
+        Some((count, at))
+    }
+
+    /// Forget the client rejected longest ago, if `expired_only` only when
+    /// its rejections are past `REJECTION_MEMORY`.
+    fn pop_oldest(&mut self, now: Instant, expired_only: bool) -> bool {
+        let key = match self.by_time.first_key_value() {
+            Some(((at, _), _))
+                if expired_only && now.saturating_duration_since(*at) <= REJECTION_MEMORY =>
+            {
+                return false
+            }
+            Some((_, key)) => key.clone(),
+            None => return false,
+        };
+        self.remove(&key);
+        true
+    }
+}
+
+impl RejectionLedger {
+    /// Remember one rejected write by `key`.
+    pub fn record(&self, key: &str) {
+        self.record_at(key, Instant::now());
+    }
+
+    /// Like `record`, at an explicit instant.
+    pub fn record_at(&self, key: &str, now: Instant) {
+        let mut entries = self.entries.lock();
+        while entries.pop_oldest(now, true) {}
+        let count = match entries.remove(key) {
+            Some((count, _)) => count,
+            None => {
+                // Full: forget the client rejected longest ago
+                if entries.by_key.len() >= MAX_REJECTION_KEYS {
+                    entries.pop_oldest(now, false);
+                }
+                0
+            }
+        };
+        entries.seq += 1;
+        let seq = entries.seq;
+        entries.by_time.insert((now, seq), key.to_string());
+        entries
+            .by_key
+            .insert(key.to_string(), (count + 1, now, seq));
+    }
+
+    /// The writes by `key` rejected within `REJECTION_MEMORY` of the last
+    /// one, clearing them.
+    pub fn take(&self, key: &str) -> u64 {
+        self.take_at(key, Instant::now())
+    }
+
+    /// Like `take`, at an explicit instant.
+    pub fn take_at(&self, key: &str, now: Instant) -> u64 {
+        match self.entries.lock().remove(key) {
+            Some((count, at)) if now.saturating_duration_since(at) <= REJECTION_MEMORY => count,
+            _ => 0,
+        }
+    }
+}
+
+/// The ledgers of live guards by registered name. Only weak references, so
+/// a dropped guard's rejections go with it.
+fn ledgers() -> &'static RwLock<HashMap<String, Weak<RejectionLedger>>> {
+    static LEDGERS: OnceLock<RwLock<HashMap<String, Weak<RejectionLedger>>>> = OnceLock::new();
+    LEDGERS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// The rejected writes of the guard registered as `name`, if it is live.
+/// A guard created later under the same name replaces it.
+pub fn rejections(name: &str) -> Option<Arc<RejectionLedger>> {
+    ledgers().read().get(name)?.upgrade()
+}
+
+/// A `METHOD /prefix` route pattern.
+struct RoutePattern {
+    method: String,
+    prefix: String,
+}
+
+impl RoutePattern {
+    fn parse_list(raw: &str) -> Vec<Self> {
+        raw.split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.split_whitespace();
+                let (method, prefix) = match (parts.next(), parts.next()) {
+                    (Some(m), Some(p)) => (m, p),
+                    // A bare path matches any method
+                    (Some(p), None) if p.starts_with('/') => ("*", p),
+                    _ => return None,
+                };
+                Some(Self {
+                    method: method.to_ascii_uppercase(),
+                    prefix: path::normalize(prefix, false),
+                })
+            })
+            .collect()
+    }
+
+    /// Length of the matched prefix, if the pattern matches.
+    fn match_len(&self, method: &str, path: &str) -> Option<usize> {
+        let method_ok = self.method == "*" || self.method.eq_ignore_ascii_case(method);
+        if method_ok && path::has_prefix(path, &self.prefix) {
+            Some(self.prefix.len())
+        } else {
+            None
+        }
     }
 }
 
+/// Longest match among the configured patterns under `key`.
+fn best_match(ctx: &dyn Context, key: &str, method: &str, path: &str) -> Option<usize> {
+    let raw = ctx.config_get(key).unwrap_or("");
+    if raw.is_empty() {
+        return None;
+    }
+    RoutePattern::parse_list(raw)
+        .iter()
+        .filter_map(|p| p.match_len(method, path))
+        .max()
+}
+
+/// Whether the request is a write, by route patterns first and action otherwise.
+fn is_write(ctx: &dyn Context, msg: &mut Message) -> bool {
+    let method = meta::http_method(msg);
+    let path = path::request_path(msg);
+    let write = best_match(ctx, "readonly_write_patterns", method.as_str(), &path);
+    let read = best_match(ctx, "readonly_read_patterns", method.as_str(), &path);
+    match (write, read) {
+        (Some(w), Some(r)) => w >= r,
+        (Some(_), None) => true,
+        (None, Some(_)) => false,
+        (None, None) => meta::is_write_action(&meta::resolved_action(ctx, msg)),
+    }
+}
+
+/// Whether a create/update request arrived without a body.
+fn missing_write_body(ctx: &dyn Context, msg: &Message) -> bool {
+    let action = meta::resolved_action(ctx, msg);
+    if action != "create" && action != "update" {
+        return false;
+    }
+    msg.data.is_empty() || msg.header("Content-Length").trim() == "0"
+}
+
 impl Block for ReadonlyGuardBlock {
     fn info(&self) -> BlockInfo {
         BlockInfo {
@@ -21,7 +251,7 @@ impl Block for ReadonlyGuardBlock {
             summary: "Blocks write operations in read-only mode".to_string(),
             instance_mode: InstanceMode::Singleton,
             allowed_modes: Vec::new(),
-            admin_ui: None,
+            admin_ui: crate::admin::ui(admin_descriptor()),
         }
     }
 
@@ -31,16 +261,40 @@ impl Block for ReadonlyGuardBlock {
             .map(|s| s == "true" || s == "1")
             .unwrap_or(self.enabled);
 
+        // Propagate the effective mode so downstream blocks can adapt, on
+        // skipped paths too
+        meta::set_flag(msg, READONLY_META, readonly);
+        if path::is_skipped(ctx, msg) {
+            return msg.clone().cont();
+        }
+
+        let require_body = ctx
+            .config_get("require_body_on_write")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+        if require_body && missing_write_body(ctx, msg) {
+            return CoreError::BadRequest(
+                "This request requires a body, but none was sent.".to_string(),
+            )
+            .respond(msg);
+        }
+
         if !readonly {
             return msg.clone().cont();
         }
+        meta::set_resp_header(msg, "X-Readonly-Mode", "true");
 
-        let action = msg.action();
-        if action == "create" || action == "update" || action == "delete" {
-            return err_forbidden(
-                msg.clone(),
-                "This instance is in read-only mode. Write operations are not allowed.",
-            );
+        if is_write(ctx, msg) {
+            meta::set_flag(msg, meta::READONLY_REJECTED, true);
+            let client = msg.remote_addr().to_string();
+            if !client.is_empty() {
+                self.rejections.record(&client);
+            }
+            Outcome::ReadonlyBlocked.record(msg);
+            return CoreError::ReadOnly(
+                "This instance is in read-only mode. Write operations are not allowed.".to_string(),
+            )
+            .respond(msg);
         }
 
         msg.clone().cont()
@@ -55,6 +309,153 @@ impl Block for ReadonlyGuardBlock {
     }
 }
 
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "readonly",
+            FieldKind::Bool,
+            "false",
+            "Reject writes while enabled",
+        )
+        .field(
+            "readonly_write_patterns",
+            FieldKind::List,
+            "",
+            "`METHOD /prefix` entries that count as writes",
+        )
+        .field(
+            "readonly_read_patterns",
+            FieldKind::List,
+            "",
+            "`METHOD /prefix` entries that count as reads",
+        )
+        .field(
+            "require_body_on_write",
+            FieldKind::Bool,
+            "false",
+            "Reject create and update requests with an empty body",
+        )
+        .field(
+            "method_actions",
+            FieldKind::List,
+            "",
+            "`METHOD=action` overrides of the method to action mapping",
+        )
+        .skip_paths()
+        .status(StatusDescriptor::new().header("X-Readonly-Mode"))
+}
+
 pub fn register(w: &mut Wafer) {
-    w.register_block("@wafer/readonly-guard", Arc::new(ReadonlyGuardBlock::new()));
+    register_as(w, DEFAULT_NAME);
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(ReadonlyGuardBlock::named(name)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// Run a request through a guard of its own, so the shared ledger of the
+    /// default name is left alone.
+    fn guard(ctx: &MockContext, req: MockRequest) -> (SimulatedResponse, Message) {
+        let block = ReadonlyGuardBlock::named("@test/guard");
+        let mut msg = req.build();
+        let result = block.handle(ctx, &mut msg);
+        let out = result.message.clone().unwrap_or(msg);
+        (SimulatedResponse::from_result(&result), out)
+    }
+
+    #[test]
+    fn each_guard_keeps_its_own_rejections() {
+        let a = ReadonlyGuardBlock::named("@test/guard-a");
+        let b = ReadonlyGuardBlock::named("@test/guard-b");
+        a.rejections.record("10.0.0.1");
+
+        let ledger = rejections("@test/guard-a").unwrap();
+        assert_eq!(ledger.take("10.0.0.1"), 1);
+        assert_eq!(rejections("@test/guard-b").unwrap().take("10.0.0.1"), 0);
+
+        drop((a, b, ledger));
+        assert!(rejections("@test/guard-a").is_none());
+    }
+
+    #[test]
+    fn rejections_expire_and_the_oldest_client_goes_first() {
+        let ledger = RejectionLedger::default();
+        let start = Instant::now();
+        ledger.record_at("old", start);
+        ledger.record_at("old", start);
+        let later = start + REJECTION_MEMORY + Duration::from_secs(1);
+        assert_eq!(ledger.take_at("old", later), 0);
+
+        for i in 0..MAX_REJECTION_KEYS {
+            ledger.record_at(&i.to_string(), start + Duration::from_millis(i as u64));
+        }
+        ledger.record_at("new", start + Duration::from_secs(30));
+        let now = start + Duration::from_secs(30);
+        assert_eq!(ledger.take_at("0", now), 0);
+        assert_eq!(ledger.take_at("1", now), 1);
+        assert_eq!(ledger.take_at("new", now), 1);
+    }
+
+    #[test]
+    fn writes_without_a_body_are_rejected_when_required() {
+        let ctx = MockContext::new().with_config("require_body_on_write", "true");
+        for req in [
+            MockRequest::post("/api/items"),
+            MockRequest::new("PUT", "/api/items/1"),
+            MockRequest::post("/api/items")
+                .body(b"{}")
+                .header("Content-Length", "0"),
+        ] {
+            assert_status(&guard(&ctx, req).0, 400, Some("bad_request"));
+        }
+
+        let post = MockRequest::post("/api/items").body(br#"{"name": "a"}"#);
+        assert_status(&guard(&ctx, post).0, 200, None);
+        // Deletes and reads need no body
+        let delete = MockRequest::new("DELETE", "/api/items/1");
+        assert_status(&guard(&ctx, delete).0, 200, None);
+        assert_status(&guard(&ctx, MockRequest::get("/api/items")).0, 200, None);
+        // Off by default
+        let empty = MockRequest::post("/api/items");
+        assert_status(&guard(&MockContext::new(), empty).0, 200, None);
+    }
+
+    #[test]
+    fn every_request_carries_the_mode() {
+        let read = || MockRequest::get("/api/items");
+        let write = || MockRequest::post("/api/items").body(b"{}");
+
+        let off = MockContext::new();
+        for req in [read(), write()] {
+            let (resp, msg) = guard(&off, req);
+            assert_status(&resp, 200, None);
+            assert_eq!(msg.get_meta(READONLY_META), "false");
+            assert_no_header(&resp, "X-Readonly-Mode");
+        }
+
+        let on = MockContext::new().with_config("readonly", "true");
+        let (resp, msg) = guard(&on, read());
+        assert_status(&resp, 200, None);
+        assert!(is_readonly(&msg));
+        assert_header(&resp, "X-Readonly-Mode", "true");
+        let (resp, msg) = guard(&on, write());
+        assert_status(&resp, 403, Some("forbidden"));
+        assert!(is_readonly(&msg));
+        assert!(meta::flag(&msg, meta::READONLY_REJECTED));
+        assert_header(&resp, "X-Readonly-Mode", "true");
+
+        // Skipped paths pass, still tagged
+        let skipping = MockContext::new()
+            .with_config("readonly", "true")
+            .with_config("skip_paths", "/health");
+        let (resp, msg) = guard(&skipping, MockRequest::post("/health"));
+        assert_status(&resp, 200, None);
+        assert!(is_readonly(&msg));
+    }
 }
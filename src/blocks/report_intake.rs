@@ -0,0 +1,175 @@
+use std::sync::Arc;
+use std::time::Duration;
+use wafer_run::*;
+
+use super::tasks::{self, TaskSet};
+use crate::errors::CoreError;
+use crate::window::{WindowKind, WindowedCounter};
+
+/// Client keys tracked by a collector's own rate limiter.
+const MAX_RATE_KEYS: usize = 10_000;
+
+/// Window the per-client report limit counts over.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Body size accepted when `max_body_bytes` is not set.
+pub const DEFAULT_MAX_BODY: usize = 65_536;
+
+/// ReportIntake holds what the browser report collectors (ReportingBlock and
+/// CspReportBlock) share: a per-client limit on posts per minute, separate
+/// from RateLimitBlock, plus the content type and `max_body_bytes` checks.
+///
+/// The limiter tracks at most 10000 clients. Expired clients are swept once
+/// a window between `start` and `stop`, so idle collectors hold no keys.
+pub struct ReportIntake {
+    limiter: Arc<WindowedCounter>,
+    tasks: TaskSet,
+}
+
+impl ReportIntake {
+    /// Intake for the block named `owner` (used in task log events).
+    pub fn new(owner: &str) -> Self {
+        Self {
+            limiter: Arc::new(WindowedCounter::new(WindowKind::Fixed, MAX_RATE_KEYS)),
+            tasks: TaskSet::new(owner),
+        }
+    }
+
+    /// Check a report post against the client's budget (`rate_key` posts per
+    /// minute, default 60), the `content_types` accepted and
+    /// `max_body_bytes`. `Some` is the error response to send.
+    pub fn admit(
+        &self,
+        ctx: &dyn Context,
+        msg: &Message,
+        rate_key: &str,
+        content_types: &[&str],
+    ) -> Option<Result_> {
+        let limit = ctx
+            .config_get(rate_key)
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(60);
+        let hit = self.limiter.hit(msg.remote_addr(), RATE_WINDOW);
+        if hit.count > limit {
+            return Some(
+                CoreError::RateLimited {
+                    message: "Too many reports".to_string(),
+                    retry_after: hit.reset_in.as_secs(),
+                }
+                .respond(msg),
+            );
+        }
+
+        let content_type = msg.header("Content-Type").to_ascii_lowercase();
+        if !content_types.iter().any(|t| content_type.starts_with(t)) {
+            return Some(
+                CoreError::custom(
+                    415,
+                    "unsupported_media_type",
+                    &format!("Expected {}", content_types.join(", ")),
+                )
+                .respond(msg),
+            );
+        }
+
+        let max_body = ctx
+            .config_get("max_body_bytes")
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_MAX_BODY);
+        if msg.data.len() > max_body {
+            return Some(CoreError::PayloadTooLarge("Report too large".to_string()).respond(msg));
+        }
+        None
+    }
+
+    /// Clients currently tracked by the limiter.
+    pub fn tracked_clients(&self) -> usize {
+        self.limiter.len()
+    }
+
+    /// Start sweeping expired clients; call on `LifecycleType::Start`.
+    pub fn start(&self) {
+        let limiter = self.limiter.clone();
+        self.tasks.spawn_interval("sweep", RATE_WINDOW, move || {
+            limiter.purge_expired(RATE_WINDOW);
+        });
+    }
+
+    /// Stop the sweep; call on `LifecycleType::Stop`.
+    pub fn stop(&self) {
+        self.tasks.stop(tasks::DEFAULT_DRAIN);
+    }
+}
+
+/// Parse an `application/reports+json` batch: a JSON array of reports.
+pub fn parse_batch(body: &[u8]) -> Option<Vec<serde_json::Value>> {
+    serde_json::from_slice(body).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use std::time::Instant;
+
+    const TYPES: &[&str] = &["application/reports+json"];
+
+    fn post(body: &str) -> Message {
+        MockRequest::post("/_reports")
+            .header("Content-Type", "application/reports+json")
+            .body(body.as_bytes())
+            .build()
+    }
+
+    #[test]
+    fn admits_within_limits() {
+        let intake = ReportIntake::new("test");
+        let ctx = MockContext::new();
+        assert!(intake.admit(&ctx, &post("[]"), "rate", TYPES).is_none());
+
+        let wrong_type = MockRequest::post("/_reports")
+            .header("Content-Type", "text/plain")
+            .build();
+        let resp = SimulatedResponse::from_result(
+            &intake.admit(&ctx, &wrong_type, "rate", TYPES).unwrap(),
+        );
+        assert_status(&resp, 415, Some("unsupported_media_type"));
+
+        let ctx = MockContext::new().with_config("max_body_bytes", "4");
+        let resp = SimulatedResponse::from_result(
+            &intake
+                .admit(&ctx, &post("[{}, {}]"), "rate", TYPES)
+                .unwrap(),
+        );
+        assert_status(&resp, 413, None);
+    }
+
+    #[test]
+    fn limits_each_client_and_sweeps_expired_ones() {
+        let intake = ReportIntake::new("test");
+        let ctx = MockContext::new().with_config("rate", "2");
+        for _ in 0..2 {
+            assert!(intake.admit(&ctx, &post("[]"), "rate", TYPES).is_none());
+        }
+        let resp = SimulatedResponse::from_result(
+            &intake.admit(&ctx, &post("[]"), "rate", TYPES).unwrap(),
+        );
+        assert_status(&resp, 429, Some("rate_limited"));
+
+        assert_eq!(intake.tracked_clients(), 1);
+        assert_eq!(
+            intake
+                .limiter
+                .purge_expired_at(RATE_WINDOW, Instant::now() + RATE_WINDOW * 3),
+            1
+        );
+        assert_eq!(intake.tracked_clients(), 0);
+    }
+
+    #[test]
+    fn batches_are_json_arrays() {
+        assert_eq!(parse_batch(br#"[{"type": "x"}]"#).unwrap().len(), 1);
+        assert!(parse_batch(br#"{"type": "x"}"#).is_none());
+        assert!(parse_batch(b"not json").is_none());
+    }
+}
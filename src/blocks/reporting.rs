@@ -0,0 +1,438 @@
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use wafer_run::*;
+
+use super::report_intake::{self, ReportIntake};
+use crate::admin::{self, AdminDescriptor, FieldKind, StatusDescriptor};
+use crate::errors::{self, CoreError};
+use crate::meta;
+use crate::path;
+
+/// Distinct (type, URL pattern) aggregates kept; the rest count as "(other)".
+const MAX_AGGREGATES: usize = 1000;
+
+/// ReportingBlock advertises a browser reporting endpoint and collects reports.
+/// Configure via node config:
+/// {"collector_path": "/_reports", "nel_max_age": "86400", "nel_failure_fraction": "0.05"}
+///
+/// Every response gets `Reporting-Endpoints`, `Report-To` and `NEL` headers
+/// pointing at `collector_path`. Browsers need an absolute endpoint URL, so
+/// the path is joined to `collector_origin` (e.g. `https://example.com`), or
+/// without it to `https://` and the request's `Host`; requests with neither
+/// a configured origin nor a well-formed `Host` get no reporting headers.
+/// The block answers the collector path itself, so place it before auth and
+/// any CSRF checks:
+///
+/// - `POST <collector_path>` takes an `application/reports+json` batch (at
+///   most `max_body_bytes`, default 65536, and `max_reports`, default 100),
+///   counted by report type and URL pattern (origin plus up to three path
+///   segments, id-like segments collapsed to `:id`). Clients are limited to
+///   `collector_rate_limit` batches per minute (default 60), separately from
+///   RateLimitBlock (see [`ReportIntake`]).
+/// - `GET <collector_path>/stats` returns the aggregates. Like other status
+///   endpoints it needs `status_public` or a user with `status_role` (see
+///   `admin::status_allowed`).
+///
+/// With `persist_samples: true`, raw reports are also stored in the `reports`
+/// table, up to `max_samples` (default 1000) per process.
+pub struct ReportingBlock {
+    aggregates: Mutex<BTreeMap<(String, String), u64>>,
+    batches: AtomicU64,
+    rejected: AtomicU64,
+    samples: AtomicU64,
+    intake: ReportIntake,
+}
+
+impl ReportingBlock {
+    pub fn new() -> Self {
+        Self {
+            aggregates: Mutex::new(BTreeMap::new()),
+            batches: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            samples: AtomicU64::new(0),
+            intake: ReportIntake::new("@wafer/reporting"),
+        }
+    }
+
+    /// Report counts by (type, URL pattern).
+    pub fn aggregates(&self) -> Vec<(String, String, u64)> {
+        self.aggregates
+            .lock()
+            .iter()
+            .map(|((t, u), n)| (t.clone(), u.clone(), *n))
+            .collect()
+    }
+
+    fn record(&self, report_type: &str, pattern: String) {
+        let mut agg = self.aggregates.lock();
+        let key = (report_type.to_string(), pattern);
+        let key = if agg.contains_key(&key) || agg.len() < MAX_AGGREGATES {
+            key
+        } else {
+            ("(other)".to_string(), "(other)".to_string())
+        };
+        *agg.entry(key).or_insert(0) += 1;
+    }
+
+    fn collect(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let accepted = ["application/reports+json", "application/json"];
+        if let Some(rejected) = self
+            .intake
+            .admit(ctx, msg, "collector_rate_limit", &accepted)
+        {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return rejected;
+        }
+
+        let reports = match report_intake::parse_batch(&msg.data) {
+            Some(r) => r,
+            None => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return CoreError::BadRequest("Expected a JSON array of reports".to_string())
+                    .respond(msg);
+            }
+        };
+        let max_reports = config_u64(ctx, "max_reports", 100) as usize;
+        if reports.len() > max_reports {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return CoreError::PayloadTooLarge(format!(
+                "At most {} reports per batch",
+                max_reports
+            ))
+            .respond(msg);
+        }
+
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        let persist = ctx
+            .config_get("persist_samples")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+        let max_samples = config_u64(ctx, "max_samples", 1000);
+        for report in &reports {
+            let report_type = report
+                .get("type")
+                .and_then(|v| v.as_str())
+                .filter(|t| !t.is_empty() && t.len() <= 64)
+                .unwrap_or("unknown");
+            let url = report.get("url").and_then(|v| v.as_str()).unwrap_or("");
+            self.record(report_type, url_pattern(url));
+
+            if persist && self.samples.fetch_add(1, Ordering::Relaxed) < max_samples {
+                persist_sample(ctx, report_type, report);
+            }
+        }
+
+        respond(msg.clone(), 204, Vec::new(), "")
+    }
+
+    fn stats(&self, msg: &Message) -> Result_ {
+        let aggregates: Vec<serde_json::Value> = self
+            .aggregates()
+            .into_iter()
+            .map(|(t, u, n)| serde_json::json!({ "type": t, "url": u, "count": n }))
+            .collect();
+        json_respond(
+            msg.clone(),
+            200,
+            &serde_json::json!({
+                "batches": self.batches.load(Ordering::Relaxed),
+                "rejected": self.rejected.load(Ordering::Relaxed),
+                "aggregates": aggregates,
+            }),
+        )
+    }
+}
+
+fn config_u64(ctx: &dyn Context, key: &str, default: u64) -> u64 {
+    ctx.config_get(key)
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(default)
+}
+
+/// The absolute URL of the collector at `collector` for this request: under
+/// `collector_origin` when configured, else `https://` and the `Host` header.
+fn collector_url(ctx: &dyn Context, msg: &Message, collector: &str) -> Option<String> {
+    let origin = match ctx
+        .config_get("collector_origin")
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+    {
+        Some(origin) => origin.trim_end_matches('/').to_string(),
+        None => {
+            let host = msg.header("Host").trim();
+            let well_formed = !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | ':' | '[' | ']'));
+            if !well_formed {
+                return None;
+            }
+            format!("https://{}", host.to_ascii_lowercase())
+        }
+    };
+    Some(format!("{}{}", origin, collector))
+}
+
+/// Reduce a report URL to a low-cardinality pattern.
+fn url_pattern(url: &str) -> String {
+    let (origin, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            (format!("{}://{}", scheme, host), path)
+        }
+        None => (String::new(), url),
+    };
+    let rest = rest.split(['?', '#']).next().unwrap_or("");
+    let normalized = path::normalize(rest, false);
+    let segments: Vec<&str> = normalized
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .take(3)
+        .map(|seg| {
+            let id_like = seg.chars().any(|c| c.is_ascii_digit())
+                && seg.chars().all(|c| c.is_ascii_hexdigit() || c == '-');
+            if id_like || seg.len() > 32 {
+                ":id"
+            } else {
+                seg
+            }
+        })
+        .collect();
+    let mut pattern = origin;
+    pattern.push('/');
+    pattern.push_str(&segments.join("/"));
+    if pattern.len() > 200 {
+        let cut = (0..=200)
+            .rev()
+            .find(|i| pattern.is_char_boundary(*i))
+            .unwrap_or(0);
+        pattern.truncate(cut);
+    }
+    pattern
+}
+
+fn persist_sample(ctx: &dyn Context, report_type: &str, report: &serde_json::Value) {
+    let db = match ctx.services().and_then(|s| s.database.as_ref()) {
+        Some(db) => db,
+        None => return,
+    };
+    let mut data = HashMap::new();
+    data.insert(
+        "type".to_string(),
+        serde_json::Value::String(report_type.to_string()),
+    );
+    data.insert("report".to_string(), report.clone());
+    data.insert(
+        "received_at".to_string(),
+        serde_json::Value::String(chrono::Utc::now().to_rfc3339()),
+    );
+    if let Err(e) = db.create("reports", data) {
+        tracing::warn!("reporting: failed to persist sample: {}", e);
+    }
+}
+
+impl Block for ReportingBlock {
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "@wafer/reporting".to_string(),
+            version: "0.1.0".to_string(),
+            interface: "middleware@v1".to_string(),
+            summary: "Reporting API and NEL headers with a report collector".to_string(),
+            instance_mode: InstanceMode::Singleton,
+            allowed_modes: Vec::new(),
+            admin_ui: crate::admin::ui(admin_descriptor()),
+        }
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let collector = path::normalize(
+            ctx.config_get("collector_path").unwrap_or("/_reports"),
+            false,
+        );
+        let req_path = path::request_path(msg);
+        if req_path == collector {
+            if meta::http_method(msg) != meta::Method::Post {
+                return errors::method_not_allowed(msg, &["POST"]);
+            }
+            return self.collect(ctx, msg);
+        }
+        if req_path == format!("{}/stats", collector) {
+            if !admin::status_allowed(ctx, msg) {
+                return CoreError::NotFound("Not found".to_string()).respond(msg);
+            }
+            return self.stats(msg);
+        }
+
+        let url = match collector_url(ctx, msg, &collector) {
+            Some(url) => url,
+            None => return msg.clone().cont(),
+        };
+
+        let max_age = config_u64(ctx, "nel_max_age", 86_400);
+        let fraction = ctx
+            .config_get("nel_failure_fraction")
+            .and_then(|s| s.parse::<f64>().ok())
+            .filter(|f| (0.0..=1.0).contains(f))
+            .unwrap_or(0.05);
+        meta::set_resp_header(msg, "Reporting-Endpoints", &format!("default=\"{}\"", url));
+        meta::set_resp_header(
+            msg,
+            "Report-To",
+            &serde_json::json!({
+                "group": "default",
+                "max_age": max_age,
+                "endpoints": [{ "url": url }],
+            })
+            .to_string(),
+        );
+        meta::set_resp_header(
+            msg,
+            "NEL",
+            &serde_json::json!({
+                "report_to": "default",
+                "max_age": max_age,
+                "failure_fraction": fraction,
+            })
+            .to_string(),
+        );
+
+        msg.clone().cont()
+    }
+
+    fn lifecycle(
+        &self,
+        _ctx: &dyn Context,
+        event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        match event.event_type {
+            LifecycleType::Start => self.intake.start(),
+            LifecycleType::Stop => self.intake.stop(),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "collector_path",
+            FieldKind::String,
+            "/_reports",
+            "Path the block receives reports on",
+        )
+        .field(
+            "collector_origin",
+            FieldKind::String,
+            "",
+            "Origin advertised for the collector; defaults to https:// and the Host",
+        )
+        .field(
+            "nel_max_age",
+            FieldKind::Integer,
+            "86400",
+            "NEL policy lifetime in seconds",
+        )
+        .field(
+            "nel_failure_fraction",
+            FieldKind::Number,
+            "0.05",
+            "Fraction of failed requests browsers report, 0 to 1",
+        )
+        .field(
+            "max_body_bytes",
+            FieldKind::Integer,
+            "65536",
+            "Largest report batch accepted",
+        )
+        .field(
+            "max_reports",
+            FieldKind::Integer,
+            "100",
+            "Most reports accepted per batch",
+        )
+        .field(
+            "collector_rate_limit",
+            FieldKind::Integer,
+            "60",
+            "Batches accepted per client per minute",
+        )
+        .field(
+            "persist_samples",
+            FieldKind::Bool,
+            "false",
+            "Store sampled reports in the database",
+        )
+        .field(
+            "max_samples",
+            FieldKind::Integer,
+            "1000",
+            "Most sampled reports kept",
+        )
+        .status_access()
+        .status(StatusDescriptor::new().endpoint("/_reports/stats", "json"))
+}
+
+pub fn register(w: &mut Wafer) {
+    register_as(w, "@wafer/reporting");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(ReportingBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn run(ctx: &MockContext, req: MockRequest) -> SimulatedResponse {
+        SimulatedResponse::from_result(&ReportingBlock::new().handle(ctx, &mut req.build()))
+    }
+
+    fn report_to(resp: &SimulatedResponse) -> Option<serde_json::Value> {
+        resp.header("Report-To")
+            .map(|v| serde_json::from_str(v).expect("Report-To is JSON"))
+    }
+
+    #[test]
+    fn endpoints_are_absolute() {
+        let req = MockRequest::get("/").header("Host", "App.example:8443");
+        let resp = run(&MockContext::new(), req);
+        let endpoint = &report_to(&resp).unwrap()["endpoints"][0]["url"];
+        assert_eq!(endpoint, "https://app.example:8443/_reports");
+        assert_header(
+            &resp,
+            "Reporting-Endpoints",
+            "default=\"https://app.example:8443/_reports\"",
+        );
+
+        let ctx = MockContext::new().with_config("collector_origin", "https://r.example/");
+        let resp = run(&ctx, MockRequest::get("/").header("Host", "app.example"));
+        let endpoint = &report_to(&resp).unwrap()["endpoints"][0]["url"];
+        assert_eq!(endpoint, "https://r.example/_reports");
+    }
+
+    #[test]
+    fn malformed_hosts_get_no_reporting_headers() {
+        for host in ["", "a\"b", "evil.example/path"] {
+            let resp = run(
+                &MockContext::new(),
+                MockRequest::get("/").header("Host", host),
+            );
+            assert_no_header(&resp, "Report-To");
+            assert_no_header(&resp, "NEL");
+        }
+    }
+
+    #[test]
+    fn stats_need_status_access() {
+        let resp = run(&MockContext::new(), MockRequest::get("/_reports/stats"));
+        assert_status(&resp, 404, Some("not_found"));
+        let req = MockRequest::get("/_reports/stats").meta(meta::AUTH_USER_ROLES, "admin");
+        assert_status(&run(&MockContext::new(), req), 200, None);
+    }
+}
@@ -0,0 +1,428 @@
+use parking_lot::Mutex;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use wafer_run::*;
+
+use super::TableSource;
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::errors::CoreError;
+use crate::meta;
+use crate::path;
+
+/// Meta key carrying the name of the matched route.
+pub const ROUTE_NAME_META: &str = meta::ROUTE_NAME;
+/// Meta key carrying the matched route's required role.
+pub const ROUTE_ROLE_META: &str = meta::ROUTE_ROLE;
+/// Meta key carrying the chain the matched route forwards to.
+pub const ROUTE_CHAIN_META: &str = meta::ROUTE_FORWARD_CHAIN;
+
+/// RouterBlock tags requests from a declarative route table.
+/// Configure via node config, `routes` as a JSON array (or `routes_file`
+/// pointing at one):
+/// [{"name": "api", "prefix": "/api", "methods": ["GET"], "role": "user"},
+///  {"name": "admin", "pattern": "^/admin/[0-9]+$", "forward_chain": "admin-pipe"},
+///  {"name": "site", "default": true, "set_meta": {"cache": "public"}}]
+///
+/// Among enabled routes matching the method, a full `pattern` match beats any
+/// `prefix`, and the longest prefix wins among prefixes; ties go to the earlier
+/// entry. The `default` route applies when nothing else matches. The match
+/// sets `route.name`, `route.role` (which IAMBlock enforces when configured
+/// with `role_from_route: true`), `route.forward_chain` and every `set_meta`
+/// entry. Blocks cannot
+/// invoke chains through the runtime, so forwarding is left to whoever reads
+/// `route.forward_chain`.
+///
+/// The table is compiled at lifecycle Start, and again only when the config
+/// or the `routes_file`'s modification time changes. An invalid table is
+/// logged with the offending entry's index and fails requests with 500
+/// `router_misconfigured`.
+pub struct RouterBlock {
+    table: Mutex<Option<(String, Result<Arc<RouteTable>, String>)>>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RouteSpec {
+    name: Option<String>,
+    prefix: Option<String>,
+    pattern: Option<String>,
+    #[serde(default)]
+    methods: Vec<String>,
+    forward_chain: Option<String>,
+    role: Option<String>,
+    #[serde(default)]
+    set_meta: HashMap<String, String>,
+    #[serde(default = "enabled_default")]
+    enabled: bool,
+    #[serde(default)]
+    default: bool,
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+enum Matcher {
+    Prefix(String),
+    Pattern(Regex),
+    Default,
+}
+
+struct Route {
+    name: String,
+    matcher: Matcher,
+    methods: Vec<String>,
+    forward_chain: Option<String>,
+    role: Option<String>,
+    set_meta: HashMap<String, String>,
+}
+
+/// A compiled route table.
+pub struct RouteTable {
+    routes: Vec<Route>,
+}
+
+impl RouteTable {
+    /// Compile a JSON route table; errors name the offending entry index.
+    pub fn compile(json: &str) -> Result<Self, String> {
+        let specs: Vec<serde_json::Value> =
+            serde_json::from_str(json).map_err(|e| format!("routes: invalid JSON: {}", e))?;
+        let mut routes = Vec::with_capacity(specs.len());
+        let mut has_default = false;
+        for (i, raw) in specs.into_iter().enumerate() {
+            let spec: RouteSpec =
+                serde_json::from_value(raw).map_err(|e| format!("route {}: {}", i, e))?;
+            if !spec.enabled {
+                continue;
+            }
+            let matcher = match (&spec.prefix, &spec.pattern, spec.default) {
+                (Some(p), None, false) => {
+                    if !p.starts_with('/') {
+                        return Err(format!("route {}: prefix must start with '/'", i));
+                    }
+                    Matcher::Prefix(path::normalize(p, false))
+                }
+                (None, Some(p), false) => Matcher::Pattern(
+                    path::whole_path_regex(p)
+                        .map_err(|e| format!("route {}: invalid pattern: {}", i, e))?,
+                ),
+                (None, None, true) => {
+                    if has_default {
+                        return Err(format!("route {}: more than one default route", i));
+                    }
+                    has_default = true;
+                    Matcher::Default
+                }
+                _ => {
+                    return Err(format!(
+                        "route {}: needs exactly one of prefix, pattern or default",
+                        i
+                    ))
+                }
+            };
+            routes.push(Route {
+                name: spec.name.unwrap_or_else(|| format!("route-{}", i)),
+                matcher,
+                methods: spec
+                    .methods
+                    .iter()
+                    .map(|m| m.to_ascii_uppercase())
+                    .collect(),
+                forward_chain: spec.forward_chain,
+                role: spec.role,
+                set_meta: spec.set_meta,
+            });
+        }
+        Ok(Self { routes })
+    }
+
+    /// Select the route for a method and (normalized) path.
+    fn select(&self, method: &str, path: &str) -> Option<&Route> {
+        let mut best: Option<(usize, &Route)> = None;
+        let mut fallback = None;
+        for route in &self.routes {
+            if !route.methods.is_empty()
+                && !route.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+            {
+                continue;
+            }
+            let score = match &route.matcher {
+                Matcher::Default => {
+                    fallback = Some(route);
+                    continue;
+                }
+                Matcher::Pattern(re) if re.is_match(path) => usize::MAX,
+                Matcher::Prefix(p) if path::has_prefix(path, p) => p.len(),
+                _ => continue,
+            };
+            if !best.is_some_and(|(s, _)| score <= s) {
+                best = Some((score, route));
+            }
+        }
+        best.map(|(_, r)| r).or(fallback)
+    }
+}
+
+impl RouterBlock {
+    pub fn new() -> Self {
+        Self {
+            table: Mutex::new(None),
+        }
+    }
+
+    /// The compiled table for the current config, recompiling when it (or
+    /// the routes file's modification time) changed. The file is read only
+    /// then.
+    fn table(&self, ctx: &dyn Context) -> Result<Arc<RouteTable>, String> {
+        let source = TableSource::from_config(ctx, "routes_file", "routes", "[]");
+        let key = source.cache_key();
+        let mut cached = self.table.lock();
+        if cached.as_ref().map(|(k, _)| k) != Some(&key) {
+            let compiled = source
+                .read()
+                .and_then(|json| RouteTable::compile(&json))
+                .map(Arc::new);
+            if let Err(e) = &compiled {
+                tracing::error!("router: {}", e);
+            }
+            *cached = Some((key, compiled));
+        }
+        cached.as_ref().expect("route table just set").1.clone()
+    }
+}
+
+impl Block for RouterBlock {
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "@wafer/router".to_string(),
+            version: "0.1.0".to_string(),
+            interface: "middleware@v1".to_string(),
+            summary: "Declarative route table".to_string(),
+            instance_mode: InstanceMode::Singleton,
+            allowed_modes: Vec::new(),
+            admin_ui: crate::admin::ui(admin_descriptor()),
+        }
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let table = match self.table(ctx) {
+            Ok(t) => t,
+            Err(e) => return CoreError::custom(500, "router_misconfigured", &e).respond(msg),
+        };
+
+        let path = path::request_path(msg);
+        let method = meta::http_method(msg);
+        let route = match table.select(method.as_str(), &path) {
+            Some(r) => r,
+            None => return msg.clone().cont(),
+        };
+
+        msg.set_meta(ROUTE_NAME_META, &route.name);
+        if let Some(role) = &route.role {
+            msg.set_meta(ROUTE_ROLE_META, role);
+        }
+        if let Some(chain) = &route.forward_chain {
+            msg.set_meta(ROUTE_CHAIN_META, chain);
+        }
+        for (k, v) in &route.set_meta {
+            msg.set_meta(k, v);
+        }
+
+        msg.clone().cont()
+    }
+
+    fn lifecycle(
+        &self,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        if matches!(event.event_type, LifecycleType::Start) {
+            // Compile eagerly so a bad table is reported at startup
+            if let Ok(t) = self.table(ctx) {
+                tracing::info!("router: {} routes loaded", t.routes.len());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "routes",
+            FieldKind::Json,
+            "[]",
+            "Route table: a JSON list of routes",
+        )
+        .field(
+            "routes_file",
+            FieldKind::String,
+            "",
+            "File holding the route table; wins over `routes`",
+        )
+}
+
+pub fn register(w: &mut Wafer) {
+    register_as(w, "@wafer/router");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(RouterBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use serde_json::json;
+    use wafer_run::ChainDef;
+
+    /// Downstream handler echoing the route meta it was given.
+    struct Echo;
+
+    impl Block for Echo {
+        fn info(&self) -> BlockInfo {
+            BlockInfo {
+                name: "@test/echo".to_string(),
+                version: "0.1.0".to_string(),
+                interface: "handler@v1".to_string(),
+                summary: "Echoes route meta".to_string(),
+                instance_mode: InstanceMode::Singleton,
+                allowed_modes: Vec::new(),
+                admin_ui: None,
+            }
+        }
+
+        fn handle(&self, _ctx: &dyn Context, msg: &mut Message) -> Result_ {
+            let body = json!({
+                "name": msg.get_meta(ROUTE_NAME_META),
+                "role": msg.get_meta(ROUTE_ROLE_META),
+                "chain": msg.get_meta(ROUTE_CHAIN_META),
+                "cache": msg.get_meta("cache"),
+            });
+            json_respond(msg.clone(), 200, &body)
+        }
+
+        fn lifecycle(
+            &self,
+            _ctx: &dyn Context,
+            _event: LifecycleEvent,
+        ) -> std::result::Result<(), WaferError> {
+            Ok(())
+        }
+    }
+
+    fn routes() -> serde_json::Value {
+        json!([
+            {"name": "api", "prefix": "/api", "role": "user"},
+            {"name": "api-admin", "prefix": "/api/admin", "role": "admin"},
+            {"name": "report", "pattern": "/reports/[0-9]+", "forward_chain": "reports"},
+            {"name": "docs", "pattern": "/docs|/docs/[a-z]+"},
+            {"name": "off", "prefix": "/api/off", "enabled": false},
+            {"name": "writes", "prefix": "/api/items", "methods": ["POST"], "role": "writer"},
+            {"name": "site", "default": true, "set_meta": {"cache": "public"}},
+        ])
+    }
+
+    fn harness(routes: &serde_json::Value) -> ChainHarness {
+        let def: ChainDef = serde_json::from_value(json!({
+            "id": "routed",
+            "config": { "on_error": "stop" },
+            "root": {
+                "block": "@wafer/router",
+                "config": { "routes": routes.to_string() },
+                "next": [{
+                    "block": "@wafer/iam",
+                    "config": { "role_from_route": "true" },
+                    "next": [{ "block": "@test/echo" }],
+                }],
+            },
+        }))
+        .unwrap();
+        ChainHarness::new()
+            .with_chain(&def)
+            .with_block("@test/echo", Arc::new(Echo))
+    }
+
+    fn get(h: &ChainHarness, req: MockRequest) -> serde_json::Value {
+        let resp = h.run("routed", req.build());
+        assert_status(&resp, 200, None);
+        resp.json().unwrap()
+    }
+
+    fn as_user(req: MockRequest, roles: &str) -> MockRequest {
+        req.meta(meta::AUTH_USER_ID, "u1")
+            .meta(meta::AUTH_USER_ROLES, roles)
+    }
+
+    #[test]
+    fn selection_prefers_patterns_then_longest_prefix() {
+        let h = harness(&routes());
+        let got = get(&h, as_user(MockRequest::get("/api/admin/x"), "admin"));
+        assert_eq!(got["name"], "api-admin");
+        let got = get(&h, as_user(MockRequest::get("/api/off/x"), "user"));
+        assert_eq!(got["name"], "api");
+        let got = get(&h, as_user(MockRequest::post("/api/items"), "writer"));
+        assert_eq!(got["name"], "writes");
+
+        let got = get(&h, as_user(MockRequest::get("/api/items"), "user"));
+        assert_eq!(got["name"], "api");
+
+        let got = get(&h, as_user(MockRequest::get("/reports/42"), "admin"));
+        assert_eq!(got["name"], "report");
+        assert_eq!(got["chain"], "reports");
+        // Patterns must match the whole path
+        let got = get(&h, as_user(MockRequest::get("/reports/42/x"), "admin"));
+        assert_eq!(got["name"], "site");
+        assert_eq!(got["cache"], "public");
+        // Any alternative may match, not only the leftmost
+        let got = get(&h, as_user(MockRequest::get("/docs/intro"), "admin"));
+        assert_eq!(got["name"], "docs");
+    }
+
+    #[test]
+    fn downstream_iam_enforces_the_route_role() {
+        let h = harness(&routes());
+        let resp = h.run(
+            "routed",
+            as_user(MockRequest::get("/api/admin/x"), "user").build(),
+        );
+        assert_status(&resp, 403, Some("forbidden"));
+        let got = get(&h, as_user(MockRequest::get("/api/x"), "user"));
+        assert_eq!(got["role"], "user");
+        // Routes without a role still need the default one
+        let resp = h.run("routed", as_user(MockRequest::get("/x"), "user").build());
+        assert_status(&resp, 403, Some("forbidden"));
+    }
+
+    #[test]
+    fn invalid_tables_name_the_entry() {
+        let err = RouteTable::compile(r#"[{"prefix": "/a"}, {"prefix": "b"}]"#).err();
+        assert_eq!(err.as_deref(), Some("route 1: prefix must start with '/'"));
+        let err = RouteTable::compile(r#"[{"default": true}, {"default": true}]"#).err();
+        assert_eq!(err.as_deref(), Some("route 1: more than one default route"));
+
+        let h = harness(&json!([{"name": "x"}]));
+        let resp = h.run("routed", MockRequest::get("/").build());
+        assert_status(&resp, 500, Some("router_misconfigured"));
+    }
+
+    #[test]
+    fn routes_file_reloads_when_it_changes() {
+        let dir = TempDir::new().with_file("routes.json", br#"[{"name": "one", "prefix": "/"}]"#);
+        let file = dir.path().join("routes.json");
+        let block = RouterBlock::new();
+        let ctx = MockContext::new().with_config("routes_file", file.to_str().unwrap());
+        let name = |block: &RouterBlock| {
+            let mut msg = MockRequest::get("/x").build();
+            block.handle(&ctx, &mut msg);
+            msg.get_meta(ROUTE_NAME_META).to_string()
+        };
+        assert_eq!(name(&block), "one");
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        dir.write("routes.json", br#"[{"name": "two", "prefix": "/"}]"#);
+        assert_eq!(name(&block), "two");
+    }
+}
@@ -1,7 +1,29 @@
 use std::sync::Arc;
 use wafer_run::*;
 
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::meta;
+
 /// SecurityHeadersBlock adds standard security headers to responses.
+///
+/// Config: `csp` and `hsts` override the defaults (an empty value omits the
+/// header); `cache_control` sets a default Cache-Control. `link` adds `Link`
+/// entries (e.g. `<https://cdn.example.com>; rel=preconnect`), merged with
+/// those other blocks write.
+///
+/// `csp_report_uri` (e.g. `/_csp-report`, CspReportBlock's default path)
+/// adds a `report-uri` directive to the policy unless it already has one.
+///
+/// WebBlock's `csp_hash_inline` mode extends the policy set here with
+/// hashes of the inline scripts it serves, via `add_script_hashes`. The
+/// default policy's `'unsafe-inline'` is then ignored by browsers, so inline
+/// event handlers stop running on those pages.
+///
+/// `alt_svc_enabled: true` advertises HTTP/3 with an `Alt-Svc` header, the
+/// `alt_svc` value (default `h3=":443"; ma=86400`). The block can't tell
+/// which protocols the edge actually serves, so enable it only where the
+/// runtime or its proxy listens for h3 on the advertised port; clients that
+/// fail to connect fall back, but they retry for the whole `ma`.
 pub struct SecurityHeadersBlock {
     csp: String,
 }
@@ -14,6 +36,65 @@ impl SecurityHeadersBlock {
     }
 }
 
+/// `Alt-Svc` value advertised when `alt_svc` is not set.
+pub const DEFAULT_ALT_SVC: &str = "h3=\":443\"; ma=86400";
+
+/// Add CSP source expressions (e.g. `'sha256-...'`) to a policy's
+/// `script-src`. Without a `script-src`, one is created from `default-src`
+/// (or `'self'`) so the hashes don't loosen the fallback for other types.
+pub fn add_script_hashes(csp: &str, hashes: &[String]) -> String {
+    if hashes.is_empty() {
+        return csp.to_string();
+    }
+    let mut directives: Vec<String> = csp
+        .split(';')
+        .map(|d| d.trim().to_string())
+        .filter(|d| !d.is_empty())
+        .collect();
+    let find = |dirs: &[String], name: &str| {
+        dirs.iter().position(|d| {
+            d.split_whitespace()
+                .next()
+                .is_some_and(|n| n.eq_ignore_ascii_case(name))
+        })
+    };
+    let idx = match find(&directives, "script-src") {
+        Some(i) => i,
+        None => {
+            let base = find(&directives, "default-src")
+                .map(|i| directives[i]["default-src".len()..].trim().to_string())
+                .unwrap_or_else(|| "'self'".to_string());
+            directives.push(format!("script-src {}", base));
+            directives.len() - 1
+        }
+    };
+    for hash in hashes {
+        let source = format!("'{}'", hash);
+        if !directives[idx].split_whitespace().any(|s| s == source) {
+            directives[idx].push(' ');
+            directives[idx].push_str(&source);
+        }
+    }
+    directives.join("; ")
+}
+
+/// Add a `report-uri` directive to a policy that has none.
+pub fn add_report_uri(csp: &str, uri: &str) -> String {
+    let has_report_uri = csp.split(';').any(|d| {
+        d.split_whitespace()
+            .next()
+            .is_some_and(|n| n.eq_ignore_ascii_case("report-uri"))
+    });
+    if has_report_uri || uri.is_empty() {
+        return csp.to_string();
+    }
+    format!(
+        "{}; report-uri {}",
+        csp.trim_end().trim_end_matches(';'),
+        uri
+    )
+}
+
 impl Block for SecurityHeadersBlock {
     fn info(&self) -> BlockInfo {
         BlockInfo {
@@ -23,30 +104,58 @@ impl Block for SecurityHeadersBlock {
             summary: "Adds standard security headers to HTTP responses".to_string(),
             instance_mode: InstanceMode::Singleton,
             allowed_modes: Vec::new(),
-            admin_ui: None,
+            admin_ui: crate::admin::ui(admin_descriptor()),
         }
     }
 
     fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
         // Read CSP from config if available
-        let csp = ctx
+        let mut csp = ctx
             .config_get("csp")
             .map(|s| s.to_string())
             .unwrap_or_else(|| self.csp.clone());
+        if let Some(uri) = ctx.config_get("csp_report_uri").filter(|s| !s.is_empty()) {
+            if !csp.is_empty() {
+                csp = add_report_uri(&csp, uri.trim());
+            }
+        }
 
-        msg.set_meta("resp.header.X-Content-Type-Options", "nosniff");
-        msg.set_meta("resp.header.X-Frame-Options", "DENY");
-        msg.set_meta("resp.header.X-XSS-Protection", "1; mode=block");
-        msg.set_meta("resp.header.Referrer-Policy", "strict-origin-when-cross-origin");
-        msg.set_meta("resp.header.Content-Security-Policy", &csp);
-        msg.set_meta(
-            "resp.header.Strict-Transport-Security",
-            "max-age=31536000; includeSubDomains",
-        );
-        msg.set_meta(
-            "resp.header.Permissions-Policy",
+        let hsts = ctx
+            .config_get("hsts")
+            .unwrap_or("max-age=31536000; includeSubDomains");
+
+        meta::set_resp_header(msg, "X-Content-Type-Options", "nosniff");
+        meta::set_resp_header(msg, "X-Frame-Options", "DENY");
+        meta::set_resp_header(msg, "X-XSS-Protection", "1; mode=block");
+        meta::set_resp_header(msg, "Referrer-Policy", "strict-origin-when-cross-origin");
+        // An empty value disables the header
+        if !csp.is_empty() {
+            meta::set_resp_header(msg, "Content-Security-Policy", &csp);
+        }
+        if !hsts.is_empty() {
+            meta::set_resp_header(msg, "Strict-Transport-Security", hsts);
+        }
+        meta::set_resp_header(
+            msg,
+            "Permissions-Policy",
             "camera=(), microphone=(), geolocation=()",
         );
+        if let Some(cc) = ctx.config_get("cache_control").filter(|s| !s.is_empty()) {
+            meta::set_resp_header(msg, "Cache-Control", cc);
+        }
+        if let Some(link) = ctx.config_get("link").filter(|s| !s.is_empty()) {
+            meta::set_resp_header(msg, "Link", link);
+        }
+        let alt_svc_enabled = ctx
+            .config_get("alt_svc_enabled")
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+        if alt_svc_enabled {
+            let alt_svc = ctx.config_get("alt_svc").unwrap_or(DEFAULT_ALT_SVC).trim();
+            if !alt_svc.is_empty() {
+                meta::set_resp_header(msg, "Alt-Svc", alt_svc);
+            }
+        }
 
         msg.clone().cont()
     }
@@ -60,6 +169,100 @@ impl Block for SecurityHeadersBlock {
     }
 }
 
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "csp",
+            FieldKind::String,
+            "",
+            "Content-Security-Policy; a restrictive same-origin policy when unset",
+        )
+        .field(
+            "csp_report_uri",
+            FieldKind::String,
+            "",
+            "report-uri added to the policy, e.g. CspReportBlock's path",
+        )
+        .field(
+            "hsts",
+            FieldKind::String,
+            "max-age=31536000; includeSubDomains",
+            "Strict-Transport-Security value",
+        )
+        .field(
+            "cache_control",
+            FieldKind::String,
+            "",
+            "Cache-Control to set on every response",
+        )
+        .field(
+            "link",
+            FieldKind::String,
+            "",
+            "Link header to set on every response",
+        )
+        .field(
+            "alt_svc_enabled",
+            FieldKind::Bool,
+            "false",
+            "Advertise alt_svc; enable only where h3 is actually served",
+        )
+        .field(
+            "alt_svc",
+            FieldKind::String,
+            DEFAULT_ALT_SVC,
+            "Alt-Svc value, e.g. h3=\":443\"; ma=86400",
+        )
+}
+
 pub fn register(w: &mut Wafer) {
-    w.register_block("@wafer/security-headers", Arc::new(SecurityHeadersBlock::new()));
+    register_as(w, "@wafer/security-headers");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(SecurityHeadersBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(list: &[&str]) -> Vec<String> {
+        list.iter().map(|h| h.to_string()).collect()
+    }
+
+    #[test]
+    fn hashes_join_the_script_sources() {
+        let csp = "default-src 'self'; script-src 'self' 'unsafe-inline'; img-src *";
+        assert_eq!(
+            add_script_hashes(csp, &hashes(&["sha256-a", "sha256-b"])),
+            "default-src 'self'; script-src 'self' 'unsafe-inline' 'sha256-a' 'sha256-b'; img-src *"
+        );
+        assert_eq!(add_script_hashes(csp, &[]), csp);
+    }
+
+    #[test]
+    fn repeated_hashes_are_added_once() {
+        let csp = "script-src 'self' 'sha256-a'";
+        assert_eq!(
+            add_script_hashes(csp, &hashes(&["sha256-a", "sha256-b", "sha256-b"])),
+            "script-src 'self' 'sha256-a' 'sha256-b'"
+        );
+    }
+
+    #[test]
+    fn policies_without_script_src_get_one_from_the_fallback() {
+        assert_eq!(
+            add_script_hashes(
+                "default-src 'self' https://cdn.example; img-src *",
+                &hashes(&["sha256-a"])
+            ),
+            "default-src 'self' https://cdn.example; img-src *; script-src 'self' https://cdn.example 'sha256-a'"
+        );
+        assert_eq!(
+            add_script_hashes("img-src *", &hashes(&["sha256-a"])),
+            "img-src *; script-src 'self' 'sha256-a'"
+        );
+    }
 }
@@ -0,0 +1,259 @@
+use parking_lot::{Condvar, Mutex};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Drain timeout used when a `TaskSet` is dropped while tasks still run.
+pub const DEFAULT_DRAIN: Duration = Duration::from_secs(2);
+
+/// TaskSet owns a block's background interval tasks.
+///
+/// Blocks spawn their periodic work (cache sweeps, snapshot flushes) from
+/// `lifecycle` on `LifecycleType::Start` and call `stop` on
+/// `LifecycleType::Stop`. Each task runs on its own thread, ticking every
+/// interval until stopped; a panicking tick is logged and the task keeps
+/// going. `stop` waits up to a drain timeout for every task to finish its
+/// current tick, then detaches stragglers with a warning. Spawning a task
+/// whose name is already running is a no-op, so repeated Start events don't
+/// duplicate work.
+pub struct TaskSet {
+    owner: String,
+    tasks: Mutex<Vec<Task>>,
+}
+
+struct Task {
+    name: String,
+    signal: Arc<Signal>,
+    handle: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct Signal {
+    state: Mutex<TaskState>,
+    cv: Condvar,
+}
+
+#[derive(Default)]
+struct TaskState {
+    stop: bool,
+    done: bool,
+}
+
+impl TaskSet {
+    /// An empty set for the block named `owner` (used in log events).
+    pub fn new(owner: &str) -> Self {
+        Self {
+            owner: owner.to_string(),
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Run `tick` every `every` until the set is stopped. The first tick
+    /// happens one interval after spawning.
+    pub fn spawn_interval<F>(&self, name: &str, every: Duration, tick: F)
+    where
+        F: Fn() + Send + 'static,
+    {
+        let mut tasks = self.tasks.lock();
+        tasks.retain(|t| !t.signal.state.lock().done);
+        if tasks.iter().any(|t| t.name == name) {
+            return;
+        }
+
+        let signal = Arc::new(Signal::default());
+        let thread_signal = signal.clone();
+        let owner = self.owner.clone();
+        let task_name = name.to_string();
+        let every = every.max(Duration::from_millis(1));
+        let spawned = std::thread::Builder::new()
+            .name(format!("{}:{}", owner, task_name))
+            .spawn(move || {
+                run(&owner, &task_name, every, tick, &thread_signal);
+                let mut state = thread_signal.state.lock();
+                state.done = true;
+                thread_signal.cv.notify_all();
+            });
+        match spawned {
+            Ok(handle) => tasks.push(Task {
+                name: name.to_string(),
+                signal,
+                handle,
+            }),
+            Err(e) => tracing::error!(
+                owner = %self.owner,
+                task = name,
+                "failed to spawn background task: {}",
+                e
+            ),
+        }
+    }
+
+    /// Number of tasks still running.
+    pub fn running(&self) -> usize {
+        self.tasks
+            .lock()
+            .iter()
+            .filter(|t| !t.signal.state.lock().done)
+            .count()
+    }
+
+    /// Stop every task, waiting at most `drain` in total for them to finish.
+    pub fn stop(&self, drain: Duration) {
+        let tasks: Vec<Task> = std::mem::take(&mut *self.tasks.lock());
+        for task in &tasks {
+            task.signal.state.lock().stop = true;
+            task.signal.cv.notify_all();
+        }
+
+        let deadline = Instant::now() + drain;
+        for task in tasks {
+            let finished = {
+                let mut state = task.signal.state.lock();
+                while !state.done {
+                    if task.signal.cv.wait_until(&mut state, deadline).timed_out() {
+                        break;
+                    }
+                }
+                state.done
+            };
+            if finished {
+                let _ = task.handle.join();
+            } else {
+                tracing::warn!(
+                    owner = %self.owner,
+                    task = %task.name,
+                    "background task did not stop within {:?}; detaching",
+                    drain
+                );
+            }
+        }
+    }
+}
+
+impl Drop for TaskSet {
+    fn drop(&mut self) {
+        self.stop(DEFAULT_DRAIN);
+    }
+}
+
+fn run<F: Fn()>(owner: &str, name: &str, every: Duration, tick: F, signal: &Signal) {
+    tracing::debug!(owner, task = name, "background task started");
+    loop {
+        {
+            let deadline = Instant::now() + every;
+            let mut state = signal.state.lock();
+            while !state.stop && Instant::now() < deadline {
+                signal.cv.wait_until(&mut state, deadline);
+            }
+            if state.stop {
+                break;
+            }
+        }
+        let started = Instant::now();
+        match catch_unwind(AssertUnwindSafe(&tick)) {
+            Ok(()) => tracing::trace!(
+                owner,
+                task = name,
+                elapsed_ms = started.elapsed().as_millis() as u64,
+                "background task tick"
+            ),
+            Err(_) => tracing::error!(owner, task = name, "background task tick panicked"),
+        }
+    }
+    tracing::debug!(owner, task = name, "background task stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const TICK: Duration = Duration::from_millis(1);
+
+    fn wait_for(cond: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !cond() {
+            assert!(Instant::now() < deadline, "condition not reached in time");
+            std::thread::sleep(TICK);
+        }
+    }
+
+    fn counter(set: &TaskSet, name: &str) -> Arc<AtomicUsize> {
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let c = ticks.clone();
+        set.spawn_interval(name, TICK, move || {
+            c.fetch_add(1, Ordering::SeqCst);
+        });
+        ticks
+    }
+
+    #[test]
+    fn tasks_tick_and_stop_across_restarts() {
+        let set = TaskSet::new("test");
+        for _ in 0..3 {
+            let a = counter(&set, "a");
+            let b = counter(&set, "b");
+            assert_eq!(set.running(), 2);
+            wait_for(|| a.load(Ordering::SeqCst) >= 3 && b.load(Ordering::SeqCst) >= 3);
+
+            set.stop(Duration::from_secs(1));
+            assert_eq!(set.running(), 0);
+            let (a_after, b_after) = (a.load(Ordering::SeqCst), b.load(Ordering::SeqCst));
+            std::thread::sleep(Duration::from_millis(20));
+            assert_eq!(a.load(Ordering::SeqCst), a_after);
+            assert_eq!(b.load(Ordering::SeqCst), b_after);
+            // Only the counters' owners are left once the threads are joined
+            assert_eq!(Arc::strong_count(&a), 1);
+            assert_eq!(Arc::strong_count(&b), 1);
+        }
+    }
+
+    #[test]
+    fn repeated_spawns_of_a_running_task_are_ignored() {
+        let set = TaskSet::new("test");
+        let first = counter(&set, "sweep");
+        let second = counter(&set, "sweep");
+        assert_eq!(set.running(), 1);
+        wait_for(|| first.load(Ordering::SeqCst) >= 2);
+        assert_eq!(second.load(Ordering::SeqCst), 0);
+        assert_eq!(Arc::strong_count(&second), 1);
+    }
+
+    #[test]
+    fn panicking_ticks_keep_the_task_running() {
+        let set = TaskSet::new("test");
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let c = ticks.clone();
+        set.spawn_interval("flaky", TICK, move || {
+            if c.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("first tick fails");
+            }
+        });
+        wait_for(|| ticks.load(Ordering::SeqCst) >= 3);
+        assert_eq!(set.running(), 1);
+        set.stop(Duration::from_secs(1));
+        assert_eq!(set.running(), 0);
+    }
+
+    #[test]
+    fn stop_detaches_ticks_that_outlast_the_drain() {
+        let set = TaskSet::new("test");
+        let started = Arc::new(AtomicUsize::new(0));
+        let s = started.clone();
+        set.spawn_interval("slow", TICK, move || {
+            s.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(300));
+        });
+        wait_for(|| started.load(Ordering::SeqCst) >= 1);
+
+        let stopping = Instant::now();
+        set.stop(Duration::from_millis(10));
+        assert!(stopping.elapsed() < Duration::from_millis(250));
+        assert_eq!(set.running(), 0);
+
+        // The detached thread still finishes its tick and exits
+        wait_for(|| Arc::strong_count(&started) == 1);
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+    }
+}
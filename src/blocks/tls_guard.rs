@@ -0,0 +1,212 @@
+use std::sync::Arc;
+use wafer_run::*;
+
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::errors::CoreError;
+use crate::path;
+
+/// Header read when `tls_version_header` is not configured.
+pub const DEFAULT_TLS_HEADER: &str = "X-Forwarded-TLS-Version";
+
+/// Minimum accepted when `min_tls_version` is not configured.
+pub const DEFAULT_MIN_TLS_VERSION: &str = "1.2";
+
+/// TlsGuardBlock rejects requests the TLS-terminating proxy negotiated with
+/// an outdated protocol version.
+/// Configure via node config:
+/// {"tls_version_header": "X-Forwarded-TLS-Version", "min_tls_version": "1.2"}
+///
+/// The header may carry `TLSv1.2`, `TLS 1.3`, `1.2` or `SSLv3` style values.
+/// Versions below the minimum, and values that can't be read, are rejected
+/// with 403 `tls_version_rejected`. Requests without the header pass unless
+/// `require_header: true`. Only the proxy may set the header, so list it in
+/// trust-boundary's `strip_headers`.
+pub struct TlsGuardBlock;
+
+impl TlsGuardBlock {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+/// Parse a protocol version into a comparable `(major, minor)` TLS version;
+/// SSL versions order below every TLS version.
+fn parse_tls_version(raw: &str) -> Option<(u8, u8)> {
+    let lower = raw.trim().to_ascii_lowercase();
+    let (ssl, rest) = if let Some(r) = lower.strip_prefix("ssl") {
+        (true, r)
+    } else {
+        (false, lower.strip_prefix("tls").unwrap_or(&lower))
+    };
+    let rest = rest.trim_start_matches(['v', ' ', '_', '-']);
+    let mut parts = rest.split(['.', '_']);
+    let major = parts.next()?.parse::<u8>().ok()?;
+    let minor = match parts.next() {
+        Some(m) => m.parse::<u8>().ok()?,
+        None => 0,
+    };
+    if ssl {
+        // SSLv2/SSLv3 predate TLS 1.0
+        return Some((0, major));
+    }
+    Some((major, minor))
+}
+
+fn rejected(msg: &mut Message, message: &str) -> Result_ {
+    CoreError::custom(403, "tls_version_rejected", message).respond(msg)
+}
+
+impl Block for TlsGuardBlock {
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "@wafer/tls-guard".to_string(),
+            version: "0.1.0".to_string(),
+            interface: "middleware@v1".to_string(),
+            summary: "Rejects requests negotiated with outdated TLS versions".to_string(),
+            instance_mode: InstanceMode::Singleton,
+            allowed_modes: Vec::new(),
+            admin_ui: crate::admin::ui(admin_descriptor()),
+        }
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        if path::is_skipped(ctx, msg) {
+            return msg.clone().cont();
+        }
+        let header = ctx
+            .config_get("tls_version_header")
+            .unwrap_or(DEFAULT_TLS_HEADER);
+        let value = msg.header(header).trim().to_string();
+        if value.is_empty() {
+            let require = ctx
+                .config_get("require_header")
+                .map(|s| s == "true" || s == "1")
+                .unwrap_or(false);
+            if require {
+                return rejected(msg, "TLS version could not be determined");
+            }
+            return msg.clone().cont();
+        }
+
+        let min_raw = ctx
+            .config_get("min_tls_version")
+            .unwrap_or(DEFAULT_MIN_TLS_VERSION);
+        let min = parse_tls_version(min_raw).unwrap_or_else(|| {
+            tracing::warn!(
+                "tls-guard: invalid min_tls_version {:?}; using {}",
+                min_raw,
+                DEFAULT_MIN_TLS_VERSION
+            );
+            (1, 2)
+        });
+        match parse_tls_version(&value) {
+            Some(version) if version >= min => msg.clone().cont(),
+            Some(_) => rejected(
+                msg,
+                &format!(
+                    "TLS version {} is not supported; upgrade your client",
+                    value
+                ),
+            ),
+            None => rejected(msg, "TLS version could not be determined"),
+        }
+    }
+
+    fn lifecycle(
+        &self,
+        _ctx: &dyn Context,
+        _event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        Ok(())
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "tls_version_header",
+            FieldKind::String,
+            DEFAULT_TLS_HEADER,
+            "Request header carrying the negotiated TLS version",
+        )
+        .field(
+            "min_tls_version",
+            FieldKind::String,
+            DEFAULT_MIN_TLS_VERSION,
+            "Lowest TLS version accepted, e.g. 1.2",
+        )
+        .field(
+            "require_header",
+            FieldKind::Bool,
+            "false",
+            "Reject requests without the TLS version header",
+        )
+        .skip_paths()
+}
+
+pub fn register(w: &mut Wafer) {
+    register_as(w, "@wafer/tls-guard");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(TlsGuardBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn run(ctx: &MockContext, version: Option<&str>) -> SimulatedResponse {
+        let mut req = MockRequest::get("/api");
+        if let Some(v) = version {
+            req = req.header(DEFAULT_TLS_HEADER, v);
+        }
+        let mut msg = req.build();
+        SimulatedResponse::from_result(&TlsGuardBlock::new().handle(ctx, &mut msg))
+    }
+
+    #[test]
+    fn versions_parse_in_every_spelling() {
+        assert_eq!(parse_tls_version("TLSv1.2"), Some((1, 2)));
+        assert_eq!(parse_tls_version("TLS 1.3"), Some((1, 3)));
+        assert_eq!(parse_tls_version("1.2"), Some((1, 2)));
+        assert_eq!(parse_tls_version("tls1_1"), Some((1, 1)));
+        assert_eq!(parse_tls_version("SSLv3"), Some((0, 3)));
+        assert!(parse_tls_version("SSLv3") < parse_tls_version("TLSv1.0"));
+        for garbage in ["", "garbage", "TLSv", "1.x"] {
+            assert_eq!(parse_tls_version(garbage), None, "{}", garbage);
+        }
+    }
+
+    #[test]
+    fn old_versions_are_rejected() {
+        let ctx = MockContext::new();
+        for version in ["TLSv1.0", "TLSv1.1", "SSLv3", "garbage"] {
+            assert_status(&run(&ctx, Some(version)), 403, Some("tls_version_rejected"));
+        }
+        let strict = MockContext::new().with_config("min_tls_version", "TLSv1.3");
+        assert_status(&run(&strict, Some("TLSv1.2")), 403, None);
+    }
+
+    #[test]
+    fn modern_versions_pass() {
+        let ctx = MockContext::new();
+        for version in ["TLSv1.2", "TLS 1.3", "1.3"] {
+            assert_status(&run(&ctx, Some(version)), 200, None);
+        }
+    }
+
+    #[test]
+    fn missing_headers_pass_unless_required() {
+        assert_status(&run(&MockContext::new(), None), 200, None);
+        let ctx = MockContext::new().with_config("require_header", "true");
+        assert_status(&run(&ctx, None), 403, Some("tls_version_rejected"));
+        assert_status(&run(&ctx, Some("TLSv1.3")), 200, None);
+
+        // The version is read from the configured header only
+        let ctx = ctx.with_config("tls_version_header", "X-TLS");
+        assert_status(&run(&ctx, Some("TLSv1.3")), 403, None);
+    }
+}
@@ -0,0 +1,362 @@
+use parking_lot::RwLock;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use wafer_run::*;
+
+use crate::errors::Outcome;
+use crate::meta;
+use crate::path;
+
+/// Cookie carrying a debug token: a token signed by the crypto service with
+/// a `debug_trace: true` claim, which enables debug tracing per request.
+pub const DEBUG_COOKIE: &str = "wafer_debug";
+
+/// Claim a debug token must carry.
+pub const DEBUG_CLAIM: &str = "debug_trace";
+
+/// TracedBlock records the blocks a request passed through.
+///
+/// Every block registered through `blocks::register_as` is wrapped, so each
+/// one appends its registered name to `trace.blocks` and sets
+/// `trace.last_block` before it runs (app blocks can call `meta::trace_block`
+/// themselves). Requests whose path does not decode cleanly are answered
+/// with 400 before the block runs (see `path::check`). Error results are
+/// counted per block in the `TraceCounters` of the MonitoringBlock that saw
+/// the request (named by `trace.counted_by` meta), which reports them as
+/// `errors_by_block`. Those tagged with an `errors::Outcome` are also
+/// counted by outcome, once, by the wrapper of the block that produced them;
+/// monitoring reports them as `outcomes`. The status of every response a
+/// block produces (any result that does not continue the chain) is counted
+/// the same way, for monitoring's `status_counts` and `error_count`.
+/// Requests no monitoring block saw are not counted.
+///
+/// With `debug_trace: true` in a block's config, or a valid `wafer_debug`
+/// cookie, the request is marked for debugging and `CoreError` responses name
+/// the block that produced them in `X-Wafer-Block`. Without either, the
+/// header is never sent. A block's `messages_file` config selects the
+/// message catalog for the request's errors from then on (see `messages`).
+pub struct TracedBlock {
+    name: String,
+    inner: Arc<dyn Block>,
+}
+
+impl TracedBlock {
+    pub fn new(name: &str, inner: Arc<dyn Block>) -> Self {
+        Self {
+            name: name.to_string(),
+            inner,
+        }
+    }
+}
+
+/// Whether the request carries a valid debug token. The signature is
+/// checked once per request; the answer is kept in `trace.debug_cookie`.
+fn has_debug_cookie(ctx: &dyn Context, msg: &mut Message) -> bool {
+    match msg.get_meta(meta::TRACE_DEBUG_COOKIE) {
+        "true" => return true,
+        "false" => return false,
+        _ => {}
+    }
+    let valid = verify_debug_cookie(ctx, msg);
+    meta::set_flag(msg, meta::TRACE_DEBUG_COOKIE, valid);
+    valid
+}
+
+fn verify_debug_cookie(ctx: &dyn Context, msg: &Message) -> bool {
+    let token = msg.cookie(DEBUG_COOKIE);
+    if token.is_empty() {
+        return false;
+    }
+    let crypto = match ctx.services().and_then(|s| s.crypto.as_ref()) {
+        Some(c) => c,
+        None => return false,
+    };
+    match crypto.verify(token) {
+        Ok(claims) => claims.get(DEBUG_CLAIM).and_then(|v| v.as_bool()) == Some(true),
+        Err(_) => false,
+    }
+}
+
+impl Block for TracedBlock {
+    fn info(&self) -> BlockInfo {
+        self.inner.info()
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        meta::trace_block(msg, &self.name);
+        if !meta::flag(msg, meta::TRACE_DEBUG) {
+            let debug = ctx
+                .config_get("debug_trace")
+                .map(|s| s == "true" || s == "1")
+                .unwrap_or(false);
+            if debug || has_debug_cookie(ctx, msg) {
+                meta::set_flag(msg, meta::TRACE_DEBUG, true);
+            }
+        }
+
+        if let Some(file) = ctx.config_get("messages_file").filter(|f| !f.is_empty()) {
+            msg.set_meta(meta::MESSAGES_CATALOG, file);
+        }
+
+        // Every block must see the same decoded path, or none at all
+        let result = match path::check(msg) {
+            Ok(()) => self.inner.handle(ctx, msg),
+            Err(e) => e.respond(msg),
+        };
+        let responded = !matches!(result.action, Action::Continue);
+        let error = super::hooks::is_error_result(&result);
+        if responded || error {
+            if let Some(counters) = TraceCounters::of(msg) {
+                let last = result
+                    .message
+                    .as_ref()
+                    .map(|m| m.get_meta(meta::TRACE_LAST_BLOCK))
+                    .filter(|b| !b.is_empty())
+                    .unwrap_or(self.name.as_str());
+                if error {
+                    counters.errors.record(last);
+                }
+                // Nested wrappers see the inner block as last; only it counts
+                if last == self.name {
+                    if responded {
+                        counters
+                            .statuses
+                            .record(super::hooks::result_status(&result));
+                    }
+                    if let Some(outcome) = Outcome::of(&result).filter(|_| error) {
+                        counters.outcomes.record(outcome);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn lifecycle(
+        &self,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        self.inner.lifecycle(ctx, event)
+    }
+}
+
+/// Error results counted by the block that produced them.
+#[derive(Default)]
+pub struct ErrorCounts {
+    counts: RwLock<BTreeMap<String, Arc<AtomicU64>>>,
+}
+
+impl ErrorCounts {
+    fn record(&self, block: &str) {
+        if let Some(c) = self.counts.read().get(block) {
+            c.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.counts
+            .write()
+            .entry(block.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current counts by block name.
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.counts
+            .read()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// Error results counted by `errors::Outcome`.
+#[derive(Default)]
+pub struct OutcomeCounts {
+    counts: [AtomicU64; Outcome::ALL.len()],
+}
+
+impl OutcomeCounts {
+    fn record(&self, outcome: Outcome) {
+        self.counts[outcome.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current counts by outcome code, including outcomes not seen yet.
+    pub fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+        Outcome::ALL
+            .iter()
+            .map(|o| (o.code(), self.counts[o.index()].load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// Lowest and one past the highest status `StatusCounts` keeps.
+const STATUS_RANGE: std::ops::Range<u16> = 100..600;
+
+/// Responses counted by HTTP status.
+pub struct StatusCounts {
+    counts: Box<[AtomicU64]>,
+}
+
+impl Default for StatusCounts {
+    fn default() -> Self {
+        Self {
+            counts: STATUS_RANGE.map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl StatusCounts {
+    fn record(&self, status: u16) {
+        if STATUS_RANGE.contains(&status) {
+            self.counts[usize::from(status - STATUS_RANGE.start)].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Current counts of the statuses seen, keyed by status code.
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        STATUS_RANGE
+            .zip(self.counts.iter())
+            .map(|(status, c)| (status, c.load(Ordering::Relaxed)))
+            .filter(|(_, n)| *n > 0)
+            .map(|(status, n)| (status.to_string(), n))
+            .collect()
+    }
+}
+
+/// The error, outcome and status counts of one MonitoringBlock. The block
+/// owns them; `register` gives them an id, which the block puts in
+/// `trace.counted_by` meta so the `TracedBlock`s downstream find them.
+#[derive(Default)]
+pub struct TraceCounters {
+    errors: ErrorCounts,
+    outcomes: OutcomeCounts,
+    statuses: StatusCounts,
+}
+
+impl TraceCounters {
+    /// New counters and the id requests name them by. They stay reachable
+    /// by id for as long as the returned `Arc` (or a clone) lives.
+    pub fn register() -> (Arc<Self>, u64) {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        let counters = Arc::new(Self::default());
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let mut live = live_counters().write();
+        live.retain(|_, c| c.strong_count() > 0);
+        live.insert(id, Arc::downgrade(&counters));
+        (counters, id)
+    }
+
+    /// The counters the request is counted in, if a live monitoring block
+    /// tagged it.
+    pub fn of(msg: &Message) -> Option<Arc<Self>> {
+        let id = msg.get_meta(meta::TRACE_COUNTED_BY).parse::<u64>().ok()?;
+        live_counters().read().get(&id)?.upgrade()
+    }
+
+    pub fn errors(&self) -> &ErrorCounts {
+        &self.errors
+    }
+
+    pub fn outcomes(&self) -> &OutcomeCounts {
+        &self.outcomes
+    }
+
+    pub fn statuses(&self) -> &StatusCounts {
+        &self.statuses
+    }
+}
+
+/// Registered counters by id. Only weak references, so a dropped
+/// MonitoringBlock's counters go with it.
+fn live_counters() -> &'static RwLock<HashMap<u64, Weak<TraceCounters>>> {
+    static LIVE: OnceLock<RwLock<HashMap<u64, Weak<TraceCounters>>>> = OnceLock::new();
+    LIVE.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use serde_json::json;
+    use wafer_run::ChainDef;
+
+    /// Three wafer-core blocks, the last failing for want of authentication,
+    /// with `debug_trace` set on the middle one when `debug`.
+    fn harness(debug: bool) -> ChainHarness {
+        let cors_config = if debug {
+            json!({ "debug_trace": "true" })
+        } else {
+            json!({})
+        };
+        let def: ChainDef = serde_json::from_value(json!({
+            "id": "traced",
+            "config": { "on_error": "stop" },
+            "root": {
+                "block": "@wafer/security-headers",
+                "next": [{
+                    "block": "@wafer/cors",
+                    "config": cors_config,
+                    "next": [{ "block": "@wafer/iam" }],
+                }],
+            },
+        }))
+        .unwrap();
+        ChainHarness::new()
+            .with_services(MockServices::new().with_crypto())
+            .with_chain(&def)
+    }
+
+    fn run(harness: &ChainHarness, req: MockRequest) -> (SimulatedResponse, Message) {
+        let result = harness.run_result("traced", req.build());
+        let msg = result
+            .message
+            .clone()
+            .expect("errors keep the request message");
+        (SimulatedResponse::from_result(&result), msg)
+    }
+
+    #[test]
+    fn blocks_are_traced_in_chain_order() {
+        let (resp, msg) = run(&harness(false), MockRequest::get("/api"));
+        assert_status(&resp, 401, Some("unauthorized"));
+        assert_eq!(
+            msg.get_meta(meta::TRACE_BLOCKS),
+            "@wafer/security-headers,@wafer/cors,@wafer/iam"
+        );
+        assert_eq!(msg.get_meta(meta::TRACE_LAST_BLOCK), "@wafer/iam");
+        assert_no_header(&resp, "X-Wafer-Block");
+    }
+
+    #[test]
+    fn debug_trace_names_the_failing_block() {
+        let (resp, msg) = run(&harness(true), MockRequest::get("/api"));
+        assert_status(&resp, 401, Some("unauthorized"));
+        assert_header(&resp, "X-Wafer-Block", "@wafer/iam");
+        assert!(meta::flag(&msg, meta::TRACE_DEBUG));
+    }
+
+    #[test]
+    fn only_signed_debug_cookies_enable_debugging() {
+        let h = harness(false);
+        let valid = MockCrypto::token(json!({ "debug_trace": true }));
+        let (resp, _) = run(&h, MockRequest::get("/api").cookie(DEBUG_COOKIE, &valid));
+        assert_header(&resp, "X-Wafer-Block", "@wafer/iam");
+
+        let forged = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &json!({ "debug_trace": true }),
+            &jsonwebtoken::EncodingKey::from_secret(b"guessed"),
+        )
+        .unwrap();
+        let without_claim = MockCrypto::token(json!({ "debug_trace": false }));
+        for cookie in [forged.as_str(), without_claim.as_str(), "not-a-token"] {
+            let (resp, msg) = run(&h, MockRequest::get("/api").cookie(DEBUG_COOKIE, cookie));
+            assert_status(&resp, 401, Some("unauthorized"));
+            assert_no_header(&resp, "X-Wafer-Block");
+            assert!(!meta::flag(&msg, meta::TRACE_DEBUG));
+            assert_eq!(msg.get_meta(meta::TRACE_DEBUG_COOKIE), "false");
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+use wafer_run::*;
+
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::meta;
+use crate::net::CidrList;
+
+/// Headers stripped when `strip_headers` is not configured.
+const DEFAULT_STRIP_HEADERS: &str =
+    "X-Forwarded-For, X-Forwarded-Host, X-Forwarded-Proto, X-Real-IP, Forwarded, X-Request-Id";
+
+/// TrustBoundaryBlock removes inbound headers that only the edge may set.
+/// Configure via node config:
+/// {"strip_headers": "X-Forwarded-For, X-Request-Id", "trusted_proxies": "10.0.0.0/8"}
+///
+/// Requests from a `trusted_proxies` address keep their headers; from anyone
+/// else the `strip_headers` are dropped, so forwarding and correlation headers
+/// cannot be spoofed by clients. Place it first in the chain. The outcome is
+/// recorded in `trust.proxy` meta ("true"/"false").
+pub struct TrustBoundaryBlock {
+    proxies: Mutex<Option<(String, CidrList)>>,
+}
+
+impl TrustBoundaryBlock {
+    pub fn new() -> Self {
+        Self {
+            proxies: Mutex::new(None),
+        }
+    }
+
+    /// Whether `addr` is a trusted proxy, re-parsing the list only when the config changes.
+    fn is_trusted(&self, raw: &str, addr: &str) -> bool {
+        let mut cached = self.proxies.lock();
+        if cached.as_ref().map(|(k, _)| k.as_str()) != Some(raw) {
+            *cached = Some((raw.to_string(), CidrList::parse(raw)));
+        }
+        cached
+            .as_ref()
+            .is_some_and(|(_, list)| list.contains_addr(addr))
+    }
+}
+
+/// Blank request header `name` under the spellings a transport may have
+/// stored it in: as configured, lowercase, and `Canonical-Case`.
+fn clear_header(msg: &mut Message, name: &str) {
+    let canonical = name
+        .split('-')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => {
+                    first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase()
+                }
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("-");
+    for spelling in [name.to_string(), name.to_ascii_lowercase(), canonical] {
+        let key = format!("{}{}", meta::HTTP_HEADER_PREFIX, spelling);
+        if !msg.get_meta(&key).is_empty() {
+            msg.set_meta(&key, "");
+        }
+    }
+}
+
+impl Block for TrustBoundaryBlock {
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "@wafer/trust-boundary".to_string(),
+            version: "0.1.0".to_string(),
+            interface: "middleware@v1".to_string(),
+            summary: "Strips edge-only headers from untrusted clients".to_string(),
+            instance_mode: InstanceMode::Singleton,
+            allowed_modes: Vec::new(),
+            admin_ui: crate::admin::ui(admin_descriptor()),
+        }
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        let proxies = ctx.config_get("trusted_proxies").unwrap_or("");
+        let trusted = !proxies.is_empty() && self.is_trusted(proxies, msg.remote_addr());
+        meta::set_flag(msg, meta::TRUST_PROXY, trusted);
+        if trusted {
+            return msg.clone().cont();
+        }
+
+        let strip = ctx
+            .config_get("strip_headers")
+            .unwrap_or(DEFAULT_STRIP_HEADERS)
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty());
+        for name in strip {
+            if !msg.header(name).is_empty() {
+                clear_header(msg, name);
+                tracing::debug!("trust-boundary: stripped {} from untrusted client", name);
+            }
+        }
+
+        msg.clone().cont()
+    }
+
+    fn lifecycle(
+        &self,
+        _ctx: &dyn Context,
+        _event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        Ok(())
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "trusted_proxies",
+            FieldKind::List,
+            "",
+            "CIDRs of proxies whose forwarding headers are kept",
+        )
+        .field(
+            "strip_headers",
+            FieldKind::List,
+            DEFAULT_STRIP_HEADERS,
+            "Headers removed from requests not sent by a trusted proxy",
+        )
+}
+
+pub fn register(w: &mut Wafer) {
+    register_as(w, "@wafer/trust-boundary");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(TrustBoundaryBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn run(ctx: &MockContext, addr: &str) -> Message {
+        let mut msg = MockRequest::get("/")
+            .remote_addr(addr)
+            .header("x-forwarded-for", "1.2.3.4")
+            .header("X-Request-Id", "spoofed")
+            .header("Accept", "text/html")
+            .build();
+        let result = TrustBoundaryBlock::new().handle(ctx, &mut msg);
+        result.message.unwrap_or(msg)
+    }
+
+    #[test]
+    fn untrusted_clients_lose_edge_headers_in_any_case() {
+        let ctx = MockContext::new().with_config("trusted_proxies", "10.0.0.0/8");
+        let msg = run(&ctx, "203.0.113.9");
+        assert_eq!(msg.header("x-forwarded-for"), "");
+        assert_eq!(msg.header("X-Request-Id"), "");
+        assert_eq!(msg.header("Accept"), "text/html");
+        assert!(!meta::flag(&msg, meta::TRUST_PROXY));
+    }
+
+    #[test]
+    fn trusted_proxies_keep_them() {
+        let ctx = MockContext::new().with_config("trusted_proxies", "10.0.0.0/8");
+        let msg = run(&ctx, "10.1.2.3");
+        assert_eq!(msg.header("x-forwarded-for"), "1.2.3.4");
+        assert!(meta::flag(&msg, meta::TRUST_PROXY));
+    }
+}
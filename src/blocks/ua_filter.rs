@@ -0,0 +1,239 @@
+use parking_lot::Mutex;
+use regex::Regex;
+use std::sync::Arc;
+use wafer_run::*;
+
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::errors::CoreError;
+use crate::path;
+
+/// UaFilterBlock rejects requests whose User-Agent matches a deny list.
+/// Configure via node config:
+/// {"deny": "[\"curl\", \"(?i)scrapy\"]", "allow": "[\"Googlebot\"]", "empty_ua_action": "deny"}
+///
+/// Patterns are regexes, given as a JSON array or a comma-separated list.
+/// Allow patterns take precedence over deny patterns. `allow` without
+/// `deny` is an allowlist: only User-Agents matching it pass. Requests
+/// without a User-Agent follow `empty_ua_action` either way.
+pub struct UaFilterBlock {
+    deny_status: u16,
+    compiled: Mutex<Option<CompiledLists>>,
+}
+
+struct CompiledLists {
+    key: (String, String),
+    deny: Vec<Regex>,
+    allow: Vec<Regex>,
+}
+
+impl UaFilterBlock {
+    pub fn new() -> Self {
+        Self {
+            deny_status: 403,
+            compiled: Mutex::new(None),
+        }
+    }
+
+    /// Returns true if the user agent is allowed through.
+    fn is_allowed(&self, deny: &str, allow: &str, ua: &str) -> bool {
+        let mut compiled = self.compiled.lock();
+        let key = (deny.to_string(), allow.to_string());
+        if compiled.as_ref().map(|c| &c.key) != Some(&key) {
+            *compiled = Some(CompiledLists {
+                deny: compile_patterns(deny),
+                allow: compile_patterns(allow),
+                key,
+            });
+        }
+        let lists = compiled.as_ref().expect("compiled lists just set");
+
+        if lists.allow.iter().any(|re| re.is_match(ua)) {
+            return true;
+        }
+        // With only an allowlist, anything it doesn't match is refused
+        if lists.deny.is_empty() {
+            return lists.allow.is_empty();
+        }
+        !lists.deny.iter().any(|re| re.is_match(ua))
+    }
+}
+
+/// Parse a pattern list from config: a JSON array of strings, or comma-separated.
+fn parse_patterns(raw: &str) -> Vec<String> {
+    let raw = raw.trim();
+    if raw.starts_with('[') {
+        if let Ok(list) = serde_json::from_str::<Vec<String>>(raw) {
+            return list;
+        }
+    }
+    raw.split(',')
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+fn compile_patterns(raw: &str) -> Vec<Regex> {
+    parse_patterns(raw)
+        .into_iter()
+        .filter_map(|p| match Regex::new(&p) {
+            Ok(re) => Some(re),
+            Err(e) => {
+                tracing::warn!("ua-filter: ignoring invalid pattern '{}': {}", p, e);
+                None
+            }
+        })
+        .collect()
+}
+
+impl Block for UaFilterBlock {
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "@wafer/ua-filter".to_string(),
+            version: "0.1.0".to_string(),
+            interface: "middleware@v1".to_string(),
+            summary: "Rejects requests from denied User-Agents".to_string(),
+            instance_mode: InstanceMode::Singleton,
+            allowed_modes: Vec::new(),
+            admin_ui: crate::admin::ui(admin_descriptor()),
+        }
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        if path::is_skipped(ctx, msg) {
+            return msg.clone().cont();
+        }
+        let status = ctx
+            .config_get("deny_status")
+            .and_then(|s| s.parse::<u16>().ok())
+            .unwrap_or(self.deny_status);
+
+        let ua = msg.header("User-Agent").trim().to_string();
+        if ua.is_empty() {
+            let empty_action = ctx.config_get("empty_ua_action").unwrap_or("allow");
+            if empty_action == "deny" {
+                return CoreError::custom(status, "forbidden", "User-Agent required").respond(msg);
+            }
+            return msg.clone().cont();
+        }
+
+        let deny = ctx.config_get("deny").unwrap_or("");
+        let allow = ctx.config_get("allow").unwrap_or("");
+        if deny.trim().is_empty() && allow.trim().is_empty() {
+            return msg.clone().cont();
+        }
+
+        if !self.is_allowed(deny, allow, &ua) {
+            return CoreError::custom(status, "forbidden", "Access denied").respond(msg);
+        }
+
+        msg.clone().cont()
+    }
+
+    fn lifecycle(
+        &self,
+        _ctx: &dyn Context,
+        _event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        Ok(())
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field("deny", FieldKind::List, "", "User-Agent patterns to reject")
+        .field(
+            "allow",
+            FieldKind::List,
+            "",
+            "User-Agent patterns let through despite `deny`; alone, the only ones let through",
+        )
+        .field(
+            "deny_status",
+            FieldKind::Integer,
+            "403",
+            "Status sent to rejected clients",
+        )
+        .field(
+            "empty_ua_action",
+            FieldKind::one_of(&["allow", "deny"]),
+            "allow",
+            "What to do with requests without a User-Agent",
+        )
+        .skip_paths()
+}
+
+pub fn register(w: &mut Wafer) {
+    register_as(w, "@wafer/ua-filter");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(UaFilterBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn status(ctx: &MockContext, ua: Option<&str>) -> u16 {
+        let mut req = MockRequest::get("/");
+        if let Some(ua) = ua {
+            req = req.header("User-Agent", ua);
+        }
+        let mut msg = req.build();
+        SimulatedResponse::from_result(&UaFilterBlock::new().handle(ctx, &mut msg)).status
+    }
+
+    #[test]
+    fn deny_list_rejects_matches() {
+        let ctx = MockContext::new().with_config("deny", r#"["curl", "(?i)scrapy"]"#);
+        assert_eq!(status(&ctx, Some("curl/8.0")), 403);
+        assert_eq!(status(&ctx, Some("Scrapy/2.1")), 403);
+        assert_eq!(status(&ctx, Some("Mozilla/5.0")), 200);
+    }
+
+    #[test]
+    fn allow_beats_deny() {
+        let ctx = MockContext::new()
+            .with_config("deny", "(?i)bot")
+            .with_config("allow", "Googlebot");
+        assert_eq!(status(&ctx, Some("Googlebot/2.1")), 200);
+        assert_eq!(status(&ctx, Some("EvilBot/1.0")), 403);
+        assert_eq!(status(&ctx, Some("Mozilla/5.0")), 200);
+    }
+
+    #[test]
+    fn allow_alone_is_an_allowlist() {
+        let ctx = MockContext::new().with_config("allow", "^internal-agent/, ^healthcheck$");
+        assert_eq!(status(&ctx, Some("internal-agent/1.2")), 200);
+        assert_eq!(status(&ctx, Some("healthcheck")), 200);
+        assert_eq!(status(&ctx, Some("Mozilla/5.0")), 403);
+        // Anchored patterns aren't fooled by the name appearing later
+        assert_eq!(status(&ctx, Some("curl internal-agent/1.2")), 403);
+    }
+
+    #[test]
+    fn spoofed_agents_match_as_sent() {
+        let ctx = MockContext::new().with_config("deny", "curl");
+        assert_eq!(status(&ctx, Some("Mozilla/5.0 (compatible; curl)")), 403);
+        // Spoofing an allowed name passes; only the string is checked
+        let ctx = ctx.with_config("allow", "Googlebot");
+        assert_eq!(status(&ctx, Some("Googlebot curl")), 200);
+    }
+
+    #[test]
+    fn empty_agents_follow_empty_ua_action() {
+        let ctx = MockContext::new().with_config("deny", "curl");
+        assert_eq!(status(&ctx, None), 200);
+        assert_eq!(status(&ctx, Some("   ")), 200);
+        let ctx = ctx
+            .with_config("empty_ua_action", "deny")
+            .with_config("deny_status", "429");
+        assert_eq!(status(&ctx, None), 429);
+        let ctx = MockContext::new()
+            .with_config("allow", "Googlebot")
+            .with_config("empty_ua_action", "deny");
+        assert_eq!(status(&ctx, None), 403);
+    }
+}
@@ -0,0 +1,674 @@
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use wafer_run::*;
+
+use super::TableSource;
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::errors::CoreError;
+use crate::meta::{self, Method};
+use crate::path;
+
+/// Body size limit when `json_max_body_bytes` is not configured.
+pub const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+
+/// Field errors reported per request, at most.
+const MAX_ERRORS: usize = 50;
+
+/// ValidateJsonBlock rejects malformed request bodies before they reach app
+/// blocks.
+/// Configure via node config, `json_schemas` as a JSON object (or
+/// `json_schemas_file` pointing at one) mapping path prefixes to schemas:
+/// {"/api/users": {"type": "object", "required": ["email"],
+///   "properties": {"email": {"type": "string", "maxLength": 254}},
+///   "additionalProperties": false}}
+///
+/// Schemas are a JSON Schema subset: `type` (a name or list of names),
+/// `properties`, `required`, `additionalProperties` (a boolean or a schema),
+/// `items`, `enum`, `minimum`, `maximum`, `minLength`, `maxLength`,
+/// `minItems` and `maxItems`. Annotations (`$schema`, `title`,
+/// `description`, ...) are ignored; any other keyword is a compile error.
+///
+/// POST, PUT and PATCH requests under a configured prefix (the longest
+/// matching one applies) must send `application/json` or `*+json` (else 415
+/// `unsupported_media_type`) no larger than `json_max_body_bytes` (default
+/// 1 MiB, else 413). Invalid JSON gets 400; a body failing its schema gets 422
+/// `validation_failed` with `details.errors`, a list of `{"path", "message"}`
+/// where `path` is a JSON pointer. Valid bodies pass with
+/// `body.json_validated` set, so downstream blocks can deserialize without
+/// their own checks.
+///
+/// Schemas are compiled at lifecycle Start, and again only when the config
+/// or the `json_schemas_file`'s modification time changes. A compile error
+/// is logged with the offending prefix and keyword, and fails validated
+/// requests with 500 `validation_misconfigured`.
+pub struct ValidateJsonBlock {
+    schemas: Mutex<Option<(String, Result<Arc<SchemaSet>, String>)>>,
+}
+
+impl ValidateJsonBlock {
+    pub fn new() -> Self {
+        Self {
+            schemas: Mutex::new(None),
+        }
+    }
+
+    /// The compiled schemas for the current config, recompiling when it (or
+    /// the schemas file's modification time) changed. The file is read only
+    /// then.
+    fn schemas(&self, ctx: &dyn Context) -> Result<Arc<SchemaSet>, String> {
+        let source = TableSource::from_config(ctx, "json_schemas_file", "json_schemas", "{}");
+        let key = source.cache_key();
+        let mut cached = self.schemas.lock();
+        if cached.as_ref().map(|(k, _)| k) != Some(&key) {
+            let compiled = source
+                .read()
+                .and_then(|json| SchemaSet::compile(&json))
+                .map(Arc::new);
+            if let Err(e) = &compiled {
+                tracing::error!("validate-json: {}", e);
+            }
+            *cached = Some((key, compiled));
+        }
+        cached.as_ref().expect("schemas just set").1.clone()
+    }
+}
+
+/// Compiled schemas by path prefix.
+pub struct SchemaSet {
+    by_prefix: Vec<(String, Schema)>,
+}
+
+impl SchemaSet {
+    /// Compile a JSON object of prefix → schema; errors name the prefix and
+    /// the keyword at fault.
+    pub fn compile(json: &str) -> Result<Self, String> {
+        let raw: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(json).map_err(|e| format!("json_schemas: invalid JSON: {}", e))?;
+        let mut by_prefix = Vec::with_capacity(raw.len());
+        for (prefix, schema) in raw {
+            if !prefix.starts_with('/') {
+                return Err(format!("schema {}: prefix must start with '/'", prefix));
+            }
+            let schema =
+                Schema::compile(&schema, "").map_err(|e| format!("schema {}: {}", prefix, e))?;
+            by_prefix.push((path::normalize(&prefix, false), schema));
+        }
+        Ok(Self { by_prefix })
+    }
+
+    /// The schema for a (normalized) path, by longest prefix.
+    fn select(&self, path: &str) -> Option<&Schema> {
+        self.by_prefix
+            .iter()
+            .filter(|(p, _)| path::has_prefix(path, p))
+            .max_by_key(|(p, _)| p.len())
+            .map(|(_, s)| s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JsonType {
+    Null,
+    Boolean,
+    Integer,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+impl JsonType {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "null" => Self::Null,
+            "boolean" => Self::Boolean,
+            "integer" => Self::Integer,
+            "number" => Self::Number,
+            "string" => Self::String,
+            "array" => Self::Array,
+            "object" => Self::Object,
+            _ => return None,
+        })
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Null => "null",
+            Self::Boolean => "boolean",
+            Self::Integer => "integer",
+            Self::Number => "number",
+            Self::String => "string",
+            Self::Array => "array",
+            Self::Object => "object",
+        }
+    }
+
+    fn matches(&self, value: &serde_json::Value) -> bool {
+        use serde_json::Value;
+        match (self, value) {
+            (Self::Null, Value::Null)
+            | (Self::Boolean, Value::Bool(_))
+            | (Self::Number, Value::Number(_))
+            | (Self::String, Value::String(_))
+            | (Self::Array, Value::Array(_))
+            | (Self::Object, Value::Object(_)) => true,
+            (Self::Integer, Value::Number(n)) => {
+                n.is_i64() || n.is_u64() || n.as_f64().is_some_and(|f| f.fract() == 0.0)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether unlisted object properties are accepted.
+enum Additional {
+    Allowed,
+    Denied,
+    Schema(Box<Schema>),
+}
+
+/// One compiled schema node.
+struct Schema {
+    types: Vec<JsonType>,
+    properties: BTreeMap<String, Schema>,
+    required: Vec<String>,
+    additional: Additional,
+    items: Option<Box<Schema>>,
+    enum_values: Vec<serde_json::Value>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+}
+
+/// Keywords accepted and ignored.
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+];
+
+impl Schema {
+    /// Compile a schema node found at `at` (a JSON pointer into the schema).
+    fn compile(raw: &serde_json::Value, at: &str) -> Result<Self, String> {
+        let obj = raw
+            .as_object()
+            .ok_or_else(|| format!("{}: a schema must be an object", pointer(at)))?;
+        let mut schema = Schema {
+            types: Vec::new(),
+            properties: BTreeMap::new(),
+            required: Vec::new(),
+            additional: Additional::Allowed,
+            items: None,
+            enum_values: Vec::new(),
+            minimum: None,
+            maximum: None,
+            min_length: None,
+            max_length: None,
+            min_items: None,
+            max_items: None,
+        };
+        for (key, value) in obj {
+            let here = format!("{}/{}", at, key);
+            let invalid = |what: &str| format!("{}: {}", pointer(&here), what);
+            let count = || {
+                value
+                    .as_u64()
+                    .map(|n| n as usize)
+                    .ok_or_else(|| invalid("must be a non-negative integer"))
+            };
+            let number = || value.as_f64().ok_or_else(|| invalid("must be a number"));
+            match key.as_str() {
+                "type" => {
+                    let names: Vec<&str> = match value {
+                        serde_json::Value::String(s) => vec![s.as_str()],
+                        serde_json::Value::Array(arr) => arr
+                            .iter()
+                            .map(|v| v.as_str())
+                            .collect::<Option<Vec<_>>>()
+                            .ok_or_else(|| invalid("must be a type name or list of names"))?,
+                        _ => return Err(invalid("must be a type name or list of names")),
+                    };
+                    for name in names {
+                        let t = JsonType::parse(name)
+                            .ok_or_else(|| invalid(&format!("unknown type {:?}", name)))?;
+                        schema.types.push(t);
+                    }
+                }
+                "properties" => {
+                    let props = value
+                        .as_object()
+                        .ok_or_else(|| invalid("must be an object"))?;
+                    for (name, prop) in props {
+                        let prop = Schema::compile(prop, &format!("{}/{}", here, name))?;
+                        schema.properties.insert(name.clone(), prop);
+                    }
+                }
+                "required" => {
+                    schema.required = value
+                        .as_array()
+                        .and_then(|arr| {
+                            arr.iter()
+                                .map(|v| v.as_str().map(|s| s.to_string()))
+                                .collect::<Option<Vec<_>>>()
+                        })
+                        .ok_or_else(|| invalid("must be a list of property names"))?;
+                }
+                "additionalProperties" => {
+                    schema.additional = match value {
+                        serde_json::Value::Bool(true) => Additional::Allowed,
+                        serde_json::Value::Bool(false) => Additional::Denied,
+                        other => Additional::Schema(Box::new(Schema::compile(other, &here)?)),
+                    };
+                }
+                "items" => schema.items = Some(Box::new(Schema::compile(value, &here)?)),
+                "enum" => {
+                    schema.enum_values = value
+                        .as_array()
+                        .cloned()
+                        .ok_or_else(|| invalid("must be a list"))?;
+                }
+                "minimum" => schema.minimum = Some(number()?),
+                "maximum" => schema.maximum = Some(number()?),
+                "minLength" => schema.min_length = Some(count()?),
+                "maxLength" => schema.max_length = Some(count()?),
+                "minItems" => schema.min_items = Some(count()?),
+                "maxItems" => schema.max_items = Some(count()?),
+                k if ANNOTATIONS.contains(&k) => {}
+                _ => return Err(invalid("unsupported keyword")),
+            }
+        }
+        Ok(schema)
+    }
+
+    /// Validate `value` found at `at`, appending failures to `errors`.
+    fn validate(&self, value: &serde_json::Value, at: &str, errors: &mut Vec<FieldError>) {
+        use serde_json::Value;
+        if !self.types.is_empty() && !self.types.iter().any(|t| t.matches(value)) {
+            let names: Vec<&str> = self.types.iter().map(|t| t.as_str()).collect();
+            push_error(errors, at, format!("expected {}", names.join(" or ")));
+            return;
+        }
+        if !self.enum_values.is_empty() && !self.enum_values.contains(value) {
+            push_error(errors, at, "must be one of the allowed values".to_string());
+        }
+
+        match value {
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or(0.0);
+                if let Some(min) = self.minimum.filter(|min| n < *min) {
+                    push_error(errors, at, format!("must be at least {}", min));
+                }
+                if let Some(max) = self.maximum.filter(|max| n > *max) {
+                    push_error(errors, at, format!("must be at most {}", max));
+                }
+            }
+            Value::String(s) => {
+                let len = s.chars().count();
+                if let Some(min) = self.min_length.filter(|min| len < *min) {
+                    push_error(errors, at, format!("must be at least {} characters", min));
+                }
+                if let Some(max) = self.max_length.filter(|max| len > *max) {
+                    push_error(errors, at, format!("must be at most {} characters", max));
+                }
+            }
+            Value::Array(arr) => {
+                if let Some(min) = self.min_items.filter(|min| arr.len() < *min) {
+                    push_error(errors, at, format!("must have at least {} items", min));
+                }
+                if let Some(max) = self.max_items.filter(|max| arr.len() > *max) {
+                    push_error(errors, at, format!("must have at most {} items", max));
+                }
+                if let Some(items) = &self.items {
+                    for (i, item) in arr.iter().enumerate() {
+                        if errors.len() >= MAX_ERRORS {
+                            break;
+                        }
+                        items.validate(item, &format!("{}/{}", at, i), errors);
+                    }
+                }
+            }
+            Value::Object(obj) => {
+                for name in &self.required {
+                    if !obj.contains_key(name) {
+                        push_error(errors, at, format!("missing required property {:?}", name));
+                    }
+                }
+                for (name, field) in obj {
+                    if errors.len() >= MAX_ERRORS {
+                        break;
+                    }
+                    let here = format!("{}/{}", at, escape_pointer(name));
+                    match (self.properties.get(name), &self.additional) {
+                        (Some(schema), _) | (None, Additional::Schema(schema)) => {
+                            schema.validate(field, &here, errors)
+                        }
+                        (None, Additional::Allowed) => {}
+                        (None, Additional::Denied) => {
+                            push_error(errors, &here, "unknown property".to_string())
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Record a failure at `at`, keeping at most `MAX_ERRORS`.
+fn push_error(errors: &mut Vec<FieldError>, at: &str, message: String) {
+    if errors.len() < MAX_ERRORS {
+        errors.push(FieldError {
+            path: pointer(at),
+            message,
+        });
+    }
+}
+
+/// One validation failure.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct FieldError {
+    /// JSON pointer to the failing value ("/" for the body itself).
+    pub path: String,
+    pub message: String,
+}
+
+fn pointer(at: &str) -> String {
+    if at.is_empty() {
+        "/".to_string()
+    } else {
+        at.to_string()
+    }
+}
+
+/// Escape a property name for use in a JSON pointer (RFC 6901).
+fn escape_pointer(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+/// Whether a Content-Type names JSON (`application/json` or `*+json`).
+fn is_json_content_type(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+impl Block for ValidateJsonBlock {
+    fn info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "@wafer/validate-json".to_string(),
+            version: "0.1.0".to_string(),
+            interface: "middleware@v1".to_string(),
+            summary: "Validates JSON request bodies against per-path schemas".to_string(),
+            instance_mode: InstanceMode::Singleton,
+            allowed_modes: Vec::new(),
+            admin_ui: crate::admin::ui(admin_descriptor()),
+        }
+    }
+
+    fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        if path::is_skipped(ctx, msg) {
+            return msg.clone().cont();
+        }
+        if !matches!(
+            meta::http_method(msg),
+            Method::Post | Method::Put | Method::Patch
+        ) {
+            return msg.clone().cont();
+        }
+        let path = path::request_path(msg);
+        let schemas = match self.schemas(ctx) {
+            Ok(s) => s,
+            Err(e) => return CoreError::custom(500, "validation_misconfigured", &e).respond(msg),
+        };
+        let schema = match schemas.select(&path) {
+            Some(s) => s,
+            None => return msg.clone().cont(),
+        };
+
+        if !is_json_content_type(msg.header("Content-Type")) {
+            return CoreError::custom(
+                415,
+                "unsupported_media_type",
+                "Expected an application/json body",
+            )
+            .respond(msg);
+        }
+        let max_body = ctx
+            .config_get("json_max_body_bytes")
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_MAX_BODY);
+        if msg.data.len() > max_body {
+            return CoreError::PayloadTooLarge(format!("Request body exceeds {} bytes", max_body))
+                .respond(msg);
+        }
+        let body: serde_json::Value = match serde_json::from_slice(&msg.data) {
+            Ok(v) => v,
+            Err(e) => {
+                return CoreError::BadRequest(format!("Invalid JSON body: {}", e)).respond(msg)
+            }
+        };
+
+        let mut errors = Vec::new();
+        schema.validate(&body, "", &mut errors);
+        if !errors.is_empty() {
+            return CoreError::custom(422, "validation_failed", "Request body is invalid")
+                .respond_with_details(msg, serde_json::json!({ "errors": errors }));
+        }
+
+        meta::set_flag(msg, meta::BODY_JSON_VALIDATED, true);
+        msg.clone().cont()
+    }
+
+    fn lifecycle(
+        &self,
+        ctx: &dyn Context,
+        event: LifecycleEvent,
+    ) -> std::result::Result<(), WaferError> {
+        if matches!(event.event_type, LifecycleType::Start) {
+            // Compile eagerly so a bad schema is reported at startup
+            if let Ok(s) = self.schemas(ctx) {
+                tracing::info!("validate-json: {} schemas loaded", s.by_prefix.len());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "json_schemas",
+            FieldKind::Json,
+            "",
+            "JSON Schemas by route, checked against request bodies",
+        )
+        .field(
+            "json_schemas_file",
+            FieldKind::String,
+            "",
+            "File holding `json_schemas`",
+        )
+        .field(
+            "json_max_body_bytes",
+            FieldKind::Integer,
+            "1048576",
+            "Largest body validated",
+        )
+        .skip_paths()
+}
+
+pub fn register(w: &mut Wafer) {
+    register_as(w, "@wafer/validate-json");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(ValidateJsonBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use serde_json::json;
+
+    fn users_schema() -> String {
+        json!({
+            "/api/users": {
+                "type": "object",
+                "required": ["email"],
+                "properties": {
+                    "email": {"type": "string", "maxLength": 254},
+                    "address": {
+                        "type": "object",
+                        "required": ["city"],
+                        "properties": {"zip": {"type": ["string", "null"], "minLength": 5}},
+                    },
+                },
+                "additionalProperties": false,
+            }
+        })
+        .to_string()
+    }
+
+    fn ctx() -> MockContext {
+        MockContext::new().with_config("json_schemas", &users_schema())
+    }
+
+    fn post(ctx: &MockContext, body: &str) -> (SimulatedResponse, Message) {
+        let req = MockRequest::post("/api/users")
+            .header("Content-Type", "application/json")
+            .body(body.as_bytes());
+        run(ctx, req)
+    }
+
+    fn run(ctx: &MockContext, req: MockRequest) -> (SimulatedResponse, Message) {
+        let mut msg = req.build();
+        let result = ValidateJsonBlock::new().handle(ctx, &mut msg);
+        let out = result.message.clone().unwrap_or(msg);
+        (SimulatedResponse::from_result(&result), out)
+    }
+
+    /// The reported `(path, message)` pairs, sorted by path.
+    fn errors(resp: &SimulatedResponse) -> Vec<(String, String)> {
+        let mut errors: Vec<(String, String)> = resp.json().unwrap()["error"]["details"]["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                let field = |k: &str| e[k].as_str().unwrap().to_string();
+                (field("path"), field("message"))
+            })
+            .collect();
+        errors.sort();
+        errors
+    }
+
+    #[test]
+    fn valid_bodies_pass_marked_as_validated() {
+        let (resp, msg) = post(
+            &ctx(),
+            r#"{"email": "a@example.com", "address": {"city": "X"}}"#,
+        );
+        assert_status(&resp, 200, None);
+        assert!(meta::flag(&msg, meta::BODY_JSON_VALIDATED));
+    }
+
+    #[test]
+    fn nested_errors_point_at_the_failing_value() {
+        let (resp, _) = post(&ctx(), r#"{"email": 5, "address": {"zip": "123"}}"#);
+        assert_status(&resp, 422, Some("validation_failed"));
+        assert_eq!(
+            errors(&resp),
+            [
+                (
+                    "/address".to_string(),
+                    "missing required property \"city\"".to_string()
+                ),
+                (
+                    "/address/zip".to_string(),
+                    "must be at least 5 characters".to_string()
+                ),
+                ("/email".to_string(), "expected string".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let (resp, _) = post(&ctx(), r#"{"email": "a@example.com", "is_admin": true}"#);
+        assert_status(&resp, 422, Some("validation_failed"));
+        assert_eq!(
+            errors(&resp),
+            [("/is_admin".to_string(), "unknown property".to_string())]
+        );
+    }
+
+    #[test]
+    fn bodies_must_be_json_within_the_limit() {
+        let req = MockRequest::post("/api/users")
+            .header("Content-Type", "text/plain")
+            .body(b"{}");
+        assert_status(&run(&ctx(), req).0, 415, Some("unsupported_media_type"));
+        let req = MockRequest::post("/api/users")
+            .header(
+                "Content-Type",
+                "application/merge-patch+json; charset=utf-8",
+            )
+            .body(br#"{"email": "a@example.com"}"#);
+        assert_status(&run(&ctx(), req).0, 200, None);
+
+        let small = ctx().with_config("json_max_body_bytes", "8");
+        assert_status(&post(&small, r#"{"email": "a@example.com"}"#).0, 413, None);
+
+        assert_status(&post(&ctx(), r#"{"email": "#).0, 400, Some("bad_request"));
+    }
+
+    #[test]
+    fn other_methods_and_paths_are_not_checked() {
+        let (resp, msg) = run(&ctx(), MockRequest::get("/api/users"));
+        assert_status(&resp, 200, None);
+        assert!(!meta::flag(&msg, meta::BODY_JSON_VALIDATED));
+        let req = MockRequest::post("/api/other")
+            .header("Content-Type", "text/plain")
+            .body(b"x");
+        assert_status(&run(&ctx(), req).0, 200, None);
+    }
+
+    #[test]
+    fn schema_errors_are_misconfigurations() {
+        for schema in [
+            json!({"/api/users": {"type": ["string", 5]}}),
+            json!({"/api/users": {"type": "text"}}),
+            json!({"/api/users": {"pattern": "^a"}}),
+            json!({"api/users": {}}),
+        ] {
+            assert!(
+                SchemaSet::compile(&schema.to_string()).is_err(),
+                "{}",
+                schema
+            );
+        }
+        let err =
+            SchemaSet::compile(r#"{"/api/users": {"properties": {"a": {"type": ["string", 5]}}}}"#)
+                .err()
+                .unwrap();
+        assert!(err.contains("/properties/a/type"), "{}", err);
+
+        let ctx = MockContext::new()
+            .with_config("json_schemas", r#"{"/api/users": {"type": ["string", 5]}}"#);
+        assert_status(&post(&ctx, "{}").0, 500, Some("validation_misconfigured"));
+    }
+}
@@ -1,9 +1,126 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use unicode_normalization::UnicodeNormalization;
 use wafer_run::*;
 
+use super::mount::Mount;
+use super::security_headers;
+use crate::admin::{AdminDescriptor, FieldKind};
+use crate::clock::{self, Clock};
+use crate::errors::{self, html_escape, CoreError};
+use crate::meta;
+use crate::path::{self, PrefixList};
+use crate::precondition;
+
 /// WebBlock serves static files with intelligent caching and SPA support.
 /// Configure via node config: {"web_root": "./dist", "web_prefix": "/site", "web_spa": true}
+///
+/// In SPA mode, `spa_exclude` (comma-separated path prefixes, e.g. "/api") lists
+/// paths that return a real 404 instead of the SPA index.
+///
+/// `web_autoindex: true` lists directories without an index file, paginated by
+/// `?page=` (`autoindex_page_size`, default 100) and capped at
+/// `autoindex_max_entries` entries read (default 10000).
+///
+/// `timing_allow_origin` (`*` or a comma-separated origin list) emits
+/// `Timing-Allow-Origin` on served files so cross-origin pages can read their
+/// Resource Timing details. Unset by default.
+///
+/// Files, including the SPA index, carry a strong content-hash `ETag`, and a
+/// matching `If-None-Match` is answered with `304 Not Modified`. The index is
+/// still `no-cache`, so browsers always revalidate but skip re-downloading an
+/// unchanged index. Revalidation hits, misses and the bytes spared are
+/// counted in `cache_stats()`, which monitoring reports as `web_cache`.
+///
+/// A single `Range: bytes=...` is answered with `206 Partial Content` (or 416
+/// when it starts past the end), honoring `If-Range` against the ETag.
+/// Ranges apply to the bytes that would be served, whatever their source.
+/// `HEAD` gets the same status and headers as `GET` (including `206` with
+/// `Content-Range` for a `Range`), with the would-be `Content-Length` and no
+/// body.
+///
+/// `html_cache_control` overrides the `no-cache` sent for HTML (files and the
+/// SPA index), e.g. `"max-age=0, stale-while-revalidate=60, stale-if-error=86400"`.
+///
+/// `web_manifest` names a build manifest (relative to `web_root` unless
+/// absolute) mapping logical asset names to hashed files, either flat
+/// (`{"app.js": "app.8f3a2c.js"}`) or Vite-style (`{"app.js": {"file": ...}}`).
+/// It is loaded at Start and reloaded on every later lifecycle event, and
+/// belongs to the block instance and root it was read from, never the
+/// process. Requests for a logical name serve the hashed file with immutable
+/// caching, and `web_asset_substitution: true` rewrites `{{asset:app.js}}`
+/// in served HTML via [`AssetManifest::asset_url`].
+///
+/// `csp_hash_inline: true` hashes every inline `<script>` in served HTML and
+/// adds the `'sha256-...'` sources to the `script-src` of the policy set by
+/// SecurityHeadersBlock (which must run earlier in the chain). Browsers then
+/// ignore `'unsafe-inline'`, so only those exact scripts run. The default
+/// policy keeps `'unsafe-inline'` for pages that don't hash, but with hashes
+/// present inline event handlers (`onclick="..."`) and `javascript:` URLs
+/// stop running: move them into scripts before enabling this.
+///
+/// `web_preload` lists critical assets as comma-separated `href as` pairs,
+/// e.g. `"/app.js script, /app.css style, /font.woff2 font"`; HTML responses
+/// then carry matching `Link: <href>; rel=preload; as=...` entries, added to
+/// any `Link` set earlier in the chain (hrefs go through the asset manifest).
+/// The runtime has no 103 Early Hints support, so the hints ride on the final
+/// response.
+///
+/// `web_case_sensitive: true` serves a file only when every segment of the
+/// request path matches the on-disk name exactly, answering 404 for
+/// `README.MD` when the file is `README.md`. Case-insensitive filesystems
+/// (macOS, Windows) otherwise serve it, bypassing rules keyed on the exact
+/// name or extension. Off by default; it lists each directory on the path.
+///
+/// `protect_sourcemaps: true` answers `.map` requests with 404 unless the
+/// client's `auth.user_roles` include `sourcemap_role` (default
+/// `developer`), so run auth before web; served maps get `Cache-Control:
+/// private, no-store`. Off by default.
+///
+/// Files under `web_download_paths` (path prefixes) or with an extension in
+/// `web_download_extensions` (e.g. `"csv, zip, pdf"`) are sent with
+/// `Content-Disposition: attachment`, as are any requested with
+/// `?download=1` when `web_download_query: true`. The filename is the served
+/// file's own name (see [`content_disposition`]), never taken from the
+/// request. Browsers ignore the header for subresources, so such files still
+/// render in `<img>` and the like. Directory index files are always inline.
+///
+/// Files are looked up by the decoded, NFC-normalized request path (see
+/// `path::request_path`), so `%C3%BCrlaub.jpg` finds `ürlaub.jpg` however
+/// the client composed it; `web_unicode_normalization: nfd` looks names up
+/// decomposed instead, for roots on filesystems storing them that way.
+/// Paths decoding to invalid UTF-8 or control characters get 400, and
+/// Windows device names (`CON`, `NUL.txt`, `COM1`) 404 on every platform.
+///
+/// A `/favicon.ico` missing from the root is answered from `favicon` (a path
+/// relative to `web_root` unless absolute) when set and present, and with an
+/// empty 204 otherwise, so browsers' automatic requests don't log 404s.
+///
+/// Errors (404s, 416) go through `CoreError::respond_negotiated`: browsers
+/// get a small HTML page, API clients the usual JSON envelope.
+///
+/// `web_root_mode: versioned` serves deploys that upload each release into
+/// its own directory: `web_root` then holds release subdirectories plus a
+/// `current` pointer, either a symlink to a release or a file containing its
+/// name. The active release is re-read at most every `web_root_check_seconds`
+/// (default 5) and a request resolves it once, so every file it serves comes
+/// from the same release; `web_manifest` and `favicon` are read from the
+/// release. With `web_activate: true`, `POST /_web/activate` and
+/// `{"release": "2024-06-01"}` repoint `current` atomically (see
+/// [`activate_release`]); protect that path upstream, e.g. with an IAM role.
+///
+/// Requests whose action is other than `retrieve` get 405. A request with no
+/// action is served whatever its method, so static forms posting to a page
+/// keep working; `web_reject_writes: true` instead maps the method to an
+/// action (see `meta::resolved_action` and `method_actions`) and answers 405
+/// to POST, PUT, PATCH and DELETE.
+///
+/// Several instances with different defaults can be registered under aliases
+/// with [`super::register_as`], e.g. `WebBlock::new().with_root("./docs")`.
 pub struct WebBlock {
     default_root: String,
     default_prefix: String,
@@ -11,6 +128,42 @@ pub struct WebBlock {
     default_index: String,
     cache_max_age: u32,
     immutable_max_age: u32,
+    /// Last resolved release per versioned `web_root`.
+    releases: Mutex<HashMap<String, ActiveRelease>>,
+    /// What each plain `web_root` serves.
+    roots: Mutex<HashMap<String, Arc<Served>>>,
+    clock: Arc<dyn Clock>,
+}
+
+/// The release a versioned root serves, as last read from its pointer.
+struct ActiveRelease {
+    name: String,
+    checked: Instant,
+    served: Arc<Served>,
+}
+
+/// A directory requests are served from and the asset manifest read from
+/// it. The two are swapped as one, so a request never pairs one release's
+/// files with another's manifest.
+struct Served {
+    root: String,
+    manifest: Arc<AssetManifest>,
+}
+
+impl Served {
+    /// `root` with its `web_manifest`, if one is configured and readable.
+    fn load(ctx: &dyn Context, root: String) -> Self {
+        let manifest = Arc::new(load_manifest(ctx, Path::new(&root)));
+        Self { root, manifest }
+    }
+
+    /// Nothing to serve: no release is active yet.
+    fn none() -> Arc<Self> {
+        Arc::new(Self {
+            root: String::new(),
+            manifest: Arc::default(),
+        })
+    }
 }
 
 impl WebBlock {
@@ -22,15 +175,144 @@ impl WebBlock {
             default_index: "index.html".to_string(),
             cache_max_age: 3600,
             immutable_max_age: 31536000,
+            releases: Mutex::new(HashMap::new()),
+            roots: Mutex::new(HashMap::new()),
+            clock: clock::system(),
         }
     }
 
+    /// Time release pointer checks with `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Default `web_root` when node config doesn't set one.
+    pub fn with_root(mut self, root: &str) -> Self {
+        self.default_root = root.to_string();
+        self
+    }
+
+    /// Default `web_prefix` when node config doesn't set one.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.default_prefix = prefix.to_string();
+        self
+    }
+
+    /// Default `web_spa` when node config doesn't set one.
+    pub fn with_spa(mut self, spa: bool) -> Self {
+        self.default_spa = spa;
+        self
+    }
+
+    /// Default `web_index` when node config doesn't set one.
+    pub fn with_index(mut self, index: &str) -> Self {
+        self.default_index = index.to_string();
+        self
+    }
+
+    /// The directory requests are served from, with its manifest: `web_root`,
+    /// or in versioned mode the active release under it. The root is empty
+    /// when no release is active.
+    fn served(&self, ctx: &dyn Context) -> Arc<Served> {
+        let root = ctx.config_get("web_root").unwrap_or(&self.default_root);
+        if ctx.config_get("web_root_mode") != Some("versioned") {
+            let mut roots = self.roots.lock();
+            if let Some(served) = roots.get(root) {
+                return served.clone();
+            }
+            let served = Arc::new(Served::load(ctx, root.to_string()));
+            roots.insert(root.to_string(), served.clone());
+            return served;
+        }
+        let check_every = Duration::from_secs(
+            ctx.config_get("web_root_check_seconds")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(5),
+        );
+        let now = self.clock.now_instant();
+        let mut releases = self.releases.lock();
+        let cached = releases.get(root);
+        let due = cached.is_none_or(|r| now.duration_since(r.checked) >= check_every);
+        if due {
+            match current_release(Path::new(root)) {
+                Ok(name) => {
+                    let served = match cached {
+                        Some(r) if r.name == name => r.served.clone(),
+                        _ => {
+                            tracing::info!("web: serving release {} of {}", name, root);
+                            let dir = Path::new(root).join(&name).to_string_lossy().into_owned();
+                            Arc::new(Served::load(ctx, dir))
+                        }
+                    };
+                    releases.insert(
+                        root.to_string(),
+                        ActiveRelease {
+                            name,
+                            checked: now,
+                            served,
+                        },
+                    );
+                }
+                Err(e) => {
+                    // Keep serving the last good release until the pointer is fixed
+                    tracing::warn!("web: {}", e);
+                    if let Some(r) = releases.get_mut(root) {
+                        r.checked = now;
+                    }
+                }
+            }
+        }
+        releases
+            .get(root)
+            .map(|r| r.served.clone())
+            .unwrap_or_else(Served::none)
+    }
+
+    /// Current URL for a logical asset name under this block's root for
+    /// `ctx` (see [`AssetManifest::asset_url`]), for templates rendered
+    /// outside the block.
+    pub fn asset_url(&self, ctx: &dyn Context, name: &str) -> String {
+        self.served(ctx).manifest.asset_url(name)
+    }
+
+    /// Handle `POST /_web/activate`.
+    fn activate(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
+        if meta::http_method(msg) != meta::Method::Post {
+            return errors::method_not_allowed(msg, &["POST"]);
+        }
+        let release = match serde_json::from_slice::<serde_json::Value>(&msg.data)
+            .ok()
+            .and_then(|v| v.get("release")?.as_str().map(|s| s.to_string()))
+        {
+            Some(r) => r,
+            None => {
+                return CoreError::BadRequest("Expected {\"release\": \"<name>\"}".to_string())
+                    .respond(msg)
+            }
+        };
+        let root = ctx.config_get("web_root").unwrap_or(&self.default_root);
+        // Held across the swap so concurrent activations apply one at a time
+        let mut releases = self.releases.lock();
+        let previous = current_release(Path::new(root)).ok();
+        if let Err(e) = activate_release(Path::new(root), &release) {
+            return CoreError::BadRequest(e).respond(msg);
+        }
+        // The next request re-reads the pointer
+        releases.remove(root);
+        tracing::info!("web: activated release {} of {}", release, root);
+        json_respond(
+            msg.clone(),
+            200,
+            &serde_json::json!({ "release": release, "previous": previous }),
+        )
+    }
+
     fn get_config<'a>(&'a self, ctx: &'a dyn Context) -> WebConfig {
+        let served = self.served(ctx);
         WebConfig {
-            root: ctx
-                .config_get("web_root")
-                .unwrap_or(&self.default_root)
-                .to_string(),
+            root: served.root.clone(),
+            manifest: served.manifest.clone(),
             prefix: ctx
                 .config_get("web_prefix")
                 .unwrap_or(&self.default_prefix)
@@ -51,36 +333,149 @@ impl WebBlock {
                 .config_get("immutable_max_age")
                 .and_then(|s| s.parse().ok())
                 .unwrap_or(self.immutable_max_age),
+            html_cache_control: ctx
+                .config_get("html_cache_control")
+                .filter(|s| !s.trim().is_empty())
+                .unwrap_or("no-cache")
+                .to_string(),
+            spa_exclude: ctx
+                .config_get("spa_exclude")
+                .map(PrefixList::parse)
+                .unwrap_or_default(),
+            autoindex: ctx
+                .config_get("web_autoindex")
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(false),
+            autoindex_page_size: ctx
+                .config_get("autoindex_page_size")
+                .and_then(|s| s.parse::<usize>().ok())
+                .filter(|n| *n > 0)
+                .unwrap_or(100),
+            autoindex_max_entries: ctx
+                .config_get("autoindex_max_entries")
+                .and_then(|s| s.parse::<usize>().ok())
+                .filter(|n| *n > 0)
+                .unwrap_or(10_000),
+            timing_allow_origin: ctx
+                .config_get("timing_allow_origin")
+                .map(|s| {
+                    s.split(',')
+                        .map(|o| o.trim())
+                        .filter(|o| !o.is_empty())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                })
+                .unwrap_or_default(),
+            preload: ctx
+                .config_get("web_preload")
+                .map(parse_preload)
+                .unwrap_or_default(),
+            csp_hash_inline: ctx
+                .config_get("csp_hash_inline")
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(false),
+            asset_substitution: ctx
+                .config_get("web_asset_substitution")
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(false),
+            favicon: ctx.config_get("favicon").unwrap_or("").to_string(),
+            case_sensitive: ctx
+                .config_get("web_case_sensitive")
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(false),
+            protect_sourcemaps: ctx
+                .config_get("protect_sourcemaps")
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(false),
+            sourcemap_role: ctx
+                .config_get("sourcemap_role")
+                .unwrap_or(DEFAULT_SOURCEMAP_ROLE)
+                .to_string(),
+            unicode_form: ctx
+                .config_get("web_unicode_normalization")
+                .map(UnicodeForm::parse)
+                .unwrap_or(UnicodeForm::Nfc),
+            download_paths: ctx
+                .config_get("web_download_paths")
+                .map(PrefixList::parse)
+                .unwrap_or_default(),
+            download_extensions: ctx
+                .config_get("web_download_extensions")
+                .map(|s| {
+                    s.split(',')
+                        .map(|e| e.trim().trim_start_matches('.').to_ascii_lowercase())
+                        .filter(|e| !e.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            download_query: ctx
+                .config_get("web_download_query")
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(false),
         }
     }
 
     fn serve_file(msg: &mut Message, config: &WebConfig) -> Result_ {
-        let mut req_path = msg.path().to_string();
-
-        // Strip prefix
-        if !config.prefix.is_empty() {
-            if let Some(stripped) = req_path.strip_prefix(&config.prefix) {
-                req_path = stripped.to_string();
-            }
+        if let Err(e) = path::check(msg) {
+            return e.respond_negotiated(msg);
         }
+        // Strip prefix; under a MountedBlock the mount already did. Both give
+        // the decoded request path, so it is never decoded again here
+        let mounted = msg.get_meta(meta::MOUNT_PATH).to_string();
+        let mut req_path = if !mounted.is_empty() {
+            mounted
+        } else {
+            Mount::new(&config.prefix, true, false)
+                .matches(msg)
+                .unwrap_or_else(|| path::request_path(msg))
+        };
 
         // Default to index
         if req_path.is_empty() || req_path == "/" {
             req_path = format!("/{}", config.index_file);
         }
+        if config.unicode_form == UnicodeForm::Nfd {
+            req_path = req_path.nfd().collect();
+        }
 
         // Clean path to prevent traversal
-        let clean = clean_path(&req_path);
+        let mut clean = path::normalize(&req_path, false);
+
+        // Logical asset names resolve to the current hashed file
+        let mut immutable = false;
+        if let Some(file) = config.manifest.resolve(&clean) {
+            clean = path::normalize(file, false);
+            immutable = true;
+        }
+
+        // Source maps only for clients holding the configured role
+        if config.protect_sourcemaps
+            && is_sourcemap(&clean)
+            && !meta::user_roles(msg).contains(&config.sourcemap_role.as_str())
+        {
+            return CoreError::NotFound("Not found".to_string()).respond_negotiated(msg);
+        }
 
         // Block dotfiles
-        if clean.split('/').any(|seg| seg.starts_with('.') && seg.len() > 1) {
-            return err_not_found(msg.clone(), "Not found");
+        if clean
+            .split('/')
+            .any(|seg| seg.starts_with('.') && seg.len() > 1)
+        {
+            return CoreError::NotFound("Not found".to_string()).respond_negotiated(msg);
+        }
+
+        // Device names open a device rather than a file on Windows
+        if clean.split('/').any(is_reserved_name) {
+            return CoreError::NotFound("Not found".to_string()).respond_negotiated(msg);
         }
 
         // Resolve absolute path
         let abs_root = match std::fs::canonicalize(&config.root) {
             Ok(p) => p,
-            Err(_) => return err_not_found(msg.clone(), "Web root not found"),
+            Err(_) => {
+                return CoreError::NotFound("Web root not found".to_string())
+                    .respond_negotiated(msg)
+            }
         };
 
         let file_path = abs_root.join(clean.trim_start_matches('/'));
@@ -88,54 +483,369 @@ impl WebBlock {
         // Resolve symlinks and verify still within root
         let resolved = match std::fs::canonicalize(&file_path) {
             Ok(p) => p,
+            Err(_) if clean == "/favicon.ico" => return serve_favicon(msg, &abs_root, config),
             Err(_) => {
-                // If SPA mode, serve index.html for non-existent paths
-                if config.spa {
+                // If SPA mode, serve index.html for non-existent paths,
+                // except under excluded prefixes (e.g. /api) which get a real 404
+                if config.spa && !config.spa_exclude.matches(&clean) {
                     let index_path = abs_root.join(&config.index_file);
-                    return serve_index_spa(msg, &index_path);
+                    return serve_index_spa(msg, &index_path, config);
                 }
-                return err_not_found(msg.clone(), "File not found");
+                return CoreError::NotFound("File not found".to_string()).respond_negotiated(msg);
             }
         };
 
         if !resolved.starts_with(&abs_root) {
-            return err_not_found(msg.clone(), "Not found");
+            return CoreError::NotFound("Not found".to_string()).respond_negotiated(msg);
+        }
+
+        if config.case_sensitive && !case_matches(&abs_root, &clean) {
+            return CoreError::NotFound("File not found".to_string()).respond_negotiated(msg);
         }
 
         // Handle directories
         if resolved.is_dir() {
             let index = resolved.join(&config.index_file);
             if index.exists() {
-                return serve_static_file(msg, &index, config);
+                return serve_static_file(msg, &index, config, false, false);
+            }
+            if config.autoindex {
+                return serve_autoindex(msg, &resolved, &clean, config);
             }
-            return err_not_found(msg.clone(), "Not found");
+            return CoreError::NotFound("Not found".to_string()).respond_negotiated(msg);
+        }
+
+        let download = config.is_download(msg, &clean);
+        serve_static_file(msg, &resolved, config, immutable, download)
+    }
+}
+
+/// Unicode form request paths are looked up in. `path::request_path`
+/// decodes every request path to NFC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnicodeForm {
+    /// Composed, as most filesystems and clients store names (default).
+    Nfc,
+    /// Decomposed, for roots on filesystems that store names that way.
+    Nfd,
+}
+
+impl UnicodeForm {
+    /// Parse `web_unicode_normalization`; unknown values mean `Nfc`.
+    pub fn parse(raw: &str) -> Self {
+        match raw.trim().to_ascii_lowercase().as_str() {
+            "nfd" => Self::Nfd,
+            _ => Self::Nfc,
+        }
+    }
+}
+
+/// Whether a path segment is a Windows device name (`CON`, `NUL`, `COM1`,
+/// ...), with or without an extension.
+fn is_reserved_name(segment: &str) -> bool {
+    let stem = segment.split('.').next().unwrap_or("").trim_end();
+    let upper = stem.to_ascii_uppercase();
+    match upper.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => {
+            (upper.starts_with("COM") || upper.starts_with("LPT"))
+                && upper.len() == 4
+                && matches!(upper.as_bytes()[3], b'1'..=b'9')
+        }
+    }
+}
+
+/// A `Content-Disposition` value naming `filename`, e.g. `attachment`.
+/// Non-ASCII names get an RFC 5987 `filename*` (`UTF-8''...`) beside an
+/// ASCII `filename` fallback for old clients.
+pub fn content_disposition(disposition: &str, filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if fallback == filename {
+        return format!("{}; filename=\"{}\"", disposition, filename);
+    }
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition,
+        fallback,
+        urlencoding::encode(filename)
+    )
+}
+
+/// Whether each segment of `clean` names an entry under `root` with exactly
+/// that spelling, whatever the filesystem's case sensitivity.
+fn case_matches(root: &Path, clean: &str) -> bool {
+    let mut dir = root.to_path_buf();
+    for seg in clean.split('/').filter(|s| !s.is_empty()) {
+        let listed = std::fs::read_dir(&dir).is_ok_and(|mut entries| {
+            entries.any(|e| e.is_ok_and(|e| e.file_name() == std::ffi::OsStr::new(seg)))
+        });
+        if !listed {
+            return false;
+        }
+        dir.push(seg);
+    }
+    true
+}
+
+/// Pointer naming the active release of a versioned web root.
+pub const CURRENT_POINTER: &str = "current";
+
+/// Whether `name` can be a release directory directly under the root.
+fn is_release_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != CURRENT_POINTER
+        && !name.starts_with('.')
+        && !name.contains(['/', '\\'])
+}
+
+/// The release the `current` pointer under `root` names: the directory a
+/// symlink points at, or the name written in a pointer file.
+pub fn current_release(root: &Path) -> Result<String, String> {
+    let pointer = root.join(CURRENT_POINTER);
+    let unreadable = |e: std::io::Error| format!("cannot read {}: {}", pointer.display(), e);
+    let is_link = std::fs::symlink_metadata(&pointer)
+        .map_err(unreadable)?
+        .file_type()
+        .is_symlink();
+    let name = if is_link {
+        let target = std::fs::read_link(&pointer).map_err(unreadable)?;
+        target
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("")
+            .to_string()
+    } else {
+        std::fs::read_to_string(&pointer)
+            .map_err(unreadable)?
+            .trim()
+            .to_string()
+    };
+    if !is_release_name(&name) || !root.join(&name).is_dir() {
+        return Err(format!(
+            "{} names no release directory ({:?})",
+            pointer.display(),
+            name
+        ));
+    }
+    Ok(name)
+}
+
+/// Point `current` under `root` at `release`, a subdirectory of it. The new
+/// pointer is written beside the old one and renamed over it, so readers
+/// see either release, never a missing pointer. A symlink pointer stays a
+/// symlink; otherwise a pointer file is written.
+pub fn activate_release(root: &Path, release: &str) -> Result<(), String> {
+    if !is_release_name(release) || !root.join(release).is_dir() {
+        return Err(format!(
+            "release {:?} not found under {}",
+            release,
+            root.display()
+        ));
+    }
+    let pointer = root.join(CURRENT_POINTER);
+    let tmp = root.join(format!(".{}.{}.tmp", CURRENT_POINTER, std::process::id()));
+    let _ = std::fs::remove_file(&tmp);
+    let is_link = std::fs::symlink_metadata(&pointer).is_ok_and(|m| m.file_type().is_symlink());
+    let written = if is_link {
+        write_symlink(release, &tmp)
+    } else {
+        std::fs::write(&tmp, format!("{}\n", release))
+    };
+    written
+        .and_then(|_| std::fs::rename(&tmp, &pointer))
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&tmp);
+            format!("cannot update {}: {}", pointer.display(), e)
+        })
+}
+
+#[cfg(unix)]
+fn write_symlink(target: &str, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn write_symlink(target: &str, link: &Path) -> std::io::Result<()> {
+    // Pointer files work everywhere; symlinks need privileges on Windows
+    std::fs::write(link, format!("{}\n", target))
+}
+
+/// AssetManifest maps logical asset names to their hashed file names.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AssetManifest {
+    entries: HashMap<String, String>,
+}
+
+impl AssetManifest {
+    /// Parse a flat or Vite-style manifest.
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let value: serde_json::Value =
+            serde_json::from_str(json).map_err(|e| format!("invalid manifest: {}", e))?;
+        let obj = value
+            .as_object()
+            .ok_or_else(|| "manifest must be a JSON object".to_string())?;
+        let entries = obj
+            .iter()
+            .filter_map(|(name, v)| {
+                let file = v
+                    .as_str()
+                    .or_else(|| v.get("file").and_then(|f| f.as_str()))?;
+                Some((
+                    name.trim_start_matches('/').to_string(),
+                    file.trim_start_matches('/').to_string(),
+                ))
+            })
+            .collect();
+        Ok(Self { entries })
+    }
+
+    /// Read and parse a manifest file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Self::parse(&raw)
+    }
+
+    /// Hashed file for a logical name (leading `/` ignored).
+    pub fn resolve(&self, name: &str) -> Option<&str> {
+        self.entries
+            .get(name.trim_start_matches('/'))
+            .map(|s| s.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Current URL for a logical asset name, or the name itself when the
+    /// manifest has no entry. A leading `/` on `name` is kept.
+    pub fn asset_url(&self, name: &str) -> String {
+        match self.resolve(name) {
+            Some(file) if name.starts_with('/') => format!("/{}", file),
+            Some(file) => file.to_string(),
+            None => name.to_string(),
         }
+    }
 
-        serve_static_file(msg, &resolved, config)
+    /// Replace every `{{asset:NAME}}` in `html` with `asset_url(NAME)`.
+    pub fn substitute(&self, html: &str) -> String {
+        const OPEN: &str = "{{asset:";
+        let mut out = String::with_capacity(html.len());
+        let mut rest = html;
+        while let Some(start) = rest.find(OPEN) {
+            let after = &rest[start + OPEN.len()..];
+            let end = match after.find("}}") {
+                Some(e) => e,
+                None => break,
+            };
+            out.push_str(&rest[..start]);
+            out.push_str(&self.asset_url(after[..end].trim()));
+            rest = &after[end + 2..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Load the `web_manifest` under `root`; empty if none is configured or it
+/// can't be read.
+fn load_manifest(ctx: &dyn Context, root: &Path) -> AssetManifest {
+    let name = match ctx.config_get("web_manifest").filter(|s| !s.is_empty()) {
+        Some(n) => n,
+        None => return AssetManifest::default(),
+    };
+    match AssetManifest::load(&root.join(name)) {
+        Ok(m) => {
+            tracing::info!("Loaded asset manifest with {} entries", m.len());
+            m
+        }
+        Err(e) => {
+            tracing::warn!("Asset manifest not loaded: {}", e);
+            AssetManifest::default()
+        }
     }
 }
 
 struct WebConfig {
     root: String,
+    manifest: Arc<AssetManifest>,
     prefix: String,
     spa: bool,
     index_file: String,
     cache_max_age: u32,
     immutable_max_age: u32,
+    html_cache_control: String,
+    spa_exclude: PrefixList,
+    autoindex: bool,
+    autoindex_page_size: usize,
+    autoindex_max_entries: usize,
+    timing_allow_origin: String,
+    asset_substitution: bool,
+    csp_hash_inline: bool,
+    preload: Vec<(String, String)>,
+    favicon: String,
+    case_sensitive: bool,
+    protect_sourcemaps: bool,
+    sourcemap_role: String,
+    unicode_form: UnicodeForm,
+    download_paths: PrefixList,
+    download_extensions: Vec<String>,
+    download_query: bool,
 }
 
-fn clean_path(p: &str) -> String {
-    let mut parts: Vec<&str> = Vec::new();
-    for seg in p.split('/') {
-        match seg {
-            "" | "." => continue,
-            ".." => {
-                parts.pop();
-            }
-            s => parts.push(s),
+impl WebConfig {
+    /// Whether the file at `clean` is served as an attachment.
+    fn is_download(&self, msg: &Message, clean: &str) -> bool {
+        if self.download_paths.matches(clean) {
+            return true;
+        }
+        let ext = clean
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, ext)| ext.to_ascii_lowercase());
+        if ext.is_some_and(|ext| self.download_extensions.contains(&ext)) {
+            return true;
         }
+        self.download_query && matches!(msg.query("download"), "1" | "true")
     }
-    format!("/{}", parts.join("/"))
+}
+
+/// Role `protect_sourcemaps` requires when `sourcemap_role` is not set.
+pub const DEFAULT_SOURCEMAP_ROLE: &str = "developer";
+
+fn is_sourcemap(path: &str) -> bool {
+    path.to_ascii_lowercase().ends_with(".map")
+}
+
+/// Parse `web_preload` into (href, as) pairs.
+fn parse_preload(raw: &str) -> Vec<(String, String)> {
+    raw.split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(href), Some(kind)) => Some((href.to_string(), kind.to_ascii_lowercase())),
+                (Some(href), None) => {
+                    tracing::warn!("web_preload: '{}' has no `as` type; skipped", href);
+                    None
+                }
+                _ => None,
+            }
+        })
+        .collect()
 }
 
 fn mime_for_ext(path: &Path) -> String {
@@ -207,49 +917,480 @@ fn is_hashed_asset(path: &Path) -> bool {
     false
 }
 
-fn cache_control(path: &Path, content_type: &str, config: &WebConfig) -> String {
-    // HTML: always revalidate
+fn cache_control(path: &Path, content_type: &str, config: &WebConfig, immutable: bool) -> String {
+    // Protected source maps must not land in shared caches
+    if config.protect_sourcemaps && is_sourcemap(&path.to_string_lossy()) {
+        return "private, no-store".to_string();
+    }
+
+    // HTML: always revalidate unless the operator opted into stale serving
     if content_type.starts_with("text/html") {
-        return "no-cache".to_string();
+        return config.html_cache_control.clone();
     }
 
-    // Hashed assets: immutable
-    if is_hashed_asset(path) {
-        return format!(
-            "public, max-age={}, immutable",
-            config.immutable_max_age
-        );
+    // Hashed assets (or manifest-resolved ones): immutable
+    if immutable || is_hashed_asset(path) {
+        return format!("public, max-age={}, immutable", config.immutable_max_age);
     }
 
     // Everything else: standard cache
     format!("public, max-age={}", config.cache_max_age)
 }
 
-fn serve_static_file(msg: &mut Message, path: &PathBuf, config: &WebConfig) -> Result_ {
+/// Headers every served file carries regardless of type.
+fn set_asset_headers(m: &mut Message, config: &WebConfig) {
+    if !config.timing_allow_origin.is_empty() {
+        meta::set_resp_header(m, "Timing-Allow-Origin", &config.timing_allow_origin);
+    }
+}
+
+/// Strong ETag derived from the file contents.
+fn content_etag(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    let hex: String = Sha256::digest(data)
+        .iter()
+        .take(16)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("\"{}\"", hex)
+}
+
+/// Apply `{{asset:...}}` substitution to HTML when enabled.
+fn prepare_body(data: Vec<u8>, content_type: &str, config: &WebConfig) -> Vec<u8> {
+    if !config.asset_substitution || !content_type.starts_with("text/html") {
+        return data;
+    }
+    match String::from_utf8(data) {
+        Ok(html) => config.manifest.substitute(&html).into_bytes(),
+        Err(e) => e.into_bytes(),
+    }
+}
+
+/// SHA-256 CSP sources (`sha256-<base64>`) for the inline scripts in `html`.
+pub fn inline_script_hashes(html: &str) -> Vec<String> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use sha2::{Digest, Sha256};
+
+    let lower = html.to_ascii_lowercase();
+    let mut hashes = Vec::new();
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find("<script") {
+        let tag_start = pos + start;
+        let tag_end = match lower[tag_start..].find('>') {
+            Some(e) => tag_start + e + 1,
+            None => break,
+        };
+        let close = match lower[tag_end..].find("</script") {
+            Some(c) => tag_end + c,
+            None => break,
+        };
+        let tag = &lower[tag_start..tag_end];
+        let has_src = tag
+            .split(|c: char| c.is_whitespace() || c == '<')
+            .any(|attr| attr.starts_with("src="));
+        let content = &html[tag_end..close];
+        if !has_src && !content.is_empty() {
+            hashes.push(format!(
+                "sha256-{}",
+                STANDARD.encode(Sha256::digest(content.as_bytes()))
+            ));
+        }
+        pos = close;
+    }
+    hashes
+}
+
+/// Stamp `Link` preload hints on HTML responses.
+fn apply_preload(m: &mut Message, content_type: &str, config: &WebConfig) {
+    if config.preload.is_empty() || !content_type.starts_with("text/html") {
+        return;
+    }
+    let links: Vec<String> = config
+        .preload
+        .iter()
+        .map(|(href, kind)| {
+            // Fonts are always fetched in CORS mode, so the hint must say so too
+            let crossorigin = if kind == "font" { "; crossorigin" } else { "" };
+            format!(
+                "<{}>; rel=preload; as={}{}",
+                config.manifest.asset_url(href),
+                kind,
+                crossorigin
+            )
+        })
+        .collect();
+    meta::set_resp_header(m, "Link", &links.join(", "));
+}
+
+/// Add hashes of the inline scripts in an HTML body to the response's CSP.
+fn apply_csp_hashes(m: &mut Message, data: &[u8], content_type: &str, config: &WebConfig) {
+    if !config.csp_hash_inline || !content_type.starts_with("text/html") {
+        return;
+    }
+    let csp = meta::resp_header(m, "Content-Security-Policy").to_string();
+    if csp.is_empty() {
+        return;
+    }
+    let hashes = inline_script_hashes(&String::from_utf8_lossy(data));
+    meta::set_resp_header(
+        m,
+        "Content-Security-Policy",
+        &security_headers::add_script_hashes(&csp, &hashes),
+    );
+}
+
+/// Outcome of a `Range` request header against a body of known length.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// Serve the whole body: no range, or one we ignore (multiple or malformed).
+    Full,
+    /// Inclusive byte offsets to serve with 206.
+    Partial(usize, usize),
+    /// Answer 416.
+    Unsatisfiable,
+}
+
+/// Resolve a single `bytes=` range (`a-b`, `a-` or `-n`) against `len` bytes.
+fn parse_range(header: &str, len: usize) -> ByteRange {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(s) if !s.contains(',') => s.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return ByteRange::Full,
+    };
+    let parse = |s: &str| s.trim().parse::<usize>().ok();
+    match (start.trim().is_empty(), end.trim().is_empty()) {
+        // Suffix: the last n bytes
+        (true, false) => match parse(end) {
+            Some(0) => ByteRange::Unsatisfiable,
+            Some(_) if len == 0 => ByteRange::Unsatisfiable,
+            Some(n) => ByteRange::Partial(len.saturating_sub(n), len - 1),
+            None => ByteRange::Full,
+        },
+        (false, _) => {
+            let first = match parse(start) {
+                Some(f) => f,
+                None => return ByteRange::Full,
+            };
+            let last = if end.trim().is_empty() {
+                len.saturating_sub(1)
+            } else {
+                match parse(end) {
+                    Some(l) if l >= first => l.min(len.saturating_sub(1)),
+                    _ => return ByteRange::Full,
+                }
+            };
+            if first >= len {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(first, last)
+            }
+        }
+        (true, true) => ByteRange::Full,
+    }
+}
+
+/// Respond with `body`, or for HEAD with its length and no bytes.
+fn respond_body(mut m: Message, status: u16, body: &[u8], content_type: &str) -> Result_ {
+    if meta::http_method(&m) == meta::Method::Head {
+        meta::set_resp_header(&mut m, "Content-Length", &body.len().to_string());
+        return respond(m, status, Vec::new(), content_type);
+    }
+    respond(m, status, body.to_vec(), content_type)
+}
+
+/// Answer a request for `data`, honoring `Range` and `If-Range`.
+///
+/// Works on the final body bytes, so it applies the same wherever they came
+/// from (disk, a cache, embedded assets).
+fn respond_range(mut m: Message, data: &[u8], content_type: &str, etag: &str) -> Result_ {
+    meta::set_resp_header(&mut m, "Accept-Ranges", "bytes");
+    let range = m.header("Range").to_string();
+    let if_range = m.header("If-Range").trim().to_string();
+    // A stale If-Range validator means the client needs the whole new body
+    if range.is_empty() || (!if_range.is_empty() && if_range != etag) {
+        return respond_body(m, 200, data, content_type);
+    }
+    match parse_range(&range, data.len()) {
+        ByteRange::Full => respond_body(m, 200, data, content_type),
+        ByteRange::Partial(first, last) => {
+            meta::set_resp_header(
+                &mut m,
+                "Content-Range",
+                &format!("bytes {}-{}/{}", first, last, data.len()),
+            );
+            respond_body(m, 206, &data[first..=last], content_type)
+        }
+        ByteRange::Unsatisfiable => {
+            meta::set_resp_header(&mut m, "Content-Range", &format!("bytes */{}", data.len()));
+            CoreError::custom(
+                416,
+                "range_not_satisfiable",
+                "Requested range not satisfiable",
+            )
+            .respond_negotiated(&m)
+        }
+    }
+}
+
+/// Conditional request outcomes for files served with an ETag.
+#[derive(Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bytes_saved: AtomicU64,
+}
+
+/// Snapshot of `CacheStats`.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct CacheStatsSnapshot {
+    /// Requests answered with 304 because the client's copy was current.
+    pub hits: u64,
+    /// Requests that had to send the body.
+    pub misses: u64,
+    /// Body bytes not sent thanks to hits.
+    pub bytes_saved: u64,
+}
+
+impl CacheStatsSnapshot {
+    /// Fraction of requests that were hits, 0 when there were none.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+impl CacheStats {
+    fn record(&self, hit: bool, body_len: usize) {
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            self.bytes_saved
+                .fetch_add(body_len as u64, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> CacheStatsSnapshot {
+        CacheStatsSnapshot {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bytes_saved: self.bytes_saved.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The process-wide conditional request counters of every WebBlock.
+pub fn cache_stats() -> &'static CacheStats {
+    static STATS: OnceLock<CacheStats> = OnceLock::new();
+    STATS.get_or_init(CacheStats::default)
+}
+
+/// Tag the response with an ETag and answer 304 when the client already has it.
+fn respond_cached(mut m: Message, data: Vec<u8>, content_type: &str) -> Result_ {
+    let etag = content_etag(&data);
+    meta::set_resp_header(&mut m, "ETag", &etag);
+    let hit = precondition::list_matches(m.header("If-None-Match"), &etag, false);
+    cache_stats().record(hit, data.len());
+    if hit {
+        return respond(m, 304, Vec::new(), content_type);
+    }
+    respond_range(m, &data, content_type, &etag)
+}
+
+/// Serve a file; with `download`, as an attachment named after it.
+fn serve_static_file(
+    msg: &mut Message,
+    path: &PathBuf,
+    config: &WebConfig,
+    immutable: bool,
+    download: bool,
+) -> Result_ {
     let data = match std::fs::read(path) {
         Ok(d) => d,
-        Err(_) => return err_not_found(msg.clone(), "File not found"),
+        Err(_) => return CoreError::NotFound("File not found".to_string()).respond_negotiated(msg),
     };
 
     let content_type = mime_for_ext(path);
-    let cc = cache_control(path, &content_type, config);
+    let cc = cache_control(path, &content_type, config, immutable);
+    let data = prepare_body(data, &content_type, config);
 
     let mut m = msg.clone();
-    m.set_meta("resp.header.Cache-Control", &cc);
+    meta::set_resp_header(&mut m, "Cache-Control", &cc);
+    set_asset_headers(&mut m, config);
+    if download {
+        // Named from the file on disk, never from the request
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name: String = name.chars().filter(|c| !c.is_control()).collect();
+        meta::set_resp_header(
+            &mut m,
+            "Content-Disposition",
+            &content_disposition("attachment", &name),
+        );
+    }
+    apply_csp_hashes(&mut m, &data, &content_type, config);
+    apply_preload(&mut m, &content_type, config);
+
+    respond_cached(m, data, &content_type)
+}
+
+/// Answer a `/favicon.ico` the root lacks: the `favicon` file, or 204.
+fn serve_favicon(msg: &mut Message, abs_root: &Path, config: &WebConfig) -> Result_ {
+    if !config.favicon.is_empty() {
+        let fallback = abs_root.join(&config.favicon);
+        if fallback.is_file() {
+            return serve_static_file(msg, &fallback, config, false, false);
+        }
+        tracing::debug!("web: favicon {} not found", fallback.display());
+    }
+    let mut m = msg.clone();
+    meta::set_resp_header(
+        &mut m,
+        "Cache-Control",
+        &format!("public, max-age={}", config.cache_max_age),
+    );
+    respond(m, 204, Vec::new(), "")
+}
+
+struct DirEntryInfo {
+    name: String,
+    is_dir: bool,
+    size: u64,
+}
+
+/// Render a paginated HTML listing of a directory.
+///
+/// At most `autoindex_max_entries` entries are read so pathological
+/// directories can't stall the server; entries are sorted directories first,
+/// then by name, so pages are stable between requests.
+fn serve_autoindex(msg: &mut Message, dir: &Path, clean: &str, config: &WebConfig) -> Result_ {
+    let read_dir = match std::fs::read_dir(dir) {
+        Ok(r) => r,
+        Err(_) => return CoreError::NotFound("Not found".to_string()).respond_negotiated(msg),
+    };
+
+    let mut entries = Vec::new();
+    let mut truncated = false;
+    for entry in read_dir.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        if entries.len() >= config.autoindex_max_entries {
+            truncated = true;
+            break;
+        }
+        let meta = entry.metadata().ok();
+        entries.push(DirEntryInfo {
+            name,
+            is_dir: meta.as_ref().is_some_and(|m| m.is_dir()),
+            size: meta.as_ref().map(|m| m.len()).unwrap_or(0),
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
 
-    respond(m, 200, data, &content_type)
+    let total = entries.len();
+    let page_size = config.autoindex_page_size;
+    let pages = total.div_ceil(page_size).max(1);
+    let page = msg
+        .query("page")
+        .parse::<usize>()
+        .ok()
+        .filter(|p| *p >= 1)
+        .unwrap_or(1)
+        .min(pages);
+
+    let url_dir = format!(
+        "{}{}",
+        config.prefix.trim_end_matches('/'),
+        if clean.ends_with('/') {
+            clean.to_string()
+        } else {
+            format!("{}/", clean)
+        }
+    );
+    let title = html_escape(&url_dir);
+
+    let mut html = String::with_capacity(256 + page_size * 96);
+    html.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of ");
+    html.push_str(&title);
+    html.push_str("</title></head><body>\n<h1>Index of ");
+    html.push_str(&title);
+    html.push_str("</h1>\n<p>");
+    html.push_str(&format!(
+        "{} entr{}{}, page {} of {}",
+        total,
+        if total == 1 { "y" } else { "ies" },
+        if truncated {
+            " (listing truncated)"
+        } else {
+            ""
+        },
+        page,
+        pages
+    ));
+    html.push_str("</p>\n<ul>\n");
+    if clean != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for e in entries.iter().skip((page - 1) * page_size).take(page_size) {
+        let href = format!(
+            "{}{}",
+            urlencoding::encode(&e.name),
+            if e.is_dir { "/" } else { "" }
+        );
+        let label = html_escape(&e.name);
+        if e.is_dir {
+            html.push_str(&format!("<li><a href=\"{}\">{}/</a></li>\n", href, label));
+        } else {
+            html.push_str(&format!(
+                "<li><a href=\"{}\">{}</a> ({} bytes)</li>\n",
+                href, label, e.size
+            ));
+        }
+    }
+    html.push_str("</ul>\n<p>");
+    if page > 1 {
+        html.push_str(&format!("<a href=\"?page={}\">&laquo; prev</a> ", page - 1));
+    }
+    if page < pages {
+        html.push_str(&format!("<a href=\"?page={}\">next &raquo;</a>", page + 1));
+    }
+    html.push_str("</p>\n</body></html>\n");
+
+    let mut m = msg.clone();
+    meta::set_resp_header(&mut m, "Cache-Control", "no-cache");
+    respond(m, 200, html.into_bytes(), "text/html; charset=utf-8")
 }
 
-fn serve_index_spa(msg: &mut Message, index_path: &PathBuf) -> Result_ {
+fn serve_index_spa(msg: &mut Message, index_path: &PathBuf, config: &WebConfig) -> Result_ {
     let data = match std::fs::read(index_path) {
         Ok(d) => d,
-        Err(_) => return err_not_found(msg.clone(), "Index file not found"),
+        Err(_) => {
+            return CoreError::NotFound("Index file not found".to_string()).respond_negotiated(msg)
+        }
     };
 
     let mut m = msg.clone();
-    m.set_meta("resp.header.Cache-Control", "no-cache");
+    meta::set_resp_header(&mut m, "Cache-Control", &config.html_cache_control);
+    set_asset_headers(&mut m, config);
 
-    respond(m, 200, data, "text/html; charset=utf-8")
+    // The index is the same bytes for every SPA route, so one ETag covers them all
+    let content_type = "text/html; charset=utf-8";
+    let data = prepare_body(data, content_type, config);
+    apply_csp_hashes(&mut m, &data, content_type, config);
+    apply_preload(&mut m, content_type, config);
+    respond_cached(m, data, content_type)
 }
 
 impl Block for WebBlock {
@@ -261,15 +1402,32 @@ impl Block for WebBlock {
             summary: "Static file server with caching and SPA support".to_string(),
             instance_mode: InstanceMode::Singleton,
             allowed_modes: vec![InstanceMode::PerNode],
-            admin_ui: None,
+            admin_ui: crate::admin::ui(admin_descriptor()),
         }
     }
 
     fn handle(&self, ctx: &dyn Context, msg: &mut Message) -> Result_ {
-        // Only handle GET requests
-        let action = msg.action();
+        if path::request_path(msg) == "/_web/activate"
+            && ctx.config_get("web_root_mode") == Some("versioned")
+            && ctx
+                .config_get("web_activate")
+                .is_some_and(|s| s == "true" || s == "1")
+        {
+            return self.activate(ctx, msg);
+        }
+
+        // Only handle GET requests. A method-only POST is served unless
+        // `web_reject_writes` asks for the method to count as its action.
+        let action = if ctx
+            .config_get("web_reject_writes")
+            .is_some_and(|s| s == "true" || s == "1")
+        {
+            meta::resolved_action(ctx, msg)
+        } else {
+            meta::action(msg).to_string()
+        };
         if !action.is_empty() && action != "retrieve" {
-            return error(msg.clone(), 405, "method_not_allowed", "Only GET is supported");
+            return errors::method_not_allowed(msg, &["GET", "HEAD"]);
         }
 
         let config = self.get_config(ctx);
@@ -283,18 +1441,733 @@ impl Block for WebBlock {
     ) -> std::result::Result<(), WaferError> {
         if matches!(event.event_type, LifecycleType::Start) {
             // Validate web root exists on startup
-            let root = ctx
-                .config_get("web_root")
-                .unwrap_or(&self.default_root);
+            let root = ctx.config_get("web_root").unwrap_or(&self.default_root);
 
             if !Path::new(root).exists() {
                 tracing::warn!("Web root '{}' does not exist", root);
             }
         }
+
+        // (Re)load the asset manifest so a deploy's new hashes are picked up
+        let root = ctx.config_get("web_root").unwrap_or(&self.default_root);
+        self.roots.lock().remove(root);
+        self.releases.lock().remove(root);
+        self.served(ctx);
         Ok(())
     }
 }
 
+/// The node config this block reads, for the admin panel.
+pub fn admin_descriptor() -> AdminDescriptor {
+    AdminDescriptor::new()
+        .field(
+            "web_root",
+            FieldKind::String,
+            "./public",
+            "Directory files are served from",
+        )
+        .field(
+            "web_prefix",
+            FieldKind::String,
+            "",
+            "URL prefix stripped before resolving files",
+        )
+        .field(
+            "web_spa",
+            FieldKind::Bool,
+            "false",
+            "Serve the index file for unknown paths",
+        )
+        .field(
+            "web_index",
+            FieldKind::String,
+            "index.html",
+            "File served for directories",
+        )
+        .field(
+            "spa_exclude",
+            FieldKind::List,
+            "",
+            "Path prefixes that 404 instead of falling back to the index",
+        )
+        .field(
+            "cache_max_age",
+            FieldKind::Integer,
+            "3600",
+            "Cache lifetime of ordinary assets in seconds",
+        )
+        .field(
+            "immutable_max_age",
+            FieldKind::Integer,
+            "31536000",
+            "Cache lifetime of hashed assets in seconds",
+        )
+        .field(
+            "html_cache_control",
+            FieldKind::String,
+            "no-cache",
+            "Cache-Control sent for HTML",
+        )
+        .field(
+            "web_autoindex",
+            FieldKind::Bool,
+            "false",
+            "List directories without an index file",
+        )
+        .field(
+            "autoindex_page_size",
+            FieldKind::Integer,
+            "100",
+            "Entries per directory listing page",
+        )
+        .field(
+            "autoindex_max_entries",
+            FieldKind::Integer,
+            "10000",
+            "Most entries read per directory",
+        )
+        .field(
+            "timing_allow_origin",
+            FieldKind::List,
+            "",
+            "Origins sent in Timing-Allow-Origin",
+        )
+        .field(
+            "web_preload",
+            FieldKind::List,
+            "",
+            "`href as` pairs preloaded from HTML responses",
+        )
+        .field(
+            "csp_hash_inline",
+            FieldKind::Bool,
+            "false",
+            "Allow inline scripts of HTML files by hash",
+        )
+        .field(
+            "web_manifest",
+            FieldKind::String,
+            "",
+            "Build manifest mapping asset names to hashed files",
+        )
+        .field(
+            "web_asset_substitution",
+            FieldKind::Bool,
+            "false",
+            "Rewrite manifest asset names in HTML",
+        )
+        .field(
+            "favicon",
+            FieldKind::String,
+            "",
+            "File answering /favicon.ico when the root lacks one",
+        )
+        .field(
+            "web_case_sensitive",
+            FieldKind::Bool,
+            "false",
+            "Reject paths whose case differs from the files on disk",
+        )
+        .field(
+            "protect_sourcemaps",
+            FieldKind::Bool,
+            "false",
+            "Serve .map files only to clients with `sourcemap_role`",
+        )
+        .field(
+            "sourcemap_role",
+            FieldKind::String,
+            DEFAULT_SOURCEMAP_ROLE,
+            "Role allowed to read source maps",
+        )
+        .field(
+            "web_download_paths",
+            FieldKind::List,
+            "",
+            "Path prefixes whose files are sent as attachments",
+        )
+        .field(
+            "web_download_extensions",
+            FieldKind::List,
+            "",
+            "Extensions of files sent as attachments, e.g. csv, zip",
+        )
+        .field(
+            "web_download_query",
+            FieldKind::Bool,
+            "false",
+            "Send files requested with ?download=1 as attachments",
+        )
+        .field(
+            "web_unicode_normalization",
+            FieldKind::one_of(&["nfc", "nfd"]),
+            "nfc",
+            "Unicode form request paths are normalized to before lookup",
+        )
+        .field(
+            "web_root_mode",
+            FieldKind::one_of(&["direct", "versioned"]),
+            "direct",
+            "Serve web_root itself, or the release its `current` pointer names",
+        )
+        .field(
+            "web_root_check_seconds",
+            FieldKind::Integer,
+            "5",
+            "How often a versioned root's pointer is re-read",
+        )
+        .field(
+            "web_activate",
+            FieldKind::Bool,
+            "false",
+            "Accept POST /_web/activate to switch releases",
+        )
+        .field(
+            "web_reject_writes",
+            FieldKind::Bool,
+            "false",
+            "Answer 405 to write methods sent without an action",
+        )
+        .field(
+            "method_actions",
+            FieldKind::List,
+            "",
+            "`METHOD=action` overrides of the method to action mapping",
+        )
+}
+
 pub fn register(w: &mut Wafer) {
-    w.register_block("@wafer/web", Arc::new(WebBlock::new()));
+    register_as(w, "@wafer/web");
+}
+
+pub fn register_as(w: &mut Wafer, name: &str) {
+    super::register_as(w, name, Arc::new(WebBlock::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const NFC: &str = "\u{00fc}rlaub.txt";
+    const NFD: &str = "u\u{0308}rlaub.txt";
+
+    fn get(root: &TempDir, config: &[(&str, &str)], req: MockRequest) -> SimulatedResponse {
+        let mut ctx = MockContext::new().with_config("web_root", &root.path_str());
+        for (k, v) in config {
+            ctx = ctx.with_config(k, v);
+        }
+        let mut msg = req.build();
+        SimulatedResponse::from_result(&WebBlock::new().handle(&ctx, &mut msg))
+    }
+
+    #[test]
+    fn nfc_files_serve_for_any_spelling() {
+        let root = TempDir::new().with_file(NFC, b"nfc");
+        for path in [
+            format!("/{}", NFC),
+            format!("/{}", NFD),
+            "/%C3%BCrlaub.txt".to_string(),
+            "/u%CC%88rlaub.txt".to_string(),
+        ] {
+            let resp = get(&root, &[], MockRequest::get(&path));
+            assert_status(&resp, 200, None);
+            assert_eq!(resp.body, b"nfc", "{}", path);
+        }
+    }
+
+    #[test]
+    fn nfd_roots_look_names_up_decomposed() {
+        let root = TempDir::new().with_file(NFD, b"nfd");
+        let config = [("web_unicode_normalization", "nfd")];
+        let resp = get(&root, &config, MockRequest::get("/%C3%BCrlaub.txt"));
+        assert_status(&resp, 200, None);
+        assert_eq!(resp.body, b"nfd");
+        // Without it the composed lookup misses the decomposed name
+        assert_status(
+            &get(&root, &[], MockRequest::get("/%C3%BCrlaub.txt")),
+            404,
+            None,
+        );
+    }
+
+    #[test]
+    fn reserved_device_names_are_not_found() {
+        let root = TempDir::new()
+            .with_file("CON", b"x")
+            .with_file("docs/nul.txt", b"x")
+            .with_file("ok.txt", b"ok");
+        for path in ["/CON", "/docs/nul.txt", "/com1", "/LPT9.log"] {
+            assert_status(
+                &get(&root, &[], MockRequest::get(path)),
+                404,
+                Some("not_found"),
+            );
+        }
+        assert_status(&get(&root, &[], MockRequest::get("/ok.txt")), 200, None);
+    }
+
+    #[test]
+    fn undecodable_paths_are_bad_requests() {
+        let root = TempDir::new().with_file("ok.txt", b"ok");
+        for path in ["/%FF.txt", "/ok%00.txt"] {
+            assert_status(
+                &get(&root, &[], MockRequest::get(path)),
+                400,
+                Some("bad_request"),
+            );
+        }
+    }
+
+    #[test]
+    fn encoded_traversal_stays_in_the_root() {
+        let outer = TempDir::new().with_file("secret.txt", b"secret");
+        let root = TempDir::new().with_file("ok.txt", b"ok");
+        let escape = format!(
+            "/%2e%2e/{}/secret.txt",
+            outer.path().file_name().unwrap().to_string_lossy()
+        );
+        assert_status(&get(&root, &[], MockRequest::get(&escape)), 404, None);
+    }
+
+    #[test]
+    fn manifests_belong_to_their_block_and_root() {
+        let a = TempDir::new()
+            .with_file("manifest.json", br#"{"app.js": "app.aaa.js"}"#)
+            .with_file("app.aaa.js", b"a");
+        let b = TempDir::new()
+            .with_file("manifest.json", br#"{"app.js": "app.bbb.js"}"#)
+            .with_file("app.bbb.js", b"b");
+        let ctx = |root: &TempDir| {
+            MockContext::new()
+                .with_config("web_root", &root.path_str())
+                .with_config("web_manifest", "manifest.json")
+        };
+        let (ctx_a, ctx_b) = (ctx(&a), ctx(&b));
+        let (block_a, block_b) = (WebBlock::new(), WebBlock::new());
+        let start = || LifecycleEvent {
+            event_type: LifecycleType::Start,
+            data: Vec::new(),
+        };
+        block_a.lifecycle(&ctx_a, start()).unwrap();
+        block_b.lifecycle(&ctx_b, start()).unwrap();
+
+        for (block, ctx, body) in [(&block_a, &ctx_a, b"a"), (&block_b, &ctx_b, b"b")] {
+            let mut msg = MockRequest::get("/app.js").build();
+            let resp = SimulatedResponse::from_result(&block.handle(ctx, &mut msg));
+            assert_status(&resp, 200, None);
+            assert_eq!(resp.body, body);
+        }
+    }
+
+    #[test]
+    fn asset_urls_resolve_through_the_manifest() {
+        let manifest = AssetManifest::parse(r#"{"app.js": {"file": "assets/app.1.js"}}"#).unwrap();
+        assert_eq!(manifest.asset_url("/app.js"), "/assets/app.1.js");
+        assert_eq!(manifest.asset_url("other.js"), "other.js");
+        let root = TempDir::new().with_file("m.json", br#"{"app.js": "app.2.js"}"#);
+        let ctx = MockContext::new()
+            .with_config("web_root", &root.path_str())
+            .with_config("web_manifest", "m.json");
+        assert_eq!(WebBlock::new().asset_url(&ctx, "app.js"), "app.2.js");
+        assert_eq!(
+            manifest.substitute(r#"<script src="{{asset:/app.js}}"></script>"#),
+            r#"<script src="/assets/app.1.js"></script>"#
+        );
+    }
+
+    #[test]
+    fn release_flips_swap_root_and_manifest_together() {
+        let root = TempDir::new()
+            .with_file("r1/manifest.json", br#"{"app.js": "app.1.js"}"#)
+            .with_file("r1/app.1.js", b"one")
+            .with_file("r1/index.html", b"{{asset:/app.js}}")
+            .with_file("r2/manifest.json", br#"{"app.js": "app.2.js"}"#)
+            .with_file("r2/app.2.js", b"two")
+            .with_file("r2/index.html", b"{{asset:/app.js}}")
+            .with_file("current", b"r1\n");
+        let clock = Arc::new(ManualClock::new());
+        let block = WebBlock::new().with_clock(clock.clone());
+        let ctx = MockContext::new()
+            .with_config("web_root", &root.path_str())
+            .with_config("web_root_mode", "versioned")
+            .with_config("web_manifest", "manifest.json")
+            .with_config("web_asset_substitution", "true");
+        let fetch = |path: &str| {
+            let mut msg = MockRequest::get(path).build();
+            SimulatedResponse::from_result(&block.handle(&ctx, &mut msg))
+        };
+
+        assert_eq!(fetch("/app.js").body, b"one");
+        activate_release(root.path(), "r2").unwrap();
+        // Until the pointer is re-read, r1's files and manifest keep serving
+        assert_eq!(fetch("/app.js").body, b"one");
+        assert_eq!(fetch("/").body, b"/app.1.js");
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(fetch("/app.js").body, b"two");
+        assert_eq!(fetch("/").body, b"/app.2.js");
+        assert_status(&fetch("/app.1.js"), 404, None);
+    }
+
+    #[test]
+    fn method_only_writes_are_served_unless_rejected() {
+        let root = TempDir::new().with_file("index.html", b"home");
+        let resp = get(&root, &[], MockRequest::post("/"));
+        assert_status(&resp, 200, None);
+        assert_eq!(resp.body, b"home");
+
+        let reject = [("web_reject_writes", "true")];
+        for method in ["POST", "PUT", "PATCH", "DELETE"] {
+            let resp = get(&root, &reject, MockRequest::new(method, "/"));
+            assert_status(&resp, 405, None);
+        }
+        assert_status(&get(&root, &reject, MockRequest::get("/")), 200, None);
+        let search = [
+            ("web_reject_writes", "true"),
+            ("method_actions", "POST=retrieve"),
+        ];
+        assert_status(&get(&root, &search, MockRequest::post("/")), 200, None);
+    }
+
+    #[test]
+    fn head_ranges_send_partial_headers_without_a_body() {
+        let root = TempDir::new().with_file("file.bin", b"0123456789");
+        let req = MockRequest::new("HEAD", "/file.bin").header("Range", "bytes=2-5");
+        let resp = get(&root, &[], req);
+        assert_status(&resp, 206, None);
+        assert_header(&resp, "Accept-Ranges", "bytes");
+        assert_header(&resp, "Content-Range", "bytes 2-5/10");
+        assert_header(&resp, "Content-Length", "4");
+        assert!(resp.body.is_empty());
+
+        // The same range through GET sends exactly those bytes
+        let req = MockRequest::get("/file.bin").header("Range", "bytes=2-5");
+        let resp = get(&root, &[], req);
+        assert_status(&resp, 206, None);
+        assert_eq!(resp.body, b"2345");
+
+        let req = MockRequest::new("HEAD", "/file.bin").header("Range", "bytes=20-");
+        let resp = get(&root, &[], req);
+        assert_status(&resp, 416, Some("range_not_satisfiable"));
+        assert_header(&resp, "Content-Range", "bytes */10");
+    }
+
+    #[test]
+    fn protected_sourcemaps_are_hidden_from_anonymous_clients() {
+        let root = TempDir::new()
+            .with_file("app.js", b"code")
+            .with_file("app.js.map", b"{}");
+        // Served to everyone by default
+        assert_status(&get(&root, &[], MockRequest::get("/app.js.map")), 200, None);
+
+        let protect = [("protect_sourcemaps", "true")];
+        let resp = get(&root, &protect, MockRequest::get("/app.js.map"));
+        assert_status(&resp, 404, Some("not_found"));
+        let viewer = MockRequest::get("/app.js.map").meta(meta::AUTH_USER_ROLES, "viewer");
+        assert_status(&get(&root, &protect, viewer), 404, Some("not_found"));
+        assert_status(
+            &get(&root, &protect, MockRequest::get("/app.js")),
+            200,
+            None,
+        );
+    }
+
+    #[test]
+    fn protected_sourcemaps_are_served_to_their_role() {
+        let root = TempDir::new().with_file("app.js.map", b"{}");
+        let dev =
+            || MockRequest::get("/app.js.map").meta(meta::AUTH_USER_ROLES, "viewer,developer");
+        let resp = get(&root, &[("protect_sourcemaps", "true")], dev());
+        assert_status(&resp, 200, None);
+        assert_eq!(resp.body, b"{}");
+        assert_header(&resp, "Cache-Control", "private, no-store");
+
+        let ops = [("protect_sourcemaps", "true"), ("sourcemap_role", "ops")];
+        assert_status(&get(&root, &ops, dev()), 404, Some("not_found"));
+        let req = MockRequest::get("/app.js.map").meta(meta::AUTH_USER_ROLES, "ops");
+        assert_status(&get(&root, &ops, req), 200, None);
+    }
+
+    const ALERT_HASH: &str = "sha256-bhHHL3z2vDgxUt0W3dWQOrprscmda2Y5pLsLg4GF+pI=";
+
+    #[test]
+    fn inline_scripts_are_hashed_and_src_scripts_skipped() {
+        let html = concat!(
+            "<script>alert(1)</script>",
+            "<script src=\"/app.js\"></script>",
+            "<SCRIPT defer SRC=/x.js></SCRIPT>",
+            "<script></script>",
+            "<script type=\"module\">a()</script>",
+        );
+        assert_eq!(
+            inline_script_hashes(html),
+            [
+                ALERT_HASH,
+                "sha256-qVpDBgj7bpq5hMAcGp3AOc79J3Y1Z4HvySTwKrWDoy4="
+            ]
+        );
+    }
+
+    #[test]
+    fn served_html_adds_its_script_hashes_to_the_policy() {
+        let root = TempDir::new().with_file(
+            "page.html",
+            b"<script>alert(1)</script><script>alert(1)</script>",
+        );
+        let csp = "default-src 'self'; script-src 'self' 'unsafe-inline'";
+        let req = || {
+            MockRequest::get("/page.html")
+                .meta(&meta::resp_header_key("Content-Security-Policy"), csp)
+        };
+
+        let resp = get(&root, &[("csp_hash_inline", "true")], req());
+        assert_status(&resp, 200, None);
+        assert_header(
+            &resp,
+            "Content-Security-Policy",
+            &format!("{} '{}'", csp, ALERT_HASH),
+        );
+
+        let resp = get(&root, &[], req());
+        assert_header(&resp, "Content-Security-Policy", csp);
+    }
+
+    #[test]
+    fn content_disposition_encodes_unicode_names() {
+        assert_eq!(
+            content_disposition("attachment", "report.csv"),
+            "attachment; filename=\"report.csv\""
+        );
+        assert_eq!(
+            content_disposition("attachment", NFC),
+            "attachment; filename=\"_rlaub.txt\"; filename*=UTF-8''%C3%BCrlaub.txt"
+        );
+        // Quotes and backslashes never reach the quoted fallback
+        assert_eq!(
+            content_disposition("attachment", "a\"b\\c.csv"),
+            "attachment; filename=\"a_b_c.csv\"; filename*=UTF-8''a%22b%5Cc.csv"
+        );
+    }
+
+    #[test]
+    fn downloads_are_named_from_the_served_file() {
+        let root = TempDir::new()
+            .with_file("data.csv", b"a,b")
+            .with_file(NFC, b"nfc")
+            .with_file("logo.png", b"png");
+        let config = [
+            ("web_download_extensions", "csv"),
+            ("web_download_query", "true"),
+        ];
+        let resp = get(&root, &config, MockRequest::get("/data.csv"));
+        assert_header(
+            &resp,
+            "Content-Disposition",
+            "attachment; filename=\"data.csv\"",
+        );
+        let req = MockRequest::get(&format!("/{}", NFC)).query("download", "1");
+        assert_header(
+            &get(&root, &config, req),
+            "Content-Disposition",
+            "attachment; filename=\"_rlaub.txt\"; filename*=UTF-8''%C3%BCrlaub.txt",
+        );
+        // Other files stay inline, so they still render in <img>
+        let resp = get(&root, &config, MockRequest::get("/logo.png"));
+        assert_no_header(&resp, "Content-Disposition");
+    }
+
+    #[test]
+    fn spa_excluded_prefixes_get_a_real_404() {
+        let root = TempDir::new().with_file("index.html", b"app");
+        let spa = [("web_spa", "true")];
+        let config = [("web_spa", "true"), ("spa_exclude", "/api, /static")];
+        for path in ["/dashboard/42", "/apidocs"] {
+            let resp = get(&root, &config, MockRequest::get(path));
+            assert_status(&resp, 200, None);
+            assert_eq!(resp.body, b"app", "{}", path);
+        }
+        for path in ["/api", "/api/users", "/static/missing.js"] {
+            let resp = get(&root, &config, MockRequest::get(path));
+            assert_status(&resp, 404, Some("not_found"));
+            // Without the exclusion the index answers them too
+            assert_eq!(get(&root, &spa, MockRequest::get(path)).body, b"app");
+        }
+    }
+
+    #[test]
+    fn timing_allow_origin_is_sent_only_when_configured() {
+        let root = TempDir::new()
+            .with_file("app.js", b"code")
+            .with_file("index.html", b"app");
+        assert_no_header(
+            &get(&root, &[], MockRequest::get("/app.js")),
+            "Timing-Allow-Origin",
+        );
+
+        let star = [("timing_allow_origin", "*")];
+        let resp = get(&root, &star, MockRequest::get("/app.js"));
+        assert_header(&resp, "Timing-Allow-Origin", "*");
+
+        let origins = [
+            (
+                "timing_allow_origin",
+                "https://a.example,, https://b.example ",
+            ),
+            ("web_spa", "true"),
+        ];
+        for path in ["/app.js", "/some/route"] {
+            assert_header(
+                &get(&root, &origins, MockRequest::get(path)),
+                "Timing-Allow-Origin",
+                "https://a.example, https://b.example",
+            );
+        }
+    }
+
+    #[test]
+    fn html_cache_control_applies_to_html_files_and_the_spa_index() {
+        let root = TempDir::new()
+            .with_file("index.html", b"app")
+            .with_file("about.html", b"about")
+            .with_file("app.js", b"code");
+        let stale = "max-age=0, stale-while-revalidate=60";
+        let config = [("web_spa", "true"), ("html_cache_control", stale)];
+        for path in ["/about.html", "/", "/some/route"] {
+            let resp = get(&root, &config, MockRequest::get(path));
+            assert_status(&resp, 200, None);
+            assert_header(&resp, "Cache-Control", stale);
+            let resp = get(&root, &[("web_spa", "true")], MockRequest::get(path));
+            assert_header(&resp, "Cache-Control", "no-cache");
+        }
+        let resp = get(&root, &config, MockRequest::get("/app.js"));
+        assert_ne!(resp.header("Cache-Control"), Some(stale));
+    }
+
+    #[test]
+    fn missing_favicons_fall_back_or_get_an_empty_204() {
+        let bare = TempDir::new().with_file("index.html", b"app");
+        let resp = get(&bare, &[], MockRequest::get("/favicon.ico"));
+        assert_status(&resp, 204, None);
+        assert!(resp.body.is_empty());
+        // Even in SPA mode, rather than the index
+        let spa = [("web_spa", "true")];
+        assert_status(
+            &get(&bare, &spa, MockRequest::get("/favicon.ico")),
+            204,
+            None,
+        );
+
+        let branded = TempDir::new().with_file("img/logo.ico", b"logo");
+        let config = [("favicon", "img/logo.ico")];
+        let resp = get(&branded, &config, MockRequest::get("/favicon.ico"));
+        assert_status(&resp, 200, None);
+        assert_eq!(resp.body, b"logo");
+        // A configured file that is missing still gets the 204
+        let resp = get(&bare, &config, MockRequest::get("/favicon.ico"));
+        assert_status(&resp, 204, None);
+
+        let own = TempDir::new()
+            .with_file("favicon.ico", b"own")
+            .with_file("img/logo.ico", b"logo");
+        let resp = get(&own, &config, MockRequest::get("/favicon.ico"));
+        assert_status(&resp, 200, None);
+        assert_eq!(resp.body, b"own");
+    }
+
+    #[test]
+    fn rejected_methods_get_the_shared_405() {
+        let root = TempDir::new().with_file("index.html", b"home");
+        let resp = get(
+            &root,
+            &[("web_reject_writes", "true")],
+            MockRequest::post("/"),
+        );
+        assert_status(&resp, 405, Some("method_not_allowed"));
+        assert_header(&resp, "Allow", "GET, HEAD");
+        assert_eq!(
+            resp.json().unwrap()["error"]["details"]["allowed"],
+            serde_json::json!(["GET", "HEAD"])
+        );
+
+        let activate = [("web_root_mode", "versioned"), ("web_activate", "true")];
+        let resp = get(&root, &activate, MockRequest::get("/_web/activate"));
+        assert_status(&resp, 405, Some("method_not_allowed"));
+        assert_header(&resp, "Allow", "POST");
+    }
+
+    #[test]
+    fn ranges_resolve_against_the_body_length() {
+        use ByteRange::*;
+        for (header, range) in [
+            ("bytes=2-5", Partial(2, 5)),
+            ("bytes=2-100", Partial(2, 9)),
+            ("bytes=4-", Partial(4, 9)),
+            ("bytes=-3", Partial(7, 9)),
+            ("bytes=-20", Partial(0, 9)),
+            ("bytes=10-", Unsatisfiable),
+            ("bytes=-0", Unsatisfiable),
+            ("bytes=5-2", Full),
+            ("bytes=0-1,4-5", Full),
+            ("bytes=-", Full),
+            ("items=0-1", Full),
+            ("", Full),
+        ] {
+            assert_eq!(parse_range(header, 10), range, "{}", header);
+        }
+        assert_eq!(parse_range("bytes=-1", 0), Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Unsatisfiable);
+    }
+
+    #[test]
+    fn ranges_apply_to_in_memory_bodies() {
+        let data = b"0123456789";
+        let send = |range: &str, if_range: &str| {
+            let mut req = MockRequest::get("/embedded");
+            if !range.is_empty() {
+                req = req.header("Range", range);
+            }
+            if !if_range.is_empty() {
+                req = req.header("If-Range", if_range);
+            }
+            let result = respond_range(req.build(), data, "text/plain", "\"v1\"");
+            SimulatedResponse::from_result(&result)
+        };
+
+        let resp = send("", "");
+        assert_status(&resp, 200, None);
+        assert_eq!(resp.body, data);
+        assert_header(&resp, "Accept-Ranges", "bytes");
+
+        for (range, body, content_range) in [
+            ("bytes=-3", &b"789"[..], "bytes 7-9/10"),
+            ("bytes=7-", &b"789"[..], "bytes 7-9/10"),
+            ("bytes=0-1", &b"01"[..], "bytes 0-1/10"),
+        ] {
+            let resp = send(range, "");
+            assert_status(&resp, 206, None);
+            assert_eq!(resp.body, body, "{}", range);
+            assert_header(&resp, "Content-Range", content_range);
+        }
+
+        let resp = send("bytes=10-", "");
+        assert_status(&resp, 416, Some("range_not_satisfiable"));
+        assert_header(&resp, "Content-Range", "bytes */10");
+
+        // A stale If-Range gets the whole body, a current one the range
+        let resp = send("bytes=0-1", "\"v0\"");
+        assert_status(&resp, 200, None);
+        assert_eq!(resp.body, data);
+        assert_status(&send("bytes=0-1", "\"v1\""), 206, None);
+    }
+
+    #[test]
+    fn file_ranges_match_in_memory_ones() {
+        let root = TempDir::new().with_file("file.bin", b"0123456789");
+        let range = |r: &str| get(&root, &[], MockRequest::get("/file.bin").header("Range", r));
+        assert_eq!(range("bytes=-3").body, b"789");
+        assert_eq!(range("bytes=7-").body, b"789");
+        assert_status(&range("bytes=0-1,4-5"), 200, None);
+        assert_status(&range("bytes=10-"), 416, Some("range_not_satisfiable"));
+    }
 }
@@ -1,12 +1,141 @@
+use std::collections::BTreeMap;
 use wafer_run::ChainDef;
 
 /// Create the standard HTTP infrastructure chain.
-/// Applies security headers, CORS, readonly guard, rate limiting, and monitoring.
+/// Applies security headers, CORS, user-agent filtering, readonly guard,
+/// rate limiting, and monitoring.
 pub fn http_infra_chain() -> Result<ChainDef, String> {
     serde_json::from_str(HTTP_INFRA_JSON)
         .map_err(|e| format!("invalid http-infra chain JSON: {}", e))
 }
 
+/// Id of the chain built by `http_infra_chain_v2`.
+pub const HTTP_INFRA_V2_ID: &str = "http-infra-v2";
+
+/// Create the v2 HTTP infrastructure chain: the `http-infra` nodes with
+/// monitoring first, so requests that ua-filter, readonly-guard or
+/// rate-limit reject are still counted. `http-infra` keeps its order and id
+/// for compatibility.
+pub fn http_infra_chain_v2() -> Result<ChainDef, String> {
+    to_chain_def(HTTP_INFRA_V2_ID, http_infra_v2_json())
+}
+
+fn http_infra_v2_json() -> serde_json::Value {
+    linear_chain(
+        HTTP_INFRA_V2_ID,
+        "Standard HTTP infrastructure, counting every request: monitoring, security headers, CORS, rate limiting",
+        &[
+            ("@wafer/monitoring", serde_json::json!({})),
+            ("@wafer/security-headers", serde_json::json!({})),
+            ("@wafer/cors", serde_json::json!({})),
+            ("@wafer/ua-filter", serde_json::json!({})),
+            ("@wafer/readonly-guard", serde_json::json!({})),
+            ("@wafer/rate-limit", serde_json::json!({})),
+        ],
+    )
+}
+
+/// Create the registered chain template `id` with its `on_error` policy
+/// replaced (every template uses `stop`). The policy is one of the runtime's
+/// and is checked when the definition is parsed.
+pub fn chain_with_on_error(id: &str, on_error: &str) -> Result<ChainDef, String> {
+    let mut def = templates()?
+        .into_iter()
+        .find(|(t, _)| *t == id)
+        .map(|(_, def)| def)
+        .ok_or_else(|| format!("unknown chain template {}", id))?;
+    def["config"]["on_error"] = serde_json::Value::String(on_error.to_string());
+    to_chain_def(id, def)
+}
+
+/// InfraProfile selects coherent node configs for the HTTP infrastructure chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InfraProfile {
+    /// Browser-facing sites: the standard `http-infra` chain.
+    Web,
+    /// JSON APIs: no CSP, `no-store` caching, literal `*` instead of origin reflection.
+    Api,
+    /// Service-to-service traffic: no CORS, no HSTS, generous rate limit.
+    Internal,
+}
+
+impl InfraProfile {
+    pub const ALL: [InfraProfile; 3] = [Self::Web, Self::Api, Self::Internal];
+
+    /// Chain id registered for this profile.
+    pub fn chain_id(&self) -> &'static str {
+        match self {
+            Self::Web => "http-infra",
+            Self::Api => "http-infra-api",
+            Self::Internal => "http-infra-internal",
+        }
+    }
+}
+
+/// Create the HTTP infrastructure chain tuned for a profile.
+pub fn http_infra_chain_with(profile: InfraProfile) -> Result<ChainDef, String> {
+    to_chain_def(profile.chain_id(), http_infra_json(profile)?)
+}
+
+/// The JSON of the HTTP infrastructure chain for a profile.
+fn http_infra_json(profile: InfraProfile) -> Result<serde_json::Value, String> {
+    let (summary, nodes) = match profile {
+        InfraProfile::Web => return parse_template("http-infra", HTTP_INFRA_JSON),
+        InfraProfile::Api => (
+            "HTTP infrastructure for JSON APIs",
+            vec![
+                (
+                    "@wafer/security-headers",
+                    serde_json::json!({ "csp": "", "cache_control": "no-store" }),
+                ),
+                ("@wafer/cors", serde_json::json!({ "reflect_wildcard": "false" })),
+                ("@wafer/ua-filter", serde_json::json!({})),
+                ("@wafer/readonly-guard", serde_json::json!({})),
+                ("@wafer/rate-limit", serde_json::json!({})),
+                ("@wafer/monitoring", serde_json::json!({})),
+            ],
+        ),
+        InfraProfile::Internal => (
+            "HTTP infrastructure for internal services",
+            vec![
+                (
+                    "@wafer/security-headers",
+                    serde_json::json!({ "hsts": "" }),
+                ),
+                ("@wafer/readonly-guard", serde_json::json!({})),
+                (
+                    "@wafer/rate-limit",
+                    serde_json::json!({ "max_requests": "100000" }),
+                ),
+                ("@wafer/monitoring", serde_json::json!({})),
+            ],
+        ),
+    };
+
+    Ok(linear_chain(profile.chain_id(), summary, &nodes))
+}
+
+/// Build the JSON of a chain running `nodes` one after another.
+fn linear_chain(id: &str, summary: &str, nodes: &[(&str, serde_json::Value)]) -> serde_json::Value {
+    let mut next: Option<serde_json::Value> = None;
+    for (block, config) in nodes.iter().rev() {
+        let mut node = serde_json::json!({ "block": block });
+        if config.as_object().is_some_and(|c| !c.is_empty()) {
+            node["config"] = config.clone();
+        }
+        if let Some(n) = next.take() {
+            node["next"] = serde_json::json!([n]);
+        }
+        next = Some(node);
+    }
+    serde_json::json!({
+        "id": id,
+        "summary": summary,
+        "config": { "on_error": "stop" },
+        "root": next.unwrap_or(serde_json::Value::Null),
+    })
+}
+
 /// Create the auth pipeline chain.
 pub fn auth_pipe_chain() -> Result<ChainDef, String> {
     serde_json::from_str(AUTH_PIPE_JSON)
@@ -24,13 +153,18 @@ const HTTP_INFRA_JSON: &str = r#"{
                 "block": "@wafer/cors",
                 "next": [
                     {
-                        "block": "@wafer/readonly-guard",
+                        "block": "@wafer/ua-filter",
                         "next": [
                             {
-                                "block": "@wafer/rate-limit",
+                                "block": "@wafer/readonly-guard",
                                 "next": [
                                     {
-                                        "block": "@wafer/monitoring"
+                                        "block": "@wafer/rate-limit",
+                                        "next": [
+                                            {
+                                                "block": "@wafer/monitoring"
+                                            }
+                                        ]
                                     }
                                 ]
                             }
@@ -84,10 +218,559 @@ const ADMIN_PIPE_JSON: &str = r#"{
     }
 }"#;
 
+/// Every chain template by id, as JSON, in registration order.
+fn templates() -> Result<Vec<(&'static str, serde_json::Value)>, String> {
+    let mut templates = Vec::new();
+    for profile in InfraProfile::ALL {
+        templates.push((profile.chain_id(), http_infra_json(profile)?));
+    }
+    templates.push((HTTP_INFRA_V2_ID, http_infra_v2_json()));
+    templates.push(("auth-pipe", parse_template("auth-pipe", AUTH_PIPE_JSON)?));
+    templates.push(("admin-pipe", parse_template("admin-pipe", ADMIN_PIPE_JSON)?));
+    Ok(templates)
+}
+
 /// Register the standard chain templates with a Wafer runtime.
 pub fn register_chains(w: &mut wafer_run::Wafer) -> Result<(), String> {
-    w.add_chain_def(&http_infra_chain()?);
-    w.add_chain_def(&auth_pipe_chain()?);
-    w.add_chain_def(&admin_pipe_chain()?);
+    register_chains_with(w, &ChainOverrides::new())
+}
+
+/// Register the standard chain templates with node config overridden, e.g.
+/// a stricter admin pipeline:
+///
+/// ```ignore
+/// let overrides = ChainOverrides::new()
+///     .set("admin-pipe", "@wafer/iam", "role", "superadmin")
+///     .set("http-infra", "@wafer/rate-limit", "max_requests", "100");
+/// chains::register_chains_with(w, &overrides)?;
+/// ```
+///
+/// Overrides are validated before anything is registered: each must name a
+/// template and a block that is a node of that template itself. Nodes of a
+/// referenced chain (admin-pipe runs http-infra's rate limit) are overridden
+/// through that chain's id.
+pub fn register_chains_with(
+    w: &mut wafer_run::Wafer,
+    overrides: &ChainOverrides,
+) -> Result<(), String> {
+    let defs = resolved_templates(overrides)?
+        .into_iter()
+        .map(|(id, def)| to_chain_def(id, def))
+        .collect::<Result<Vec<_>, _>>()?;
+    for def in &defs {
+        w.add_chain_def(def);
+    }
     Ok(())
 }
+
+/// Every chain template by id, as JSON, with `overrides` applied.
+pub(crate) fn resolved_templates(
+    overrides: &ChainOverrides,
+) -> Result<Vec<(&'static str, serde_json::Value)>, String> {
+    let mut templates = templates()?;
+    for ((chain, block), config) in &overrides.entries {
+        let root = match templates.iter_mut().find(|(id, _)| id == chain) {
+            Some((_, def)) => &mut def["root"],
+            None => {
+                let ids: Vec<&str> = templates.iter().map(|(id, _)| *id).collect();
+                return Err(format!(
+                    "override for unknown chain {} (templates: {})",
+                    chain,
+                    ids.join(", ")
+                ));
+            }
+        };
+        if apply_override(root, block, config) == 0 {
+            let mut blocks = Vec::new();
+            node_blocks(root, &mut blocks);
+            return Err(format!(
+                "override for {}: no {} node (nodes: {})",
+                chain,
+                block,
+                blocks.join(", ")
+            ));
+        }
+    }
+    Ok(templates)
+}
+
+/// Node config overrides for `register_chains_with`, keyed by chain id and
+/// block name. Values are merged into the node's `config`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChainOverrides {
+    entries: BTreeMap<(String, String), serde_json::Map<String, serde_json::Value>>,
+}
+
+impl ChainOverrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set config `key` to `value` on every `block` node of chain `chain`.
+    pub fn set(mut self, chain: &str, block: &str, key: &str, value: &str) -> Self {
+        self.entries
+            .entry((chain.to_string(), block.to_string()))
+            .or_default()
+            .insert(
+                key.to_string(),
+                serde_json::Value::String(value.to_string()),
+            );
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Merge `config` into every `block` node under `node`; returns how many matched.
+fn apply_override(
+    node: &mut serde_json::Value,
+    block: &str,
+    config: &serde_json::Map<String, serde_json::Value>,
+) -> usize {
+    let mut applied = 0;
+    if node.get("block").and_then(|b| b.as_str()) == Some(block) {
+        if !node.get("config").is_some_and(|c| c.is_object()) {
+            node["config"] = serde_json::json!({});
+        }
+        if let Some(existing) = node["config"].as_object_mut() {
+            for (k, v) in config {
+                existing.insert(k.clone(), v.clone());
+            }
+        }
+        applied += 1;
+    }
+    if let Some(next) = node.get_mut("next").and_then(|n| n.as_array_mut()) {
+        for child in next {
+            applied += apply_override(child, block, config);
+        }
+    }
+    applied
+}
+
+/// Collect the block (or referenced `chain:`) names of every node under `node`.
+fn node_blocks(node: &serde_json::Value, out: &mut Vec<String>) {
+    if let Some(block) = node.get("block").and_then(|b| b.as_str()) {
+        out.push(block.to_string());
+    } else if let Some(chain) = node.get("chain").and_then(|c| c.as_str()) {
+        out.push(format!("chain:{}", chain));
+    }
+    if let Some(next) = node.get("next").and_then(|n| n.as_array()) {
+        for child in next {
+            node_blocks(child, out);
+        }
+    }
+}
+
+/// Parse a template's JSON.
+fn parse_template(id: &str, json: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(json).map_err(|e| format!("invalid {} chain JSON: {}", id, e))
+}
+
+/// Turn a template's JSON into a `ChainDef`.
+fn to_chain_def(id: &str, def: serde_json::Value) -> Result<ChainDef, String> {
+    serde_json::from_value(def).map_err(|e| format!("invalid {} chain JSON: {}", id, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta;
+    use crate::testing::*;
+    use serde_json::json;
+    use std::sync::Arc;
+    use wafer_run::*;
+
+    /// App handler answering 200 with the authenticated user.
+    struct Hello;
+
+    impl Block for Hello {
+        fn info(&self) -> BlockInfo {
+            BlockInfo {
+                name: "@app/hello".to_string(),
+                version: "0.1.0".to_string(),
+                interface: "handler@v1".to_string(),
+                summary: "Test handler".to_string(),
+                instance_mode: InstanceMode::Singleton,
+                allowed_modes: Vec::new(),
+                admin_ui: None,
+            }
+        }
+
+        fn handle(&self, _ctx: &dyn Context, msg: &mut Message) -> Result_ {
+            let user = msg.get_meta(meta::AUTH_USER_ID).to_string();
+            json_respond(msg.clone(), 200, &json!({ "user": user }))
+        }
+
+        fn lifecycle(
+            &self,
+            _ctx: &dyn Context,
+            _event: LifecycleEvent,
+        ) -> std::result::Result<(), WaferError> {
+            Ok(())
+        }
+    }
+
+    /// App handler failing with a 500 built from a fresh message.
+    struct Boom;
+
+    impl Block for Boom {
+        fn info(&self) -> BlockInfo {
+            BlockInfo {
+                name: "@app/boom".to_string(),
+                ..Hello.info()
+            }
+        }
+
+        fn handle(&self, _ctx: &dyn Context, _msg: &mut Message) -> Result_ {
+            let fresh = Message::new("http.request", Vec::new());
+            crate::errors::CoreError::custom(500, "internal_error", "boom").respond(&fresh)
+        }
+
+        fn lifecycle(
+            &self,
+            _ctx: &dyn Context,
+            _event: LifecycleEvent,
+        ) -> std::result::Result<(), WaferError> {
+            Ok(())
+        }
+    }
+
+    /// App handler failing with a 500 built from the request message.
+    struct Fails;
+
+    impl Block for Fails {
+        fn info(&self) -> BlockInfo {
+            BlockInfo {
+                name: "@app/fails".to_string(),
+                ..Hello.info()
+            }
+        }
+
+        fn handle(&self, _ctx: &dyn Context, msg: &mut Message) -> Result_ {
+            crate::errors::CoreError::custom(500, "internal_error", "failed").respond(msg)
+        }
+
+        fn lifecycle(
+            &self,
+            _ctx: &dyn Context,
+            _event: LifecycleEvent,
+        ) -> std::result::Result<(), WaferError> {
+            Ok(())
+        }
+    }
+
+    fn bearer(claims: serde_json::Value) -> String {
+        let exp = chrono::Utc::now().timestamp() + 600;
+        let mut claims = claims;
+        claims["exp"] = json!(exp);
+        format!("Bearer {}", MockCrypto::token(claims))
+    }
+
+    fn harness(overrides: &ChainOverrides, db: MockDatabase) -> ChainHarness {
+        ChainHarness::with_overrides(overrides)
+            .with_services(MockServices::new().with_database(db).with_crypto())
+    }
+
+    #[test]
+    fn http_infra_sets_security_headers() {
+        let resp = run_chain("http-infra", MockRequest::get("/").build());
+        assert_status(&resp, 200, None);
+        assert_security_headers(&resp);
+        assert_no_header(&resp, "Server");
+    }
+
+    #[test]
+    fn http_infra_rate_limits_with_429() {
+        let overrides =
+            ChainOverrides::new().set("http-infra", "@wafer/rate-limit", "max_requests", "2");
+        let harness = harness(&overrides, MockDatabase::new());
+        for _ in 0..2 {
+            assert_status(
+                &harness.run("http-infra", MockRequest::get("/").build()),
+                200,
+                None,
+            );
+        }
+        let resp = harness.run("http-infra", MockRequest::get("/").build());
+        assert_status(&resp, 429, Some("rate_limited"));
+        assert!(resp.header("Retry-After").is_some());
+        assert_security_headers(&resp);
+
+        // Other clients keep their own budget
+        let other = MockRequest::get("/").remote_addr("198.51.100.7").build();
+        assert_status(&harness.run("http-infra", other), 200, None);
+    }
+
+    #[test]
+    fn http_infra_v2_counts_rate_limited_responses() {
+        let overrides =
+            ChainOverrides::new().set(HTTP_INFRA_V2_ID, "@wafer/rate-limit", "max_requests", "1");
+        let harness = harness(&overrides, MockDatabase::new());
+        assert_status(
+            &harness.run(HTTP_INFRA_V2_ID, MockRequest::get("/").build()),
+            200,
+            None,
+        );
+        let resp = harness.run(HTTP_INFRA_V2_ID, MockRequest::get("/").build());
+        assert_status(&resp, 429, Some("rate_limited"));
+
+        let stats = harness
+            .run(HTTP_INFRA_V2_ID, MockRequest::get("/_stats").build())
+            .json()
+            .unwrap();
+        assert_eq!(stats["total_requests"], json!(2));
+        assert_eq!(stats["status_counts"], json!({"429": 1}));
+        assert_eq!(stats["error_count"], json!(1));
+        assert_eq!(stats["outcomes"]["rate_limited"], json!(1));
+    }
+
+    #[test]
+    fn auth_pipe_counts_failed_authentication() {
+        let harness = harness(&ChainOverrides::new(), MockDatabase::new());
+        let resp = harness.run("auth-pipe", MockRequest::get("/api").build());
+        assert_status(&resp, 401, Some("unauthorized"));
+
+        let stats = harness
+            .run("auth-pipe", MockRequest::get("/_stats").build())
+            .json()
+            .unwrap();
+        assert_eq!(stats["status_counts"], json!({"401": 1}));
+        assert_eq!(stats["outcomes"]["auth_failed"], json!(1));
+        assert_eq!(stats["outcomes"]["rate_limited"], json!(0));
+    }
+
+    #[test]
+    fn auth_pipe_rejects_missing_and_invalid_tokens() {
+        let harness = harness(&ChainOverrides::new(), MockDatabase::new());
+        let resp = harness.run("auth-pipe", MockRequest::get("/api").build());
+        assert_status(&resp, 401, Some("unauthorized"));
+        assert_security_headers(&resp);
+
+        let req = MockRequest::get("/api")
+            .header("Authorization", "Bearer forged")
+            .build();
+        assert_status(&harness.run("auth-pipe", req), 401, Some("unauthorized"));
+    }
+
+    #[test]
+    fn auth_pipe_admits_valid_tokens() {
+        let harness = harness(&ChainOverrides::new(), MockDatabase::new());
+        let req = MockRequest::get("/api")
+            .header("Authorization", &bearer(json!({"user_id": "u1"})))
+            .build();
+        let result = harness.run_result("auth-pipe", req);
+        assert_eq!(
+            result
+                .message
+                .as_ref()
+                .map(|m| m.get_meta(meta::AUTH_USER_ID)),
+            Some("u1")
+        );
+        assert_security_headers(&SimulatedResponse::from_result(&result));
+    }
+
+    #[test]
+    fn admin_pipe_requires_the_admin_role() {
+        let db = MockDatabase::new()
+            .with_row(
+                "iam_user_roles",
+                json!({"user_id": "root", "role": "admin"}),
+            )
+            .with_row("iam_user_roles", json!({"user_id": "u1", "role": "editor"}));
+        let harness = harness(&ChainOverrides::new(), db);
+
+        let resp = harness.run("admin-pipe", MockRequest::get("/admin").build());
+        assert_status(&resp, 401, Some("unauthorized"));
+
+        let req = MockRequest::get("/admin")
+            .header(
+                "Authorization",
+                &bearer(json!({"user_id": "u1", "roles": ["admin"]})),
+            )
+            .build();
+        let resp = harness.run("admin-pipe", req);
+        assert_status(&resp, 403, Some("forbidden"));
+        assert_security_headers(&resp);
+
+        let req = MockRequest::get("/admin")
+            .header("Authorization", &bearer(json!({"user_id": "root"})))
+            .build();
+        assert_status(&harness.run("admin-pipe", req), 200, None);
+    }
+
+    #[test]
+    fn admin_pipe_rate_limits_through_http_infra() {
+        let overrides =
+            ChainOverrides::new().set("http-infra", "@wafer/rate-limit", "max_requests", "1");
+        let harness = harness(&overrides, MockDatabase::new());
+        assert_status(
+            &harness.run("admin-pipe", MockRequest::get("/admin").build()),
+            401,
+            Some("unauthorized"),
+        );
+        let resp = harness.run("admin-pipe", MockRequest::get("/admin").build());
+        assert_status(&resp, 429, Some("rate_limited"));
+    }
+
+    #[test]
+    fn app_chains_run_after_templates() {
+        let def: ChainDef = serde_json::from_value(json!({
+            "id": "app",
+            "config": { "on_error": "stop" },
+            "root": { "chain": "auth-pipe", "next": [{ "block": "@app/hello" }] },
+        }))
+        .unwrap();
+        let harness = harness(&ChainOverrides::new(), MockDatabase::new())
+            .with_chain(&def)
+            .with_block("@app/hello", Arc::new(Hello));
+
+        let req = MockRequest::get("/hello")
+            .header("Authorization", &bearer(json!({"user_id": "u1"})))
+            .build();
+        let resp = harness.run("app", req);
+        assert_status(&resp, 200, None);
+        assert_eq!(resp.json().unwrap()["user"], "u1");
+        assert_security_headers(&resp);
+
+        let resp = harness.run("app", MockRequest::get("/hello").build());
+        assert_status(&resp, 401, Some("unauthorized"));
+    }
+
+    fn cors_app(handler: &str) -> ChainDef {
+        serde_json::from_value(json!({
+            "id": "app",
+            "config": { "on_error": "stop" },
+            "root": { "chain": "http-infra", "next": [{ "block": handler }] },
+        }))
+        .unwrap()
+    }
+
+    fn from_app() -> Message {
+        MockRequest::get("/api")
+            .header("Origin", "https://app.example")
+            .build()
+    }
+
+    #[test]
+    fn downstream_errors_keep_cors_headers() {
+        // Registered through blocks::register_as, so wrapped in the default hooks
+        let harness = ChainHarness::new()
+            .with_chain(&cors_app("@app/boom"))
+            .with_registered_block("@app/boom", Arc::new(Boom));
+        let resp = harness.run("app", from_app());
+        assert_status(&resp, 500, Some("internal_error"));
+        assert_header(&resp, "Access-Control-Allow-Origin", "https://app.example");
+    }
+
+    #[test]
+    fn plain_handlers_keep_cors_headers_only_on_request_responses() {
+        // Registered with Wafer::register_block, so no hooks run
+        let harness = ChainHarness::new()
+            .with_chain(&cors_app("@app/fails"))
+            .with_block("@app/fails", Arc::new(Fails));
+        let resp = harness.run("app", from_app());
+        assert_status(&resp, 500, Some("internal_error"));
+        assert_header(&resp, "Access-Control-Allow-Origin", "https://app.example");
+
+        let harness = ChainHarness::new()
+            .with_chain(&cors_app("@app/boom"))
+            .with_block("@app/boom", Arc::new(Boom));
+        let resp = harness.run("app", from_app());
+        assert_status(&resp, 500, Some("internal_error"));
+        assert_no_header(&resp, "Access-Control-Allow-Origin");
+    }
+
+    #[test]
+    fn block_errors_are_counted_per_monitoring_instance() {
+        let boom = || {
+            ChainHarness::new()
+                .with_chain(&cors_app("@app/boom"))
+                .with_registered_block("@app/boom", Arc::new(Boom))
+        };
+        let (first, second) = (boom(), boom());
+
+        assert_status(&first.run("app", MockRequest::get("/api").build()), 500, None);
+        let stats = |h: &ChainHarness| {
+            h.run("http-infra", MockRequest::get("/_stats").build())
+                .json()
+                .unwrap()
+        };
+        assert_eq!(stats(&first)["errors_by_block"]["@app/boom"], json!(1));
+        assert!(stats(&second)["errors_by_block"].get("@app/boom").is_none());
+    }
+
+    type Node = (String, serde_json::Map<String, serde_json::Value>);
+
+    /// The `(block, config)` of each node of a profile's chain, in order.
+    fn profile_nodes(profile: InfraProfile) -> Vec<Node> {
+        let def = serde_json::to_value(http_infra_chain_with(profile).unwrap()).unwrap();
+        assert_eq!(def["id"], json!(profile.chain_id()));
+        let mut nodes = Vec::new();
+        let mut node = Some(&def["root"]);
+        while let Some(n) = node {
+            let block = n["block"].as_str().unwrap().to_string();
+            let config = n["config"].as_object().cloned().unwrap_or_default();
+            nodes.push((block, config));
+            node = n["next"].get(0);
+        }
+        nodes
+    }
+
+    fn blocks(nodes: &[Node]) -> Vec<&str> {
+        nodes.iter().map(|(b, _)| b.as_str()).collect()
+    }
+
+    const INFRA_BLOCKS: [&str; 6] = [
+        "@wafer/security-headers",
+        "@wafer/cors",
+        "@wafer/ua-filter",
+        "@wafer/readonly-guard",
+        "@wafer/rate-limit",
+        "@wafer/monitoring",
+    ];
+
+    #[test]
+    fn web_profile_is_the_standard_chain() {
+        let nodes = profile_nodes(InfraProfile::Web);
+        assert_eq!(blocks(&nodes), INFRA_BLOCKS);
+        assert!(nodes.iter().all(|(_, c)| c.is_empty()));
+    }
+
+    #[test]
+    fn api_profile_drops_csp_and_caching_and_sends_a_literal_wildcard() {
+        let nodes = profile_nodes(InfraProfile::Api);
+        assert_eq!(blocks(&nodes), INFRA_BLOCKS);
+        assert_eq!(nodes[0].1["csp"], json!(""));
+        assert_eq!(nodes[0].1["cache_control"], json!("no-store"));
+        assert_eq!(nodes[1].1["reflect_wildcard"], json!("false"));
+    }
+
+    #[test]
+    fn internal_profile_has_no_cors_no_hsts_and_a_generous_limit() {
+        let nodes = profile_nodes(InfraProfile::Internal);
+        assert_eq!(
+            blocks(&nodes),
+            [
+                "@wafer/security-headers",
+                "@wafer/readonly-guard",
+                "@wafer/rate-limit",
+                "@wafer/monitoring",
+            ]
+        );
+        assert_eq!(nodes[0].1["hsts"], json!(""));
+        assert_eq!(nodes[2].1["max_requests"], json!("100000"));
+    }
+
+    #[test]
+    fn each_profile_registers_its_own_chain() {
+        let ids: std::collections::HashSet<_> =
+            InfraProfile::ALL.iter().map(|p| p.chain_id()).collect();
+        assert_eq!(ids.len(), InfraProfile::ALL.len());
+        let registered: Vec<_> = templates().unwrap().into_iter().map(|(id, _)| id).collect();
+        for id in ids {
+            assert!(registered.contains(&id), "{}", id);
+        }
+    }
+}
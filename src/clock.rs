@@ -0,0 +1,37 @@
+//! Time sources for blocks.
+//!
+//! Blocks that compare against the current time (rate-limit windows, token
+//! and API key expiry, lockout cooldowns) read it from a `Clock`, so tests
+//! can substitute `testing::ManualClock` (feature `test-util`) and step
+//! across boundaries without sleeping.
+
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// A source of the current time, both monotonic and wall-clock.
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for measuring durations.
+    fn now_instant(&self) -> Instant;
+    /// Wall-clock time, for comparing against timestamps.
+    fn now_utc(&self) -> DateTime<Utc>;
+}
+
+/// The real clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// The real clock, as blocks hold it.
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
@@ -0,0 +1,530 @@
+//! Shared error taxonomy for wafer-core blocks.
+//!
+//! Every block reports failures through `CoreError`, so clients see one JSON
+//! envelope shape and one set of machine-readable codes:
+//!
+//! ```json
+//! {"error": {"code": "rate_limited", "message": "Too many requests", "details": {...}}}
+//! ```
+//!
+//! `details` is only present when supplied. Codes are part of the wire
+//! contract and must not change; messages are for humans, and are localized
+//! per request when a message catalog is installed (see `messages`).
+//!
+//! Blocks whose responses browsers navigate to (static files) answer with
+//! `respond_negotiated`, which sends a small HTML page instead of the
+//! envelope when the request's `Accept` prefers HTML over JSON.
+//!
+//! Wire change: blocks used to answer with the runtime's `error()` result,
+//! an error action the host rendered in its own format. They now send the
+//! envelope above as a JSON response with the error's status, and 401s add
+//! `WWW-Authenticate: Bearer`. The codes themselves are unchanged, so
+//! clients matching on `error.code` keep working; clients that parsed the
+//! host's error body need to read the envelope instead.
+//!
+//! Blocks that gate requests (auth, iam, rate-limit, readonly-guard) also
+//! tag their rejections with an `Outcome`, a fixed set of codes monitoring
+//! counts separately from generic errors.
+
+use wafer_run::*;
+
+use crate::messages;
+use crate::meta;
+
+/// CoreError is a failure a block answers the request with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CoreError {
+    /// 400 `bad_request`
+    BadRequest(String),
+    /// 401 `unauthorized`; adds `WWW-Authenticate: Bearer`
+    Unauthorized(String),
+    /// 403 `forbidden`
+    Forbidden(String),
+    /// 404 `not_found`
+    NotFound(String),
+    /// 405 `method_not_allowed`; adds `Allow` (a comma-separated method list)
+    MethodNotAllowed { message: String, allow: String },
+    /// 429 `rate_limited`; adds `Retry-After`
+    RateLimited { message: String, retry_after: u64 },
+    /// 403 `forbidden` (the code predates this variant and is kept for compatibility)
+    ReadOnly(String),
+    /// 413 `payload_too_large`
+    PayloadTooLarge(String),
+    /// 500 `internal_error`
+    Internal(String),
+    /// 503 `service_unavailable`
+    Unavailable(String),
+    /// Any other status and code, for block-specific failures.
+    Custom {
+        status: u16,
+        code: String,
+        message: String,
+    },
+}
+
+impl CoreError {
+    /// Build a block-specific error.
+    pub fn custom(status: u16, code: &str, message: &str) -> Self {
+        Self::Custom {
+            status,
+            code: code.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// HTTP status of the error.
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::BadRequest(_) => 400,
+            Self::Unauthorized(_) => 401,
+            Self::Forbidden(_) | Self::ReadOnly(_) => 403,
+            Self::NotFound(_) => 404,
+            Self::MethodNotAllowed { .. } => 405,
+            Self::PayloadTooLarge(_) => 413,
+            Self::RateLimited { .. } => 429,
+            Self::Internal(_) => 500,
+            Self::Unavailable(_) => 503,
+            Self::Custom { status, .. } => *status,
+        }
+    }
+
+    /// Stable machine-readable code.
+    pub fn code(&self) -> &str {
+        match self {
+            Self::BadRequest(_) => "bad_request",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden(_) | Self::ReadOnly(_) => "forbidden",
+            Self::NotFound(_) => "not_found",
+            Self::MethodNotAllowed { .. } => "method_not_allowed",
+            Self::PayloadTooLarge(_) => "payload_too_large",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::Internal(_) => "internal_error",
+            Self::Unavailable(_) => "service_unavailable",
+            Self::Custom { code, .. } => code,
+        }
+    }
+
+    /// Human-readable message.
+    pub fn message(&self) -> &str {
+        match self {
+            Self::BadRequest(m)
+            | Self::Unauthorized(m)
+            | Self::Forbidden(m)
+            | Self::NotFound(m)
+            | Self::ReadOnly(m)
+            | Self::PayloadTooLarge(m)
+            | Self::Internal(m)
+            | Self::Unavailable(m) => m,
+            Self::RateLimited { message, .. }
+            | Self::MethodNotAllowed { message, .. }
+            | Self::Custom { message, .. } => message,
+        }
+    }
+
+    /// Response headers that accompany this error.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Self::Unauthorized(_) => vec![("WWW-Authenticate", "Bearer".to_string())],
+            Self::RateLimited { retry_after, .. } => {
+                vec![("Retry-After", retry_after.to_string())]
+            }
+            Self::MethodNotAllowed { allow, .. } => vec![("Allow", allow.clone())],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The canonical JSON envelope.
+    pub fn envelope(&self, details: Option<&serde_json::Value>) -> serde_json::Value {
+        self.envelope_with_message(self.message(), details)
+    }
+
+    fn envelope_with_message(
+        &self,
+        message: &str,
+        details: Option<&serde_json::Value>,
+    ) -> serde_json::Value {
+        let mut body = serde_json::json!({
+            "code": self.code(),
+            "message": message,
+        });
+        if let Some(d) = details {
+            body["details"] = d.clone();
+        }
+        serde_json::json!({ "error": body })
+    }
+
+    /// Answer the request with this error.
+    pub fn respond(&self, msg: &Message) -> Result_ {
+        self.render(msg, None, false)
+    }
+
+    /// Answer the request with this error and structured details.
+    pub fn respond_with_details(&self, msg: &Message, details: serde_json::Value) -> Result_ {
+        self.render(msg, Some(&details), false)
+    }
+
+    /// Answer the request with this error as an HTML page when its `Accept`
+    /// prefers `text/html` to JSON (see `prefers_html`), as the JSON
+    /// envelope otherwise. Adds `Vary: Accept`.
+    pub fn respond_negotiated(&self, msg: &Message) -> Result_ {
+        self.render(msg, None, true)
+    }
+
+    /// The HTML error page, with `message` in place of the error's own.
+    fn html_page(&self, message: &str) -> String {
+        let title = format!("{} {}", self.status(), html_escape(self.code()));
+        format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body><h1>{title}</h1><p>{}</p></body></html>\n",
+            html_escape(message),
+        )
+    }
+
+    /// Placeholder values for a localized message template.
+    fn message_params(
+        &self,
+        msg: &Message,
+        details: Option<&serde_json::Value>,
+    ) -> Vec<(String, String)> {
+        let mut params = Vec::new();
+        let retry_after = match self {
+            Self::RateLimited { retry_after, .. } => retry_after.to_string(),
+            _ => meta::resp_header(msg, "Retry-After").to_string(),
+        };
+        if !retry_after.is_empty() {
+            params.push(("retry_after".to_string(), retry_after));
+        }
+        if let Some(serde_json::Value::Object(fields)) = details {
+            for (name, value) in fields {
+                match value {
+                    serde_json::Value::String(s) => params.push((name.clone(), s.clone())),
+                    serde_json::Value::Number(n) => params.push((name.clone(), n.to_string())),
+                    _ => {}
+                }
+            }
+        }
+        params
+    }
+
+    fn render(
+        &self,
+        msg: &Message,
+        details: Option<&serde_json::Value>,
+        negotiate: bool,
+    ) -> Result_ {
+        let mut m = msg.clone();
+        for (name, value) in self.headers() {
+            meta::set_resp_header(&mut m, name, &value);
+        }
+        let mut message = self.message().to_string();
+        if let Some(catalog) = messages::for_request(msg) {
+            meta::set_resp_header(&mut m, "Vary", "Accept-Language");
+            let params = self.message_params(msg, details);
+            if let Some((text, language)) =
+                catalog.localize(msg.header("Accept-Language"), self.code(), &params)
+            {
+                meta::set_resp_header(&mut m, "Content-Language", &language);
+                message = text;
+            }
+        }
+        // Name the responsible block only for requests marked for debugging
+        if meta::flag(msg, meta::TRACE_DEBUG) {
+            let block = msg.get_meta(meta::TRACE_LAST_BLOCK);
+            if !block.is_empty() {
+                meta::set_resp_header(&mut m, "X-Wafer-Block", block);
+            }
+        }
+        // Recorded for response hooks and monitoring
+        m.set_meta(meta::RESP_STATUS, &self.status().to_string());
+        m.set_meta(meta::ERROR_CODE, self.code());
+        if negotiate {
+            meta::set_resp_header(&mut m, "Vary", "Accept");
+            if prefers_html(msg.header("Accept")) {
+                return respond(
+                    m,
+                    self.status(),
+                    self.html_page(&message).into_bytes(),
+                    "text/html; charset=utf-8",
+                );
+            }
+        }
+        json_respond(
+            m,
+            self.status(),
+            &self.envelope_with_message(&message, details),
+        )
+    }
+}
+
+/// Why a gatekeeping block rejected a request, recorded as `outcome.code`
+/// meta on its error result. The set is closed so the counters keyed by it
+/// stay bounded; `trace::TraceCounters` tallies them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    /// `auth_failed`: missing or invalid credentials (AuthBlock, IAMBlock 401s)
+    AuthFailed,
+    /// `auth_locked`: identity locked out after repeated failures (AuthBlock)
+    AuthLocked,
+    /// `forbidden_role`: authenticated but denied by a role or policy
+    /// (IAMBlock), including denials `iam_hide_as_404` answers with 404
+    ForbiddenRole,
+    /// `rate_limited`: over a RateLimitBlock budget
+    RateLimited,
+    /// `readonly_blocked`: a write refused in read-only mode (ReadonlyGuardBlock)
+    ReadonlyBlocked,
+}
+
+impl Outcome {
+    pub const ALL: [Outcome; 5] = [
+        Self::AuthFailed,
+        Self::AuthLocked,
+        Self::ForbiddenRole,
+        Self::RateLimited,
+        Self::ReadonlyBlocked,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::AuthFailed => "auth_failed",
+            Self::AuthLocked => "auth_locked",
+            Self::ForbiddenRole => "forbidden_role",
+            Self::RateLimited => "rate_limited",
+            Self::ReadonlyBlocked => "readonly_blocked",
+        }
+    }
+
+    pub fn parse(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|o| o.code() == code)
+    }
+
+    /// Position in `ALL`, for array-backed counters.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Tag the request; call before building the error result from it.
+    pub fn record(self, msg: &mut Message) {
+        msg.set_meta(meta::OUTCOME_CODE, self.code());
+    }
+
+    /// Tag an error result already built.
+    pub fn record_result(self, result: &mut Result_) {
+        if let Some(m) = result.message.as_mut() {
+            self.record(m);
+        }
+    }
+
+    /// The outcome a result was tagged with, if any.
+    pub fn of(result: &Result_) -> Option<Self> {
+        result
+            .message
+            .as_ref()
+            .and_then(|m| Self::parse(m.get_meta(meta::OUTCOME_CODE)))
+    }
+}
+
+/// Answer with 405, listing the `allowed` methods in `Allow` and in the
+/// envelope's `details.allowed`, so every block's 405 looks the same.
+pub fn method_not_allowed(msg: &Message, allowed: &[&str]) -> Result_ {
+    let allow = allowed.join(", ");
+    CoreError::MethodNotAllowed {
+        message: format!("Method not allowed; use {}", allow),
+        allow,
+    }
+    .respond_with_details(msg, serde_json::json!({ "allowed": allowed }))
+}
+
+/// Whether an `Accept` header weights `text/html` (or XHTML) above JSON.
+/// Wildcards count for neither, so `*/*` clients (curl, `fetch`) get JSON
+/// and browsers, which list `text/html` first, get HTML.
+pub fn prefers_html(accept: &str) -> bool {
+    let mut html = 0.0f32;
+    let mut json = 0.0f32;
+    for part in accept.split(',') {
+        let mut pieces = part.split(';');
+        let media = pieces.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = pieces
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match media.as_str() {
+            "text/html" | "application/xhtml+xml" => html = html.max(q),
+            "application/json" | "application/problem+json" => json = json.max(q),
+            _ => {}
+        }
+    }
+    html > 0.0 && html > json
+}
+
+/// Escape text for HTML element content and attribute values.
+pub fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+impl std::fmt::Display for CoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}): {}", self.code(), self.status(), self.message())
+    }
+}
+
+impl std::error::Error for CoreError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use serde_json::json;
+
+    fn render(err: &CoreError, details: Option<serde_json::Value>) -> SimulatedResponse {
+        let msg = MockRequest::get("/").build();
+        let result = match details {
+            Some(d) => err.respond_with_details(&msg, d),
+            None => err.respond(&msg),
+        };
+        SimulatedResponse::from_result(&result)
+    }
+
+    /// Status, extra headers and envelope of every variant, as clients see them.
+    #[test]
+    fn envelope_snapshots() {
+        let cases: Vec<(CoreError, u16, Vec<(&str, &str)>, serde_json::Value)> = vec![
+            (
+                CoreError::BadRequest("Bad input".into()),
+                400,
+                vec![],
+                json!({"error": {"code": "bad_request", "message": "Bad input"}}),
+            ),
+            (
+                CoreError::Unauthorized("Authentication required".into()),
+                401,
+                vec![("WWW-Authenticate", "Bearer")],
+                json!({"error": {"code": "unauthorized", "message": "Authentication required"}}),
+            ),
+            (
+                CoreError::Forbidden("Requires 'admin' role".into()),
+                403,
+                vec![],
+                json!({"error": {"code": "forbidden", "message": "Requires 'admin' role"}}),
+            ),
+            (
+                CoreError::NotFound("Not found".into()),
+                404,
+                vec![],
+                json!({"error": {"code": "not_found", "message": "Not found"}}),
+            ),
+            (
+                CoreError::MethodNotAllowed {
+                    message: "Method not allowed; use GET".into(),
+                    allow: "GET".into(),
+                },
+                405,
+                vec![("Allow", "GET")],
+                json!({"error": {"code": "method_not_allowed", "message": "Method not allowed; use GET"}}),
+            ),
+            (
+                CoreError::RateLimited {
+                    message: "Too many requests".into(),
+                    retry_after: 30,
+                },
+                429,
+                vec![("Retry-After", "30")],
+                json!({"error": {"code": "rate_limited", "message": "Too many requests"}}),
+            ),
+            (
+                CoreError::ReadOnly("Read-only".into()),
+                403,
+                vec![],
+                json!({"error": {"code": "forbidden", "message": "Read-only"}}),
+            ),
+            (
+                CoreError::PayloadTooLarge("Body too large".into()),
+                413,
+                vec![],
+                json!({"error": {"code": "payload_too_large", "message": "Body too large"}}),
+            ),
+            (
+                CoreError::Internal("Oops".into()),
+                500,
+                vec![],
+                json!({"error": {"code": "internal_error", "message": "Oops"}}),
+            ),
+            (
+                CoreError::Unavailable("Down".into()),
+                503,
+                vec![],
+                json!({"error": {"code": "service_unavailable", "message": "Down"}}),
+            ),
+            (
+                CoreError::custom(418, "teapot", "Short and stout"),
+                418,
+                vec![],
+                json!({"error": {"code": "teapot", "message": "Short and stout"}}),
+            ),
+        ];
+        for (err, status, headers, envelope) in cases {
+            let resp = render(&err, None);
+            assert_eq!(resp.status, status, "{}", err);
+            assert_eq!(resp.json(), Some(envelope), "{}", err);
+            for (name, value) in headers {
+                assert_header(&resp, name, value);
+            }
+            for name in ["WWW-Authenticate", "Retry-After", "Allow"] {
+                if !err.headers().iter().any(|(h, _)| *h == name) {
+                    assert_no_header(&resp, name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn details_snapshot() {
+        let resp = method_not_allowed(&MockRequest::get("/").build(), &["GET", "HEAD"]);
+        let resp = SimulatedResponse::from_result(&resp);
+        assert_eq!(
+            resp.json(),
+            Some(json!({"error": {
+                "code": "method_not_allowed",
+                "message": "Method not allowed; use GET, HEAD",
+                "details": {"allowed": ["GET", "HEAD"]},
+            }}))
+        );
+        assert_header(&resp, "Allow", "GET, HEAD");
+
+        let resp = render(
+            &CoreError::BadRequest("Invalid field".into()),
+            Some(json!({"field": "email"})),
+        );
+        assert_eq!(
+            resp.json(),
+            Some(json!({"error": {
+                "code": "bad_request",
+                "message": "Invalid field",
+                "details": {"field": "email"},
+            }}))
+        );
+    }
+
+    #[test]
+    fn negotiated_errors_send_html_to_browsers() {
+        let msg = MockRequest::get("/")
+            .header("Accept", "text/html,application/xhtml+xml,*/*;q=0.8")
+            .build();
+        let resp = SimulatedResponse::from_result(
+            &CoreError::NotFound("No <such> page".into()).respond_negotiated(&msg),
+        );
+        assert_eq!(resp.status, 404);
+        assert!(resp.text().contains("<h1>404 not_found</h1>"));
+        assert!(resp.text().contains("No &lt;such&gt; page"));
+        assert_header(&resp, "Vary", "Accept");
+    }
+}
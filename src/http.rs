@@ -0,0 +1,94 @@
+//! Outbound HTTP client abstraction shared by blocks that call external services.
+//!
+//! Blocks take an `Arc<dyn HttpClient>` so tests and apps can substitute their
+//! own transport. With the `http-client` feature, `default_client()` returns a
+//! client backed by reqwest; without it, it returns `None` and the blocks
+//! report the missing client instead of making the call.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Default timeout for outbound calls.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// HttpClient performs the JSON-returning requests blocks need.
+pub trait HttpClient: Send + Sync {
+    /// POST a form and parse the JSON response.
+    fn post_form(&self, url: &str, form: &[(&str, &str)]) -> Result<serde_json::Value, String>;
+    /// POST a JSON body and parse the JSON response.
+    fn post_json(
+        &self,
+        url: &str,
+        body: &serde_json::Value,
+        timeout: Duration,
+    ) -> Result<serde_json::Value, String>;
+    /// GET a JSON document, with a bearer token unless `bearer` is empty.
+    fn get_json(&self, url: &str, bearer: &str) -> Result<serde_json::Value, String>;
+}
+
+/// The built-in client, if the `http-client` feature is enabled.
+#[cfg(feature = "http-client")]
+pub fn default_client() -> Option<Arc<dyn HttpClient>> {
+    Some(Arc::new(ReqwestClient::default()))
+}
+
+/// The built-in client, if the `http-client` feature is enabled.
+#[cfg(not(feature = "http-client"))]
+pub fn default_client() -> Option<Arc<dyn HttpClient>> {
+    None
+}
+
+/// HttpClient backed by reqwest's blocking client.
+#[cfg(feature = "http-client")]
+#[derive(Default)]
+pub struct ReqwestClient {
+    client: reqwest::blocking::Client,
+}
+
+#[cfg(feature = "http-client")]
+impl HttpClient for ReqwestClient {
+    fn post_form(&self, url: &str, form: &[(&str, &str)]) -> Result<serde_json::Value, String> {
+        self.client
+            .post(url)
+            .header("Accept", "application/json")
+            .form(form)
+            .timeout(DEFAULT_TIMEOUT)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.json())
+            .map_err(|e| e.to_string())
+    }
+
+    fn post_json(
+        &self,
+        url: &str,
+        body: &serde_json::Value,
+        timeout: Duration,
+    ) -> Result<serde_json::Value, String> {
+        self.client
+            .post(url)
+            .header("Accept", "application/json")
+            .json(body)
+            .timeout(timeout)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.json())
+            .map_err(|e| e.to_string())
+    }
+
+    fn get_json(&self, url: &str, bearer: &str) -> Result<serde_json::Value, String> {
+        let mut req = self
+            .client
+            .get(url)
+            .header("Accept", "application/json")
+            .header("User-Agent", "wafer-core");
+        if !bearer.is_empty() {
+            req = req.bearer_auth(bearer);
+        }
+        req.timeout(DEFAULT_TIMEOUT)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.json())
+            .map_err(|e| e.to_string())
+    }
+}
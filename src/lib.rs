@@ -4,17 +4,194 @@
 //! rate limiting, auth, etc.) and chain templates that can be used by
 //! any WAFER application.
 
+pub mod admin;
 pub mod blocks;
 pub mod chains;
+pub mod clock;
+pub mod errors;
+pub mod http;
+pub mod messages;
+pub mod meta;
+pub mod net;
+pub mod path;
+pub mod precondition;
+pub mod startup;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+pub mod window;
+
+/// Declares the block table once, so `BLOCKS` (registration), `catalog`
+/// (descriptions) and `admin_descriptors` cannot drift apart.
+macro_rules! block_table {
+    ($(($name:literal, $module:ident, $ty:ident)),* $(,)?) => {
+        /// Every wafer-core block by registered name, in `register_all` order.
+        pub const BLOCKS: &[(&str, fn(&mut wafer_run::Wafer))] =
+            &[$(($name, blocks::$module::register)),*];
+
+        /// The `BlockInfo` of every wafer-core block, in `register_all` order,
+        /// from the same constructors registration uses; no `Wafer` needed.
+        /// Useful for capability pages and comparing versions across
+        /// deployments.
+        pub fn catalog() -> Vec<wafer_run::BlockInfo> {
+            use wafer_run::Block;
+            vec![$(blocks::$module::$ty::new().info()),*]
+        }
+
+        /// A new instance of the wafer-core block registered as `name`,
+        /// wrapped as `register_all` wraps it, or `None` if there is no such
+        /// block. Each call builds a fresh instance with its own state.
+        pub fn new_block(name: &str) -> Option<std::sync::Arc<dyn wafer_run::Block>> {
+            match name {
+                $($name => Some(blocks::traced(
+                    name,
+                    std::sync::Arc::new(blocks::$module::$ty::new()),
+                )),)*
+                _ => None,
+            }
+        }
+
+        /// The admin UI descriptor of every wafer-core block by registered
+        /// name, in `register_all` order (see `admin`), including the fields
+        /// registration adds.
+        pub fn admin_descriptors() -> Vec<(&'static str, admin::AdminDescriptor)> {
+            vec![$(($name, blocks::$module::admin_descriptor().registered())),*]
+        }
+    };
+}
+
+block_table![
+    ("@wafer/trust-boundary", trust_boundary, TrustBoundaryBlock),
+    ("@wafer/tls-guard", tls_guard, TlsGuardBlock),
+    (
+        "@wafer/security-headers",
+        security_headers,
+        SecurityHeadersBlock
+    ),
+    ("@wafer/cors", cors, CorsBlock),
+    ("@wafer/deprecation", deprecation, DeprecationBlock),
+    ("@wafer/reporting", reporting, ReportingBlock),
+    ("@wafer/csp-report", csp_report, CspReportBlock),
+    ("@wafer/router", router, RouterBlock),
+    ("@wafer/ua-filter", ua_filter, UaFilterBlock),
+    ("@wafer/rate-limit", rate_limit, RateLimitBlock),
+    (
+        "@wafer/circuit-breaker",
+        circuit_breaker,
+        CircuitBreakerBlock
+    ),
+    ("@wafer/readonly-guard", readonly_guard, ReadonlyGuardBlock),
+    ("@wafer/monitoring", monitoring, MonitoringBlock),
+    ("@wafer/client-hints", client_hints, ClientHintsBlock),
+    ("@wafer/experiment", experiment, ExperimentBlock),
+    ("@wafer/auth", auth, AuthBlock),
+    ("@wafer/iam", iam, IAMBlock),
+    ("@wafer/quota", quota, QuotaBlock),
+    ("@wafer/validate-json", validate_json, ValidateJsonBlock),
+    ("@wafer/oauth", oauth, OAuthBlock),
+    ("@wafer/web", web, WebBlock),
+];
 
 /// Register all wafer-core blocks with a Wafer runtime.
 pub fn register_all(w: &mut wafer_run::Wafer) {
-    blocks::security_headers::register(w);
-    blocks::cors::register(w);
-    blocks::rate_limit::register(w);
-    blocks::readonly_guard::register(w);
-    blocks::monitoring::register(w);
-    blocks::auth::register(w);
-    blocks::iam::register(w);
-    blocks::web::register(w);
+    for (_, register) in BLOCKS {
+        register(w);
+    }
+}
+
+/// Register all wafer-core blocks, then check the standard chain templates'
+/// node config (see `startup`). Returns the issues found for the host app to
+/// log; apps registering chains with overrides check those with
+/// `startup::check_chains`.
+pub fn register_all_checked(w: &mut wafer_run::Wafer) -> Vec<startup::Warning> {
+    register_all(w);
+    startup::check_chains(&chains::ChainOverrides::new()).unwrap_or_else(|e| {
+        vec![startup::Warning {
+            chain: String::new(),
+            block: String::new(),
+            key: None,
+            code: "invalid_chain_templates",
+            message: e,
+        }]
+    })
+}
+
+/// Register only the named blocks (e.g. `&["@wafer/web", "@wafer/security-headers"]`).
+///
+/// Fails without registering anything if a name is not a wafer-core block.
+pub fn register_blocks(w: &mut wafer_run::Wafer, names: &[&str]) -> Result<(), String> {
+    for (_, register) in select_blocks(names)? {
+        register(w);
+    }
+    Ok(())
+}
+
+/// A `BLOCKS` entry: registered name and registration function.
+type BlockEntry = (&'static str, fn(&mut wafer_run::Wafer));
+
+/// The `BLOCKS` entries named in `names`, in `register_all` order, or an
+/// error listing every name that is not a wafer-core block.
+fn select_blocks(names: &[&str]) -> Result<Vec<&'static BlockEntry>, String> {
+    let unknown: Vec<&str> = names
+        .iter()
+        .copied()
+        .filter(|n| !BLOCKS.iter().any(|(name, _)| name == n))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!("unknown wafer-core blocks: {}", unknown.join(", ")));
+    }
+    Ok(BLOCKS
+        .iter()
+        .filter(|(name, _)| names.contains(name))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_block_ships_a_valid_admin_ui() {
+        let descriptors = admin_descriptors();
+        let catalog = catalog();
+        assert_eq!(catalog.len(), BLOCKS.len());
+        for (info, (name, descriptor)) in catalog.iter().zip(&descriptors) {
+            assert_eq!(info.name, *name);
+            let ui = info
+                .admin_ui
+                .as_ref()
+                .unwrap_or_else(|| panic!("{}: no admin_ui", name));
+            let parsed = admin::AdminDescriptor::parse(&ui.to_string())
+                .unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert_eq!(&parsed, descriptor, "{}", name);
+            for key in ["debug_trace", "messages_file", "error_page_5xx"] {
+                assert!(
+                    parsed.fields.iter().any(|f| f.key == key),
+                    "{}: no {} field",
+                    name,
+                    key
+                );
+            }
+        }
+    }
+
+    fn selected(names: &[&str]) -> Result<Vec<&'static str>, String> {
+        select_blocks(names).map(|blocks| blocks.iter().map(|(name, _)| *name).collect())
+    }
+
+    #[test]
+    fn only_requested_blocks_are_selected_in_registration_order() {
+        assert_eq!(
+            selected(&["@wafer/web", "@wafer/security-headers", "@wafer/web"]),
+            Ok(vec!["@wafer/security-headers", "@wafer/web"])
+        );
+        assert_eq!(selected(&[]), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn unknown_block_names_are_refused() {
+        assert_eq!(
+            selected(&["@wafer/web", "@wafer/nope", "web"]),
+            Err("unknown wafer-core blocks: @wafer/nope, web".to_string())
+        );
+    }
 }
@@ -0,0 +1,303 @@
+//! Localized error messages.
+//!
+//! The messages blocks write where they fail are the English defaults. A
+//! `MessageCatalog`, read from the JSON file named by a block's
+//! `messages_file` config, supplies templates for other languages or
+//! replaces the English ones, keyed by error code:
+//!
+//! ```json
+//! {
+//!   "default_language": "en",
+//!   "supported": ["en", "de", "fr"],
+//!   "messages": {
+//!     "de": {"forbidden": "Rolle '{role}' erforderlich", "rate_limited": "Zu viele Anfragen, bitte in {retry_after} s erneut versuchen"},
+//!     "fr": {"unauthorized": "Authentification requise"}
+//!   }
+//! }
+//! ```
+//!
+//! The wrapper registration puts around every block (`TracedBlock`) records
+//! a configured `messages_file` in `messages.catalog` meta, so the setting
+//! holds for the rest of the chain until another block names a different
+//! file. Each file is read once and reloaded when it changes; chains with
+//! different files never see each other's messages.
+//!
+//! `CoreError` picks the language from the request's `Accept-Language`
+//! (see `MessageCatalog::negotiate`) and, when that language has a template
+//! for the error's code, sends it as `message` with `Content-Language`. The
+//! `code` is never translated. A language lacking the code falls back to the
+//! default language's template, then to the English message.
+//!
+//! Placeholders are filled from `{retry_after}` (the error's or the
+//! response's `Retry-After`) and the string and number fields of the error's
+//! `details`, such as `{role}` on IAM role denials. A template naming a value
+//! the error doesn't carry is skipped.
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+use wafer_run::Message;
+
+use crate::meta;
+
+/// Error message templates by language and error code.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageCatalog {
+    default_language: String,
+    supported: Vec<String>,
+    /// Templates by lowercase language tag, then error code.
+    templates: HashMap<String, HashMap<String, String>>,
+}
+
+#[derive(serde::Deserialize)]
+struct CatalogFile {
+    #[serde(default)]
+    default_language: Option<String>,
+    #[serde(default)]
+    supported: Vec<String>,
+    #[serde(default)]
+    messages: HashMap<String, HashMap<String, String>>,
+}
+
+impl MessageCatalog {
+    /// An empty catalog answering in `default_language`.
+    pub fn new(default_language: &str) -> Self {
+        Self {
+            default_language: default_language.to_ascii_lowercase(),
+            supported: vec![default_language.to_ascii_lowercase()],
+            templates: HashMap::new(),
+        }
+    }
+
+    /// Add the template for `code` in `language`, which becomes supported.
+    pub fn with_message(mut self, language: &str, code: &str, template: &str) -> Self {
+        let language = language.to_ascii_lowercase();
+        if !self.supported.contains(&language) {
+            self.supported.push(language.clone());
+        }
+        self.templates
+            .entry(language)
+            .or_default()
+            .insert(code.to_string(), template.to_string());
+        self
+    }
+
+    /// Parse the JSON form shown in the module docs. `supported` defaults to
+    /// every language with messages, and `default_language` to `en`.
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let file: CatalogFile =
+            serde_json::from_str(json).map_err(|e| format!("invalid message catalog: {}", e))?;
+        let mut catalog = Self::new(file.default_language.as_deref().unwrap_or("en"));
+        for language in file.supported {
+            let language = language.to_ascii_lowercase();
+            if !catalog.supported.contains(&language) {
+                catalog.supported.push(language);
+            }
+        }
+        for (language, messages) in file.messages {
+            for (code, template) in messages {
+                catalog = catalog.with_message(&language, &code, &template);
+            }
+        }
+        Ok(catalog)
+    }
+
+    /// Read and parse a catalog file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Self::parse(&raw)
+    }
+
+    pub fn default_language(&self) -> &str {
+        &self.default_language
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+
+    /// The supported language best matching an `Accept-Language` header.
+    ///
+    /// Preferences are tried by descending `q`; each matches a supported tag
+    /// exactly, or else by primary subtag (`de-AT` matches `de`, `de`
+    /// matches `de-DE`). Without a match, the default language.
+    pub fn negotiate(&self, accept_language: &str) -> &str {
+        let mut prefs: Vec<(String, f32)> = accept_language
+            .split(',')
+            .filter_map(|part| {
+                let mut pieces = part.split(';');
+                let tag = pieces.next()?.trim().to_ascii_lowercase();
+                let q = pieces
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+                (!tag.is_empty() && q > 0.0).then_some((tag, q))
+            })
+            .collect();
+        // Stable, so equal weights keep the client's order
+        prefs.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let primary = |tag: &str| tag.split('-').next().unwrap_or("").to_string();
+        for (tag, _) in &prefs {
+            if tag == "*" {
+                break;
+            }
+            if let Some(s) = self.supported.iter().find(|s| *s == tag) {
+                return s;
+            }
+            let wanted = primary(tag);
+            if let Some(s) = self.supported.iter().find(|s| primary(s) == wanted) {
+                return s;
+            }
+        }
+        &self.default_language
+    }
+
+    /// The template for `code` in `language` with `params` substituted, or
+    /// `None` when there is none or it names a value not in `params`.
+    pub fn render(
+        &self,
+        language: &str,
+        code: &str,
+        params: &[(String, String)],
+    ) -> Option<String> {
+        let mut text = self.templates.get(language)?.get(code)?.clone();
+        for (name, value) in params {
+            text = text.replace(&format!("{{{}}}", name), value);
+        }
+        let unresolved = text
+            .find('{')
+            .is_some_and(|open| text[open..].contains('}'));
+        (!unresolved).then_some(text)
+    }
+
+    /// The localized message for `code` and the language it is in, for a
+    /// request sending `accept_language`. A language without the code falls
+    /// back to the default language's template.
+    pub fn localize(
+        &self,
+        accept_language: &str,
+        code: &str,
+        params: &[(String, String)],
+    ) -> Option<(String, String)> {
+        let language = self.negotiate(accept_language);
+        [language, self.default_language()]
+            .into_iter()
+            .find_map(|lang| {
+                self.render(lang, code, params)
+                    .map(|text| (text, lang.to_string()))
+            })
+    }
+}
+
+/// Catalogs by file path, with the modification time they were read at.
+/// A file that fails to load is cached as an empty catalog until it changes.
+type Loaded = HashMap<String, (Option<SystemTime>, Arc<MessageCatalog>)>;
+
+fn loaded() -> &'static Mutex<Loaded> {
+    static LOADED: OnceLock<Mutex<Loaded>> = OnceLock::new();
+    LOADED.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The catalog in the file at `path`, read on first use and again when the
+/// file's modification time changes.
+pub fn catalog_file(path: &str) -> Arc<MessageCatalog> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut loaded = loaded().lock();
+    if let Some((at, catalog)) = loaded.get(path) {
+        if *at == modified {
+            return catalog.clone();
+        }
+    }
+    let catalog = match MessageCatalog::load(Path::new(path)) {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::warn!("messages: {}; using the default messages", e);
+            MessageCatalog::default()
+        }
+    };
+    let catalog = Arc::new(catalog);
+    loaded.insert(path.to_string(), (modified, catalog.clone()));
+    catalog
+}
+
+/// The catalog named by the request's `messages.catalog` meta, if any.
+pub fn for_request(msg: &Message) -> Option<Arc<MessageCatalog>> {
+    let path = msg.get_meta(meta::MESSAGES_CATALOG);
+    if path.is_empty() {
+        return None;
+    }
+    Some(catalog_file(path)).filter(|c| !c.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::CoreError;
+    use crate::testing::*;
+
+    fn catalog() -> MessageCatalog {
+        MessageCatalog::new("en")
+            .with_message("de", "forbidden", "Rolle '{role}' erforderlich")
+            .with_message("fr-ca", "forbidden", "Rôle requis")
+            .with_message("en", "rate_limited", "Retry in {retry_after} s")
+    }
+
+    #[test]
+    fn negotiation_follows_q_values_and_primary_subtags() {
+        let c = catalog();
+        assert_eq!(c.negotiate("fr-CA"), "fr-ca");
+        assert_eq!(c.negotiate("de-AT,fr;q=0.5"), "de");
+        assert_eq!(c.negotiate("fr;q=0.9,de;q=0.4"), "fr-ca");
+        assert_eq!(c.negotiate("es, de;q=0"), "en");
+        assert_eq!(c.negotiate("*"), "en");
+        assert_eq!(c.negotiate(""), "en");
+    }
+
+    #[test]
+    fn placeholders_fill_or_skip_the_template() {
+        let c = catalog();
+        let role = [("role".to_string(), "admin".to_string())];
+        assert_eq!(
+            c.render("de", "forbidden", &role).as_deref(),
+            Some("Rolle 'admin' erforderlich")
+        );
+        // A template naming a value the error lacks is not used
+        assert_eq!(c.render("de", "forbidden", &[]), None);
+        // A language without the code falls back to the default language
+        let retry = [("retry_after".to_string(), "30".to_string())];
+        assert_eq!(
+            c.localize("de", "rate_limited", &retry),
+            Some(("Retry in 30 s".to_string(), "en".to_string()))
+        );
+    }
+
+    #[test]
+    fn errors_use_the_catalog_their_request_names() {
+        let dir = TempDir::new().with_file(
+            "de.json",
+            br#"{"messages": {"de": {"not_found": "Nicht gefunden"}}}"#,
+        );
+        let file = dir.path().join("de.json");
+        let err = CoreError::NotFound("Not found".to_string());
+
+        let named = MockRequest::get("/")
+            .header("Accept-Language", "de")
+            .meta(meta::MESSAGES_CATALOG, file.to_str().unwrap())
+            .build();
+        let resp = SimulatedResponse::from_result(&err.respond(&named));
+        let body = resp.json().unwrap();
+        assert_eq!(body["error"]["message"], "Nicht gefunden");
+        assert_eq!(body["error"]["code"], "not_found");
+        assert_header(&resp, "Content-Language", "de");
+
+        let unnamed = MockRequest::get("/")
+            .header("Accept-Language", "de")
+            .build();
+        let resp = SimulatedResponse::from_result(&err.respond(&unnamed));
+        assert_eq!(resp.json().unwrap()["error"]["message"], "Not found");
+        assert_no_header(&resp, "Content-Language");
+    }
+}
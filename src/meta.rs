@@ -0,0 +1,660 @@
+//! Meta keys read or written by wafer-core blocks, and typed accessors.
+//!
+//! Blocks communicate through string-keyed message meta. Every key this crate
+//! uses is defined here so a typo can't silently break the contract between
+//! two blocks, and so applications have one place to look them up.
+//!
+//! Response headers are `resp.header.<Name>` meta. Header names are
+//! case-insensitive, so `set_resp_header` replaces a header however an earlier
+//! writer spelled it, and merges list-typed headers (see `MERGEABLE_HEADERS`)
+//! instead of overwriting them.
+//!
+//! Which blocks depend on which keys:
+//!
+//! | Key | Written by | Read by |
+//! |-----|------------|---------|
+//! | `http.method` | runtime / `TransportMapping` | cors, readonly-guard, rate-limit, web, reporting, router |
+//! | action (`msg.action()`) | runtime / `TransportMapping` | readonly-guard, rate-limit, web, iam (external authz) |
+//! | `http.header.*` | runtime | trust-boundary (strips), every block reading headers |
+//! | `auth.user_*` | auth | iam, experiment, quota |
+//! | `auth.token_binding` | auth | app blocks, logging |
+//! | `auth.token_expired` | auth | app blocks |
+//! | `route.*` | router | iam (`route.role`) |
+//! | `mount.*` | `MountedBlock` | web |
+//! | `resp.header.*`, `resp.status`, `error.code` | every block | runtime, hooks, monitoring |
+//! | `outcome.code` | auth, iam, rate-limit, readonly-guard | trace (outcome counts), monitoring |
+//! | `trace.*` | every registered block (`TracedBlock`) | errors (`X-Wafer-Block`) |
+//! | `trace.counted_by` | monitoring | `TracedBlock` (error and outcome counts) |
+//! | `messages.catalog` | every registered block (`TracedBlock`, from `messages_file`) | errors (localized messages) |
+//! | `circuit_breaker.id` | circuit-breaker | `circuit_breaker::outcome_hook` |
+//! | `trust.proxy` | trust-boundary | `net::client_ip` (monitoring) |
+//! | `body.json_validated` | validate-json | app blocks |
+//! | `iam.source` | iam | app blocks, logging |
+//! | `iam.scope` | iam | app blocks |
+//! | `readonly.rejected` | readonly-guard | hooks, logging |
+//!
+//! Chains served over a transport other than HTTP (a message queue, an RPC
+//! front end) don't set an HTTP method or CRUD action. Install a
+//! `TransportMapping` once, before registering blocks, so `http_method` and
+//! `action` resolve them from the transport's operation names instead.
+//! Blocks deciding what a request does use `resolved_action`, which falls
+//! back from the action to the HTTP method.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use wafer_run::{Context, Message};
+
+/// Prefix of request header meta (`http.header.<Name>`).
+pub const HTTP_HEADER_PREFIX: &str = "http.header.";
+/// The request's HTTP method.
+pub const HTTP_METHOD: &str = "http.method";
+/// Prefix of response header meta (`resp.header.<Name>`).
+pub const RESP_HEADER_PREFIX: &str = "resp.header.";
+/// Response status recorded by error results, for hooks and monitoring.
+pub const RESP_STATUS: &str = "resp.status";
+/// Machine-readable error code recorded by error results.
+pub const ERROR_CODE: &str = "error.code";
+/// Why a gatekeeping block turned the request away, one of the
+/// `errors::Outcome` codes.
+pub const OUTCOME_CODE: &str = "outcome.code";
+
+/// Authenticated user ID (AuthBlock).
+pub const AUTH_USER_ID: &str = "auth.user_id";
+/// Authenticated user email, when known (AuthBlock).
+pub const AUTH_USER_EMAIL: &str = "auth.user_email";
+/// Comma-separated roles of the authenticated user (AuthBlock).
+pub const AUTH_USER_ROLES: &str = "auth.user_roles";
+/// Token binding check result, "ok"/"mismatch" (AuthBlock `auth_bind_tokens`).
+pub const AUTH_TOKEN_BINDING: &str = "auth.token_binding";
+/// "true" when the token expired within AuthBlock's `auth_expired_grace_secs`.
+pub const AUTH_TOKEN_EXPIRED: &str = "auth.token_expired";
+/// Degraded mode in effect when services were unavailable (AuthBlock, IAMBlock).
+pub const DEGRADED_MODE: &str = "degraded.mode";
+
+/// Request's normalized path, cached by `path::request_path`.
+pub const REQUEST_PATH_NORMALIZED: &str = "request.path_normalized";
+/// "true" when the request path does not decode cleanly (`path::request_path`).
+pub const REQUEST_PATH_INVALID: &str = "request.path_invalid";
+/// Whether the client is a trusted proxy, "true"/"false" (TrustBoundaryBlock).
+pub const TRUST_PROXY: &str = "trust.proxy";
+/// Effective read-only state, "true"/"false" (ReadonlyGuardBlock).
+pub const READONLY_ACTIVE: &str = "readonly.active";
+/// "true" on writes ReadonlyGuardBlock rejected.
+pub const READONLY_REJECTED: &str = "readonly.rejected";
+
+/// Name of the matched route (RouterBlock).
+pub const ROUTE_NAME: &str = "route.name";
+/// Role required by the matched route (RouterBlock, read by IAMBlock).
+pub const ROUTE_ROLE: &str = "route.role";
+/// Chain the matched route forwards to (RouterBlock).
+pub const ROUTE_FORWARD_CHAIN: &str = "route.forward_chain";
+
+/// Path below the mount prefix (MountedBlock).
+pub const MOUNT_PATH: &str = "mount.path";
+/// The mount prefix that matched (MountedBlock).
+pub const MOUNT_PREFIX: &str = "mount.prefix";
+
+/// Client is a mobile device, "true"/"false" (ClientHintsBlock).
+pub const CLIENT_MOBILE: &str = "client.mobile";
+/// Client platform, e.g. "Android" (ClientHintsBlock).
+pub const CLIENT_PLATFORM: &str = "client.platform";
+/// Client asked for reduced data usage, "true"/"false" (ClientHintsBlock).
+pub const CLIENT_SAVE_DATA: &str = "client.save_data";
+/// Client's estimated downlink in Mbps (ClientHintsBlock).
+pub const CLIENT_DOWNLINK: &str = "client.downlink";
+
+/// Comma-separated names of the blocks a request passed through, capped at
+/// `MAX_TRACE_BLOCKS` (most recent kept).
+pub const TRACE_BLOCKS: &str = "trace.blocks";
+/// Name of the most recent block to handle the request.
+pub const TRACE_LAST_BLOCK: &str = "trace.last_block";
+/// Request is marked for debug tracing, "true"/"false" (TracedBlock).
+pub const TRACE_DEBUG: &str = "trace.debug";
+/// Whether the `wafer_debug` cookie verified, "true"/"false", set by the
+/// first TracedBlock to check it so later blocks skip the signature check.
+pub const TRACE_DEBUG_COOKIE: &str = "trace.debug_cookie";
+/// Id of the `trace::TraceCounters` the request's results are counted in,
+/// set by the MonitoringBlock that saw it.
+pub const TRACE_COUNTED_BY: &str = "trace.counted_by";
+/// Path of the message catalog for the request's errors, from the
+/// `messages_file` config of the latest block naming one (TracedBlock).
+pub const MESSAGES_CATALOG: &str = "messages.catalog";
+/// Upper bound on entries kept in `trace.blocks`.
+pub const MAX_TRACE_BLOCKS: usize = 32;
+
+/// Id of the `CircuitBreaker` that admitted the request, set by
+/// CircuitBreakerBlock middleware for `circuit_breaker::outcome_hook`.
+pub const CIRCUIT_BREAKER: &str = "circuit_breaker.id";
+
+/// Requests counted against the user's quota this period (QuotaBlock).
+pub const QUOTA_USED: &str = "quota.used";
+/// Soft quota exceeded, "true" when set (QuotaBlock).
+pub const QUOTA_WARNING: &str = "quota.warning";
+
+/// Request body is JSON that passed its schema, "true" when set (ValidateJsonBlock).
+pub const BODY_JSON_VALIDATED: &str = "body.json_validated";
+
+/// What decided the last role check: `authz`, `db`, `meta`, `public` for
+/// `public_paths`, or `none` when a fallback policy decided without
+/// consulting roles (IAMBlock).
+pub const IAM_SOURCE: &str = "iam.source";
+
+/// Scope the role was checked in, from the path (IAMBlock `scope_path`).
+pub const IAM_SCOPE: &str = "iam.scope";
+
+/// Prefix of assigned experiment variants (`experiment.<name>`, ExperimentBlock).
+pub const EXPERIMENT_PREFIX: &str = "experiment.";
+
+/// Response headers whose values are comma-separated lists, so several blocks
+/// can each contribute entries. `set_resp_header` merges into these instead of
+/// replacing them:
+///
+/// - `Vary`: CorsBlock (`Origin`), ClientHintsBlock (the consumed hints)
+/// - `Link`: SecurityHeadersBlock (`link` config), WebBlock (`web_preload`)
+/// - `Access-Control-Expose-Headers`: CorsBlock (`expose_headers` config)
+///
+/// Duplicate entries are dropped. Header-name lists compare case-insensitively;
+/// `Link` entries contain URLs and compare exactly.
+pub const MERGEABLE_HEADERS: &[&str] = &["Vary", "Link", "Access-Control-Expose-Headers"];
+
+/// Mergeable headers whose entries are compared case-sensitively.
+const CASE_SENSITIVE_LISTS: &[&str] = &["Link"];
+
+/// HTTP request method.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Patch,
+    Delete,
+    Options,
+    /// Any other method as sent, or "" when the message carries none.
+    Other(String),
+}
+
+impl Method {
+    pub fn parse(s: &str) -> Self {
+        match s.to_ascii_uppercase().as_str() {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "PATCH" => Method::Patch,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            _ => Method::Other(s.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Other(s) => s,
+        }
+    }
+
+    /// Whether the method is read-only (GET, HEAD or OPTIONS).
+    pub fn is_safe(&self) -> bool {
+        matches!(self, Method::Get | Method::Head | Method::Options)
+    }
+
+    /// The CRUD action the method implies by default: POST `create`, PUT
+    /// and PATCH `update`, DELETE `delete`, GET and HEAD `retrieve`, and ""
+    /// for any other.
+    pub fn default_action(&self) -> &'static str {
+        match self {
+            Method::Post => "create",
+            Method::Put | Method::Patch => "update",
+            Method::Delete => "delete",
+            Method::Get | Method::Head => "retrieve",
+            Method::Options | Method::Other(_) => "",
+        }
+    }
+}
+
+/// Maps a transport's operations onto the CRUD action and HTTP method the
+/// blocks expect:
+///
+/// ```ignore
+/// meta::set_transport_mapping(
+///     TransportMapping::new("rpc.operation")
+///         .map("users.get", "retrieve", Method::Get)
+///         .map("users.create", "create", Method::Post),
+/// )?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TransportMapping {
+    operation_key: String,
+    operations: HashMap<String, (String, Method)>,
+}
+
+impl TransportMapping {
+    /// Read the operation name from meta `operation_key`, or from the
+    /// message's action when empty.
+    pub fn new(operation_key: &str) -> Self {
+        Self {
+            operation_key: operation_key.to_string(),
+            operations: HashMap::new(),
+        }
+    }
+
+    /// Map `operation` to a CRUD `action` and HTTP `method`.
+    pub fn map(mut self, operation: &str, action: &str, method: Method) -> Self {
+        self.operations
+            .insert(operation.to_string(), (action.to_string(), method));
+        self
+    }
+
+    fn lookup(&self, msg: &Message) -> Option<&(String, Method)> {
+        let operation = if self.operation_key.is_empty() {
+            msg.action()
+        } else {
+            msg.get_meta(&self.operation_key)
+        };
+        self.operations.get(operation)
+    }
+}
+
+static TRANSPORT_MAPPING: OnceLock<TransportMapping> = OnceLock::new();
+
+/// Install the process-wide transport mapping. It can be set only once.
+pub fn set_transport_mapping(mapping: TransportMapping) -> Result<(), String> {
+    TRANSPORT_MAPPING
+        .set(mapping)
+        .map_err(|_| "transport mapping is already set".to_string())
+}
+
+fn mapped(msg: &Message) -> Option<&'static (String, Method)> {
+    TRANSPORT_MAPPING.get()?.lookup(msg)
+}
+
+/// The request's HTTP method, from `http.method` meta or the transport mapping.
+pub fn http_method(msg: &Message) -> Method {
+    let raw = msg.get_meta(HTTP_METHOD);
+    if raw.is_empty() {
+        if let Some((_, method)) = mapped(msg) {
+            return method.clone();
+        }
+    }
+    Method::parse(raw)
+}
+
+/// The request's CRUD action. A transport mapping entry for the operation
+/// takes precedence over the message's own action.
+pub fn action(msg: &Message) -> &str {
+    match mapped(msg) {
+        Some((action, _)) => action,
+        None => msg.action(),
+    }
+}
+
+/// What a request is, as a CRUD action: its `action` when set, otherwise
+/// its HTTP method mapped to one. The node's `method_actions` config
+/// overrides the mapping per method, e.g. `"POST=retrieve, PURGE=delete"`
+/// for an API that searches with POST; unlisted methods keep
+/// `Method::default_action`. "" when neither says.
+///
+/// Blocks that decide by action (readonly-guard, rate-limit, web) use this,
+/// so they agree on which requests are writes.
+pub fn resolved_action(ctx: &dyn Context, msg: &Message) -> String {
+    let explicit = action(msg);
+    if !explicit.is_empty() {
+        return explicit.to_string();
+    }
+    let method = http_method(msg);
+    let overrides = ctx.config_get("method_actions").unwrap_or("");
+    overrides
+        .split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .find(|(m, _)| m.trim().eq_ignore_ascii_case(method.as_str()))
+        .map(|(_, a)| a.trim().to_ascii_lowercase())
+        .unwrap_or_else(|| method.default_action().to_string())
+}
+
+/// Whether `action` changes state (create, update or delete).
+pub fn is_write_action(action: &str) -> bool {
+    matches!(action, "create" | "update" | "delete")
+}
+
+/// The authenticated user ID, if an auth block set one.
+pub fn user_id(msg: &Message) -> Option<&str> {
+    Some(msg.get_meta(AUTH_USER_ID)).filter(|s| !s.is_empty())
+}
+
+/// The authenticated user's roles.
+pub fn user_roles(msg: &Message) -> Vec<&str> {
+    msg.get_meta(AUTH_USER_ROLES)
+        .split(',')
+        .map(|r| r.trim())
+        .filter(|r| !r.is_empty())
+        .collect()
+}
+
+/// Whether a meta flag is set to "true".
+pub fn flag(msg: &Message, key: &str) -> bool {
+    msg.get_meta(key) == "true"
+}
+
+/// Set a meta flag to "true" or "false".
+pub fn set_flag(msg: &mut Message, key: &str, value: bool) {
+    msg.set_meta(key, if value { "true" } else { "false" });
+}
+
+/// Record that block `name` is handling the request, in `trace.blocks` and
+/// `trace.last_block`. wafer-core blocks are traced automatically; app blocks
+/// registered without `blocks::register_as` can call this themselves.
+pub fn trace_block(msg: &mut Message, name: &str) {
+    let mut blocks: Vec<&str> = msg
+        .get_meta(TRACE_BLOCKS)
+        .split(',')
+        .filter(|b| !b.is_empty())
+        .collect();
+    blocks.push(name);
+    let skip = blocks.len().saturating_sub(MAX_TRACE_BLOCKS);
+    let joined = blocks[skip..].join(",");
+    msg.set_meta(TRACE_BLOCKS, &joined);
+    msg.set_meta(TRACE_LAST_BLOCK, name);
+}
+
+/// Meta key of response header `name`, as spelled.
+pub fn resp_header_key(name: &str) -> String {
+    format!("{}{}", RESP_HEADER_PREFIX, name)
+}
+
+/// Whether `name` is one of the `MERGEABLE_HEADERS`.
+pub fn is_mergeable(name: &str) -> bool {
+    MERGEABLE_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+/// Meta key an existing value for header `name` is stored under, in any case.
+fn existing_key(msg: &Message, name: &str) -> Option<String> {
+    let exact = resp_header_key(name);
+    if msg.meta.contains_key(&exact) {
+        return Some(exact);
+    }
+    msg.meta
+        .keys()
+        .find(|k| {
+            k.strip_prefix(RESP_HEADER_PREFIX)
+                .is_some_and(|n| n.eq_ignore_ascii_case(name))
+        })
+        .cloned()
+}
+
+/// A response header set earlier in the chain, matched case-insensitively,
+/// or "" if unset.
+pub fn resp_header<'a>(msg: &'a Message, name: &str) -> &'a str {
+    match existing_key(msg, name) {
+        Some(key) => msg.get_meta(&key),
+        None => "",
+    }
+}
+
+/// Split a list header value on top-level commas, ignoring commas inside
+/// quoted strings and `<...>` URI references (as in `Link`).
+fn split_list(value: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let (mut start, mut quoted, mut in_uri) = (0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' if !in_uri => quoted = !quoted,
+            '<' if !quoted => in_uri = true,
+            '>' if !quoted => in_uri = false,
+            ',' if !quoted && !in_uri => {
+                items.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&value[start..]);
+    items
+        .into_iter()
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Merge the list entries of `value` into `existing`, skipping entries
+/// already present.
+pub fn merge_list(existing: &str, value: &str, case_sensitive: bool) -> String {
+    let same = |a: &str, b: &str| {
+        if case_sensitive {
+            a == b
+        } else {
+            a.eq_ignore_ascii_case(b)
+        }
+    };
+    let mut items = split_list(existing);
+    for v in split_list(value) {
+        if !items.iter().any(|i| same(i, v)) {
+            items.push(v);
+        }
+    }
+    items.join(", ")
+}
+
+/// Add list entries to response header `name`, keeping any existing entries,
+/// whether or not it is one of the `MERGEABLE_HEADERS`.
+pub fn append_resp_header(msg: &mut Message, name: &str, value: &str) {
+    let case_sensitive = CASE_SENSITIVE_LISTS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name));
+    let merged = match existing_key(msg, name) {
+        Some(key) => {
+            let merged = merge_list(msg.get_meta(&key), value, case_sensitive);
+            msg.meta.remove(&key);
+            merged
+        }
+        None => merge_list("", value, case_sensitive),
+    };
+    msg.set_meta(&resp_header_key(name), &merged);
+}
+
+/// Set response header `name`.
+///
+/// Any existing value is replaced, whatever case its name was written in,
+/// except for `MERGEABLE_HEADERS`, whose entries are merged with it.
+pub fn set_resp_header(msg: &mut Message, name: &str, value: &str) {
+    if is_mergeable(name) {
+        append_resp_header(msg, name, value);
+        return;
+    }
+    if let Some(key) = existing_key(msg, name) {
+        msg.meta.remove(&key);
+    }
+    msg.set_meta(&resp_header_key(name), value);
+}
+
+/// Remove response header `name`, in any case.
+pub fn remove_resp_header(msg: &mut Message, name: &str) {
+    msg.meta.retain(|k, _| {
+        !k.strip_prefix(RESP_HEADER_PREFIX)
+            .is_some_and(|n| n.eq_ignore_ascii_case(name))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn resolve(config: &[(&str, &str)], method: &str) -> String {
+        let mut ctx = MockContext::new();
+        for (k, v) in config {
+            ctx = ctx.with_config(k, v);
+        }
+        resolved_action(&ctx, &MockRequest::new(method, "/").build())
+    }
+
+    #[test]
+    fn methods_map_to_actions() {
+        for (method, action) in [
+            ("GET", "retrieve"),
+            ("HEAD", "retrieve"),
+            ("POST", "create"),
+            ("PUT", "update"),
+            ("PATCH", "update"),
+            ("DELETE", "delete"),
+            ("OPTIONS", ""),
+        ] {
+            assert_eq!(resolve(&[], method), action, "{}", method);
+        }
+    }
+
+    #[test]
+    fn method_actions_override_the_mapping() {
+        let config = [("method_actions", "POST=retrieve, purge = Delete")];
+        assert_eq!(resolve(&config, "POST"), "retrieve");
+        assert_eq!(resolve(&config, "PURGE"), "delete");
+        assert_eq!(resolve(&config, "PUT"), "update");
+        assert!(!is_write_action(&resolve(&config, "POST")));
+        assert!(is_write_action(&resolve(&config, "PURGE")));
+    }
+
+    #[test]
+    fn meta_keys_are_pinned_and_distinct() {
+        // Blocks and apps agree on these spellings; a rename must show up here.
+        let keys = [
+            (HTTP_HEADER_PREFIX, "http.header."),
+            (HTTP_METHOD, "http.method"),
+            (RESP_HEADER_PREFIX, "resp.header."),
+            (RESP_STATUS, "resp.status"),
+            (ERROR_CODE, "error.code"),
+            (OUTCOME_CODE, "outcome.code"),
+            (AUTH_USER_ID, "auth.user_id"),
+            (AUTH_USER_EMAIL, "auth.user_email"),
+            (AUTH_USER_ROLES, "auth.user_roles"),
+            (AUTH_TOKEN_BINDING, "auth.token_binding"),
+            (AUTH_TOKEN_EXPIRED, "auth.token_expired"),
+            (DEGRADED_MODE, "degraded.mode"),
+            (REQUEST_PATH_NORMALIZED, "request.path_normalized"),
+            (REQUEST_PATH_INVALID, "request.path_invalid"),
+            (TRUST_PROXY, "trust.proxy"),
+            (READONLY_ACTIVE, "readonly.active"),
+            (READONLY_REJECTED, "readonly.rejected"),
+            (ROUTE_NAME, "route.name"),
+            (ROUTE_ROLE, "route.role"),
+            (ROUTE_FORWARD_CHAIN, "route.forward_chain"),
+            (MOUNT_PATH, "mount.path"),
+            (MOUNT_PREFIX, "mount.prefix"),
+            (CLIENT_MOBILE, "client.mobile"),
+            (CLIENT_PLATFORM, "client.platform"),
+            (CLIENT_SAVE_DATA, "client.save_data"),
+            (CLIENT_DOWNLINK, "client.downlink"),
+            (TRACE_BLOCKS, "trace.blocks"),
+            (TRACE_LAST_BLOCK, "trace.last_block"),
+            (TRACE_DEBUG, "trace.debug"),
+            (TRACE_DEBUG_COOKIE, "trace.debug_cookie"),
+            (TRACE_COUNTED_BY, "trace.counted_by"),
+            (MESSAGES_CATALOG, "messages.catalog"),
+            (CIRCUIT_BREAKER, "circuit_breaker.id"),
+            (QUOTA_USED, "quota.used"),
+            (QUOTA_WARNING, "quota.warning"),
+            (BODY_JSON_VALIDATED, "body.json_validated"),
+            (IAM_SOURCE, "iam.source"),
+            (IAM_SCOPE, "iam.scope"),
+            (EXPERIMENT_PREFIX, "experiment."),
+        ];
+        let mut seen = std::collections::HashSet::new();
+        for (key, spelled) in keys {
+            assert_eq!(key, spelled);
+            assert!(seen.insert(key), "{} is defined twice", key);
+        }
+        assert_eq!(MAX_TRACE_BLOCKS, 32);
+        assert_eq!(
+            MERGEABLE_HEADERS,
+            ["Vary", "Link", "Access-Control-Expose-Headers"]
+        );
+    }
+
+    #[test]
+    fn auth_accessors_read_the_auth_keys() {
+        let msg = MockRequest::get("/").build();
+        assert_eq!(user_id(&msg), None);
+        assert!(user_roles(&msg).is_empty());
+
+        let msg = MockRequest::get("/")
+            .meta(AUTH_USER_ID, "u1")
+            .meta(AUTH_USER_ROLES, " admin, ,editor ")
+            .build();
+        assert_eq!(user_id(&msg), Some("u1"));
+        assert_eq!(user_roles(&msg), ["admin", "editor"]);
+    }
+
+    #[test]
+    fn methods_parse_in_any_case() {
+        let msg = MockRequest::get("/").meta(HTTP_METHOD, "patch").build();
+        assert_eq!(http_method(&msg), Method::Patch);
+        assert_eq!(Method::parse("PURGE"), Method::Other("PURGE".to_string()));
+        assert_eq!(Method::parse("PURGE").as_str(), "PURGE");
+        assert!(Method::Head.is_safe());
+        assert!(!Method::Delete.is_safe());
+    }
+
+    #[test]
+    fn flags_round_trip() {
+        let mut msg = MockRequest::get("/").build();
+        assert!(!flag(&msg, READONLY_ACTIVE));
+        set_flag(&mut msg, READONLY_ACTIVE, true);
+        assert!(flag(&msg, READONLY_ACTIVE));
+        assert_eq!(msg.get_meta(READONLY_ACTIVE), "true");
+        set_flag(&mut msg, READONLY_ACTIVE, false);
+        assert!(!flag(&msg, READONLY_ACTIVE));
+    }
+
+    #[test]
+    fn traces_keep_the_latest_blocks() {
+        let mut msg = MockRequest::get("/").build();
+        for i in 0..MAX_TRACE_BLOCKS + 2 {
+            trace_block(&mut msg, &format!("b{}", i));
+        }
+        let blocks: Vec<&str> = msg.get_meta(TRACE_BLOCKS).split(',').collect();
+        assert_eq!(blocks.len(), MAX_TRACE_BLOCKS);
+        assert_eq!(blocks[0], "b2");
+        assert_eq!(msg.get_meta(TRACE_LAST_BLOCK), "b33");
+    }
+
+    #[test]
+    fn resp_headers_replace_in_any_case() {
+        let mut msg = MockRequest::get("/")
+            .meta(&resp_header_key("cache-control"), "no-store")
+            .build();
+        assert_eq!(resp_header(&msg, "Cache-Control"), "no-store");
+        set_resp_header(&mut msg, "Cache-Control", "public");
+        assert_eq!(resp_header(&msg, "cache-control"), "public");
+        assert_eq!(msg.get_meta(&resp_header_key("cache-control")), "");
+
+        remove_resp_header(&mut msg, "CACHE-CONTROL");
+        assert_eq!(resp_header(&msg, "Cache-Control"), "");
+    }
+
+    #[test]
+    fn mergeable_headers_append() {
+        let mut msg = MockRequest::get("/")
+            .meta(&resp_header_key("vary"), "Accept")
+            .build();
+        set_resp_header(&mut msg, "Vary", "origin, Accept-Encoding");
+        assert_eq!(resp_header(&msg, "Vary"), "Accept, origin, Accept-Encoding");
+        assert!(is_mergeable("vary"));
+        assert!(!is_mergeable("Cache-Control"));
+
+        set_resp_header(&mut msg, "Link", r#"</a.css>; rel="preload, x""#);
+        append_resp_header(&mut msg, "Link", "</A.css>; rel=preload");
+        assert_eq!(
+            resp_header(&msg, "Link"),
+            r#"</a.css>; rel="preload, x", </A.css>; rel=preload"#
+        );
+        assert_eq!(merge_list("a, b", "B, c", false), "a, b, c");
+    }
+}
@@ -0,0 +1,163 @@
+//! Shared IP address and CIDR matching.
+//!
+//! Blocks that trust or exempt clients by network (trusted proxies, allow
+//! lists) parse their config through `CidrList` so every block accepts the
+//! same syntax: comma-separated CIDRs or bare addresses, IPv4 or IPv6.
+//! `client_ip` and `request_host` read who sent a request and which host it
+//! was for.
+
+use std::net::{IpAddr, SocketAddr};
+use wafer_run::Message;
+
+use crate::meta;
+
+/// Parse a client address as reported by the runtime: a bare IP, `ip:port`,
+/// or `[v6]:port`. IPv4-mapped IPv6 addresses are returned as IPv4.
+pub fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    let ip = s
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| s.parse::<SocketAddr>().ok().map(|a| a.ip()))
+        .or_else(|| {
+            s.strip_prefix('[')
+                .and_then(|r| r.strip_suffix(']'))
+                .and_then(|r| r.parse::<IpAddr>().ok())
+        })?;
+    Some(canonical(ip))
+}
+
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+/// Cidr is one network, e.g. `10.0.0.0/8` or `fd00::/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parse `addr/prefix`, or a bare address as a single-host network.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let addr = canonical(
+            addr.parse::<IpAddr>()
+                .map_err(|_| format!("invalid address in '{}'", s))?,
+        );
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length in '{}'", s))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    /// Whether `ip` lies within this network.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, canonical(*ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// CidrList is a set of networks parsed from a comma-separated config value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CidrList {
+    cidrs: Vec<Cidr>,
+}
+
+impl CidrList {
+    /// Parse a comma-separated list; invalid entries are skipped with a warning.
+    pub fn parse(raw: &str) -> Self {
+        Self {
+            cidrs: raw
+                .split(',')
+                .map(|c| c.trim())
+                .filter(|c| !c.is_empty())
+                .filter_map(|c| match Cidr::parse(c) {
+                    Ok(cidr) => Some(cidr),
+                    Err(e) => {
+                        tracing::warn!("ignoring CIDR entry: {}", e);
+                        None
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cidrs.is_empty()
+    }
+
+    /// Whether any network contains `ip`.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.cidrs.iter().any(|c| c.contains(ip))
+    }
+
+    /// Whether any network contains the address in `addr` (see `parse_ip`).
+    pub fn contains_addr(&self, addr: &str) -> bool {
+        parse_ip(addr).is_some_and(|ip| self.contains(&ip))
+    }
+}
+
+/// The client address of a request, proxy-aware: when TrustBoundaryBlock
+/// marked the peer as a trusted proxy (`trust.proxy`), the last
+/// `X-Forwarded-For` entry (the address the proxy saw), else `X-Real-IP`;
+/// otherwise the peer address itself.
+pub fn client_ip(msg: &Message) -> String {
+    if meta::flag(msg, meta::TRUST_PROXY) {
+        let forwarded = msg
+            .header("X-Forwarded-For")
+            .rsplit(',')
+            .map(|s| s.trim())
+            .find(|s| !s.is_empty());
+        if let Some(ip) = forwarded.filter(|s| parse_ip(s).is_some()) {
+            return ip.to_string();
+        }
+        let real_ip = msg.header("X-Real-IP").trim();
+        if parse_ip(real_ip).is_some() {
+            return real_ip.to_string();
+        }
+    }
+    msg.remote_addr().to_string()
+}
+
+/// The host a request was sent to, from `Host`: lowercased, without the
+/// port or a trailing dot, and with IPv6 brackets kept. "" when absent.
+pub fn request_host(msg: &Message) -> String {
+    let host = msg.header("Host").trim();
+    let name = match host.strip_prefix('[') {
+        Some(v6) => v6
+            .split(']')
+            .next()
+            .map(|a| format!("[{}]", a))
+            .unwrap_or_default(),
+        None => host.split(':').next().unwrap_or("").to_string(),
+    };
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
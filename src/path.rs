@@ -0,0 +1,255 @@
+//! Shared request path normalization and prefix matching.
+//!
+//! Blocks that apply rules by path prefix must match against the normalized
+//! path, otherwise `//api///users` or `/api/./users` slips past a rule for
+//! `/api`.
+//!
+//! Blocks read the request path through `request_path`, which percent-decodes
+//! it once, normalizes it to Unicode NFC, normalizes it as above and caches
+//! the result in `request.path_normalized` meta, so routing, access rules,
+//! rate limits, monitoring and file lookup all agree on the same value:
+//! `/%61dmin` and `/admin` are the same path to every block. A path that
+//! decodes to invalid UTF-8 or control characters is rejected with 400 by
+//! `TracedBlock` before any registered block runs (see `check`).
+//!
+//! Infrastructure middleware (auth, iam, rate-limit, quota, monitoring
+//! counting, ua-filter, readonly-guard, tls-guard, validate-json) honors a
+//! `skip_paths` config through `is_skipped`: a comma-separated `PrefixList`
+//! such as `"/healthz, /metrics, /assets"`. Matching requests pass the block
+//! untouched, so health checks and static assets need no separate chain.
+//! Skipping auth or iam on a path makes it public; list only paths that are
+//! meant to be.
+
+use parking_lot::RwLock;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use unicode_normalization::UnicodeNormalization;
+use wafer_run::{Context, Message};
+
+use crate::errors::CoreError;
+use crate::meta;
+
+/// Meta key caching the request's normalized path.
+pub const NORMALIZED_PATH_META: &str = meta::REQUEST_PATH_NORMALIZED;
+
+/// Meta key marking a request path that does not decode cleanly.
+pub const INVALID_PATH_META: &str = meta::REQUEST_PATH_INVALID;
+
+/// The request's decoded, NFC, normalized (case-preserving) path, computed on
+/// first use. A path that does not decode cleanly is marked invalid (see
+/// `check`) and reads as its undecoded normalized form.
+pub fn request_path(msg: &mut Message) -> String {
+    let cached = msg.get_meta(NORMALIZED_PATH_META);
+    if !cached.is_empty() {
+        return cached.to_string();
+    }
+    let (p, valid) = compute_request_path(msg);
+    if !valid {
+        msg.set_meta(INVALID_PATH_META, "true");
+    }
+    msg.set_meta(NORMALIZED_PATH_META, &p);
+    p
+}
+
+/// `request_path` for a message that cannot be written; nothing is cached.
+pub fn request_path_of(msg: &Message) -> String {
+    let cached = msg.get_meta(NORMALIZED_PATH_META);
+    if !cached.is_empty() {
+        return cached.to_string();
+    }
+    compute_request_path(msg).0
+}
+
+fn compute_request_path(msg: &Message) -> (String, bool) {
+    match decode(msg.path()) {
+        Some(decoded) => (normalize(&decoded, false), true),
+        None => (normalize(msg.path(), false), false),
+    }
+}
+
+/// Reject a request whose path decodes to invalid UTF-8 or control
+/// characters with 400.
+pub fn check(msg: &mut Message) -> Result<(), CoreError> {
+    request_path(msg);
+    if msg.get_meta(INVALID_PATH_META) == "true" {
+        return Err(CoreError::BadRequest("Invalid request path".to_string()));
+    }
+    Ok(())
+}
+
+/// Percent-decode a raw path once and normalize it to Unicode NFC. `None`
+/// when it decodes to invalid UTF-8 or contains control characters.
+pub fn decode(raw: &str) -> Option<String> {
+    let decoded =
+        String::from_utf8(urlencoding::decode_binary(raw.as_bytes()).into_owned()).ok()?;
+    if decoded.chars().any(char::is_control) {
+        return None;
+    }
+    Some(decoded.nfc().collect())
+}
+
+/// Normalize a request path: collapse repeated slashes, resolve `.` and `..`
+/// (never above the root), and optionally lowercase. The result always starts
+/// with `/` and has no trailing slash, except for the root itself.
+pub fn normalize(p: &str, lowercase: bool) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for seg in p.split('/') {
+        match seg {
+            "" | "." => continue,
+            ".." => {
+                parts.pop();
+            }
+            s => parts.push(s),
+        }
+    }
+    let out = format!("/{}", parts.join("/"));
+    if lowercase {
+        out.to_lowercase()
+    } else {
+        out
+    }
+}
+
+/// Whether `path` is `prefix` or lies beneath it, matching whole segments
+/// only (`/api` matches `/api` and `/api/x`, not `/apix`). Both arguments
+/// should already be normalized; a trailing slash on `prefix` is ignored.
+pub fn has_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() {
+        return true;
+    }
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Compile a path `pattern` that must match the whole path, as
+/// `^(?:pattern)$`. Checking that a plain search's match spans the path
+/// misses some paths: leftmost-first alternation finds `/a` for `/a|/a/b`
+/// against `/a/b`.
+pub fn whole_path_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", pattern))
+}
+
+/// Upper bound on distinct `skip_paths` values kept parsed.
+const MAX_PARSED_SKIP_PATHS: usize = 256;
+
+/// Whether the request falls under the block's `skip_paths` config. Each
+/// distinct value is parsed once.
+pub fn is_skipped(ctx: &dyn Context, msg: &mut Message) -> bool {
+    let raw = ctx.config_get("skip_paths").unwrap_or("");
+    if raw.trim().is_empty() {
+        return false;
+    }
+    skip_paths(raw).matches(&request_path(msg))
+}
+
+/// The parsed `skip_paths` value `raw`, keyed by the raw config.
+fn skip_paths(raw: &str) -> Arc<PrefixList> {
+    static PARSED: OnceLock<RwLock<HashMap<String, Arc<PrefixList>>>> = OnceLock::new();
+    let parsed = PARSED.get_or_init(|| RwLock::new(HashMap::new()));
+    if let Some(list) = parsed.read().get(raw) {
+        return list.clone();
+    }
+    let list = Arc::new(PrefixList::parse(raw));
+    let mut parsed = parsed.write();
+    // Config values churning past the bound start the cache over
+    if parsed.len() >= MAX_PARSED_SKIP_PATHS {
+        parsed.clear();
+    }
+    parsed.insert(raw.to_string(), list.clone());
+    list
+}
+
+/// PrefixList is a set of path prefixes parsed from a comma-separated config value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrefixList {
+    prefixes: Vec<String>,
+}
+
+impl PrefixList {
+    /// Parse a comma-separated list; each entry is normalized.
+    pub fn parse(raw: &str) -> Self {
+        Self {
+            prefixes: raw
+                .split(',')
+                .map(|p| p.trim())
+                .filter(|p| !p.is_empty())
+                .map(|p| normalize(p, false))
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+
+    /// Whether any prefix matches the (normalized) path.
+    pub fn matches(&self, path: &str) -> bool {
+        self.prefixes.iter().any(|p| has_prefix(path, p))
+    }
+
+    /// The longest prefix matching the (normalized) path.
+    pub fn longest_match(&self, path: &str) -> Option<&str> {
+        self.prefixes
+            .iter()
+            .filter(|p| has_prefix(path, p))
+            .max_by_key(|p| p.len())
+            .map(|p| p.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockRequest;
+
+    fn path_of(raw: &str) -> (String, Result<(), CoreError>) {
+        let mut msg = MockRequest::get(raw).build();
+        let p = request_path(&mut msg);
+        (p, check(&mut msg))
+    }
+
+    #[test]
+    fn request_path_decodes_once() {
+        assert_eq!(path_of("/%61dmin//users/").0, "/admin/users");
+        assert_eq!(path_of("/a/%2e%2e/admin").0, "/admin");
+        // A second round of decoding would turn %2541 into A
+        assert_eq!(path_of("/%2541").0, "/%41");
+    }
+
+    #[test]
+    fn request_path_is_nfc() {
+        let nfd = "/u\u{0308}rlaub.jpg";
+        assert_eq!(path_of(nfd).0, "/\u{00fc}rlaub.jpg");
+        assert_eq!(path_of("/%C3%BCrlaub.jpg").0, "/\u{00fc}rlaub.jpg");
+        assert_eq!(path_of("/u%CC%88rlaub.jpg").0, "/\u{00fc}rlaub.jpg");
+    }
+
+    #[test]
+    fn undecodable_paths_are_rejected() {
+        for raw in ["/%FF", "/a%00b", "/a%0Ab", "/%C3"] {
+            let (p, checked) = path_of(raw);
+            assert!(checked.is_err(), "{} accepted", raw);
+            assert_eq!(p, normalize(raw, false));
+        }
+        assert!(path_of("/ok").1.is_ok());
+    }
+
+    #[test]
+    fn request_path_of_matches_request_path() {
+        let msg = MockRequest::get("/%61pi/./x").build();
+        assert_eq!(request_path_of(&msg), "/api/x");
+    }
+
+    #[test]
+    fn prefix_lists_match_whole_segments() {
+        let list = PrefixList::parse("/api, /static/");
+        assert!(list.matches("/api"));
+        assert!(list.matches("/static/app.js"));
+        assert!(!list.matches("/apix"));
+        assert_eq!(list.longest_match("/api/v1"), Some("/api"));
+    }
+}
@@ -0,0 +1,156 @@
+//! Shared evaluation of HTTP preconditions (RFC 9110 section 13).
+//!
+//! Blocks that change a resource (uploads, storage writes) check the
+//! request's `If-Match` and `If-None-Match` against the resource's current
+//! ETag with `evaluate_write` before writing, so concurrent editors cannot
+//! overwrite each other's changes:
+//!
+//! ```ignore
+//! let current = store.etag(&key); // None when the resource doesn't exist
+//! if precondition::evaluate_write(msg, current.as_deref()) == Precondition::Failed {
+//!     return precondition::failed(msg);
+//! }
+//! ```
+//!
+//! `If-Match` uses strong comparison and `If-None-Match` weak comparison;
+//! `*` matches any existing resource. WebBlock's `304` handling uses
+//! `list_matches` for the read side.
+
+use wafer_run::*;
+
+use crate::errors::CoreError;
+
+/// Outcome of a write's preconditions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    /// No precondition, or all of them hold: perform the write.
+    Proceed,
+    /// A precondition does not hold: answer 412 without writing.
+    Failed,
+}
+
+/// Whether an entity tag is weak (`W/"..."`).
+fn is_weak(tag: &str) -> bool {
+    tag.starts_with("W/")
+}
+
+/// Whether a comma-separated ETag list (an `If-Match` or `If-None-Match`
+/// value) matches `etag`, using strong comparison when `strong` (weak tags
+/// never match) and weak comparison otherwise. `*` matches any ETag.
+pub fn list_matches(list: &str, etag: &str, strong: bool) -> bool {
+    if strong && is_weak(etag) {
+        return list.split(',').any(|t| t.trim() == "*");
+    }
+    let opaque = etag.trim_start_matches("W/");
+    list.split(',')
+        .map(|t| t.trim())
+        .any(|t| t == "*" || (!(strong && is_weak(t)) && t.trim_start_matches("W/") == opaque))
+}
+
+/// Evaluate `If-Match` and `If-None-Match` (raw header values, "" when
+/// absent) for a write to a resource whose current ETag is `current`
+/// (`None` when it doesn't exist).
+///
+/// `If-Match` fails unless the resource exists and, unless the value is
+/// `*`, its ETag strongly matches. `If-None-Match` fails when the resource
+/// exists and the value is `*` (create-only) or weakly matches its ETag.
+pub fn evaluate(if_match: &str, if_none_match: &str, current: Option<&str>) -> Precondition {
+    let if_match = if_match.trim();
+    if !if_match.is_empty() {
+        let holds = current.is_some_and(|etag| list_matches(if_match, etag, true));
+        if !holds {
+            return Precondition::Failed;
+        }
+    }
+    let if_none_match = if_none_match.trim();
+    if !if_none_match.is_empty() {
+        if let Some(etag) = current {
+            if list_matches(if_none_match, etag, false) {
+                return Precondition::Failed;
+            }
+        }
+    }
+    Precondition::Proceed
+}
+
+/// `evaluate` with the request's own `If-Match` and `If-None-Match`.
+pub fn evaluate_write(msg: &Message, current: Option<&str>) -> Precondition {
+    evaluate(msg.header("If-Match"), msg.header("If-None-Match"), current)
+}
+
+/// Answer a write whose preconditions failed with 412.
+pub fn failed(msg: &Message) -> Result_ {
+    CoreError::custom(
+        412,
+        "precondition_failed",
+        "The resource has changed; fetch it again before writing",
+    )
+    .respond(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const V1: &str = "\"v1\"";
+    const WEAK_V1: &str = "W/\"v1\"";
+
+    #[test]
+    fn lists_match_listed_tags() {
+        assert!(list_matches("\"v1\"", V1, true));
+        assert!(list_matches("\"v0\", \"v1\"", V1, true));
+        assert!(!list_matches("\"v2\"", V1, true));
+        assert!(!list_matches("\"v2\", \"v3\"", V1, false));
+        assert!(!list_matches("", V1, false));
+    }
+
+    #[test]
+    fn star_matches_any_tag() {
+        assert!(list_matches("*", V1, true));
+        assert!(list_matches("*", WEAK_V1, true));
+        assert!(list_matches("\"v2\", *", V1, false));
+    }
+
+    #[test]
+    fn weak_tags_match_only_weakly() {
+        assert!(list_matches(WEAK_V1, V1, false));
+        assert!(list_matches("\"v1\"", WEAK_V1, false));
+        assert!(!list_matches(WEAK_V1, V1, true));
+        assert!(!list_matches("\"v1\"", WEAK_V1, true));
+        assert!(!list_matches(WEAK_V1, WEAK_V1, true));
+    }
+
+    #[test]
+    fn if_match_needs_an_existing_strong_match() {
+        assert_eq!(evaluate(V1, "", Some(V1)), Precondition::Proceed);
+        assert_eq!(evaluate("\"v2\"", "", Some(V1)), Precondition::Failed);
+        assert_eq!(evaluate(V1, "", Some(WEAK_V1)), Precondition::Failed);
+        assert_eq!(evaluate("*", "", Some(V1)), Precondition::Proceed);
+        assert_eq!(evaluate("*", "", None), Precondition::Failed);
+    }
+
+    #[test]
+    fn if_none_match_fails_on_any_weak_match() {
+        assert_eq!(evaluate("", "*", None), Precondition::Proceed);
+        assert_eq!(evaluate("", "*", Some(V1)), Precondition::Failed);
+        assert_eq!(evaluate("", WEAK_V1, Some(V1)), Precondition::Failed);
+        assert_eq!(evaluate("", "\"v2\"", Some(V1)), Precondition::Proceed);
+        assert_eq!(evaluate("", "", Some(V1)), Precondition::Proceed);
+    }
+
+    #[test]
+    fn writes_read_the_request_headers() {
+        let msg = MockRequest::new("PUT", "/doc")
+            .header("If-Match", "\"v2\"")
+            .build();
+        assert_eq!(evaluate_write(&msg, Some(V1)), Precondition::Failed);
+        assert_status(
+            &SimulatedResponse::from_result(&failed(&msg)),
+            412,
+            Some("precondition_failed"),
+        );
+        let msg = MockRequest::new("PUT", "/doc").build();
+        assert_eq!(evaluate_write(&msg, Some(V1)), Precondition::Proceed);
+    }
+}
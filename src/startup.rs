@@ -0,0 +1,506 @@
+//! Startup checks of node config.
+//!
+//! Blocks read their config per request and fall back to defaults on bad
+//! values, so a typo or a risky combination only shows up in behavior. These
+//! checks read chain definitions up front and report what looks wrong as
+//! `Warning`s for the host app to log or fail on:
+//!
+//! ```ignore
+//! for warning in wafer_core::register_all_checked(&mut w) {
+//!     tracing::warn!("{}", warning);
+//! }
+//! chains::register_chains_with(&mut w, &overrides)?;
+//! for warning in startup::check_chains(&overrides)? {
+//!     tracing::warn!("{}", warning);
+//! }
+//! ```
+//!
+//! Every key is checked against the block's `admin_descriptor()` (unknown
+//! keys, values of the wrong type or outside an enum, unparseable JSON), and
+//! some blocks add checks of their own, such as reflected wildcard CORS
+//! granting credentials, a web root that does not exist or a versioned one
+//! without an active release.
+
+use serde_json::{Map, Value};
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::admin::FieldKind;
+use crate::blocks::cors::{MethodsMap, OriginPolicy};
+use crate::blocks::deprecation::DeprecationRule;
+use crate::blocks::rate_limit::{RouteLimits, TenantLimit};
+use crate::blocks::web;
+use crate::chains::{self, ChainOverrides};
+
+/// A config issue found at startup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    /// Chain id the node belongs to; empty when checking a bare config.
+    pub chain: String,
+    pub block: String,
+    /// The config key at fault, if one.
+    pub key: Option<String>,
+    /// Stable identifier such as `unknown_key` or `cors_wildcard_credentials`.
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Warning {
+    fn new(block: &str, key: Option<&str>, code: &'static str, message: String) -> Self {
+        Self {
+            chain: String::new(),
+            block: block.to_string(),
+            key: key.map(|k| k.to_string()),
+            code,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.chain.is_empty() {
+            write!(f, "{}: ", self.chain)?;
+        }
+        write!(f, "{}: {} [{}]", self.block, self.message, self.code)
+    }
+}
+
+/// Check the standard chain templates with `overrides` applied, as
+/// `chains::register_chains_with` would register them.
+pub fn check_chains(overrides: &ChainOverrides) -> Result<Vec<Warning>, String> {
+    Ok(chains::resolved_templates(overrides)?
+        .iter()
+        .flat_map(|(_, def)| check_chain(def))
+        .collect())
+}
+
+/// Check every block node of a chain definition in JSON form.
+pub fn check_chain(def: &Value) -> Vec<Warning> {
+    let chain = def.get("id").and_then(|v| v.as_str()).unwrap_or("");
+    let mut warnings = Vec::new();
+    check_node(&def["root"], &mut warnings);
+    for w in &mut warnings {
+        w.chain = chain.to_string();
+    }
+    warnings
+}
+
+fn check_node(node: &Value, out: &mut Vec<Warning>) {
+    if let Some(block) = node.get("block").and_then(|b| b.as_str()) {
+        let empty = Map::new();
+        let config = node
+            .get("config")
+            .and_then(|c| c.as_object())
+            .unwrap_or(&empty);
+        out.extend(check_config(block, config));
+    }
+    if let Some(next) = node.get("next").and_then(|n| n.as_array()) {
+        for child in next {
+            check_node(child, out);
+        }
+    }
+}
+
+/// Check one node's config. Blocks other than wafer-core's are not checked.
+pub fn check_config(block: &str, config: &Map<String, Value>) -> Vec<Warning> {
+    let descriptor = match crate::admin_descriptors()
+        .into_iter()
+        .find(|(name, _)| *name == block)
+    {
+        Some((_, d)) => d,
+        None => return Vec::new(),
+    };
+    let values: Vec<(&str, String)> = config
+        .iter()
+        .map(|(k, v)| {
+            let v = match v {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            (k.as_str(), v)
+        })
+        .collect();
+    let get = |key: &str| {
+        values
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    };
+
+    let mut warnings = Vec::new();
+    for (key, value) in &values {
+        let field = match descriptor.fields.iter().find(|f| f.key == *key) {
+            Some(f) => f,
+            None => {
+                warnings.push(Warning::new(
+                    block,
+                    Some(key),
+                    "unknown_key",
+                    format!("unknown config key {:?}", key),
+                ));
+                continue;
+            }
+        };
+        if let Some(problem) = invalid_value(&field.kind, value) {
+            warnings.push(Warning::new(
+                block,
+                Some(key),
+                "invalid_value",
+                format!("{}: {}", key, problem),
+            ));
+        }
+    }
+    check_block(block, &get, &mut warnings);
+    warnings
+}
+
+/// Why `value` does not fit `kind`, if it doesn't. Empty values mean "unset".
+fn invalid_value(kind: &FieldKind, value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+    match kind {
+        FieldKind::Bool => (!matches!(value, "true" | "false" | "1" | "0"))
+            .then(|| format!("{:?} is not a boolean", value)),
+        FieldKind::Integer => value
+            .parse::<u64>()
+            .is_err()
+            .then(|| format!("{:?} is not a non-negative integer", value)),
+        FieldKind::Number => value
+            .parse::<f64>()
+            .is_err()
+            .then(|| format!("{:?} is not a number", value)),
+        FieldKind::Json => serde_json::from_str::<Value>(value)
+            .err()
+            .map(|e| format!("invalid JSON: {}", e)),
+        FieldKind::Enum(values) => (!values.iter().any(|v| v == value))
+            .then(|| format!("{:?} is not one of {}", value, values.join(", "))),
+        FieldKind::String | FieldKind::List => None,
+    }
+}
+
+/// Checks of value combinations specific to one block.
+fn check_block(block: &str, get: &dyn Fn(&str) -> Option<&str>, out: &mut Vec<Warning>) {
+    match block {
+        "@wafer/cors" => {
+            let origins = get("allowed_origins").unwrap_or("*").trim();
+            let reflect = get("reflect_wildcard").is_none_or(|s| s == "true" || s == "1");
+            let policies: Map<String, Value> = get("origin_policies")
+                .and_then(|s| serde_json::from_str(s).ok())
+                .unwrap_or_default();
+            let grants_credentials = policies.values().any(|p| {
+                serde_json::from_value::<OriginPolicy>(p.clone())
+                    .is_ok_and(|p| p.credentials == Some(true))
+            });
+            if origins == "*" && reflect && grants_credentials {
+                out.push(Warning::new(
+                    block,
+                    Some("origin_policies"),
+                    "cors_wildcard_credentials",
+                    "allowed_origins is * with reflect_wildcard, so any origin matching a \
+                     credentials policy gets credentialed access; list the origins instead"
+                        .to_string(),
+                ));
+            }
+            if let Some(raw) = get("cors_methods_map").filter(|s| !s.trim().is_empty()) {
+                if let Err(e) = MethodsMap::parse(raw) {
+                    out.push(Warning::new(
+                        block,
+                        Some("cors_methods_map"),
+                        "invalid_value",
+                        e,
+                    ));
+                }
+                let auto =
+                    get("allowed_methods").is_some_and(|s| s.trim().eq_ignore_ascii_case("auto"));
+                if !auto {
+                    out.push(Warning::new(
+                        block,
+                        Some("cors_methods_map"),
+                        "cors_methods_map_unused",
+                        "cors_methods_map has no effect unless allowed_methods is auto".to_string(),
+                    ));
+                }
+            }
+        }
+        "@wafer/security-headers" => {
+            let hsts = get("hsts").unwrap_or("max-age=31536000; includeSubDomains");
+            let lower = hsts.to_ascii_lowercase();
+            if !hsts.trim().is_empty() && !lower.contains("max-age=") {
+                out.push(Warning::new(
+                    block,
+                    Some("hsts"),
+                    "hsts_without_max_age",
+                    format!("hsts {:?} has no max-age; browsers ignore it", hsts),
+                ));
+            }
+            if lower.contains("preload") && !lower.contains("includesubdomains") {
+                out.push(Warning::new(
+                    block,
+                    Some("hsts"),
+                    "hsts_preload_without_subdomains",
+                    "hsts preload requires includeSubDomains to be accepted by preload lists"
+                        .to_string(),
+                ));
+            }
+        }
+        "@wafer/web" => {
+            let mut root = PathBuf::from(get("web_root").unwrap_or("./public"));
+            if !root.is_dir() {
+                out.push(Warning::new(
+                    block,
+                    Some("web_root"),
+                    "web_root_missing",
+                    format!("web_root {} is not a directory", root.display()),
+                ));
+                return;
+            }
+            if get("web_root_mode") == Some("versioned") {
+                match web::current_release(&root) {
+                    Ok(release) => root.push(release),
+                    Err(e) => {
+                        out.push(Warning::new(
+                            block,
+                            Some("web_root"),
+                            "web_release_missing",
+                            e,
+                        ));
+                        return;
+                    }
+                }
+            }
+            if let Some(name) = get("web_manifest").filter(|s| !s.is_empty()) {
+                if !root.join(name).is_file() {
+                    out.push(Warning::new(
+                        block,
+                        Some("web_manifest"),
+                        "web_manifest_missing",
+                        format!("web_manifest {} not found under {}", name, root.display()),
+                    ));
+                }
+            }
+        }
+        "@wafer/auth" | "@wafer/iam" => {
+            if get("degraded_mode") == Some("allow_all") {
+                let dev = get("allow_insecure_dev_mode").is_some_and(|s| s == "true" || s == "1");
+                out.push(if dev {
+                    Warning::new(
+                        block,
+                        Some("degraded_mode"),
+                        "degraded_allow_all",
+                        "degraded_mode allow_all lets every request through when services are \
+                         missing; use only in development"
+                            .to_string(),
+                    )
+                } else {
+                    Warning::new(
+                        block,
+                        Some("degraded_mode"),
+                        "degraded_allow_all_refused",
+                        "degraded_mode allow_all is ignored without allow_insecure_dev_mode: true; \
+                         the block fails closed"
+                            .to_string(),
+                    )
+                });
+            }
+            let scope_path = get("scope_path").filter(|s| !s.trim().is_empty());
+            let scope_field = get("scope_field").filter(|s| !s.trim().is_empty());
+            if scope_path.is_some() != scope_field.is_some() {
+                out.push(Warning::new(
+                    block,
+                    Some(if scope_path.is_some() {
+                        "scope_field"
+                    } else {
+                        "scope_path"
+                    }),
+                    "iam_scope_incomplete",
+                    if scope_path.is_some() {
+                        "scope_path and scope_field must be set together; every request \
+                         gets 500"
+                    } else {
+                        "scope_path and scope_field must be set together; roles are checked \
+                         globally"
+                    }
+                    .to_string(),
+                ));
+            } else if scope_path.is_some_and(|t| !t.split('/').any(|s| s.starts_with(':'))) {
+                out.push(Warning::new(
+                    block,
+                    Some("scope_path"),
+                    "invalid_value",
+                    "scope_path needs a :name segment naming the scope; every request \
+                     is denied"
+                        .to_string(),
+                ));
+            }
+        }
+        "@wafer/rate-limit" => {
+            if get("write_window_seconds").is_some() && get("write_max_requests").is_none() {
+                out.push(Warning::new(
+                    block,
+                    Some("write_window_seconds"),
+                    "write_window_unused",
+                    "write_window_seconds has no effect without write_max_requests".to_string(),
+                ));
+            }
+            // Invalid JSON is already reported against the descriptor
+            let raw = get("tenant_limits").filter(|s| serde_json::from_str::<Value>(s).is_ok());
+            if let Some(Err(e)) = raw.map(TenantLimit::parse_map) {
+                out.push(Warning::new(
+                    block,
+                    Some("tenant_limits"),
+                    "invalid_value",
+                    e,
+                ));
+            }
+            let raw = get("route_limits").filter(|s| serde_json::from_str::<Value>(s).is_ok());
+            if let Some(Err(e)) = raw.map(RouteLimits::compile) {
+                out.push(Warning::new(
+                    block,
+                    Some("route_limits"),
+                    "invalid_value",
+                    e,
+                ));
+            }
+        }
+        "@wafer/deprecation" => {
+            // Invalid JSON is already reported against the descriptor
+            let raw = get("deprecation_rules").filter(|s| serde_json::from_str::<Value>(s).is_ok());
+            if let Some(Err(e)) = raw.map(DeprecationRule::parse_list) {
+                out.push(Warning::new(
+                    block,
+                    Some("deprecation_rules"),
+                    "invalid_value",
+                    e,
+                ));
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use serde_json::json;
+
+    /// The (code, key) of each warning for `block` with `config`.
+    fn warnings(block: &str, config: Value) -> Vec<(&'static str, Option<String>)> {
+        check_config(block, config.as_object().unwrap())
+            .into_iter()
+            .map(|w| (w.code, w.key))
+            .collect()
+    }
+
+    fn has(found: &[(&'static str, Option<String>)], code: &str, key: &str) -> bool {
+        found
+            .iter()
+            .any(|(c, k)| *c == code && k.as_deref() == Some(key))
+    }
+
+    #[test]
+    fn keys_and_values_are_checked_against_the_descriptor() {
+        let found = warnings(
+            "@wafer/rate-limit",
+            json!({"max_request": "10", "window_seconds": "soon", "route_limits": "[{"}),
+        );
+        assert!(has(&found, "unknown_key", "max_request"));
+        assert!(has(&found, "invalid_value", "window_seconds"));
+        assert!(has(&found, "invalid_value", "route_limits"));
+        assert_eq!(found.len(), 3);
+
+        let found = warnings("@wafer/iam", json!({"db_error_policy": "maybe"}));
+        assert!(has(&found, "invalid_value", "db_error_policy"));
+        assert!(warnings("@app/custom", json!({"anything": "goes"})).is_empty());
+    }
+
+    #[test]
+    fn reflected_wildcard_cors_with_credentials_is_reported() {
+        let policies = json!({"https://*.example.com": {"credentials": true}}).to_string();
+        let found = warnings("@wafer/cors", json!({ "origin_policies": policies }));
+        assert!(has(&found, "cors_wildcard_credentials", "origin_policies"));
+
+        let listed = json!({
+            "allowed_origins": "https://app.example.com",
+            "origin_policies": policies,
+        });
+        assert!(warnings("@wafer/cors", listed).is_empty());
+
+        let found = warnings("@wafer/cors", json!({"cors_methods_map": "/a=GET"}));
+        assert!(has(&found, "cors_methods_map_unused", "cors_methods_map"));
+    }
+
+    #[test]
+    fn weak_hsts_is_reported() {
+        let found = warnings(
+            "@wafer/security-headers",
+            json!({"hsts": "includeSubDomains"}),
+        );
+        assert!(has(&found, "hsts_without_max_age", "hsts"));
+        let found = warnings(
+            "@wafer/security-headers",
+            json!({"hsts": "max-age=63072000; preload"}),
+        );
+        assert!(has(&found, "hsts_preload_without_subdomains", "hsts"));
+        assert!(warnings("@wafer/security-headers", json!({})).is_empty());
+    }
+
+    #[test]
+    fn missing_web_roots_and_releases_are_reported() {
+        let found = warnings("@wafer/web", json!({"web_root": "/nonexistent/wafer/root"}));
+        assert!(has(&found, "web_root_missing", "web_root"));
+
+        let root = TempDir::new().with_file("index.html", b"");
+        let config = json!({"web_root": root.path_str(), "web_manifest": "manifest.json"});
+        let found = warnings("@wafer/web", config);
+        assert!(has(&found, "web_manifest_missing", "web_manifest"));
+
+        let config = json!({"web_root": root.path_str(), "web_root_mode": "versioned"});
+        let found = warnings("@wafer/web", config);
+        assert!(has(&found, "web_release_missing", "web_root"));
+
+        let root = TempDir::new().with_file("r1/index.html", b"");
+        web::activate_release(root.path(), "r1").unwrap();
+        let config = json!({"web_root": root.path_str(), "web_root_mode": "versioned"});
+        assert!(warnings("@wafer/web", config).is_empty());
+    }
+
+    #[test]
+    fn risky_auth_and_scope_settings_are_reported() {
+        let found = warnings("@wafer/auth", json!({"degraded_mode": "allow_all"}));
+        assert!(has(&found, "degraded_allow_all_refused", "degraded_mode"));
+
+        let found = warnings("@wafer/iam", json!({"scope_path": "/tenants/:tenant_id"}));
+        assert!(has(&found, "iam_scope_incomplete", "scope_field"));
+        let found = warnings(
+            "@wafer/iam",
+            json!({"scope_path": "/tenants", "scope_field": "tenant_id"}),
+        );
+        assert!(has(&found, "invalid_value", "scope_path"));
+
+        let found = warnings("@wafer/rate-limit", json!({"write_window_seconds": "10"}));
+        assert!(has(&found, "write_window_unused", "write_window_seconds"));
+    }
+
+    #[test]
+    fn chain_warnings_name_their_chain() {
+        let def = json!({
+            "id": "app",
+            "root": {
+                "block": "@wafer/cors",
+                "next": [{"block": "@wafer/rate-limit", "config": {"bogus": "1"}}],
+            },
+        });
+        let found = check_chain(&def);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].chain, "app");
+        assert_eq!(found[0].block, "@wafer/rate-limit");
+        assert_eq!(
+            found[0].to_string(),
+            "app: @wafer/rate-limit: unknown config key \"bogus\" [unknown_key]"
+        );
+    }
+}
@@ -0,0 +1,920 @@
+//! Test support for apps and blocks built on wafer-core (feature `test-util`).
+//!
+//! `SimulatedResponse` collects what a block or chain result would send, so
+//! tests can check status, body and headers without a transport, and the
+//! `assert_*` helpers catch header regressions before production scans do:
+//!
+//! ```ignore
+//! let resp = SimulatedResponse::from_result(&block.handle(&ctx, &mut msg));
+//! assert_security_headers(&resp);
+//! assert_no_header(&resp, "Server");
+//! ```
+//!
+//! `MockDatabase` and `MockCrypto` implement wafer-run's `DatabaseService`
+//! and `CryptoService` in memory, with the behavior auth and iam depend on:
+//! `Equal` filters ANDed together with a `limit`, HMAC-SHA256 hashing, and
+//! HS256 tokens signed with `TEST_KEY`. `MockServices` bundles them and
+//! `MockContext` hands them, with per-node config, to a block as its
+//! `Context`; `MockRequest` builds the request:
+//!
+//! ```ignore
+//! let db = MockDatabase::new().with_row("iam_user_roles", json!({"user_id": "u1", "role": "admin"}));
+//! let ctx = MockContext::new()
+//!     .with_config("role", "admin")
+//!     .with_services(MockServices::new().with_database(db).with_crypto());
+//! let mut msg = MockRequest::get("/admin").meta(meta::AUTH_USER_ID, "u1").build();
+//! let resp = SimulatedResponse::from_result(&IAMBlock::new().handle(&ctx, &mut msg));
+//! ```
+//!
+//! `ChainHarness` runs chain templates (or an app's own `ChainDef`s) end to
+//! end over those mocks, and `run_chain` runs one request through a
+//! standard template:
+//!
+//! ```ignore
+//! let resp = run_chain("http-infra", MockRequest::get("/").build());
+//! assert_security_headers(&resp);
+//! ```
+//!
+//! `ManualClock` stands in for the system clock in blocks that take a
+//! `Clock`, so windows, expiry and lockouts can be stepped through:
+//!
+//! ```ignore
+//! let clock = Arc::new(ManualClock::new());
+//! let block = RateLimitBlock::new().with_clock(clock.clone());
+//! // ... exhaust the window ...
+//! clock.advance(Duration::from_secs(60));
+//! ```
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use wafer_run::services::crypto::{CryptoError, CryptoService};
+use wafer_run::services::database::{
+    DatabaseError, DatabaseService, FilterOp, ListOptions, Record, RecordList,
+};
+use wafer_run::services::Services;
+use wafer_run::{
+    Action, Block, ChainDef, Context, LifecycleEvent, LifecycleType, Message, Result_,
+};
+
+use crate::blocks::{self, hooks};
+use crate::chains::{self, ChainOverrides};
+use crate::clock::Clock;
+use crate::meta;
+
+/// Headers `assert_security_headers` requires, as SecurityHeadersBlock sets
+/// them by default.
+pub const SECURITY_HEADERS: &[&str] = &[
+    "X-Content-Type-Options",
+    "X-Frame-Options",
+    "Referrer-Policy",
+    "Permissions-Policy",
+];
+
+/// The status, body and response headers a result would produce.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatedResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+    /// Response headers by name as set, from `resp.header.*` meta.
+    pub headers: BTreeMap<String, String>,
+}
+
+impl SimulatedResponse {
+    pub fn from_result(result: &Result_) -> Self {
+        let mut headers = BTreeMap::new();
+        let mut collect = |m: &std::collections::HashMap<String, String>| {
+            for (key, value) in m {
+                if let Some(name) = key.strip_prefix(meta::RESP_HEADER_PREFIX) {
+                    headers.insert(name.to_string(), value.clone());
+                }
+            }
+        };
+        if let Some(msg) = &result.message {
+            collect(&msg.meta);
+        }
+        // The response's own headers win over those left on the message
+        if let Some(resp) = &result.response {
+            collect(&resp.meta);
+        }
+        Self {
+            status: hooks::result_status(result),
+            body: result
+                .response
+                .as_ref()
+                .map(|r| r.data.clone())
+                .unwrap_or_default(),
+            headers,
+        }
+    }
+
+    /// A header's value, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The body as (lossy) UTF-8.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// The body parsed as JSON, e.g. to read an error envelope's `code`.
+    pub fn json(&self) -> Option<serde_json::Value> {
+        serde_json::from_slice(&self.body).ok()
+    }
+
+    /// The `error.code` of a `CoreError` body, if any.
+    pub fn error_code(&self) -> Option<String> {
+        self.json()?
+            .get("error")?
+            .get("code")?
+            .as_str()
+            .map(|s| s.to_string())
+    }
+}
+
+/// Panic unless every header in `SECURITY_HEADERS` is present.
+#[track_caller]
+pub fn assert_security_headers(resp: &SimulatedResponse) {
+    let missing: Vec<&str> = SECURITY_HEADERS
+        .iter()
+        .copied()
+        .filter(|h| resp.header(h).is_none())
+        .collect();
+    assert!(
+        missing.is_empty(),
+        "missing security headers {:?}; response headers: {:?}",
+        missing,
+        resp.headers
+    );
+}
+
+/// Panic if `name` is present.
+#[track_caller]
+pub fn assert_no_header(resp: &SimulatedResponse, name: &str) {
+    if let Some(value) = resp.header(name) {
+        panic!("unexpected header {}: {}", name, value);
+    }
+}
+
+/// Panic unless `name` is present with `value`.
+#[track_caller]
+pub fn assert_header(resp: &SimulatedResponse, name: &str, value: &str) {
+    assert_eq!(
+        resp.header(name),
+        Some(value),
+        "header {}; response headers: {:?}",
+        name,
+        resp.headers
+    );
+}
+
+/// Panic unless the response has `status` and, when given, error `code`.
+#[track_caller]
+pub fn assert_status(resp: &SimulatedResponse, status: u16, code: Option<&str>) {
+    assert_eq!(
+        resp.status,
+        status,
+        "status; body: {}",
+        String::from_utf8_lossy(&resp.body)
+    );
+    if let Some(code) = code {
+        assert_eq!(resp.error_code().as_deref(), Some(code), "error code");
+    }
+}
+
+/// Key `MockCrypto` signs and hashes with.
+pub const TEST_KEY: &[u8] = b"wafer-core-test-key";
+
+/// Meta keys `MockRequest` writes for what `Message::path`, `query` and
+/// `remote_addr` read, as the HTTP transport sets them.
+pub const REQ_PATH: &str = "req.resource";
+pub const REQ_QUERY_PREFIX: &str = "req.query.";
+pub const REQ_REMOTE_ADDR: &str = "http.remote_addr";
+
+/// A synthetic HTTP request, built up and turned into a `Message`:
+///
+/// ```ignore
+/// let msg = MockRequest::get("/admin").header("Authorization", &bearer).build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+    meta: Vec<(String, String)>,
+}
+
+impl MockRequest {
+    pub fn new(method: &str, path: &str) -> Self {
+        Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: Vec::new(),
+            meta: vec![(REQ_REMOTE_ADDR.to_string(), "192.0.2.1".to_string())],
+        }
+    }
+
+    pub fn get(path: &str) -> Self {
+        Self::new("GET", path)
+    }
+
+    pub fn post(path: &str) -> Self {
+        Self::new("POST", path)
+    }
+
+    pub fn header(self, name: &str, value: &str) -> Self {
+        self.meta(&format!("{}{}", meta::HTTP_HEADER_PREFIX, name), value)
+    }
+
+    /// Add a cookie to the `Cookie` header.
+    pub fn cookie(mut self, name: &str, value: &str) -> Self {
+        let key = format!("{}Cookie", meta::HTTP_HEADER_PREFIX);
+        let pair = format!("{}={}", name, value);
+        match self.meta.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = format!("{}; {}", v, pair),
+            None => self.meta.push((key, pair)),
+        }
+        self
+    }
+
+    pub fn query(self, name: &str, value: &str) -> Self {
+        self.meta(&format!("{}{}", REQ_QUERY_PREFIX, name), value)
+    }
+
+    /// The peer address (default `192.0.2.1`).
+    pub fn remote_addr(self, addr: &str) -> Self {
+        self.meta(REQ_REMOTE_ADDR, addr)
+    }
+
+    pub fn body(mut self, body: &[u8]) -> Self {
+        self.body = body.to_vec();
+        self
+    }
+
+    /// Set any meta key, replacing an earlier value.
+    pub fn meta(mut self, key: &str, value: &str) -> Self {
+        self.meta.retain(|(k, _)| k != key);
+        self.meta.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn build(self) -> Message {
+        let mut msg = Message::new("http.request", self.body);
+        msg.set_meta(meta::HTTP_METHOD, &self.method);
+        msg.set_meta(REQ_PATH, &self.path);
+        for (key, value) in &self.meta {
+            msg.set_meta(key, value);
+        }
+        msg
+    }
+}
+
+/// In-memory tables honoring the `ListOptions` subset the blocks use:
+/// `Equal` filters ANDed together, plus `limit`.
+#[derive(Debug, Default)]
+pub struct MockDatabase {
+    tables: parking_lot::Mutex<HashMap<String, Vec<Record>>>,
+    failing: parking_lot::Mutex<HashSet<String>>,
+    next_id: AtomicU64,
+}
+
+impl MockDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seed a row, builder style.
+    pub fn with_row(self, table: &str, data: serde_json::Value) -> Self {
+        self.insert(table, data);
+        self
+    }
+
+    /// Make every operation on `table` fail, as an unreachable database would.
+    pub fn with_failing(self, table: &str) -> Self {
+        self.set_failing(table, true);
+        self
+    }
+
+    pub fn set_failing(&self, table: &str, failing: bool) {
+        let mut set = self.failing.lock();
+        if failing {
+            set.insert(table.to_string());
+        } else {
+            set.remove(table);
+        }
+    }
+
+    /// Add a row from a JSON object; an `id` field becomes the row id.
+    pub fn insert(&self, table: &str, data: serde_json::Value) -> Record {
+        let data = match data {
+            serde_json::Value::Object(map) => map.into_iter().collect(),
+            _ => HashMap::new(),
+        };
+        self.store(table, data)
+    }
+
+    /// Every row of `table`, in insertion order.
+    pub fn rows(&self, table: &str) -> Vec<Record> {
+        self.tables.lock().get(table).cloned().unwrap_or_default()
+    }
+
+    fn store(&self, table: &str, data: HashMap<String, serde_json::Value>) -> Record {
+        let id = data
+            .get("id")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| (self.next_id.fetch_add(1, Ordering::Relaxed) + 1).to_string());
+        let record = Record { id, data };
+        self.tables
+            .lock()
+            .entry(table.to_string())
+            .or_default()
+            .push(record.clone());
+        record
+    }
+
+    fn check(&self, table: &str) -> Result<(), DatabaseError> {
+        if self.failing.lock().contains(table) {
+            return Err(DatabaseError::Internal(format!(
+                "mock table {} is failing",
+                table
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl DatabaseService for MockDatabase {
+    fn get(&self, table: &str, id: &str) -> Result<Record, DatabaseError> {
+        self.check(table)?;
+        self.tables
+            .lock()
+            .get(table)
+            .and_then(|rows| rows.iter().find(|r| r.id == id))
+            .cloned()
+            .ok_or(DatabaseError::NotFound)
+    }
+
+    /// Rows matching every filter, at most `limit` (when positive). Only
+    /// `Equal` is supported; any other operator matches nothing.
+    fn list(&self, table: &str, opts: &ListOptions) -> Result<RecordList, DatabaseError> {
+        self.check(table)?;
+        let tables = self.tables.lock();
+        let rows = tables.get(table).map(|r| r.as_slice()).unwrap_or_default();
+        let matching = rows.iter().filter(|row| {
+            opts.filters.iter().all(|f| match f.operator {
+                FilterOp::Equal => row.data.get(&f.field) == Some(&f.value),
+                _ => false,
+            })
+        });
+        let records: Vec<Record> = if opts.limit > 0 {
+            matching.take(opts.limit as usize).cloned().collect()
+        } else {
+            matching.cloned().collect()
+        };
+        Ok(RecordList {
+            total_count: records.len() as i64,
+            records,
+            ..Default::default()
+        })
+    }
+
+    fn create(
+        &self,
+        table: &str,
+        data: HashMap<String, serde_json::Value>,
+    ) -> Result<Record, DatabaseError> {
+        self.check(table)?;
+        Ok(self.store(table, data))
+    }
+
+    /// Merge `data` into row `id`.
+    fn update(
+        &self,
+        table: &str,
+        id: &str,
+        data: HashMap<String, serde_json::Value>,
+    ) -> Result<Record, DatabaseError> {
+        self.check(table)?;
+        let mut tables = self.tables.lock();
+        let row = tables
+            .get_mut(table)
+            .and_then(|rows| rows.iter_mut().find(|r| r.id == id))
+            .ok_or(DatabaseError::NotFound)?;
+        row.data.extend(data);
+        Ok(row.clone())
+    }
+
+    fn delete(&self, table: &str, id: &str) -> Result<(), DatabaseError> {
+        self.check(table)?;
+        let mut tables = self.tables.lock();
+        let rows = tables.get_mut(table).ok_or(DatabaseError::NotFound)?;
+        let before = rows.len();
+        rows.retain(|r| r.id != id);
+        if rows.len() == before {
+            return Err(DatabaseError::NotFound);
+        }
+        Ok(())
+    }
+}
+
+/// Deterministic crypto keyed by `TEST_KEY`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MockCrypto;
+
+impl MockCrypto {
+    /// Hex HMAC-SHA256 of `input`, as stored in `api_keys.key_hash`.
+    pub fn digest(input: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(TEST_KEY).expect("HMAC accepts any key length");
+        mac.update(input.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// An HS256 token carrying `claims` exactly as given, with no `exp`
+    /// added, e.g. to test claim variations.
+    pub fn token(claims: serde_json::Value) -> String {
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(TEST_KEY),
+        )
+        .expect("claims serialize")
+    }
+}
+
+impl CryptoService for MockCrypto {
+    fn hash(&self, input: &str) -> Result<String, CryptoError> {
+        Ok(Self::digest(input))
+    }
+
+    fn compare_hash(&self, input: &str, hash: &str) -> Result<(), CryptoError> {
+        if Self::digest(input) == hash {
+            Ok(())
+        } else {
+            Err(CryptoError::Other("hash mismatch".to_string()))
+        }
+    }
+
+    /// An HS256 token carrying `claims`, expiring after `ttl` unless the
+    /// claims set `exp` themselves.
+    fn sign(
+        &self,
+        claims: HashMap<String, serde_json::Value>,
+        ttl: Duration,
+    ) -> Result<String, CryptoError> {
+        let mut claims: serde_json::Map<String, serde_json::Value> = claims.into_iter().collect();
+        let exp = chrono::Utc::now().timestamp() + ttl.as_secs() as i64;
+        claims
+            .entry("exp")
+            .or_insert_with(|| serde_json::Value::from(exp));
+        Ok(Self::token(serde_json::Value::Object(claims)))
+    }
+
+    /// The claims of a token signed with `TEST_KEY`, if it is valid and
+    /// unexpired.
+    fn verify(&self, token: &str) -> Result<HashMap<String, serde_json::Value>, CryptoError> {
+        let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
+        validation.validate_aud = false;
+        jsonwebtoken::decode::<HashMap<String, serde_json::Value>>(
+            token,
+            &jsonwebtoken::DecodingKey::from_secret(TEST_KEY),
+            &validation,
+        )
+        .map(|data| data.claims)
+        .map_err(|e| CryptoError::Other(e.to_string()))
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        Ok(plaintext.to_vec())
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        Ok(ciphertext.to_vec())
+    }
+
+    /// `n` bytes counting up from a per-call seed: unique per call, not random.
+    fn random_bytes(&self, n: usize) -> Result<Vec<u8>, CryptoError> {
+        static SEED: AtomicU64 = AtomicU64::new(0);
+        let seed = SEED.fetch_add(1, Ordering::Relaxed);
+        Ok((0..n as u64)
+            .map(|i| (seed.wrapping_mul(31).wrapping_add(i) % 256) as u8)
+            .collect())
+    }
+}
+
+/// Builds the `Services` a `MockContext` hands to blocks.
+#[derive(Debug, Default, Clone)]
+pub struct MockServices {
+    database: Option<Arc<MockDatabase>>,
+    crypto: bool,
+}
+
+impl MockServices {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_database(mut self, db: MockDatabase) -> Self {
+        self.database = Some(Arc::new(db));
+        self
+    }
+
+    pub fn with_crypto(mut self) -> Self {
+        self.crypto = true;
+        self
+    }
+
+    /// The mock database, to seed or inspect while tests run.
+    pub fn database(&self) -> Option<&Arc<MockDatabase>> {
+        self.database.as_ref()
+    }
+
+    pub fn build(&self) -> Services {
+        Services {
+            database: self
+                .database
+                .clone()
+                .map(|db| db as Arc<dyn DatabaseService>),
+            crypto: self
+                .crypto
+                .then(|| Arc::new(MockCrypto) as Arc<dyn CryptoService>),
+            ..Default::default()
+        }
+    }
+}
+
+/// Per-node config plus the mock services, as a block sees them through
+/// `Context`.
+pub struct MockContext {
+    config: HashMap<String, String>,
+    services: Option<Arc<Services>>,
+    mocks: MockServices,
+}
+
+impl MockContext {
+    /// A context with no config and no services.
+    pub fn new() -> Self {
+        Self {
+            config: HashMap::new(),
+            services: None,
+            mocks: MockServices::new(),
+        }
+    }
+
+    pub fn with_config(mut self, key: &str, value: &str) -> Self {
+        self.config.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Node config from a chain node's `config` object; non-string values
+    /// are passed as their JSON text.
+    pub fn with_node_config(mut self, config: &serde_json::Value) -> Self {
+        if let Some(map) = config.as_object() {
+            for (key, value) in map {
+                let value = match value {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                self.config.insert(key.clone(), value);
+            }
+        }
+        self
+    }
+
+    pub fn with_services(mut self, mocks: MockServices) -> Self {
+        self.services = Some(Arc::new(mocks.build()));
+        self.mocks = mocks;
+        self
+    }
+
+    /// Shorthand for `with_services` with only a database.
+    pub fn with_database(self, db: MockDatabase) -> Self {
+        let mocks = self.mocks.clone().with_database(db);
+        self.with_services(mocks)
+    }
+
+    /// Shorthand for `with_services` adding `MockCrypto`.
+    pub fn with_crypto(self) -> Self {
+        let mocks = self.mocks.clone().with_crypto();
+        self.with_services(mocks)
+    }
+
+    /// The mock database, to seed or inspect while tests run.
+    pub fn database(&self) -> Option<&Arc<MockDatabase>> {
+        self.mocks.database()
+    }
+}
+
+impl Default for MockContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Context for MockContext {
+    fn config_get(&self, key: &str) -> Option<&str> {
+        self.config.get(key).map(|s| s.as_str())
+    }
+
+    fn services(&self) -> Option<&Services> {
+        self.services.as_deref()
+    }
+}
+
+/// Runs chain definitions over blocks the way the runtime does, with mock
+/// services and without a transport.
+///
+/// Each node's block gets a `MockContext` with the node's `config` and the
+/// harness's services. A block continuing passes its message on to the
+/// node's `next`; anything else ends the chain with that result, as
+/// `on_error: stop` does. `{"chain": id}` nodes run the named chain first.
+/// Blocks are created once per harness (wafer-core blocks by name through
+/// `new_block`, others with `with_block` or `with_registered_block`) and
+/// started with the config of the first node that runs them, so state such
+/// as rate-limit windows carries across requests.
+///
+/// ```ignore
+/// let harness = ChainHarness::new().with_services(MockServices::new().with_crypto());
+/// let resp = harness.run("auth-pipe", MockRequest::get("/api").build());
+/// assert_status(&resp, 401, Some("unauthorized"));
+/// ```
+pub struct ChainHarness {
+    chains: HashMap<String, serde_json::Value>,
+    services: MockServices,
+    blocks: parking_lot::Mutex<HashMap<String, (Arc<dyn Block>, bool)>>,
+}
+
+impl ChainHarness {
+    /// A harness with the standard chain templates.
+    pub fn new() -> Self {
+        Self::with_overrides(&ChainOverrides::new())
+    }
+
+    /// A harness with the standard chain templates, overridden as
+    /// `chains::register_chains_with` would.
+    pub fn with_overrides(overrides: &ChainOverrides) -> Self {
+        let chains = chains::resolved_templates(overrides)
+            .expect("chain templates resolve")
+            .into_iter()
+            .map(|(id, def)| (id.to_string(), def))
+            .collect();
+        Self {
+            chains,
+            services: MockServices::new(),
+            blocks: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_services(mut self, services: MockServices) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// Add (or replace) a chain definition, e.g. an app chain ending in a
+    /// handler added with `with_block`.
+    pub fn with_chain(mut self, def: &ChainDef) -> Self {
+        let def = serde_json::to_value(def).expect("chain definitions serialize");
+        let id = def["id"].as_str().unwrap_or("").to_string();
+        self.chains.insert(id, def);
+        self
+    }
+
+    /// Run `block` for nodes naming `name` as the runtime runs an app block
+    /// registered with `Wafer::register_block`: unwrapped, so without
+    /// tracing or response hooks.
+    pub fn with_block(self, name: &str, block: Arc<dyn Block>) -> Self {
+        self.blocks.lock().insert(name.to_string(), (block, false));
+        self
+    }
+
+    /// Run `block` for nodes naming `name`, wrapped as `blocks::register_as`
+    /// wraps it.
+    pub fn with_registered_block(self, name: &str, block: Arc<dyn Block>) -> Self {
+        self.with_block(name, blocks::traced(name, block))
+    }
+
+    /// The services blocks see, to seed or inspect the mock database.
+    pub fn services(&self) -> &MockServices {
+        &self.services
+    }
+
+    /// Run `request` through chain `chain_id` and collect the response.
+    pub fn run(&self, chain_id: &str, request: Message) -> SimulatedResponse {
+        SimulatedResponse::from_result(&self.run_result(chain_id, request))
+    }
+
+    /// Run `request` through chain `chain_id`, returning the final result.
+    pub fn run_result(&self, chain_id: &str, request: Message) -> Result_ {
+        let root = self
+            .chains
+            .get(chain_id)
+            .unwrap_or_else(|| panic!("unknown chain {}", chain_id))["root"]
+            .clone();
+        self.run_node(&root, request)
+    }
+
+    /// Send lifecycle Stop to every started block, as runtime shutdown does.
+    pub fn stop(&self) {
+        let started: Vec<Arc<dyn Block>> = self
+            .blocks
+            .lock()
+            .values()
+            .filter(|(_, started)| *started)
+            .map(|(block, _)| block.clone())
+            .collect();
+        let ctx = self.context(&serde_json::Value::Null);
+        for block in started {
+            let _ = block.lifecycle(&ctx, lifecycle_event(LifecycleType::Stop));
+        }
+    }
+
+    fn context(&self, config: &serde_json::Value) -> MockContext {
+        MockContext::new()
+            .with_node_config(config)
+            .with_services(self.services.clone())
+    }
+
+    /// The block for `name`, created and started on first use.
+    fn block(&self, name: &str, ctx: &MockContext) -> Arc<dyn Block> {
+        let mut blocks = self.blocks.lock();
+        let entry = blocks.entry(name.to_string()).or_insert_with(|| {
+            let block = crate::new_block(name)
+                .unwrap_or_else(|| panic!("unknown block {}; add it with with_block", name));
+            (block, false)
+        });
+        if !entry.1 {
+            entry.1 = true;
+            if let Err(e) = entry
+                .0
+                .lifecycle(ctx, lifecycle_event(LifecycleType::Start))
+            {
+                panic!("{} failed to start: {:?}", name, e);
+            }
+        }
+        entry.0.clone()
+    }
+
+    fn run_node(&self, node: &serde_json::Value, mut msg: Message) -> Result_ {
+        let config = node.get("config").cloned().unwrap_or_default();
+        let result = if let Some(name) = node.get("block").and_then(|b| b.as_str()) {
+            let ctx = self.context(&config);
+            let block = self.block(name, &ctx);
+            block.handle(&ctx, &mut msg)
+        } else if let Some(chain) = node.get("chain").and_then(|c| c.as_str()) {
+            self.run_result(chain, msg.clone())
+        } else {
+            panic!("chain node names neither a block nor a chain: {}", node);
+        };
+        if !matches!(result.action, Action::Continue) {
+            return result;
+        }
+        let next = match node.get("next").and_then(|n| n.as_array()) {
+            Some(next) if !next.is_empty() => next,
+            _ => return result,
+        };
+        let mut msg = result.message.unwrap_or(msg);
+        let mut last = None;
+        for child in next {
+            let result = self.run_node(child, msg.clone());
+            if !matches!(result.action, Action::Continue) {
+                return result;
+            }
+            if let Some(m) = &result.message {
+                msg = m.clone();
+            }
+            last = Some(result);
+        }
+        last.expect("next is not empty")
+    }
+}
+
+impl Default for ChainHarness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn lifecycle_event(event_type: LifecycleType) -> LifecycleEvent {
+    LifecycleEvent {
+        event_type,
+        data: Vec::new(),
+    }
+}
+
+/// Run `request` through the standard chain template `chain_id` with fresh
+/// blocks and no services.
+pub fn run_chain(chain_id: &str, request: Message) -> SimulatedResponse {
+    ChainHarness::new().run(chain_id, request)
+}
+
+/// A directory under the system temp dir, removed on drop, e.g. a web root.
+#[derive(Debug)]
+pub struct TempDir {
+    path: std::path::PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "wafer-core-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).expect("create temp dir");
+        Self { path }
+    }
+
+    /// Write `contents` to `rel`, creating parent directories, builder style.
+    pub fn with_file(self, rel: &str, contents: &[u8]) -> Self {
+        self.write(rel, contents);
+        self
+    }
+
+    pub fn write(&self, rel: &str, contents: &[u8]) {
+        let file = self.path.join(rel);
+        if let Some(parent) = file.parent() {
+            std::fs::create_dir_all(parent).expect("create parent dirs");
+        }
+        std::fs::write(file, contents).expect("write temp file");
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// The path as a string, e.g. for `web_root` config.
+    pub fn path_str(&self) -> String {
+        self.path.to_string_lossy().into_owned()
+    }
+}
+
+impl Default for TempDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug)]
+pub struct ManualClock {
+    instant: Instant,
+    utc: chrono::DateTime<chrono::Utc>,
+    offset: parking_lot::Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// A clock reading the current time until advanced.
+    pub fn new() -> Self {
+        Self::starting_at(chrono::Utc::now())
+    }
+
+    /// A clock whose wall-clock time starts at `utc`.
+    pub fn starting_at(utc: chrono::DateTime<chrono::Utc>) -> Self {
+        Self {
+            instant: Instant::now(),
+            utc,
+            offset: parking_lot::Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Move both readings forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        *self.offset.lock() += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_instant(&self) -> Instant {
+        self.instant + *self.offset.lock()
+    }
+
+    fn now_utc(&self) -> chrono::DateTime<chrono::Utc> {
+        let offset = *self.offset.lock();
+        self.utc + chrono::Duration::from_std(offset).unwrap_or(chrono::Duration::MAX)
+    }
+}
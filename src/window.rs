@@ -0,0 +1,508 @@
+//! Shared windowed counting.
+//!
+//! `WindowedCounter` counts events per key over a time window, either in
+//! fixed windows or a sliding window, and is what rate limiting and any other
+//! "N events per period" check should build on. Each key holds one
+//! independent counter per `Lane`, so budgets counted side by side (such as
+//! reads and writes) share one map entry.
+//!
+//! Keys are spread over `DEFAULT_SHARDS` separately locked maps by hash, so
+//! concurrent events for different keys rarely wait on each other; limiters
+//! count every request, and one lock around all keys serializes them.
+
+use parking_lot::Mutex;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+/// Number of separately locked maps a counter spreads its keys over.
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// How a `WindowedCounter` attributes events to windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowKind {
+    /// Counts reset when a window of `window` length, starting at the key's
+    /// first event, has fully elapsed: `[start, start + window)`.
+    Fixed,
+    /// Consecutive fixed windows, with the previous window's count weighted
+    /// by how much of it still overlaps the sliding window ending now. This
+    /// smooths the burst a fixed window allows at its boundary.
+    Sliding,
+}
+
+/// Result of counting an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowCount {
+    /// Events in the window, including the one just counted.
+    pub count: u64,
+    /// Time until the current window ends.
+    pub reset_in: Duration,
+}
+
+/// One of the independent counters each key holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    /// The lane `hit` and `peek` count in.
+    Primary,
+    Secondary,
+}
+
+impl Lane {
+    pub const ALL: [Lane; 2] = [Self::Primary, Self::Secondary];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Number of independent counters each key holds.
+pub const LANES: usize = Lane::ALL.len();
+
+#[derive(Clone, Copy)]
+struct LaneState {
+    start: Instant,
+    current: u64,
+    previous: u64,
+}
+
+struct Slot {
+    lanes: [LaneState; LANES],
+    /// This slot's entry in `Shard::by_start`.
+    indexed: (Instant, u64),
+}
+
+impl Slot {
+    /// Start of the most recently begun window across lanes.
+    fn latest_start(&self) -> Instant {
+        self.lanes
+            .iter()
+            .map(|l| l.start)
+            .max()
+            .expect("LANES is non-zero")
+    }
+}
+
+/// One locked map of slots, indexed by their latest window start so expired
+/// and oldest keys are found without scanning every key.
+#[derive(Default)]
+struct Shard {
+    slots: HashMap<String, Slot>,
+    by_start: BTreeMap<(Instant, u64), String>,
+    seq: u64,
+}
+
+impl Shard {
+    fn insert(&mut self, key: &str, now: Instant) -> &mut Slot {
+        self.seq += 1;
+        let indexed = (now, self.seq);
+        self.by_start.insert(indexed, key.to_string());
+        self.slots.entry(key.to_string()).or_insert(Slot {
+            lanes: [LaneState {
+                start: now,
+                current: 0,
+                previous: 0,
+            }; LANES],
+            indexed,
+        })
+    }
+
+    /// Move `key` in the index after its lanes advanced.
+    fn reindex(&mut self, key: &str) {
+        let Some(slot) = self.slots.get_mut(key) else {
+            return;
+        };
+        let start = slot.latest_start();
+        if slot.indexed.0 != start {
+            self.by_start.remove(&slot.indexed);
+            self.seq += 1;
+            slot.indexed = (start, self.seq);
+            self.by_start.insert(slot.indexed, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(slot) = self.slots.remove(key) {
+            self.by_start.remove(&slot.indexed);
+        }
+    }
+
+    /// Drop the key with the oldest latest window start, if it began
+    /// `horizon` or more before `now` (or regardless, without a horizon).
+    fn pop_oldest(&mut self, horizon: Option<Duration>, now: Instant) -> bool {
+        let key = match self.by_start.first_key_value() {
+            Some(((start, _), _))
+                if horizon.is_some_and(|h| now.saturating_duration_since(*start) < h) =>
+            {
+                return false
+            }
+            Some((_, key)) => key.clone(),
+            None => return false,
+        };
+        self.remove(&key);
+        true
+    }
+
+    /// Drop every key whose windows have all expired; returns how many.
+    fn purge(&mut self, horizon: Duration, now: Instant) -> usize {
+        let mut dropped = 0;
+        while self.pop_oldest(Some(horizon), now) {
+            dropped += 1;
+        }
+        dropped
+    }
+}
+
+/// WindowedCounter is a thread-safe, bounded per-key event counter.
+///
+/// At most `max_keys` keys are tracked, split evenly across shards. When a
+/// shard is full, its expired keys are dropped first and otherwise its key
+/// with the oldest window is evicted, so memory stays bounded under key
+/// churn at the cost of forgetting the stalest key. Each shard keeps its
+/// keys ordered by window start, so eviction never scans the shard.
+pub struct WindowedCounter {
+    kind: WindowKind,
+    /// Keys kept per shard.
+    max_keys: usize,
+    shards: Vec<Mutex<Shard>>,
+    hasher: RandomState,
+}
+
+impl WindowedCounter {
+    pub fn new(kind: WindowKind, max_keys: usize) -> Self {
+        Self::with_shards(kind, max_keys, DEFAULT_SHARDS)
+    }
+
+    /// A counter over `shards` locked maps (at most `max_keys`, at least one).
+    pub fn with_shards(kind: WindowKind, max_keys: usize, shards: usize) -> Self {
+        let max_keys = max_keys.max(1);
+        let shards = shards.clamp(1, max_keys);
+        Self {
+            kind,
+            max_keys: max_keys.div_ceil(shards),
+            shards: (0..shards).map(|_| Mutex::new(Shard::default())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// The shard holding `key`.
+    fn shard(&self, key: &str) -> &Mutex<Shard> {
+        let i = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[i]
+    }
+
+    pub fn kind(&self) -> WindowKind {
+        self.kind
+    }
+
+    /// Count one event for `key` in a window of length `window`.
+    pub fn hit(&self, key: &str, window: Duration) -> WindowCount {
+        self.hit_at(key, window, Instant::now())
+    }
+
+    /// Like `hit`, at an explicit instant.
+    pub fn hit_at(&self, key: &str, window: Duration, now: Instant) -> WindowCount {
+        self.hit_lane_at(key, Lane::Primary, window, now)
+    }
+
+    /// Count one event in counter `lane` of `key`. Lanes have their own
+    /// windows; `hit` counts in `Lane::Primary`.
+    pub fn hit_lane(&self, key: &str, lane: Lane, window: Duration) -> WindowCount {
+        self.hit_lane_at(key, lane, window, Instant::now())
+    }
+
+    /// Like `hit_lane`, at an explicit instant.
+    pub fn hit_lane_at(
+        &self,
+        key: &str,
+        lane: Lane,
+        window: Duration,
+        now: Instant,
+    ) -> WindowCount {
+        self.add_lane_at(key, lane, window, 1, now)
+    }
+
+    /// Count `n` events at once in counter `lane` of `key`, e.g. to charge
+    /// a costly request more than one unit.
+    pub fn add_lane_at(
+        &self,
+        key: &str,
+        lane: Lane,
+        window: Duration,
+        n: u64,
+        now: Instant,
+    ) -> WindowCount {
+        let mut shard = self.shard(key).lock();
+        if !shard.slots.contains_key(key) {
+            if shard.slots.len() >= self.max_keys {
+                self.evict(&mut shard, window, now);
+            }
+            shard.insert(key, now);
+        }
+        let slot = shard.slots.get_mut(key).expect("slot just ensured");
+        let lane = &mut slot.lanes[lane.index()];
+        self.advance(lane, window, now);
+        lane.current += n;
+        let count = self.count_of(lane, window, now);
+        shard.reindex(key);
+        count
+    }
+
+    /// Current count for `key` without counting an event.
+    pub fn peek(&self, key: &str, window: Duration) -> u64 {
+        self.peek_at(key, window, Instant::now())
+    }
+
+    /// Like `peek`, at an explicit instant.
+    pub fn peek_at(&self, key: &str, window: Duration, now: Instant) -> u64 {
+        let mut shard = self.shard(key).lock();
+        let count = match shard.slots.get_mut(key) {
+            Some(slot) => {
+                let lane = &mut slot.lanes[Lane::Primary.index()];
+                self.advance(lane, window, now);
+                self.count_of(lane, window, now).count
+            }
+            None => return 0,
+        };
+        shard.reindex(key);
+        count
+    }
+
+    /// Forget `key`.
+    pub fn reset(&self, key: &str) {
+        self.shard(key).lock().remove(key);
+    }
+
+    /// Drop keys whose windows have fully expired in every lane; pass the
+    /// longest window the lanes use. Returns how many were dropped.
+    pub fn purge_expired(&self, window: Duration) -> usize {
+        self.purge_expired_at(window, Instant::now())
+    }
+
+    /// Like `purge_expired`, at an explicit instant.
+    /// Shards are swept one at a time, so counting proceeds in the others;
+    /// each sweep only visits the keys it drops.
+    pub fn purge_expired_at(&self, window: Duration, now: Instant) -> usize {
+        let horizon = self.horizon(window);
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().purge(horizon, now))
+            .sum()
+    }
+
+    /// Number of tracked keys.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().slots.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards
+            .iter()
+            .all(|shard| shard.lock().slots.is_empty())
+    }
+
+    /// Move a lane's window forward to the one containing `now`.
+    fn advance(&self, slot: &mut LaneState, window: Duration, now: Instant) {
+        let elapsed = now.saturating_duration_since(slot.start);
+        if elapsed < window {
+            return;
+        }
+        match self.kind {
+            WindowKind::Fixed => {
+                slot.start = now;
+                slot.previous = 0;
+            }
+            WindowKind::Sliding => {
+                // Exactly one window later the old count still overlaps;
+                // two or more windows later nothing does.
+                if elapsed < window * 2 {
+                    slot.previous = slot.current;
+                    slot.start += window;
+                } else {
+                    slot.previous = 0;
+                    slot.start = now;
+                }
+            }
+        }
+        slot.current = 0;
+    }
+
+    fn count_of(&self, slot: &LaneState, window: Duration, now: Instant) -> WindowCount {
+        let elapsed = now.saturating_duration_since(slot.start);
+        let reset_in = window.saturating_sub(elapsed);
+        let count = match self.kind {
+            WindowKind::Fixed => slot.current,
+            WindowKind::Sliding if window.is_zero() => slot.current,
+            WindowKind::Sliding => {
+                let overlap = reset_in.as_secs_f64() / window.as_secs_f64();
+                slot.current + (slot.previous as f64 * overlap).floor() as u64
+            }
+        };
+        WindowCount { count, reset_in }
+    }
+
+    /// Age after which a slot no longer contributes to any count.
+    fn horizon(&self, window: Duration) -> Duration {
+        match self.kind {
+            WindowKind::Fixed => window,
+            WindowKind::Sliding => window * 2,
+        }
+    }
+
+    /// Make room in a full shard: drop its expired keys, else its oldest.
+    fn evict(&self, shard: &mut Shard, window: Duration, now: Instant) {
+        shard.purge(self.horizon(window), now);
+        if shard.slots.len() >= self.max_keys {
+            shard.pop_oldest(None, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const W: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn fixed_windows_reset_exactly_at_the_boundary() {
+        let counter = WindowedCounter::new(WindowKind::Fixed, 100);
+        let t0 = Instant::now();
+        assert_eq!(counter.hit_at("k", W, t0).count, 1);
+        let last = counter.hit_at("k", W, t0 + W - Duration::from_nanos(1));
+        assert_eq!(last.count, 2);
+        assert_eq!(last.reset_in, Duration::from_nanos(1));
+
+        let next = counter.hit_at("k", W, t0 + W);
+        assert_eq!(next.count, 1);
+        assert_eq!(next.reset_in, W);
+    }
+
+    #[test]
+    fn sliding_windows_weight_the_previous_window() {
+        let counter = WindowedCounter::new(WindowKind::Sliding, 100);
+        let t0 = Instant::now();
+        for _ in 0..10 {
+            counter.hit_at("k", W, t0);
+        }
+        // Half of the previous window still overlaps: 5 of its 10, plus this one
+        assert_eq!(counter.hit_at("k", W, t0 + W + W / 2).count, 6);
+        // Two windows on, nothing overlaps
+        assert_eq!(counter.hit_at("k", W, t0 + W * 4).count, 1);
+    }
+
+    #[test]
+    fn lanes_count_apart() {
+        let counter = WindowedCounter::new(WindowKind::Fixed, 100);
+        let t0 = Instant::now();
+        counter.hit_lane_at("k", Lane::Secondary, W, t0);
+        counter.add_lane_at("k", Lane::Secondary, W, 4, t0);
+        assert_eq!(counter.hit_lane_at("k", Lane::Primary, W, t0).count, 1);
+        assert_eq!(counter.peek_at("k", W, t0), 1);
+        assert_eq!(counter.hit_lane_at("k", Lane::Secondary, W, t0).count, 6);
+    }
+
+    #[test]
+    fn full_shards_drop_expired_then_oldest_keys() {
+        let counter = WindowedCounter::with_shards(WindowKind::Fixed, 2, 1);
+        let t0 = Instant::now();
+        let at = |secs: u64| t0 + Duration::from_secs(secs);
+        counter.hit_at("a", W, at(0));
+        counter.hit_at("b", W, at(1));
+        // Full with nothing expired: the oldest window goes
+        counter.hit_at("c", W, at(2));
+        assert_eq!(counter.len(), 2);
+        assert_eq!(counter.peek_at("a", W, at(2)), 0);
+        assert_eq!(counter.peek_at("b", W, at(2)), 1);
+
+        // "b" has expired by now, so it goes instead of the live "c"
+        counter.hit_at("d", W, at(11));
+        assert_eq!(counter.peek_at("c", W, at(11)), 1);
+        assert_eq!(counter.len(), 2);
+
+        assert_eq!(counter.purge_expired_at(W, at(30)), 2);
+        assert!(counter.is_empty());
+    }
+
+    /// Whether a hit on `key` finishes within `wait` while this thread
+    /// holds the shard of `held`. The hit always finishes once it is released.
+    fn hit_while_held(
+        counter: &Arc<WindowedCounter>,
+        held: &str,
+        key: &str,
+        wait: Duration,
+    ) -> bool {
+        let guard = counter.shard(held).lock();
+        let (done, finished) = std::sync::mpsc::channel();
+        let worker = {
+            let counter = counter.clone();
+            let key = key.to_string();
+            std::thread::spawn(move || {
+                counter.hit(&key, W);
+                done.send(()).unwrap();
+            })
+        };
+        let proceeded = finished.recv_timeout(wait).is_ok();
+        drop(guard);
+        worker.join().unwrap();
+        proceeded
+    }
+
+    #[test]
+    fn keys_in_other_shards_never_wait_on_a_held_shard() {
+        let counter = Arc::new(WindowedCounter::new(WindowKind::Fixed, 1000));
+        let other = (0..)
+            .map(|i| format!("client-{}", i))
+            .find(|k| !std::ptr::eq(counter.shard(k), counter.shard("a")))
+            .unwrap();
+        assert!(hit_while_held(
+            &counter,
+            "a",
+            &other,
+            Duration::from_secs(5)
+        ));
+        assert_eq!(counter.peek(&other, W), 1);
+
+        // With one lock around every key, the same hit has to wait
+        let single = Arc::new(WindowedCounter::with_shards(WindowKind::Fixed, 1000, 1));
+        assert!(!hit_while_held(
+            &single,
+            "a",
+            &other,
+            Duration::from_millis(50)
+        ));
+        assert_eq!(single.peek(&other, W), 1);
+    }
+
+    #[test]
+    fn concurrent_hits_count_exactly_across_shards() {
+        const THREADS: usize = 8;
+        const KEYS: usize = 64;
+        const ROUNDS: usize = 50;
+        let counter = Arc::new(WindowedCounter::new(WindowKind::Fixed, 1000));
+        let workers: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let counter = counter.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..ROUNDS {
+                        for k in 0..KEYS {
+                            counter.hit(&format!("client-{}", k), W);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(counter.len(), KEYS);
+        for k in 0..KEYS {
+            let count = counter.peek(&format!("client-{}", k), W);
+            assert_eq!(count, (THREADS * ROUNDS) as u64);
+        }
+    }
+}